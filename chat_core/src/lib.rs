@@ -19,9 +19,49 @@ pub struct User {
     pub full_name: String,
     pub email: String,
     #[sqlx(default)]
+    #[serde(default)]
+    pub email_verified: bool,
+    #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
     pub created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// tokens issued with an older version than the user's current one are revoked
+    #[sqlx(default)]
+    #[serde(default)]
+    pub token_version: i32,
+    /// role in the workspace of `ws_id`, looked up again on every request
+    #[sqlx(default)]
+    #[serde(default)]
+    pub role: WorkspaceRole,
+}
+
+/// roles are ordered, a higher role has all the permissions of the lower ones
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    ToSchema,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    sqlx::Type,
+)]
+#[sqlx(type_name = "workspace_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    /// service identity of an API key, it can only use the chats of its key
+    Bot,
+    #[default]
+    Member,
+    Admin,
+    Owner,
 }
 
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
@@ -40,6 +80,53 @@ pub struct ChatUser {
     #[serde(alias = "fullName")]
     pub full_name: String,
     pub email: String,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// custom status, none if not set or expired
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub status: Option<UserStatus>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStatus {
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+    /// the status is cleared after this time
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "presence_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Presence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    /// when the status last changed, none if the user never connected
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// the members of a chat with an open event stream, sent when one of them joins or leaves
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatPresence {
+    pub chat_id: i64,
+    /// the member who joined or left
+    pub user_id: i64,
+    pub joined: bool,
+    /// the members connected now
+    pub online: Vec<i64>,
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
@@ -63,10 +150,30 @@ pub struct Chat {
     #[serde(alias = "wsId")]
     pub ws_id: i64,
     pub name: Option<String>,
+    /// url of the uploaded avatar, served by the files route
+    #[serde(default)]
+    pub avatar_url: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    #[serde(alias = "ownerId")]
+    pub owner_id: i64,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
+    /// preview of the latest top-level message, only set in chat listings
+    #[sqlx(default)]
+    #[serde(default, alias = "lastMessage")]
+    pub last_message: Option<String>,
+    #[sqlx(default)]
+    #[serde(default, alias = "lastMessageAt")]
+    pub last_message_at: Option<DateTime<Utc>>,
+    /// members' profiles, only set in chat listings with `expand=members`
+    #[sqlx(json, default)]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        alias = "memberDetails"
+    )]
+    pub member_details: Option<Vec<ChatUser>>,
 }
 
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
@@ -77,10 +184,244 @@ pub struct Message {
     pub chat_id: i64,
     #[serde(alias = "senderId")]
     pub sender_id: i64,
+    /// the top-level message this message replies to
+    #[serde(alias = "parentId")]
+    pub parent_id: Option<i64>,
     pub content: String,
     pub files: Vec<String>,
+    /// metadata of the uploaded files, in the order of `files`
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// users mentioned in the content with `@name` or `@id`
+    #[sqlx(default)]
+    #[serde(default)]
+    pub mentions: Vec<i64>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    /// set when the sender edited the message
+    #[serde(alias = "updatedAt")]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// set when the message is deleted, content and files are cleared in responses
+    #[serde(alias = "deletedAt")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+    #[sqlx(default)]
+    #[serde(default, alias = "replyCount")]
+    pub reply_count: i64,
+    /// members (other than the sender) who have read the message
+    #[sqlx(default)]
+    #[serde(default, alias = "readBy")]
+    pub read_by: Vec<i64>,
+    /// link previews of the urls in the content, filled in asynchronously
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub previews: Vec<LinkPreview>,
+    /// rendered-safe html of the content, only set when asked for with `format=html`
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    /// set when the message is a poll
+    #[sqlx(json, default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    /// set when the reader blocked the sender, clients may collapse the message
+    #[sqlx(skip)]
+    #[serde(
+        default,
+        alias = "senderBlocked",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub sender_blocked: bool,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Reaction {
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    pub emoji: String,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ReadState {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    #[serde(alias = "lastReadMessageId")]
+    pub last_read_message_id: i64,
+    #[serde(alias = "readAt")]
+    pub read_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Attachment {
+    pub id: i64,
+    #[serde(alias = "wsId")]
+    pub ws_id: i64,
+    #[serde(alias = "uploaderId")]
+    pub uploader_id: i64,
+    pub url: String,
+    pub hash: String,
+    pub filename: String,
+    pub size: i64,
+    pub mime: String,
+    /// downscaled versions of images, for the chat lists
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub thumbnails: Thumbnails,
+    /// duration and dimensions of audios and videos, with their rendition browsers play
+    #[sqlx(json, default)]
+    #[serde(default)]
+    pub media: MediaInfo,
+    /// files are only served once the content scanner cleared them
+    #[sqlx(default)]
+    #[serde(default, alias = "scanStatus")]
+    pub scan_status: ScanStatus,
+    /// what the scanner found in an infected file
+    #[sqlx(default)]
+    #[serde(default, alias = "scanResult", skip_serializing_if = "Option::is_none")]
+    pub scan_result: Option<String>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "scan_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    Pending,
+    #[default]
+    Clean,
+    /// the file is quarantined
+    Infected,
+    /// the scanner couldn't be reached, the file is scanned again when uploaded again
+    Failed,
+}
+
+/// urls of the thumbnails of an image, the image itself when it's already small enough
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Thumbnails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub small: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+}
+
+/// what the server found out about an audio or a video
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct MediaInfo {
+    /// in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// url of an mp4 transcoded from a file browsers may not play
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendition: Option<String>,
+}
+
+/// where the server is with an upload, told to the uploader through the notify server
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UploadProgress {
+    /// chosen by the client when it uploads
+    #[serde(alias = "uploadId")]
+    pub upload_id: String,
+    pub filename: String,
+    pub stage: UploadStage,
+    /// bytes of the request received so far
+    pub received: u64,
+    /// bytes of the whole request, when the client told
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// url of the file, once it's done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// why the upload failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadStage {
+    Receiving,
+    Scanning,
+    Thumbnailing,
+    Transcoding,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct DeliveryState {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    #[serde(alias = "lastDeliveredMessageId")]
+    pub last_delivered_message_id: i64,
+    #[serde(alias = "deliveredAt")]
+    pub delivered_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct LinkPreview {
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    #[serde(alias = "siteName")]
+    pub site_name: Option<String>,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Poll {
+    pub id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "creatorId")]
+    pub creator_id: i64,
+    pub question: String,
+    pub options: Vec<String>,
+    /// no more votes are accepted after this time
+    #[serde(alias = "expiresAt")]
+    pub expires_at: Option<DateTime<Utc>>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
+    /// number of votes of each option
+    pub votes: Vec<i64>,
 }
 
 impl User {
@@ -91,8 +432,12 @@ impl User {
             ws_name: "".to_string(),
             full_name: full_name.to_string(),
             email: email.to_string(),
+            email_verified: false,
             password_hash: None,
             created_at: Utc::now(),
+            avatar_url: None,
+            token_version: 0,
+            role: WorkspaceRole::Member,
         }
     }
 }
@@ -0,0 +1,138 @@
+use std::{net::IpAddr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{error_response, ClientIp};
+
+/// The networks the clients may connect from, everyone by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkAcl {
+    /// when not empty, only the clients of these networks are let in
+    pub allow: Vec<IpNet>,
+    /// the clients of these networks are turned away, even if allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl NetworkAcl {
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+            && !self.deny.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Answer 403 to the clients the ACL doesn't permit, and to those of unknown IP unless the
+/// ACL is open
+pub async fn ip_acl(State(acl): State<Arc<NetworkAcl>>, req: Request, next: Next) -> Response {
+    if acl.is_open() {
+        return next.run(req).await;
+    }
+    match ClientIp::of(&req) {
+        Some(ip) if acl.permits(&ip) => next.run(req).await,
+        ip => {
+            warn!("Refused {} to {:?}", req.uri().path(), ip);
+            error_response(
+                StatusCode::FORBIDDEN,
+                "forbidden: the network of the client isn't allowed".to_string(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn acl_should_apply_deny_over_allow() {
+        let acl = NetworkAcl {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.9.0/24".parse().unwrap()],
+        };
+        assert!(acl.permits(&"10.1.2.3".parse().unwrap()));
+        assert!(!acl.permits(&"10.0.9.1".parse().unwrap()));
+        assert!(!acl.permits(&"192.168.1.1".parse().unwrap()));
+
+        let acl = NetworkAcl {
+            deny: vec!["192.168.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(acl.permits(&"10.1.2.3".parse().unwrap()));
+        assert!(!acl.permits(&"192.168.1.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn ip_acl_should_refuse_unknown_clients_unless_open() -> anyhow::Result<()> {
+        let acl = NetworkAcl {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let call = |acl: NetworkAcl, ip: Option<&str>| {
+            let app = Router::new()
+                .route("/", get(|| async { "OK" }))
+                .layer(from_fn_with_state(Arc::new(acl), ip_acl));
+            let mut req = Request::builder().uri("/").body(Body::empty()).unwrap();
+            if let Some(ip) = ip {
+                req.extensions_mut().insert(ClientIp(ip.parse().unwrap()));
+            }
+            app.oneshot(req)
+        };
+
+        assert_eq!(call(acl.clone(), Some("10.0.0.1")).await?.status(), 200);
+        assert_eq!(call(acl.clone(), Some("8.8.8.8")).await?.status(), 403);
+        assert_eq!(call(acl, None).await?.status(), 403);
+        assert_eq!(call(NetworkAcl::default(), None).await?.status(), 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_layer_should_refuse_denied_clients_on_metrics() -> anyhow::Result<()> {
+        use crate::middlewares::{set_layer, CompressionConfig, CorsConfig, MetricsConfig};
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        let acl = NetworkAcl {
+            deny: vec!["192.168.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        let metrics = MetricsConfig {
+            token: Some("scraper".to_string()),
+        };
+        let app = set_layer(
+            Router::new(),
+            &acl,
+            &metrics,
+            &[],
+            &CorsConfig::default(),
+            &CompressionConfig::default(),
+        );
+        let call = |ip: &str| {
+            let mut req = Request::builder()
+                .uri("/metrics")
+                .header("Authorization", "Bearer scraper")
+                .body(Body::empty())
+                .unwrap();
+            let addr: SocketAddr = format!("{}:1234", ip).parse().unwrap();
+            req.extensions_mut().insert(ConnectInfo(addr));
+            app.clone().oneshot(req)
+        };
+
+        assert_eq!(call("192.168.1.1").await?.status(), 403);
+        assert_eq!(call("10.0.0.1").await?.status(), 200);
+        Ok(())
+    }
+}
@@ -1,17 +1,25 @@
 use axum::{
     extract::{FromRequestParts, Query, Request, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::Response,
 };
 use axum_extra::{
+    extract::CookieJar,
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
 use serde::Deserialize;
-use tracing::warn;
+use tracing::{warn, Span};
 
-use super::TokenVerify;
+use super::{error_response, TokenVerify};
+
+/// name of the HttpOnly cookie holding the access token, for the browser clients
+pub const ACCESS_TOKEN_COOKIE: &str = "access_token";
+/// name of the cookie holding the CSRF token, readable by the browser clients which send it
+/// back in the CSRF header of their writes
+pub const CSRF_TOKEN_COOKIE: &str = "csrf_token";
+pub const CSRF_TOKEN_HEADER: &str = "x-csrf-token";
 
 #[derive(Debug, Deserialize)]
 struct Params {
@@ -24,29 +32,41 @@ where
 {
     let (mut parts, body) = req.into_parts();
 
+    // the Authorization header, then the cookie of the browser clients, then the query
+    // params of the SSE clients
+    let cookie = CookieJar::from_headers(&parts.headers)
+        .get(ACCESS_TOKEN_COOKIE)
+        .map(|cookie| cookie.value().to_string());
     let token =
         match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
             Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
-            Err(e) => {
-                if e.is_missing() {
-                    match Query::<Params>::from_request_parts(&mut parts, &state).await {
-                        Ok(params) => params.access_token.clone(),
-                        Err(e) => {
-                            let msg = format!("Failed to parse query params: {}", e);
-                            warn!(msg);
-                            return (StatusCode::UNAUTHORIZED, msg).into_response();
-                        }
-                    }
-                } else {
-                    let msg = format!("Failed to parse Authorization header: {}", e);
+            Err(e) if !e.is_missing() => {
+                let msg = format!("Failed to parse Authorization header: {}", e);
+                warn!(msg);
+                return error_response(StatusCode::UNAUTHORIZED, msg);
+            }
+            Err(_) => match cookie {
+                // the browsers send the cookie along with the cross-site requests too
+                Some(_) if !parts.method.is_safe() && !csrf_token_matches(&parts.headers) => {
+                    let msg = "Missing or mismatched CSRF token".to_string();
                     warn!(msg);
-                    return (StatusCode::UNAUTHORIZED, msg).into_response();
+                    return error_response(StatusCode::FORBIDDEN, msg);
                 }
-            }
+                Some(token) => token,
+                None => match Query::<Params>::from_request_parts(&mut parts, &state).await {
+                    Ok(params) => params.access_token.clone(),
+                    Err(e) => {
+                        let msg = format!("Failed to parse query params: {}", e);
+                        warn!(msg);
+                        return error_response(StatusCode::UNAUTHORIZED, msg);
+                    }
+                },
+            },
         };
 
-    let req = match state.verify(&token) {
+    let req = match state.verify(&token).await {
         Ok(user) => {
+            Span::current().record("user_id", user.id);
             let mut req = Request::from_parts(parts, body);
             req.extensions_mut().insert(user);
             req
@@ -54,23 +74,42 @@ where
         Err(e) => {
             let msg = format!("Failed to verify token: {:?}", e);
             warn!(msg);
-            return (StatusCode::FORBIDDEN, msg).into_response();
+            return error_response(StatusCode::FORBIDDEN, msg);
         }
     };
 
     next.run(req).await
 }
 
+/// Whether the CSRF header matches the CSRF cookie, the cross-site pages can have the
+/// cookies sent but can't read them
+pub fn csrf_token_matches(headers: &HeaderMap) -> bool {
+    let jar = CookieJar::from_headers(headers);
+    let Some(cookie) = jar.get(CSRF_TOKEN_COOKIE) else {
+        return false;
+    };
+    !cookie.value().is_empty()
+        && headers
+            .get(CSRF_TOKEN_HEADER)
+            .is_some_and(|header| header.as_bytes() == cookie.value().as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::sync::Arc;
 
-    use crate::{DecodingKey, EncodingKey, User};
+    use crate::{
+        middlewares::{set_request_id, TrustedProxies, REQUEST_ID_HEADER},
+        DecodingKey, EncodingKey, User,
+    };
 
     use super::*;
     use anyhow::Result;
-    use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+    use axum::{
+        body::Body, middleware::from_fn_with_state, response::IntoResponse, routing::get, Router,
+    };
+    use serde_json::Value;
     use tower::ServiceExt;
 
     #[derive(Clone)]
@@ -84,7 +123,7 @@ mod tests {
     impl TokenVerify for AppState {
         type Error = ();
 
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<User, Self::Error> {
             self.0.dk.verify(token).map_err(|_| ())
         }
     }
@@ -93,6 +132,15 @@ mod tests {
         (StatusCode::OK, "OK")
     }
 
+    // the error of the json body, which must carry the id of the request
+    async fn error_of(resp: Response) -> Result<String> {
+        let request_id = resp.headers()[REQUEST_ID_HEADER].to_str()?.to_string();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+        let body: Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["request_id"], request_id.as_str());
+        Ok(body["error"].as_str().unwrap_or_default().to_string())
+    }
+
     #[tokio::test]
     async fn test_verify_token_middleware_should_work() -> Result<()> {
         let encoding_pem = include_str!("../../fixtures/private.pem");
@@ -105,8 +153,12 @@ mod tests {
         let token = state.0.ek.sign(user)?;
 
         let app = Router::new()
-            .route("/", get(handler))
+            .route("/", get(handler).post(handler))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .layer(from_fn_with_state(
+                TrustedProxies::default(),
+                set_request_id,
+            ))
             .with_state(state);
 
         // good token
@@ -124,10 +176,65 @@ mod tests {
         let resp = app.clone().oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::OK);
 
+        // good token in the cookie
+        let req = Request::builder()
+            .uri("/")
+            .header("Cookie", format!("{}={}", ACCESS_TOKEN_COOKIE, token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // a write with the cookie must send the CSRF token back
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Cookie", format!("{}={}", ACCESS_TOKEN_COOKIE, token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_of(resp).await?, "Missing or mismatched CSRF token");
+
+        let cookie = format!(
+            "{}={}; {}=abc",
+            ACCESS_TOKEN_COOKIE, token, CSRF_TOKEN_COOKIE
+        );
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Cookie", &cookie)
+            .header(CSRF_TOKEN_HEADER, "abd")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_of(resp).await?, "Missing or mismatched CSRF token");
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Cookie", &cookie)
+            .header(CSRF_TOKEN_HEADER, "abc")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
         // no token
         let req = Request::builder().uri("/").body(Body::empty())?;
         let resp = app.clone().oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(error_of(resp)
+            .await?
+            .starts_with("Failed to parse query params"));
+
+        // malformed Authorization header
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", "Basic abc")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(error_of(resp)
+            .await?
+            .starts_with("Failed to parse Authorization header"));
 
         // bad token
         let req = Request::builder()
@@ -136,6 +243,7 @@ mod tests {
             .body(Body::empty())?;
         let resp = app.clone().oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(error_of(resp).await?.starts_with("Failed to verify token"));
 
         // bad token in query params
         let req = Request::builder()
@@ -143,6 +251,7 @@ mod tests {
             .body(Body::empty())?;
         let resp = app.oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(error_of(resp).await?.starts_with("Failed to verify token"));
 
         Ok(())
     }
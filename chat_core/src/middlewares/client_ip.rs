@@ -0,0 +1,104 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+
+use super::TrustedProxies;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// The IP of the client, the peer of the connection unless it's a trusted proxy, in which case
+/// it's the first untrusted hop of X-Forwarded-For. Only set when served with connect info,
+/// i.e. not in the tests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// The client IP of the request, if known
+    pub fn of(req: &Request) -> Option<IpAddr> {
+        req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip)
+    }
+}
+
+pub async fn set_client_ip(
+    State(proxies): State<TrustedProxies>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        let ip = client_ip(peer, req.headers(), &proxies);
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+/// Walk X-Forwarded-For from the closest hop while the hops are trusted proxies, each of them
+/// appended the address it got the request from
+fn client_ip(peer: IpAddr, headers: &HeaderMap, proxies: &TrustedProxies) -> IpAddr {
+    let mut ip = peer;
+    if !proxies.contains(&ip) {
+        return ip;
+    }
+    let hops: Vec<_> = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in hops.iter().rev() {
+        // a hop that isn't an address can't be trusted further
+        let Ok(hop) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop;
+        if !proxies.contains(&ip) {
+            break;
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use ipnet::IpNet;
+
+    fn resolve(peer: &str, forwarded: &[&str]) -> String {
+        let proxies: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        for v in forwarded {
+            headers.append(FORWARDED_FOR_HEADER, HeaderValue::from_str(v).unwrap());
+        }
+        client_ip(
+            peer.parse().unwrap(),
+            &headers,
+            &TrustedProxies::new(&proxies),
+        )
+        .to_string()
+    }
+
+    #[test]
+    fn client_ip_should_skip_trusted_proxies_only() {
+        // the header of an untrusted peer is ignored
+        assert_eq!(resolve("203.0.113.9", &["198.51.100.1"]), "203.0.113.9");
+        assert_eq!(resolve("10.0.0.1", &["198.51.100.1"]), "198.51.100.1");
+        // a client can't spoof the hops before the proxies
+        assert_eq!(
+            resolve("10.0.0.1", &["1.1.1.1, 198.51.100.1", "10.0.0.2"]),
+            "198.51.100.1"
+        );
+        assert_eq!(resolve("10.0.0.1", &["10.0.0.3, 10.0.0.2"]), "10.0.0.3");
+        assert_eq!(resolve("10.0.0.1", &["unknown"]), "10.0.0.1");
+        assert_eq!(resolve("10.0.0.1", &[]), "10.0.0.1");
+    }
+}
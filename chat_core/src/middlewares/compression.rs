@@ -0,0 +1,191 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, Extensions, HeaderMap, StatusCode, Version},
+};
+use serde::{Deserialize, Serialize};
+use tower::util::MapRequestLayer;
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, SizeAbove},
+        CompressionLayer, Predicate,
+    },
+    decompression::{DecompressionBody, RequestDecompressionLayer},
+};
+
+/// the request decompression, with the body boxed again for the handlers
+type DecompressionLayer = (
+    RequestDecompressionLayer,
+    MapRequestLayer<fn(Request<DecompressionBody<Body>>) -> Request>,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Br,
+    Deflate,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// encodings of the responses and the request bodies, none disables the compression
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// bytes a response must be larger than to be compressed
+    pub min_size: u16,
+    /// content types compressed, matched by prefix, e.g. `text/` for all the text ones
+    pub content_types: Vec<String>,
+    /// accept request bodies sent with one of the algorithms in content-encoding
+    pub decompress_requests: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![
+                CompressionAlgorithm::Gzip,
+                CompressionAlgorithm::Br,
+                CompressionAlgorithm::Deflate,
+                CompressionAlgorithm::Zstd,
+            ],
+            min_size: 1024,
+            content_types: vec!["application/json".to_string(), "text/".to_string()],
+            decompress_requests: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn layer(&self) -> CompressionLayer<impl Predicate> {
+        let content_types: Arc<[String]> = self.content_types.clone().into();
+        let compressible = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            content_types.iter().any(|t| content_type.starts_with(t))
+        };
+        // the events of a stream must not wait for a compressed block to fill up
+        let predicate = SizeAbove::new(self.min_size)
+            .and(NotForContentType::SSE)
+            .and(compressible);
+        CompressionLayer::new()
+            .gzip(self.has(CompressionAlgorithm::Gzip))
+            .br(self.has(CompressionAlgorithm::Br))
+            .deflate(self.has(CompressionAlgorithm::Deflate))
+            .zstd(self.has(CompressionAlgorithm::Zstd))
+            .compress_when(predicate)
+    }
+
+    /// The body limits apply to the decompressed bodies
+    pub fn decompression_layer(&self) -> DecompressionLayer {
+        let enabled = |algorithm| self.decompress_requests && self.has(algorithm);
+        let decompression = RequestDecompressionLayer::new()
+            .gzip(enabled(CompressionAlgorithm::Gzip))
+            .br(enabled(CompressionAlgorithm::Br))
+            .deflate(enabled(CompressionAlgorithm::Deflate))
+            .zstd(enabled(CompressionAlgorithm::Zstd))
+            .pass_through_unaccepted(!self.decompress_requests);
+        let boxed: fn(Request<DecompressionBody<Body>>) -> Request = |req| req.map(Body::new);
+        (decompression, MapRequestLayer::new(boxed))
+    }
+
+    fn has(&self, algorithm: CompressionAlgorithm) -> bool {
+        self.algorithms.contains(&algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    fn app(config: &CompressionConfig) -> Router {
+        Router::new()
+            .route("/echo", post(|body: String| async move { body }))
+            .route(
+                "/json",
+                post(|| async { axum::Json(vec!["message history"; 200]) }),
+            )
+            .layer(config.decompression_layer())
+            .layer(config.layer())
+    }
+
+    fn request(uri: &str, body: Body) -> Request {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn large_json_responses_should_be_compressed() -> anyhow::Result<()> {
+        let config = CompressionConfig::default();
+        let resp = app(&config)
+            .oneshot(request("/json", Body::empty()))
+            .await?;
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+
+        // not a listed content type
+        let config = CompressionConfig {
+            content_types: vec!["application/json".to_string()],
+            ..Default::default()
+        };
+        let resp = app(&config)
+            .oneshot(request("/echo", Body::from("x".repeat(2048))))
+            .await?;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+
+        // too small
+        let config = CompressionConfig::default();
+        let resp = app(&config)
+            .oneshot(request("/echo", Body::from("x".repeat(512))))
+            .await?;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let config = CompressionConfig {
+            algorithms: vec![],
+            ..Default::default()
+        };
+        let resp = app(&config)
+            .oneshot(request("/json", Body::empty()))
+            .await?;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gzip_request_bodies_should_be_decompressed() -> anyhow::Result<()> {
+        // "hello" compressed with gzip
+        let body: &[u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00, 0x00,
+        ];
+        let compressed = || {
+            let mut req = request("/echo", Body::from(body));
+            req.headers_mut()
+                .insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+            req
+        };
+
+        let config = CompressionConfig::default();
+        let resp = app(&config).oneshot(compressed()).await?;
+        let text = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+        assert_eq!(text, "hello");
+
+        let config = CompressionConfig {
+            algorithms: vec![CompressionAlgorithm::Br],
+            ..Default::default()
+        };
+        let resp = app(&config).oneshot(compressed()).await?;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        Ok(())
+    }
+}
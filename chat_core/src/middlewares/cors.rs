@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+use super::{REQUEST_ID_HEADER, SERVER_TIME_HEADER};
+
+/// matches any origin or header
+const WILDCARD: &str = "*";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// origins of the web apps calling the server, e.g. `https://chat.acme.org`, `*` for any
+    pub allowed_origins: Vec<String>,
+    /// headers the apps may send besides the safelisted ones, `*` for any
+    pub allowed_headers: Vec<String>,
+    /// whether the apps may send cookies, only with listed origins and headers
+    pub allow_credentials: bool,
+    /// seconds the browsers keep the result of a preflight
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![WILDCARD.to_string()],
+            allowed_headers: vec![WILDCARD.to_string()],
+            allow_credentials: false,
+            max_age: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Check the origins and headers parse, browsers reject credentials with a wildcard
+    pub fn validate(&self) -> Result<()> {
+        for origin in self.origins() {
+            HeaderValue::from_str(origin)?;
+        }
+        for header in self.headers() {
+            HeaderName::from_bytes(header.as_bytes())?;
+        }
+        if self.allow_credentials && (self.any_origin() || self.any_header()) {
+            bail!("cors: credentials can't be allowed with `*` origins or headers");
+        }
+        Ok(())
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let origins = if self.any_origin() {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.origins()
+                    .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+            )
+        };
+        let headers = if self.any_header() {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(
+                self.headers()
+                    .filter_map(|header| HeaderName::from_bytes(header.as_bytes()).ok()),
+            )
+        };
+        CorsLayer::new()
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PATCH,
+                Method::DELETE,
+                Method::PUT,
+            ])
+            .allow_origin(origins)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .expose_headers([
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderName::from_static(SERVER_TIME_HEADER),
+            ])
+            .max_age(Duration::from_secs(self.max_age))
+    }
+
+    fn any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == WILDCARD)
+    }
+
+    fn any_header(&self) -> bool {
+        self.allowed_headers.iter().any(|h| h == WILDCARD)
+    }
+
+    fn origins(&self) -> impl Iterator<Item = &str> {
+        self.allowed_origins
+            .iter()
+            .map(|o| o.trim_end_matches('/'))
+            .filter(|o| *o != WILDCARD)
+    }
+
+    fn headers(&self) -> impl Iterator<Item = &str> {
+        self.allowed_headers
+            .iter()
+            .map(|h| h.as_str())
+            .filter(|h| *h != WILDCARD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{header, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[test]
+    fn cors_config_should_reject_credentials_with_wildcards() {
+        let config = CorsConfig {
+            allow_credentials: true,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = CorsConfig {
+            allowed_origins: vec!["https://chat.acme.org".to_string()],
+            allowed_headers: vec!["authorization".to_string(), "content-type".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = CorsConfig {
+            allowed_headers: vec!["bad header".to_string()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn cors_layer_should_allow_listed_origins() -> anyhow::Result<()> {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://chat.acme.org/".to_string()],
+            allowed_headers: vec!["authorization".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/chats", get(|| async { "chats" }))
+            .layer(config.layer());
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/chats")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+        };
+        let resp = app
+            .clone()
+            .oneshot(preflight("https://chat.acme.org")?)
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://chat.acme.org"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let resp = app.oneshot(preflight("https://evil.example")?).await?;
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac_sha256::Hash;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+use tracing::warn;
+
+use super::error_response;
+
+/// the route label of the requests no route matched, so that scanners can't blow up the
+/// number of series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Who may scrape `/metrics`, it's served on the public port along with the API
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// bearer token the scrapers send, `/metrics` isn't served without one
+    pub token: Option<String>,
+}
+
+struct HttpMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+}
+
+/// registered once in the default registry, shared by all the routers of the process
+fn http_metrics() -> &'static HttpMetrics {
+    static METRICS: OnceLock<HttpMetrics> = OnceLock::new();
+    METRICS.get_or_init(|| HttpMetrics {
+        requests: register_int_counter_vec!(
+            "http_requests_total",
+            "Number of HTTP requests",
+            &["method", "route", "status"]
+        )
+        .expect("Failed to register http_requests_total"),
+        duration: register_histogram_vec!(
+            "http_request_duration_seconds",
+            "Time to respond to HTTP requests, until the headers are sent",
+            &["method", "route", "status"]
+        )
+        .expect("Failed to register http_request_duration_seconds"),
+    })
+}
+
+/// Record the count and the latency of the requests, by method, route and status class
+#[derive(Clone)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsMiddleware { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsMiddleware<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for MetricsMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or(UNMATCHED_ROUTE, |path| path.as_str())
+            .to_string();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let resp: Response = future.await?;
+            let status = status_class(resp.status());
+            let labels = [method.as_str(), route.as_str(), status];
+            let metrics = http_metrics();
+            metrics.requests.with_label_values(&labels).inc();
+            metrics
+                .duration
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+            Ok(resp)
+        })
+    }
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// The metrics of the default registry, in the Prometheus text format
+pub async fn metrics_handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        warn!("Failed to encode the metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buf,
+    )
+        .into_response()
+}
+
+/// Answer 401 to the scrapers not sending the token, the digests are compared so that the
+/// time taken doesn't tell how much of it matched
+pub(super) async fn require_metrics_token(
+    State(token): State<[u8; 32]>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let sent = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match sent {
+        Some(sent) if Hash::hash(sent.as_bytes()) == token => next.run(req).await,
+        _ => error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized: the metrics token is missing or wrong".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn metrics_layer_should_record_requests() -> anyhow::Result<()> {
+        let app = Router::new()
+            .route("/chats/:id", get(|| async { "chat" }))
+            .route("/metrics", get(metrics_handler))
+            .layer(MetricsLayer);
+
+        for uri in ["/chats/1", "/chats/2", "/missing"] {
+            let req = Request::builder().uri(uri).body(Body::empty())?;
+            app.clone().oneshot(req).await?;
+        }
+
+        let metrics = http_metrics();
+        let chats = metrics
+            .requests
+            .with_label_values(&["GET", "/chats/:id", "2xx"])
+            .get();
+        assert_eq!(chats, 2);
+        let missing = metrics
+            .requests
+            .with_label_values(&["GET", UNMATCHED_ROUTE, "4xx"])
+            .get();
+        assert!(missing >= 1);
+
+        let req = Request::builder().uri("/metrics").body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+        let body = String::from_utf8(body.to_vec())?;
+        assert!(
+            body.contains(r#"http_requests_total{method="GET",route="/chats/:id",status="2xx"} 2"#)
+        );
+        assert!(body.contains("http_request_duration_seconds_bucket"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_should_require_the_token() -> anyhow::Result<()> {
+        use crate::middlewares::{set_layer, CompressionConfig, CorsConfig, NetworkAcl};
+
+        let call = |token: Option<&str>, sent: Option<&str>| {
+            let metrics = MetricsConfig {
+                token: token.map(str::to_string),
+            };
+            let app = set_layer(
+                Router::new(),
+                &NetworkAcl::default(),
+                &metrics,
+                &[],
+                &CorsConfig::default(),
+                &CompressionConfig::default(),
+            );
+            let mut req = Request::builder().uri("/metrics");
+            if let Some(sent) = sent {
+                req = req.header("Authorization", format!("Bearer {}", sent));
+            }
+            app.oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(call(Some("scraper"), Some("scraper")).await?.status(), 200);
+        assert_eq!(call(Some("scraper"), Some("other")).await?.status(), 401);
+        assert_eq!(call(Some("scraper"), None).await?.status(), 401);
+        assert_eq!(call(None, None).await?.status(), 404);
+        Ok(())
+    }
+}
@@ -1,40 +1,114 @@
+mod acl;
 mod auth;
+mod client_ip;
+mod compression;
+mod cors;
+mod metrics;
+mod rate_limit;
 mod request_id;
+mod role;
 mod server_time;
 
 use core::fmt;
+use std::{future::Future, sync::Arc};
 
 use crate::User;
 
-use self::request_id::set_request_id;
+use self::{
+    client_ip::set_client_ip,
+    request_id::{make_span, set_request_id},
+};
 
-use axum::{middleware::from_fn, Router};
+use axum::{
+    http::StatusCode,
+    middleware::from_fn_with_state,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use ipnet::IpNet;
+use metrics::{require_metrics_token, MetricsLayer};
+use serde::Serialize;
 use server_time::ServerTimeLayer;
 use tower::ServiceBuilder;
 use tower_http::{
-    compression::CompressionLayer,
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
     LatencyUnit,
 };
 use tracing::Level;
 
-pub use auth::verify_token;
+pub use acl::{ip_acl, NetworkAcl};
+pub use auth::{
+    csrf_token_matches, verify_token, ACCESS_TOKEN_COOKIE, CSRF_TOKEN_COOKIE, CSRF_TOKEN_HEADER,
+};
+pub use client_ip::ClientIp;
+pub use compression::{CompressionAlgorithm, CompressionConfig};
+pub use cors::CorsConfig;
+pub use metrics::{metrics_handler, MetricsConfig};
+pub use rate_limit::{
+    rate_limit, RateLimit, RateLimiter, RouteClass, RouteRateLimit, RouteRateLimiter,
+    RouteRateLimits,
+};
+pub use request_id::{current_request_id, TrustedProxies};
+pub use role::require_role;
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME_HEADER: &str = "x-server-time";
 
+/// the body of the errors answered by the middlewares, like the ErrorOutput of the servers
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
 pub trait TokenVerify {
     type Error: fmt::Debug;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    /// Verify the token and return its user, it may look up whether the token was revoked
+    fn verify(&self, token: &str) -> impl Future<Output = Result<User, Self::Error>> + Send;
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+    let body = ErrorBody {
+        error,
+        request_id: current_request_id(),
+    };
+    (status, Json(body)).into_response()
 }
 
-pub fn set_layer(app: Router) -> Router {
+/// The common layers of the servers, with the Prometheus metrics of the requests served at
+/// `/metrics` to the scrapers sending the token of `metrics`. The request ids and the
+/// X-Forwarded-For sent by `trusted_proxies` are kept, the browsers are allowed cross-origin
+/// calls according to `cors`. The clients `acl` doesn't permit are turned away from all the
+/// routes, `/metrics` included.
+pub fn set_layer(
+    app: Router,
+    acl: &NetworkAcl,
+    metrics: &MetricsConfig,
+    trusted_proxies: &[IpNet],
+    cors: &CorsConfig,
+    compression: &CompressionConfig,
+) -> Router {
+    let proxies = TrustedProxies::new(trusted_proxies);
+    let app = match &metrics.token {
+        Some(token) => app.route(
+            "/metrics",
+            get(metrics_handler).layer(from_fn_with_state(
+                hmac_sha256::Hash::hash(token.as_bytes()),
+                require_metrics_token,
+            )),
+        ),
+        None => app,
+    };
     app.layer(
         ServiceBuilder::new()
+            .layer(from_fn_with_state(proxies.clone(), set_request_id))
+            .layer(from_fn_with_state(proxies, set_client_ip))
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                    .make_span_with(make_span)
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
                     .on_response(
                         DefaultOnResponse::new()
@@ -42,8 +116,11 @@ pub fn set_layer(app: Router) -> Router {
                             .latency_unit(LatencyUnit::Micros),
                     ),
             )
-            .layer(CompressionLayer::new().gzip(true).br(true).deflate(true))
-            .layer(from_fn(set_request_id))
-            .layer(ServerTimeLayer),
+            .layer(cors.layer())
+            .layer(compression.layer())
+            .layer(compression.decompression_layer())
+            .layer(ServerTimeLayer)
+            .layer(MetricsLayer)
+            .layer(from_fn_with_state(Arc::new(acl.clone()), ip_acl)),
     )
 }
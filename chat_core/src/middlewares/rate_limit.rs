@@ -0,0 +1,332 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{error_response, ClientIp};
+use crate::User;
+
+/// buckets are pruned once there are this many of them
+const MAX_BUCKETS: usize = 10_000;
+/// the buckets are pruned at most this often, going through them all takes a while
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a token bucket, `burst` requests in a row then `per_minute` requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// 0 disables the limit
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// in-memory token buckets, keyed by e.g. client IP, email or user
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<String, Bucket>,
+    /// none until the first prune
+    pruned_at: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// the budgets of the routes, per user once authenticated, per client IP otherwise
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteRateLimits {
+    /// the GET requests
+    pub read: RateLimit,
+    /// the other requests
+    pub write: RateLimit,
+    /// sending a message, on top of the write budget
+    pub send: RateLimit,
+    /// uploading a file, on top of the write budget
+    pub upload: RateLimit,
+}
+
+/// the budget a route takes from, see `RouteRateLimits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    Read,
+    Write,
+    Send,
+    Upload,
+}
+
+/// The buckets of the route rate limits, kept by the state so that they outlive the routers
+/// rebuilt on reload
+#[derive(Debug, Default)]
+pub struct RouteRateLimiter {
+    read: RateLimiter,
+    write: RateLimiter,
+    send: RateLimiter,
+    upload: RateLimiter,
+}
+
+/// The state of `rate_limit`
+#[derive(Debug, Clone)]
+pub struct RouteRateLimit {
+    limiter: Arc<RouteRateLimiter>,
+    limits: RouteRateLimits,
+    /// by the method when not set
+    class: Option<RouteClass>,
+}
+
+impl RateLimiter {
+    /// Take a request from the bucket of the key, or the seconds until the next one is allowed
+    pub fn check(&self, key: &str, limit: &RateLimit) -> Result<(), u64> {
+        if limit.burst == 0 {
+            return Ok(());
+        }
+        if self.buckets.len() >= MAX_BUCKETS && self.prune_due() {
+            // a full bucket is the same as none
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(limit);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated_at: Instant::now(),
+        });
+        bucket.refill(limit);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let per_sec = limit.per_minute.max(1) as f64 / 60.0;
+        Err(((1.0 - bucket.tokens) / per_sec).ceil() as u64)
+    }
+
+    /// Whether the buckets weren't pruned for a while, the caller prunes them if so
+    fn prune_due(&self) -> bool {
+        // another request is deciding
+        let Ok(mut pruned_at) = self.pruned_at.try_lock() else {
+            return false;
+        };
+        let now = Instant::now();
+        if pruned_at.is_some_and(|at| now.duration_since(at) < PRUNE_INTERVAL) {
+            return false;
+        }
+        *pruned_at = Some(now);
+        true
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        let refilled = self.tokens + elapsed * limit.per_minute as f64 / 60.0;
+        self.tokens = refilled.min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+impl Default for RouteRateLimits {
+    fn default() -> Self {
+        Self {
+            read: RateLimit {
+                burst: 300,
+                per_minute: 600,
+            },
+            write: RateLimit {
+                burst: 60,
+                per_minute: 120,
+            },
+            send: RateLimit {
+                burst: 20,
+                per_minute: 60,
+            },
+            upload: RateLimit {
+                burst: 10,
+                per_minute: 20,
+            },
+        }
+    }
+}
+
+impl RouteRateLimiter {
+    fn check(&self, class: RouteClass, key: &str, limits: &RouteRateLimits) -> Result<(), u64> {
+        match class {
+            RouteClass::Read => self.read.check(key, &limits.read),
+            RouteClass::Write => self.write.check(key, &limits.write),
+            RouteClass::Send => self.send.check(key, &limits.send),
+            RouteClass::Upload => self.upload.check(key, &limits.upload),
+        }
+    }
+}
+
+impl RouteRateLimit {
+    /// The read budget for the GET and HEAD requests, the write budget for the others
+    pub fn new(limiter: Arc<RouteRateLimiter>, limits: &RouteRateLimits) -> Self {
+        Self {
+            limiter,
+            limits: limits.clone(),
+            class: None,
+        }
+    }
+
+    /// The same limits, for the routes of `class`
+    pub fn class(&self, class: RouteClass) -> Self {
+        Self {
+            class: Some(class),
+            ..self.clone()
+        }
+    }
+}
+
+/// Limit the requests per user, or per client IP before the token is verified, answering
+/// 429 with the seconds to wait in Retry-After
+pub async fn rate_limit(State(limit): State<RouteRateLimit>, req: Request, next: Next) -> Response {
+    let class = limit.class.unwrap_or(match *req.method() {
+        Method::GET | Method::HEAD => RouteClass::Read,
+        _ => RouteClass::Write,
+    });
+    let key = match req.extensions().get::<User>() {
+        Some(user) => format!("user:{}", user.id),
+        // only known when served with connect info, i.e. not in the tests
+        None => {
+            let ip = ClientIp::of(&req).map(|ip| ip.to_string());
+            format!("ip:{}", ip.unwrap_or_default())
+        }
+    };
+
+    if let Err(secs) = limit.limiter.check(class, &key, &limit.limits) {
+        warn!("Too many {:?} requests from {}", class, key);
+        let error = format!("too many requests, retry after {secs} seconds");
+        let mut resp = error_response(StatusCode::TOO_MANY_REQUESTS, error);
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        return resp;
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn rate_limiter_should_work() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            burst: 2,
+            per_minute: 6,
+        };
+        assert!(limiter.check("a", &limit).is_ok());
+        assert!(limiter.check("a", &limit).is_ok());
+        assert_eq!(limiter.check("a", &limit), Err(10));
+        // each key has its own bucket
+        assert!(limiter.check("b", &limit).is_ok());
+
+        let unlimited = RateLimit {
+            burst: 0,
+            per_minute: 0,
+        };
+        for _ in 0..10 {
+            assert!(limiter.check("a", &unlimited).is_ok());
+        }
+    }
+
+    #[test]
+    fn rate_limiter_should_prune_at_most_every_interval() {
+        let limiter = RateLimiter::default();
+        // refilled within a millisecond
+        let limit = RateLimit {
+            burst: 1,
+            per_minute: 600_000,
+        };
+        let fill = |from: usize| {
+            for i in from..from + MAX_BUCKETS {
+                assert!(limiter.check(&i.to_string(), &limit).is_ok());
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        fill(0);
+        assert!(limiter.check("new", &limit).is_ok());
+        assert_eq!(limiter.buckets.len(), 1);
+
+        // pruned a moment ago, the buckets are left alone
+        fill(MAX_BUCKETS);
+        assert!(limiter.check("newer", &limit).is_ok());
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS + 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_should_take_from_the_budget_of_the_route() -> anyhow::Result<()> {
+        let limits = RouteRateLimits {
+            read: RateLimit {
+                burst: 2,
+                per_minute: 60,
+            },
+            write: RateLimit {
+                burst: 0,
+                per_minute: 0,
+            },
+            send: RateLimit {
+                burst: 1,
+                per_minute: 60,
+            },
+            ..Default::default()
+        };
+        let limit = RouteRateLimit::new(Arc::default(), &limits);
+        let app = Router::new()
+            .route(
+                "/messages",
+                get(|| async { "list" })
+                    .post(|| async { "sent" })
+                    .layer(from_fn_with_state(
+                        limit.class(RouteClass::Send),
+                        rate_limit,
+                    )),
+            )
+            .route("/chats", get(|| async { "chats" }))
+            .layer(from_fn_with_state(limit, rate_limit));
+        let send = |method: &str, uri: &str, user: Option<i64>| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            if let Some(id) = user {
+                req.extensions_mut()
+                    .insert(User::new(id, "Tyr Chen", "tchen@acme.org"));
+            }
+            app.clone().oneshot(req)
+        };
+
+        assert_eq!(send("POST", "/messages", Some(1)).await?.status(), 200);
+        let resp = send("POST", "/messages", Some(1)).await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+        // each user has their own budget
+        assert_eq!(send("POST", "/messages", Some(2)).await?.status(), 200);
+
+        // the reads of all the routes share a budget
+        assert_eq!(send("GET", "/messages", None).await?.status(), 200);
+        assert_eq!(send("GET", "/chats", None).await?.status(), 200);
+        let resp = send("GET", "/chats", None).await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+        assert!(String::from_utf8(body.to_vec())?.contains("too many requests"));
+        Ok(())
+    }
+}
@@ -1,12 +1,64 @@
-use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
-use tracing::warn;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use tracing::{field, info_span, warn, Span};
 
 use super::REQUEST_ID_HEADER;
 
-pub async fn set_request_id(mut req: Request, next: Next) -> Response {
-    // if x-request-id exists, do nothing, otherwise generate a new one
-    let id = match req.headers().get(REQUEST_ID_HEADER) {
-        Some(v) => Some(v.clone()),
+/// longest request id taken from a proxy
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request being handled by this task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// The proxies whose x-request-id is kept, a new id is given to the requests of the others
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<[IpNet]>);
+
+impl TrustedProxies {
+    pub fn new(nets: &[IpNet]) -> Self {
+        Self(nets.into())
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    fn trust(&self, req: &Request) -> bool {
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| self.contains(&addr.ip()))
+    }
+}
+
+pub async fn set_request_id(
+    State(proxies): State<TrustedProxies>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    // keep the x-request-id of a trusted proxy, otherwise generate a new one
+    let inbound = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|v| is_valid(v) && proxies.trust(&req))
+        .cloned();
+    let id = match inbound {
+        Some(v) => Some(v),
         None => {
             let request_id = uuid::Uuid::now_v7().to_string();
             match HeaderValue::from_str(&request_id) {
@@ -15,6 +67,7 @@ pub async fn set_request_id(mut req: Request, next: Next) -> Response {
                     Some(v)
                 }
                 Err(e) => {
+                    req.headers_mut().remove(REQUEST_ID_HEADER);
                     warn!("Failed to parse request id: {}", e);
                     None
                 }
@@ -22,11 +75,100 @@ pub async fn set_request_id(mut req: Request, next: Next) -> Response {
         }
     };
 
-    let mut resp = next.run(req).await;
     let Some(id) = id else {
-        return resp;
+        return next.run(req).await;
     };
 
+    // valid ids are visible ascii
+    let scoped = id.to_str().unwrap_or_default().to_string();
+    let mut resp = REQUEST_ID.scope(scoped, next.run(req)).await;
     resp.headers_mut().insert(REQUEST_ID_HEADER, id);
     resp
 }
+
+/// The span of a request, with its id to correlate the logs of the request, the user is
+/// recorded once the token is verified
+pub fn make_span(req: &Request) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        route,
+        request_id,
+        user_id = field::Empty,
+    )
+}
+
+fn is_valid(id: &HeaderValue) -> bool {
+    let id = id.as_bytes();
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn request_id(proxies: &[IpNet], peer: &str, id: Option<&str>) -> (String, String) {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { current_request_id().unwrap_or_default() }),
+            )
+            .layer(from_fn_with_state(
+                TrustedProxies::new(proxies),
+                set_request_id,
+            ));
+        let mut req = Request::builder().uri("/");
+        if let Some(id) = id {
+            req = req.header(REQUEST_ID_HEADER, id);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+
+        let resp = app.oneshot(req).await.unwrap();
+        let header = resp.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn request_id_should_be_kept_from_trusted_proxies_only() {
+        let proxies: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let (header, current) = request_id(&proxies, "10.1.2.3:4000", Some("lb-42")).await;
+        assert_eq!(header, "lb-42");
+        assert_eq!(current, "lb-42");
+
+        let (header, current) = request_id(&proxies, "192.168.1.2:4000", Some("lb-42")).await;
+        assert_ne!(header, "lb-42");
+        assert_eq!(header, current);
+
+        let (header, _) = request_id(&proxies, "10.1.2.3:4000", Some("a b")).await;
+        assert_ne!(header, "a b");
+
+        assert!(current_request_id().is_none());
+    }
+}
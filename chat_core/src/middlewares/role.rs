@@ -0,0 +1,77 @@
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
+use tracing::warn;
+
+use super::error_response;
+use crate::{User, WorkspaceRole};
+
+/// Reject the request unless the user has at least the given role in the current workspace.
+///
+/// It must run after `verify_token`, e.g.
+/// `from_fn(|req, next| require_role(WorkspaceRole::Admin, req, next))`.
+pub async fn require_role(role: WorkspaceRole, req: Request, next: Next) -> Response {
+    match req.extensions().get::<User>() {
+        Some(user) if user.role >= role => next.run(req).await,
+        Some(user) => {
+            let msg = format!(
+                "permission denied: User {} requires the {:?} role in workspace {}",
+                user.id, role, user.ws_id
+            );
+            warn!(msg);
+            error_response(StatusCode::FORBIDDEN, msg)
+        }
+        None => error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized: Missing user".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::header, middleware::from_fn, response::IntoResponse, routing::get, Router,
+    };
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "OK")
+    }
+
+    fn app(role: Option<WorkspaceRole>) -> Router {
+        let router = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn(|req, next| {
+                require_role(WorkspaceRole::Admin, req, next)
+            }));
+        match role {
+            Some(role) => {
+                let mut user = User::new(1, "Tyr Chen", "tchen@acme.org");
+                user.role = role;
+                router.layer(axum::Extension(user))
+            }
+            None => router,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_role_middleware_should_work() -> Result<()> {
+        let cases = [
+            (Some(WorkspaceRole::Owner), StatusCode::OK),
+            (Some(WorkspaceRole::Admin), StatusCode::OK),
+            (Some(WorkspaceRole::Member), StatusCode::FORBIDDEN),
+            (None, StatusCode::UNAUTHORIZED),
+        ];
+        for (role, status) in cases {
+            let req = Request::builder().uri("/").body(Body::empty())?;
+            let resp = app(role).oneshot(req).await?;
+            assert_eq!(resp.status(), status);
+            if status != StatusCode::OK {
+                assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+            }
+        }
+
+        Ok(())
+    }
+}
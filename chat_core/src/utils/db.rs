@@ -0,0 +1,15 @@
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+
+use crate::middlewares::current_request_id;
+
+/// Begin a transaction tagged with the id of the current request, for the writes whose
+/// triggers send notifications so that they can be traced back to the request. The id is
+/// set for the transaction only, the connection goes back to the pool without it
+pub async fn begin_tagged(pool: &PgPool) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if let Some(id) = current_request_id() {
+        tx.execute(sqlx::query("SELECT set_config('app.request_id', $1, true)").bind(id))
+            .await?;
+    }
+    Ok(tx)
+}
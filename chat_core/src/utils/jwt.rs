@@ -1,53 +1,361 @@
+use chrono::{DateTime, Utc};
+use hmac_sha256::Hash;
 use jwt_simple::prelude::*;
+use sqlx::PgPool;
+use std::sync::RwLock;
+use uuid::Uuid;
 
 use crate::User;
 
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
-const JWT_ISSUER: &str = "chat_server";
-const JWT_AUDIENCE: &str = "chat_web";
+/// the claims of the access tokens, the servers signing and verifying them must agree on them
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct JwtConfig {
+    /// the algorithm of the keys, changing it requires a restart
+    pub algorithm: JwtAlgorithm,
+    /// seconds an access token is valid, short-lived as clients renew it with a refresh token
+    pub duration: u64,
+    pub issuer: String,
+    pub audience: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    #[default]
+    Ed25519,
+    /// ECDSA with the P-256 curve
+    #[serde(rename = "ES256")]
+    Es256,
+    /// RSA PKCS#1 v1.5 with SHA-256
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: JwtAlgorithm::default(),
+            duration: 60 * 15,
+            issuer: "chat_server".to_string(),
+            audience: "chat_web".to_string(),
+        }
+    }
+}
+
+/// signs the access tokens, the id of the key is embedded in their `kid` header
+pub struct EncodingKey {
+    key: RwLock<SigningKey>,
+    config: JwtConfig,
+}
+
+/// verifies the access tokens against any of its keys, so that the tokens signed by
+/// the previous key stay valid during a key rotation
+pub struct DecodingKey {
+    keys: RwLock<Vec<VerifyingKey>>,
+    config: JwtConfig,
+}
 
-pub struct EncodingKey(Ed25519KeyPair);
+/// the signing key and the verifying keys of a reload, all loaded before any is swapped so
+/// that a bad key leaves the ones in use untouched
+pub struct KeyReload {
+    key: SigningKey,
+    keys: Vec<VerifyingKey>,
+}
 
-#[allow(unused)]
-pub struct DecodingKey(Ed25519PublicKey);
+/// a verified access token
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub user: User,
+    /// unique id of the token, used to revoke it
+    pub jti: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+enum SigningKey {
+    Ed25519(Ed25519KeyPair),
+    Es256(ES256KeyPair),
+    Rs256(Box<RS256KeyPair>),
+}
+
+enum VerifyingKey {
+    Ed25519(Ed25519PublicKey),
+    Es256(ES256PublicKey),
+    Rs256(Box<RS256PublicKey>),
+}
 
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        let key = Ed25519KeyPair::from_pem(pem)?;
-        Ok(Self(key))
+        Self::load_with_config(pem, JwtConfig::default())
+    }
+
+    /// Load the key for the algorithm of the config
+    pub fn load_with_config(pem: &str, config: JwtConfig) -> Result<Self, jwt_simple::Error> {
+        Ok(Self {
+            key: RwLock::new(SigningKey::from_pem(config.algorithm, pem)?),
+            config,
+        })
+    }
+
+    /// Id of the signing key, derived from its public key
+    pub fn key_id(&self) -> String {
+        self.key.read().expect("signing key lock poisoned").key_id()
+    }
+
+    /// Swap the signing key, the tokens signed from now on carry the id of the new key
+    pub fn reload(&self, pem: &str) -> Result<(), jwt_simple::Error> {
+        let key = SigningKey::from_pem(self.config.algorithm, pem)?;
+        *self.key.write().expect("signing key lock poisoned") = key;
+        Ok(())
     }
 
     pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(JWT_DURATION))
-            .with_issuer(JWT_ISSUER)
-            .with_audience(JWT_AUDIENCE);
-        self.0.sign(claims)
+        let duration = Duration::from_secs(self.config.duration);
+        let claims = Claims::with_custom_claims(user.into(), duration)
+            .with_issuer(&self.config.issuer)
+            .with_audience(&self.config.audience)
+            .with_jwt_id(Uuid::now_v7());
+        self.key
+            .read()
+            .expect("signing key lock poisoned")
+            .sign(claims)
     }
 }
 
 impl DecodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        let key = Ed25519PublicKey::from_pem(pem)?;
-        Ok(Self(key))
+        Self::load_with_config([pem], JwtConfig::default())
+    }
+
+    /// Load all the keys the tokens may be signed with, e.g. the current one and the previous one,
+    /// for the algorithm of the config
+    pub fn load_with_config<'a>(
+        pems: impl IntoIterator<Item = &'a str>,
+        config: JwtConfig,
+    ) -> Result<Self, jwt_simple::Error> {
+        Ok(Self {
+            keys: RwLock::new(VerifyingKey::from_pems(config.algorithm, pems)?),
+            config,
+        })
+    }
+
+    /// Ids of the keys, in the order they were loaded
+    pub fn key_ids(&self) -> Vec<String> {
+        self.keys
+            .read()
+            .expect("verifying keys lock poisoned")
+            .iter()
+            .map(VerifyingKey::key_id)
+            .collect()
+    }
+
+    /// Swap all the keys at once, the tokens signed by a dropped key are rejected from now on
+    pub fn reload<'a>(
+        &self,
+        pems: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), jwt_simple::Error> {
+        let keys = VerifyingKey::from_pems(self.config.algorithm, pems)?;
+        *self.keys.write().expect("verifying keys lock poisoned") = keys;
+        Ok(())
     }
 
     #[allow(unused)]
     pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
+        Ok(self.verify_access_token(token)?.user)
+    }
+
+    pub fn verify_access_token(&self, token: &str) -> Result<AccessToken, jwt_simple::Error> {
         // let mut options = VerificationOptions::default();
         // options.allowed_issuers = Some(HashSet::from_strings(&[JWT_ISSUER]));
         // options.allowed_audiences = Some(HashSet::from_strings(&[JWT_AUDIENCE]));
 
         let options = VerificationOptions {
-            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
-            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+            allowed_issuers: Some(HashSet::from_strings(&[&self.config.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.audience])),
             ..Default::default()
         };
 
-        let claims = self.0.verify_token::<User>(token, Some(options))?;
-        Ok(claims.custom)
+        // tokens signed before the key ids were introduced have none, any key may match them
+        let kid = Token::decode_metadata(token)?.key_id().map(str::to_string);
+        let keys = self.keys.read().expect("verifying keys lock poisoned");
+        let mut ret = Err(jwt_simple::JWTError::KeyIdentifierMismatch.into());
+        for key in keys.iter() {
+            if kid.as_ref().is_some_and(|kid| *kid != key.key_id()) {
+                continue;
+            }
+            ret = key.verify_token(token, options.clone());
+            if ret.is_ok() {
+                break;
+            }
+        }
+
+        let claims = ret?;
+        let expires_at = claims
+            .expires_at
+            .and_then(|t| DateTime::from_timestamp(t.as_secs() as _, 0));
+        Ok(AccessToken {
+            user: claims.custom,
+            jti: claims.jwt_id,
+            expires_at,
+        })
+    }
+}
+
+impl KeyReload {
+    pub fn load<'a>(
+        algorithm: JwtAlgorithm,
+        sk: &str,
+        pks: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, jwt_simple::Error> {
+        Ok(Self {
+            key: SigningKey::from_pem(algorithm, sk)?,
+            keys: VerifyingKey::from_pems(algorithm, pks)?,
+        })
+    }
+
+    /// Swap the keys of both, the new public keys are trusted before the new key signs
+    pub fn apply(self, ek: &EncodingKey, dk: &DecodingKey) {
+        *dk.keys.write().expect("verifying keys lock poisoned") = self.keys;
+        *ek.key.write().expect("signing key lock poisoned") = self.key;
     }
 }
 
+impl SigningKey {
+    fn from_pem(algorithm: JwtAlgorithm, pem: &str) -> Result<Self, jwt_simple::Error> {
+        let key = match algorithm {
+            JwtAlgorithm::Ed25519 => {
+                let key = Ed25519KeyPair::from_pem(pem)?;
+                let kid = key_id(&key.public_key().to_der());
+                Self::Ed25519(key.with_key_id(&kid))
+            }
+            JwtAlgorithm::Es256 => {
+                let key = ES256KeyPair::from_pem(pem)?;
+                let kid = key_id(&key.public_key().to_der()?);
+                Self::Es256(key.with_key_id(&kid))
+            }
+            JwtAlgorithm::Rs256 => {
+                let key = RS256KeyPair::from_pem(pem)?;
+                let kid = key_id(&key.public_key().to_der()?);
+                Self::Rs256(Box::new(key.with_key_id(&kid)))
+            }
+        };
+        Ok(key)
+    }
+
+    fn key_id(&self) -> String {
+        let kid = match self {
+            Self::Ed25519(key) => key.key_id(),
+            Self::Es256(key) => key.key_id(),
+            Self::Rs256(key) => key.key_id(),
+        };
+        kid.clone().unwrap_or_default()
+    }
+
+    fn sign(&self, claims: JWTClaims<User>) -> Result<String, jwt_simple::Error> {
+        match self {
+            Self::Ed25519(key) => key.sign(claims),
+            Self::Es256(key) => key.sign(claims),
+            Self::Rs256(key) => key.sign(claims),
+        }
+    }
+}
+
+impl VerifyingKey {
+    fn from_pems<'a>(
+        algorithm: JwtAlgorithm,
+        pems: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<Self>, jwt_simple::Error> {
+        pems.into_iter()
+            .map(|pem| {
+                let key = match algorithm {
+                    JwtAlgorithm::Ed25519 => {
+                        let key = Ed25519PublicKey::from_pem(pem)?;
+                        let kid = key_id(&key.to_der());
+                        Self::Ed25519(key.with_key_id(&kid))
+                    }
+                    JwtAlgorithm::Es256 => {
+                        let key = ES256PublicKey::from_pem(pem)?;
+                        let kid = key_id(&key.to_der()?);
+                        Self::Es256(key.with_key_id(&kid))
+                    }
+                    JwtAlgorithm::Rs256 => {
+                        let key = RS256PublicKey::from_pem(pem)?;
+                        let kid = key_id(&key.to_der()?);
+                        Self::Rs256(Box::new(key.with_key_id(&kid)))
+                    }
+                };
+                Ok(key)
+            })
+            .collect()
+    }
+
+    fn key_id(&self) -> String {
+        let kid = match self {
+            Self::Ed25519(key) => key.key_id(),
+            Self::Es256(key) => key.key_id(),
+            Self::Rs256(key) => key.key_id(),
+        };
+        kid.clone().unwrap_or_default()
+    }
+
+    fn verify_token(
+        &self,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<User>, jwt_simple::Error> {
+        match self {
+            Self::Ed25519(key) => key.verify_token(token, Some(options)),
+            Self::Es256(key) => key.verify_token(token, Some(options)),
+            Self::Rs256(key) => key.verify_token(token, Some(options)),
+        }
+    }
+}
+
+/// the key id is derived from the DER encoding of the public key, the same for all the servers
+fn key_id(der: &[u8]) -> String {
+    Hash::hash(der)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Whether the token was revoked by a signout, the denylist is shared by all the servers
+pub async fn is_token_denied(pool: &PgPool, jti: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM token_denylist WHERE jti = $1)")
+        .bind(jti)
+        .fetch_one(pool)
+        .await
+}
+
+/// Whether the token was issued before a password change, the version of the user's tokens
+/// is bumped by it
+pub async fn is_token_outdated(pool: &PgPool, user: &User) -> Result<bool, sqlx::Error> {
+    let version: Option<i32> = sqlx::query_scalar("SELECT token_version FROM users WHERE id = $1")
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(version != Some(user.token_version))
+}
+
+/// Whether an admin deactivated the user in the workspace, its tokens are refused until reactivated
+pub async fn is_member_deactivated(
+    pool: &PgPool,
+    ws_id: i64,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NOT NULL
+        )
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -71,6 +379,141 @@ mod tests {
         let user2 = dk.verify(&token)?;
         assert_eq!(user, user2);
 
+        // every token has its own id
+        let token = dk.verify_access_token(&ek.sign(user.clone())?)?;
+        let other = dk.verify_access_token(&ek.sign(user)?)?;
+        assert!(token.jti.is_some());
+        assert_ne!(token.jti, other.jti);
+        assert!(token.expires_at.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_config_should_be_honored() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let config = JwtConfig {
+            duration: 60,
+            issuer: "acme_chat".to_string(),
+            audience: "acme_web".to_string(),
+            ..Default::default()
+        };
+        let ek = EncodingKey::load_with_config(encoding_pem, config.clone())?;
+        let token = ek.sign(User::new(1, "alon", "alon@gmail.com"))?;
+
+        let dk = DecodingKey::load_with_config([decoding_pem], config)?;
+        let access = dk.verify_access_token(&token)?;
+        let expires_at = access.expires_at.expect("token should expire");
+        assert!(expires_at <= Utc::now() + chrono::Duration::seconds(61));
+
+        // the default issuer and audience don't match
+        let dk = DecodingKey::load(decoding_pem)?;
+        assert!(dk.verify(&token).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_key_rotation_should_work() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let new_key = Ed25519KeyPair::generate();
+        let new_encoding_pem = new_key.to_pem();
+        let new_decoding_pem = new_key.public_key().to_pem();
+
+        let ek = EncodingKey::load(encoding_pem)?;
+        let user = User::new(1, "alon", "alon@gmail.com");
+        let old_token = ek.sign(user.clone())?;
+        let kid = Token::decode_metadata(&old_token)?
+            .key_id()
+            .map(str::to_string);
+        assert_eq!(kid, Some(ek.key_id()));
+
+        // the new key is trusted before it's used to sign, the old one until its tokens expire
+        let dk = DecodingKey::load_with_config(
+            [decoding_pem, new_decoding_pem.as_str()],
+            JwtConfig::default(),
+        )?;
+        ek.reload(&new_encoding_pem)?;
+        let new_token = ek.sign(user.clone())?;
+        assert_ne!(ek.key_id(), kid.unwrap_or_default());
+        assert_eq!(dk.verify(&old_token)?, user);
+        assert_eq!(dk.verify(&new_token)?, user);
+
+        dk.reload([new_decoding_pem.as_str()])?;
+        assert_eq!(dk.key_ids(), [ek.key_id()]);
+        assert!(dk.verify(&old_token).is_err());
+        assert_eq!(dk.verify(&new_token)?, user);
+
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_key_reload_should_swap_nothing_on_a_bad_key() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/private.pem");
+        let decoding_pem = include_str!("../../fixtures/public.pem");
+        let new_key = Ed25519KeyPair::generate();
+        let new_encoding_pem = new_key.to_pem();
+        let new_decoding_pem = new_key.public_key().to_pem();
+
+        let ek = EncodingKey::load(encoding_pem)?;
+        let dk = DecodingKey::load(decoding_pem)?;
+        let (kid, kids) = (ek.key_id(), dk.key_ids());
+
+        let ret = KeyReload::load(JwtAlgorithm::Ed25519, &new_encoding_pem, ["not a key"]);
+        assert!(ret.is_err());
+        let ret = KeyReload::load(
+            JwtAlgorithm::Ed25519,
+            "not a key",
+            [new_decoding_pem.as_str()],
+        );
+        assert!(ret.is_err());
+
+        KeyReload::load(
+            JwtAlgorithm::Ed25519,
+            &new_encoding_pem,
+            [new_decoding_pem.as_str()],
+        )?
+        .apply(&ek, &dk);
+        assert_ne!(ek.key_id(), kid);
+        assert_ne!(dk.key_ids(), kids);
+        assert_eq!(dk.key_ids(), [ek.key_id()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_algorithms_should_work() -> Result<()> {
+        let keys = [
+            (
+                JwtAlgorithm::Es256,
+                include_str!("../../fixtures/es256_private.pem"),
+                include_str!("../../fixtures/es256_public.pem"),
+            ),
+            (
+                JwtAlgorithm::Rs256,
+                include_str!("../../fixtures/rs256_private.pem"),
+                include_str!("../../fixtures/rs256_public.pem"),
+            ),
+        ];
+        let user = User::new(1, "alon", "alon@gmail.com");
+        for (algorithm, encoding_pem, decoding_pem) in keys {
+            let config = JwtConfig {
+                algorithm,
+                ..Default::default()
+            };
+            let ek = EncodingKey::load_with_config(encoding_pem, config.clone())?;
+            let dk = DecodingKey::load_with_config([decoding_pem], config)?;
+            let token = ek.sign(user.clone())?;
+            assert_eq!(dk.verify(&token)?, user);
+            assert_eq!(dk.key_ids(), [ek.key_id()]);
+
+            // the tokens of the other algorithms are rejected
+            let dk = DecodingKey::load(include_str!("../../fixtures/public.pem"))?;
+            assert!(dk.verify(&token).is_err());
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,115 @@
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer as _, Registry,
+};
+
+/// the filter of the installed logger, swapped when the config is reloaded
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Pretty,
+    /// a JSON object per line, with the fields of the request being handled
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// level of the modules without a filter
+    pub level: String,
+    /// level of some modules, e.g. `sqlx: warn`
+    pub filters: BTreeMap<String, String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: "info".to_string(),
+            filters: BTreeMap::new(),
+        }
+    }
+}
+
+impl LogConfig {
+    fn directives(&self) -> String {
+        let mut directives = vec![self.level.clone()];
+        for (target, level) in &self.filters {
+            directives.push(format!("{target}={level}"));
+        }
+        directives.join(",")
+    }
+
+    fn filter(&self) -> Result<EnvFilter> {
+        // a bare word is taken for a module by the filter, the levels are checked first
+        for level in std::iter::once(&self.level).chain(self.filters.values()) {
+            if level.parse::<LevelFilter>().is_err() {
+                bail!("invalid log level: {level}");
+            }
+        }
+        Ok(EnvFilter::builder().parse(self.directives())?)
+    }
+}
+
+/// Install the logger of the process, the lines are filtered by the level and the filters
+pub fn init_logging(config: &LogConfig) -> Result<()> {
+    let layer = match config.format {
+        LogFormat::Pretty => fmt::layer().boxed(),
+        // the span of a request holds its id, route and user, the response event its latency
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    let (filter, handle) = reload::Layer::new(config.filter()?);
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .try_init()?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Apply the level and the filters of the config to the installed logger, the format can
+/// only be changed by a restart
+pub fn reload_logging(config: &LogConfig) -> Result<()> {
+    let filter = config.filter()?;
+    if let Some(handle) = FILTER.get() {
+        handle.reload(filter)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_filters_should_be_parsed() -> Result<()> {
+        let config = LogConfig {
+            filters: [("sqlx", "warn"), ("tower_http", "debug")]
+                .map(|(target, level)| (target.to_string(), level.to_string()))
+                .into(),
+            ..Default::default()
+        };
+        assert_eq!(config.directives(), "info,sqlx=warn,tower_http=debug");
+        assert!(config.filter().is_ok());
+
+        let config = LogConfig {
+            level: "loud".to_string(),
+            ..Default::default()
+        };
+        assert!(config.filter().is_err());
+        Ok(())
+    }
+}
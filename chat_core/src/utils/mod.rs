@@ -1,3 +1,14 @@
+mod config;
+mod db;
 mod jwt;
+mod log;
+mod serve;
 
-pub use jwt::{DecodingKey, EncodingKey};
+pub use config::{reload_config, ConfigError, ConfigLoader, ConfigReload, ReloadTrigger};
+pub use db::begin_tagged;
+pub use jwt::{
+    is_member_deactivated, is_token_denied, is_token_outdated, AccessToken, DecodingKey,
+    EncodingKey, JwtAlgorithm, JwtConfig, KeyReload,
+};
+pub use log::{init_logging, reload_logging, LogConfig, LogFormat};
+pub use serve::{serve, ListenAddr, ReloadableRouter, UNIX_PEER};
@@ -0,0 +1,282 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use axum::{extract::Request, Router};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tower::{service_fn, ServiceExt};
+use tracing::info;
+
+/// the address the clients of a unix socket are seen from, by the rate limits and the trusted
+/// proxies
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Where a server listens, a TCP address like `0.0.0.0:6688`, or the path of a unix socket
+/// like `unix:/run/chat/chat.sock`, the prefix being optional for absolute paths
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        if s.starts_with('/') {
+            return Ok(Self::Unix(s.into()));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|e| format!("invalid listen address {s}: {e}"))
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A router that can be replaced while it's served, e.g. with the layers of a reloaded config,
+/// the requests in flight end on the previous one
+#[derive(Clone)]
+pub struct ReloadableRouter(Arc<ArcSwap<Router>>);
+
+impl ReloadableRouter {
+    pub fn new(app: Router) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(app)))
+    }
+
+    pub fn store(&self, app: Router) {
+        self.0.store(Arc::new(app));
+    }
+
+    /// The router to serve, each request is routed by the router stored last
+    pub fn router(&self) -> Router {
+        let current = self.0.clone();
+        Router::new().fallback_service(service_fn(move |req: Request| {
+            let app = Router::clone(&current.load());
+            app.oneshot(req)
+        }))
+    }
+}
+
+/// Serve the app until `signal`, then wait for the open connections to end
+pub async fn serve(
+    app: Router,
+    addr: &ListenAddr,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    match addr {
+        ListenAddr::Tcp(tcp) => {
+            let listener = TcpListener::bind(tcp).await?;
+            info!("Listening on: {}", addr);
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(signal)
+            .await
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => unix::serve(app, path, signal).await,
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets aren't supported on this platform",
+        )),
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{convert::Infallible, future::Future, io, os::unix::fs::FileTypeExt, path::Path};
+
+    use axum::{
+        body::Body,
+        extract::{ConnectInfo, Request},
+        Router,
+    };
+    use hyper::body::Incoming;
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto::Builder, graceful::GracefulShutdown},
+    };
+    use tokio::{net::UnixListener, time};
+    use tower::Service;
+    use tracing::{debug, info, warn};
+
+    use super::UNIX_PEER;
+
+    pub(super) async fn serve(
+        app: Router,
+        path: &Path,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<()> {
+        remove_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        info!("Listening on: unix:{}", path.display());
+
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(signal);
+        loop {
+            let stream = tokio::select! {
+                ret = listener.accept() => match ret {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // e.g. out of file descriptors, wait for some to be closed
+                        warn!("Failed to accept a connection: {}", e);
+                        time::sleep(time::Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = &mut signal => break,
+            };
+
+            let app = app.clone();
+            let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+                let mut req = req.map(Body::new);
+                req.extensions_mut().insert(ConnectInfo(UNIX_PEER));
+                let mut app = app.clone();
+                async move { Ok::<_, Infallible>(app.call(req).await.unwrap_or_else(|e| match e {})) }
+            });
+            let conn = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let conn = graceful.watch(conn);
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    debug!("Connection closed: {}", e);
+                }
+            });
+        }
+
+        drop(listener);
+        remove_socket(path)?;
+        graceful.shutdown().await;
+        Ok(())
+    }
+
+    /// Remove the socket left by a previous run, the bind fails otherwise, anything else at
+    /// the path is kept
+    fn remove_socket(path: &Path) -> io::Result<()> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get};
+
+    #[tokio::test]
+    async fn reloadable_router_should_route_with_the_last_stored() -> anyhow::Result<()> {
+        let reloadable = ReloadableRouter::new(Router::new().route("/", get(|| async { "v1" })));
+        let app = reloadable.router();
+        let body = |app: Router| async move {
+            let req = Request::builder().uri("/").body(Body::empty())?;
+            let resp = app.oneshot(req).await?;
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+            anyhow::Ok(String::from_utf8(body.to_vec())?)
+        };
+        assert_eq!(body(app.clone()).await?, "v1");
+
+        reloadable.store(Router::new().route("/", get(|| async { "v2" })));
+        assert_eq!(body(app).await?, "v2");
+        Ok(())
+    }
+
+    #[test]
+    fn listen_addr_should_parse() {
+        let addr: ListenAddr = "0.0.0.0:6688".parse().unwrap();
+        assert_eq!(addr, ListenAddr::Tcp(([0, 0, 0, 0], 6688).into()));
+        let addr: ListenAddr = "unix:/run/chat/chat.sock".parse().unwrap();
+        assert_eq!(addr, ListenAddr::Unix("/run/chat/chat.sock".into()));
+        assert_eq!(addr.to_string(), "unix:/run/chat/chat.sock");
+        let addr: ListenAddr = "/run/chat/chat.sock".parse().unwrap();
+        assert_eq!(addr, ListenAddr::Unix("/run/chat/chat.sock".into()));
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_should_listen_on_unix_sockets() -> anyhow::Result<()> {
+        use axum::{extract::ConnectInfo, routing::get};
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::UnixStream,
+            sync::oneshot,
+        };
+
+        let path = std::env::temp_dir().join(format!("chat-{}.sock", uuid::Uuid::now_v7()));
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.to_string() }),
+        );
+        let (tx, rx) = oneshot::channel::<()>();
+        let addr = ListenAddr::Unix(path.clone());
+        let server = tokio::spawn(async move {
+            serve(app, &addr, async {
+                rx.await.ok();
+            })
+            .await
+        });
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: chat\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        assert!(resp.ends_with("127.0.0.1:0"));
+
+        tx.send(()).ok();
+        server.await??;
+        assert!(!path.exists());
+        Ok(())
+    }
+}
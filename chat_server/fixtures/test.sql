@@ -42,20 +42,25 @@ VALUES
         '$argon2id$v=19$m=19456,t=2,p=1$MxGhY+ib/kplwBPLa7u2ug$c5h9u7Sc8Px8J5+qgNdOjSY7ZJO2QN4rugKpapGW4XU'
     );
 
+-- tchen owns acme, alice is an admin
+UPDATE workspaces SET owner_id = 1 WHERE id = 1;
+UPDATE workspace_members SET role = 'owner' WHERE ws_id = 1 AND user_id = 1;
+UPDATE workspace_members SET role = 'admin' WHERE ws_id = 1 AND user_id = 2;
+
 -- insert 4 chats
 -- insert public/private channel
 INSERT INTO
-    chats(ws_id, name, type, members)
+    chats(ws_id, name, type, members, owner_id)
 VALUES
-    (1, 'general', 'public_channel', '{1,2,3,4,5}'),
-    (1, 'private', 'private_channel', '{1,2,3}');
+    (1, 'general', 'public_channel', '{1,2,3,4,5}', 1),
+    (1, 'private', 'private_channel', '{1,2,3}', 1);
 
 -- insert unnamed chat
 INSERT INTO
-    chats(ws_id, type, members)
+    chats(ws_id, type, members, owner_id)
 VALUES
-    (1, 'single', '{1,2}'),
-    (1, 'group', '{1,3,4}');
+    (1, 'single', '{1,2}', 1),
+    (1, 'group', '{1,3,4}', 1);
 
 INSERT INTO
     messages(chat_id, sender_id, content)
@@ -70,3 +75,8 @@ VALUES
     (1, 3, 'How are you?'),
     (1, 1, 'Hello, world!'),
     (1, 1, 'Hello, world!');
+
+-- the fixtures aren't events anyone was sent
+DELETE FROM user_events;
+
+UPDATE users SET event_seq = 0;
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically mail the users offline for long enough the mentions and direct messages
+/// they missed
+pub fn spawn_email_digest(state: AppState) -> JoinHandle<()> {
+    let interval = state.config().digest.interval;
+    let offline_after = state.config().digest.offline_after;
+    let max_messages = state.config().digest.max_messages.max(1);
+    tokio::spawn(async move {
+        if interval == 0 {
+            info!("Email digests are disabled");
+            return;
+        }
+
+        let mut interval = time::interval(Duration::from_secs(interval));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let offline_before = Utc::now() - chrono::Duration::seconds(offline_after as _);
+            match state.send_digests(offline_before, max_messages as _).await {
+                Ok(0) => {}
+                Ok(sent) => info!("Sent {} email digests", sent),
+                Err(e) => warn!("Failed to send email digests: {}", e),
+            }
+        }
+    })
+}
@@ -1,15 +1,37 @@
 use axum::{
-    http::{self, StatusCode},
+    extract::multipart::MultipartError,
+    http::{self, header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use chat_core::middlewares::current_request_id;
 use serde::{Deserialize, Serialize};
+use sqlx::{error::ErrorKind, postgres::PgDatabaseError};
 use thiserror::Error;
+use tracing::{error, warn};
 use utoipa::ToSchema;
+use validator::ValidationErrors;
+
+/// the suffixes postgres and the migrations give the names of the constraints
+const CONSTRAINT_SUFFIXES: [&str; 7] =
+    ["_pkey", "_fkey", "_fk", "_key", "_check", "_index", "_idx"];
 
 #[derive(Debug, ToSchema, Serialize, Deserialize)]
 pub struct ErrorOutput {
     pub error: String,
+    /// what exactly is wrong with the input, only set for validation errors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ValidationIssue>,
+    /// the id of the failed request, to look it up in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ValidationIssue {
+    pub field: String,
+    pub code: String,
+    pub message: String,
 }
 
 #[derive(Debug, Error)]
@@ -26,12 +48,45 @@ pub enum AppError {
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
+    #[error("invalid content: {}", .0.iter().map(|v| v.message.as_str()).collect::<Vec<_>>().join(", "))]
+    ValidationError(Vec<ValidationIssue>),
+
+    #[error("search error: {0}")]
+    SearchError(String),
+
+    #[error("poll error: {0}")]
+    PollError(String),
+
+    #[error("invalid cursor: {0}")]
+    InvalidCursor(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
     #[error("chat file error: {0}")]
     ChatFileError(String),
 
+    #[error("file in use: {0}")]
+    FileInUse(String),
+
+    #[error("file scan pending: {0}")]
+    ScanPending(String),
+
     #[error("not found: {0}")]
     NotFound(String),
 
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("too many requests, retry after {0} seconds")]
+    TooManyRequests(u64),
+
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -46,16 +101,82 @@ pub enum AppError {
 
     #[error("http header parse error: {0}")]
     HttpHeaderError(#[from] http::header::InvalidHeaderValue),
+
+    #[error("http client error: {0}")]
+    HttpClientError(#[from] reqwest::Error),
+
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("mail error: {0}")]
+    MailError(String),
+
+    #[error("storage error: {0}")]
+    StorageError(String),
+
+    #[error("scan error: {0}")]
+    ScanError(String),
+
+    #[error("media error: {0}")]
+    MediaError(String),
 }
 
 impl ErrorOutput {
     pub fn new(error: impl Into<String>) -> Self {
         Self {
             error: error.into(),
+            details: vec![],
+            request_id: current_request_id(),
         }
     }
 }
 
+impl ValidationIssue {
+    pub fn new(field: &str, code: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(e.body_text()),
+            _ => Self::ChatFileError(e.body_text()),
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut issues: Vec<_> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |e| {
+                    // a rule across fields names the field it is about
+                    let field = e
+                        .params
+                        .get("field")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or(&field);
+                    let message = match &e.message {
+                        Some(message) => message.to_string(),
+                        None => format!("{field} is invalid"),
+                    };
+                    ValidationIssue::new(field, &e.code, message)
+                })
+            })
+            .collect();
+        // the map of the errors has no order
+        issues.sort_by(|a, b| a.field.cmp(&b.field));
+        Self::ValidationError(issues)
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let status = match &self {
@@ -63,15 +184,194 @@ impl IntoResponse for AppError {
             Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
             Self::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::SearchError(_) => StatusCode::BAD_REQUEST,
+            Self::PollError(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidCursor(_) => StatusCode::BAD_REQUEST,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
+            Self::FileInUse(_) => StatusCode::CONFLICT,
+            Self::ScanPending(_) => StatusCode::CONFLICT,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            // see database_error
             Self::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::JwtError(_) => StatusCode::FORBIDDEN,
             Self::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::HttpClientError(_) => StatusCode::BAD_GATEWAY,
+            Self::JsonError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MailError(_) => StatusCode::BAD_GATEWAY,
+            Self::StorageError(_) => StatusCode::BAD_GATEWAY,
+            Self::ScanError(_) => StatusCode::BAD_GATEWAY,
+            Self::MediaError(_) => StatusCode::BAD_GATEWAY,
+        };
+
+        if let Self::SqlxError(e) = &self {
+            let (status, output) = database_error(e);
+            return (status, Json(output)).into_response();
+        }
+
+        let mut output = ErrorOutput::new(self.to_string());
+        let retry_after = match self {
+            Self::ValidationError(issues) => {
+                output.details = issues;
+                None
+            }
+            Self::TooManyRequests(secs) => Some(secs),
+            _ => None,
         };
 
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let mut resp = (status, Json(output)).into_response();
+        if let Some(secs) = retry_after {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        resp
+    }
+}
+
+/// What a database error means for the client, a violated constraint is the fault of the
+/// input, the SQL details only go to the logs
+fn database_error(e: &sqlx::Error) -> (StatusCode, ErrorOutput) {
+    let internal = || {
+        error!("Database error: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorOutput::new("internal error"),
+        )
+    };
+    let Some(db) = e.as_database_error() else {
+        return internal();
+    };
+    let Some(pg) = db.try_downcast_ref::<PgDatabaseError>() else {
+        return internal();
+    };
+    warn!("Database error: {}", e);
+
+    let field = constraint_field(pg);
+    let (status, code, message) = match db.kind() {
+        ErrorKind::UniqueViolation => (StatusCode::CONFLICT, "unique", "already exists"),
+        // deleting a row others still refer to
+        ErrorKind::ForeignKeyViolation
+            if pg
+                .detail()
+                .is_some_and(|d| d.contains("is still referenced")) =>
+        {
+            (StatusCode::CONFLICT, "in_use", "is still in use")
+        }
+        ErrorKind::ForeignKeyViolation => (StatusCode::BAD_REQUEST, "foreign_key", "doesn't exist"),
+        ErrorKind::NotNullViolation => (StatusCode::BAD_REQUEST, "required", "is required"),
+        ErrorKind::CheckViolation => (StatusCode::BAD_REQUEST, "check", "is invalid"),
+        // serialization failure and deadlock, nothing was changed
+        _ if matches!(pg.code(), "40001" | "40P01") => {
+            let output = ErrorOutput::new("conflict: a concurrent change, retry the request");
+            return (StatusCode::CONFLICT, output);
+        }
+        _ => return internal(),
+    };
+
+    if field.is_empty() {
+        return (
+            status,
+            ErrorOutput::new(format!("invalid input: a value {message}")),
+        );
+    }
+    let mut output = ErrorOutput::new(format!("invalid input: {field} {message}"));
+    output.details = vec![ValidationIssue::new(
+        &field,
+        code,
+        format!("{field} {message}"),
+    )];
+    (status, output)
+}
+
+/// The columns of the violated constraint, e.g. `email` or `ws_id, name`
+fn constraint_field(pg: &PgDatabaseError) -> String {
+    // e.g. Key (email)=(tchen@acme.org) already exists, the values are left out
+    let keys = pg
+        .detail()
+        .and_then(|d| d.strip_prefix("Key ("))
+        .and_then(|d| d.split_once(")=("))
+        .map(|(keys, _)| keys)
+        // the expression of an index
+        .filter(|keys| !keys.contains('('));
+    if let Some(keys) = keys {
+        return keys.to_string();
+    }
+    if let Some(column) = pg.column() {
+        return column.to_string();
+    }
+
+    let Some(constraint) = pg.constraint() else {
+        return String::new();
+    };
+    let name = pg
+        .table()
+        .and_then(|table| constraint.strip_prefix(table))
+        .and_then(|name| name.strip_prefix('_'))
+        .unwrap_or(constraint);
+    CONSTRAINT_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    async fn query_error(state: &AppState, sql: &str) -> (StatusCode, ErrorOutput) {
+        let e = sqlx::query(sql)
+            .execute(&state.pool)
+            .await
+            .expect_err("the query should fail");
+        let resp = AppError::from(e).into_response();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn database_errors_should_map_to_statuses() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let sql = "INSERT INTO workspaces(name, owner_id) VALUES ('acme', 0)";
+        let (status, output) = query_error(&state, sql).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(output.details[0].field, "name");
+        assert_eq!(output.details[0].code, "unique");
+        assert!(!output.error.contains("acme"));
+
+        let (status, output) =
+            query_error(&state, "UPDATE users SET ws_id = 999 WHERE id = 1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(output.details[0].field, "ws_id");
+        assert_eq!(output.details[0].code, "foreign_key");
+
+        let (status, output) = query_error(&state, "DELETE FROM workspaces WHERE id = 1").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(output.details[0].code, "in_use");
+
+        let (status, output) =
+            query_error(&state, "UPDATE users SET email = NULL WHERE id = 1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(output.details[0].field, "email");
+        assert_eq!(output.details[0].code, "required");
+
+        // the SQL details stay in the logs
+        let (status, output) = query_error(&state, "SELECT secret FROM users").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(output.error, "internal error");
+        Ok(())
     }
 }
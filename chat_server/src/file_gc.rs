@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically remove the stored files no message or metadata references anymore
+pub fn spawn_file_gc(state: AppState) -> JoinHandle<()> {
+    let interval = state.config().files.gc_interval;
+    let grace = state.config().files.gc_grace;
+    tokio::spawn(async move {
+        if interval == 0 {
+            info!("File garbage collection is disabled");
+            return;
+        }
+
+        let mut interval = time::interval(Duration::from_secs(interval));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - chrono::Duration::seconds(grace as _);
+            match state.collect_file_garbage(cutoff).await {
+                Ok(stats) if stats.files == 0 => {}
+                Ok(stats) => info!("Removed {} unreferenced files", stats.files),
+                Err(e) => warn!("Failed to collect unreferenced files: {}", e),
+            }
+        }
+    })
+}
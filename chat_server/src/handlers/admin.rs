@@ -0,0 +1,214 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    ApiKey, AppError, AppState, CreateApiKey, CreatedApiKey, ErrorOutput, ListWorkspaceMembers,
+    Page, SetMemberRole, WorkspaceMember,
+};
+
+/// List the users of the current workspace with their role and state, admin only.
+#[utoipa::path(
+    get,
+    path = "/api/admin/users",
+    params(
+        ListWorkspaceMembers
+    ),
+    responses(
+        (status = 200, description = "Page of ws members", body = Page<WorkspaceMember>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_list_users_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListWorkspaceMembers>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state.list_workspace_members(user.ws_id as _, input).await?;
+    Ok(Json(members))
+}
+
+/// Deactivate a user of the current workspace, admin only.
+///
+/// The user's tokens are rejected by the workspace until reactivated and the user is left out of the member lists.
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/deactivate",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "User deactivated", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_deactivate_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.set_member_deactivated(&user, id, true).await?;
+    Ok(Json(member))
+}
+
+/// Reactivate a deactivated user of the current workspace, admin only.
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/reactivate",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "User reactivated", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_reactivate_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.set_member_deactivated(&user, id, false).await?;
+    Ok(Json(member))
+}
+
+/// Force a password reset of a user of the current workspace, admin only.
+///
+/// - The password is replaced with a temporary one mailed to the user.
+/// - All the tokens of the user are revoked.
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/password_reset",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 204, description = "Password reset"),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_reset_password_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_user_password(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Change the role of a user of the current workspace, admin only.
+///
+/// Admins can promote members, only the owner can demote admins.
+#[utoipa::path(
+    put,
+    path = "/api/admin/users/{id}/role",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    request_body = SetMemberRole,
+    responses(
+        (status = 200, description = "Role changed", body = WorkspaceMember),
+        (status = 403, description = "Not allowed to manage the user", body = ErrorOutput),
+        (status = 404, description = "User not found", body = ErrorOutput),
+        (status = 422, description = "Invalid role", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_set_user_role_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<SetMemberRole>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.set_member_role(&user, id, &input).await?;
+    Ok(Json(member))
+}
+
+/// Create an API key for a bot, admin only.
+///
+/// - The key acts as a new bot user, which joins the given group chats and channels.
+/// - Bots can only post and read messages in their chats.
+/// - The key is only returned here, keep it safe.
+#[utoipa::path(
+    post,
+    path = "/api/apikeys",
+    request_body = CreateApiKey,
+    responses(
+        (status = 201, description = "API key created", body = CreatedApiKey),
+        (status = 422, description = "Invalid name or chats", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_api_key_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateApiKey>,
+) -> Result<impl IntoResponse, AppError> {
+    let api_key = state.create_api_key(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(api_key)))
+}
+
+/// List the API keys of the current workspace, admin only.
+#[utoipa::path(
+    get,
+    path = "/api/apikeys",
+    responses(
+        (status = 200, description = "List of API keys", body = Vec<ApiKey>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_api_keys_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let api_keys = state.list_api_keys(user.ws_id as _).await?;
+    Ok(Json(api_keys))
+}
+
+/// Revoke an API key of the current workspace, admin only.
+#[utoipa::path(
+    delete,
+    path = "/api/apikeys/{id}",
+    params(
+        ("id" = u64, Path, description = "API key id")
+    ),
+    responses(
+        (status = 200, description = "API key revoked", body = ApiKey),
+        (status = 404, description = "API key not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn revoke_api_key_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let api_key = state.revoke_api_key(user.ws_id as _, id).await?;
+    Ok(Json(api_key))
+}
@@ -1,36 +1,171 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use axum_extra::{
+    extract::{
+        cookie::{Cookie, SameSite},
+        CookieJar,
+    },
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chat_core::{
+    middlewares::{csrf_token_matches, ACCESS_TOKEN_COOKIE, CSRF_TOKEN_COOKIE},
+    User,
+};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-use crate::{models::SigninUser, AppError, AppState, CreateUser, ErrorOutput};
+use crate::{
+    config::CookieSameSite, models::SigninUser, AppError, AppState, CreateUser, ErrorOutput,
+    RefreshToken, Signout, ValidJson, REFRESH_TOKEN_TTL,
+};
+
+/// name of the HttpOnly cookie holding the refresh token, only sent to the auth routes
+const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
 
 #[derive(Debug, ToSchema, Serialize, Deserialize)]
 pub struct AuthOutput {
-    token: String,
+    /// Short-lived access token
+    pub(crate) token: String,
+    /// Single use token to get a new pair from `POST /api/token/refresh`
+    pub(crate) refresh_token: String,
+}
+
+/// Sign an access token for the user along with a refresh token for the session
+pub(crate) async fn auth_output(state: &AppState, user: User) -> Result<AuthOutput, AppError> {
+    let refresh_token = state.create_refresh_token(&user).await?;
+    let token = state.ek.sign(user)?;
+    Ok(AuthOutput {
+        token,
+        refresh_token,
+    })
+}
+
+/// Sign the tokens of the user, they're also set as cookies when the cookie mode is enabled,
+/// along with the CSRF token the browser clients send back in the CSRF header of their writes
+pub(crate) async fn auth_response(
+    state: &AppState,
+    jar: CookieJar,
+    user: User,
+) -> Result<(CookieJar, Json<AuthOutput>), AppError> {
+    let output = auth_output(state, user).await?;
+    let config = &state.config().auth;
+    if !config.cookie.enabled {
+        return Ok((jar, Json(output)));
+    }
+
+    let access = auth_cookie(
+        state,
+        ACCESS_TOKEN_COOKIE,
+        output.token.clone(),
+        time::Duration::seconds(config.jwt.duration as _),
+    );
+    let refresh = auth_cookie(
+        state,
+        REFRESH_TOKEN_COOKIE,
+        output.refresh_token.clone(),
+        time::Duration::days(REFRESH_TOKEN_TTL as _),
+    );
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let csrf = auth_cookie(
+        state,
+        CSRF_TOKEN_COOKIE,
+        hex::encode(bytes),
+        time::Duration::days(REFRESH_TOKEN_TTL as _),
+    );
+    Ok((jar.add(access).add(refresh).add(csrf), Json(output)))
+}
+
+/// Drop the token cookies, if any
+fn clear_auth_cookies(state: &AppState, jar: CookieJar) -> CookieJar {
+    if !state.config().auth.cookie.enabled {
+        return jar;
+    }
+    jar.remove(auth_cookie(
+        state,
+        ACCESS_TOKEN_COOKIE,
+        "",
+        time::Duration::ZERO,
+    ))
+    .remove(auth_cookie(
+        state,
+        REFRESH_TOKEN_COOKIE,
+        "",
+        time::Duration::ZERO,
+    ))
+    .remove(auth_cookie(
+        state,
+        CSRF_TOKEN_COOKIE,
+        "",
+        time::Duration::ZERO,
+    ))
+}
+
+fn refresh_token_cookie(jar: &CookieJar) -> Option<String> {
+    jar.get(REFRESH_TOKEN_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// the refresh token is only sent to the api, where the auth routes are, the CSRF token is
+/// the only one the browser clients can read
+fn auth_cookie<'a>(
+    state: &AppState,
+    name: &'a str,
+    value: impl Into<String>,
+    max_age: time::Duration,
+) -> Cookie<'a> {
+    let config = &state.config().auth.cookie;
+    let same_site = match config.same_site {
+        CookieSameSite::Strict => SameSite::Strict,
+        CookieSameSite::Lax => SameSite::Lax,
+        CookieSameSite::None => SameSite::None,
+    };
+    let path = if name == REFRESH_TOKEN_COOKIE {
+        "/api"
+    } else {
+        "/"
+    };
+    Cookie::build((name, value.into()))
+        .http_only(name != CSRF_TOKEN_COOKIE)
+        .secure(config.secure)
+        .same_site(same_site)
+        .path(path)
+        .max_age(max_age)
+        .build()
 }
 
 /// Create a new user in the chat system with email, password workspace and full name.
 ///
 /// - If the email already exists, it will return 409.
+/// - If a field is invalid, e.g. a password shorter than 8 characters, it will return 422.
 /// - Otherwise, it will return 201 with a token.
 /// - If the workspace doesn't exist, it will create one.
 #[utoipa::path(
     post,
     path = "/api/signup",
     responses(
-        (status = 201, description = "User created", body = AuthOutput)
+        (status = 201, description = "User created", body = AuthOutput),
+        (status = 409, description = "Email already exists", body = ErrorOutput),
+        (status = 422, description = "Invalid full name, email, workspace or password", body = ErrorOutput),
+        (status = 429, description = "Too many attempts, retry after the Retry-After seconds", body = ErrorOutput),
     )
 )]
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
-    Json(input): Json<CreateUser>,
+    jar: CookieJar,
+    ValidJson(input): ValidJson<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
     // let mut header = HeaderMap::new();
     // header.insert("X-Token", HeaderValue::from_str(&token)?);
     // Ok((StatusCode::CREATED, header))
-    let body = Json(AuthOutput { token });
+    let body = auth_response(&state, jar, user).await?;
     Ok((StatusCode::CREATED, body))
 }
 
@@ -39,19 +174,21 @@ pub(crate) async fn signup_handler(
     post,
     path = "/api/signin",
     responses(
-        (status = 200, description = "User signed in", body = AuthOutput)
+        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 429, description = "Too many attempts, retry after the Retry-After seconds", body = ErrorOutput),
     )
 )]
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    jar: CookieJar,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.verify_user(&input).await?;
 
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            let body = auth_response(&state, jar, user).await?;
+            Ok((StatusCode::OK, body).into_response())
         }
         None => Ok((
             StatusCode::FORBIDDEN,
@@ -61,12 +198,135 @@ pub(crate) async fn signin_handler(
     }
 }
 
+/// Exchange a refresh token for a new access token and refresh token.
+///
+/// - A refresh token can only be used once, the returned one replaces it.
+/// - Changing the password revokes all the refresh tokens of the user.
+/// - In the cookie mode, the refresh token is taken from its cookie when not given, the CSRF
+///   token must be sent back in the `X-CSRF-Token` header then.
+#[utoipa::path(
+    post,
+    path = "/api/token/refresh",
+    request_body = RefreshToken,
+    responses(
+        (status = 200, description = "New tokens", body = AuthOutput),
+        (status = 401, description = "Invalid, used or expired refresh token", body = ErrorOutput),
+        (status = 403, description = "Missing or mismatched CSRF token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn refresh_token_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    input: Option<Json<RefreshToken>>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = match input {
+        Some(Json(input)) => input.refresh_token,
+        None => {
+            let token = refresh_token_cookie(&jar)
+                .ok_or_else(|| AppError::Unauthorized("Missing refresh token".to_string()))?;
+            // the browsers send the cookie along with the cross-site requests too
+            if !csrf_token_matches(&headers) {
+                return Err(AppError::PermissionDenied(
+                    "Missing or mismatched CSRF token".to_string(),
+                ));
+            }
+            token
+        }
+    };
+    let user = state.use_refresh_token(&token).await?;
+    auth_response(&state, jar, user).await
+}
+
+/// Sign out of the current session.
+///
+/// - The access token is revoked right away, on the chat and the notify server.
+/// - The refresh token of the session is revoked when given, other sessions stay signed in.
+/// - In the cookie mode, the tokens are taken from their cookies and the cookies are dropped.
+#[utoipa::path(
+    post,
+    path = "/api/signout",
+    request_body = Signout,
+    responses(
+        (status = 204, description = "Signed out"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn signout_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    jar: CookieJar,
+    input: Option<Json<Signout>>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = match &bearer {
+        Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
+        None => jar.get(ACCESS_TOKEN_COOKIE).map(|cookie| cookie.value()),
+    };
+    let token = token.ok_or_else(|| AppError::Unauthorized("Missing access token".to_string()))?;
+    let token = state.dk.verify_access_token(token)?;
+    let mut input = input.map(|Json(input)| input).unwrap_or_default();
+    if input.refresh_token.is_none() {
+        input.refresh_token = refresh_token_cookie(&jar);
+    }
+    state.signout(user.id as _, &token, &input).await?;
+    Ok((clear_auth_cookies(&state, jar), StatusCode::NO_CONTENT))
+}
+
+/// Verify the email of a user with the token mailed at signup.
+#[utoipa::path(
+    get,
+    path = "/api/verify_email/{token}",
+    params(
+        ("token" = String, Path, description = "Verification token from the mail")
+    ),
+    responses(
+        (status = 204, description = "Email verified"),
+        (status = 404, description = "Invalid or expired token", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.verify_email(&token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mail the verification link again, e.g. when the first one was lost or expired.
+///
+/// - The links sent before stop working.
+/// - Nothing is sent when the email is already verified.
+#[utoipa::path(
+    post,
+    path = "/api/verify_email/resend",
+    responses(
+        (status = 204, description = "Verification link sent"),
+        (status = 429, description = "A link was just sent, retry after the Retry-After seconds", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn resend_verification_email_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.resend_verification_email(user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
     use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use chat_core::middlewares::CSRF_TOKEN_HEADER;
     use http_body_util::BodyExt as _;
+    use tower::ServiceExt;
 
     #[tokio::test]
     async fn test_signup_should_work() -> Result<()> {
@@ -77,7 +337,7 @@ mod tests {
         let password = "hunter42";
         let input = CreateUser::new("Default Workspace", email, full_name, password);
 
-        let ret = signup_handler(State(state), Json(input))
+        let ret = signup_handler(State(state), CookieJar::default(), ValidJson(input))
             .await?
             .into_response();
 
@@ -99,7 +359,7 @@ mod tests {
         let password = "123456";
         let input = CreateUser::new("Default Workspace", email, full_name, password);
 
-        let ret = signup_handler(State(state), Json(input))
+        let ret = signup_handler(State(state), CookieJar::default(), ValidJson(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::CONFLICT);
@@ -119,7 +379,7 @@ mod tests {
         let password = "123456";
         let input = SigninUser::new(email, password);
 
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), CookieJar::default(), Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
@@ -139,7 +399,7 @@ mod tests {
         let password = "hunter42";
         let input = SigninUser::new(email, password);
 
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), CookieJar::default(), Json(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::FORBIDDEN);
@@ -150,4 +410,80 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn signin_should_set_cookies_in_cookie_mode() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        state.update_config(|config| config.auth.cookie.enabled = true);
+
+        let input = SigninUser::new("tchen@acme.org", "123456");
+        let ret = signin_handler(State(state.clone()), CookieJar::default(), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let cookies: Vec<_> = ret
+            .headers()
+            .get_all(axum::http::header::SET_COOKIE)
+            .iter()
+            .map(|v| Cookie::parse(v.to_str().unwrap_or_default().to_string()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies
+            .iter()
+            .all(|c| c.http_only().unwrap_or_default() == (c.name() != CSRF_TOKEN_COOKIE)));
+        assert!(cookies.iter().all(|c| c.secure() == Some(true)));
+
+        // the refresh token is taken from its cookie, along with the CSRF token
+        let cookie = cookies
+            .iter()
+            .map(|c| c.stripped().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut headers = HeaderMap::new();
+        headers.insert(axum::http::header::COOKIE, cookie.parse()?);
+        let jar = CookieJar::from_headers(&headers);
+        assert!(jar.get(ACCESS_TOKEN_COOKIE).is_some());
+        let ret =
+            refresh_token_handler(State(state.clone()), headers.clone(), jar.clone(), None).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let csrf = jar.get(CSRF_TOKEN_COOKIE).expect("csrf cookie").value();
+        headers.insert(CSRF_TOKEN_HEADER, csrf.parse()?);
+        let ret = refresh_token_handler(State(state), headers, jar, None)
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_work_without_a_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.expect("user should exist");
+        let token = state.ek.sign(user)?;
+        sqlx::query("DELETE FROM workspace_members WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+
+        let app = crate::get_router(state).await?;
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/signout")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = app.clone().oneshot(req).await?;
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        // the token is revoked
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/signout")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = app.oneshot(req).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
 }
@@ -1,19 +1,31 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{Multipart, Path, Query, State},
+    http::{header, StatusCode},
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Chat, User};
+use chat_core::{Chat, Presence, ReadState, User};
 
-use crate::{AppError, AppState, CreateChat, ErrorOutput, UpdateChat};
+use crate::{
+    AddChatMember, AppError, AppState, Badges, ChatBan, ChatSettings, ChatUnread, CreateChat,
+    ErrorOutput, ExportChat, ListChats, MarkRead, MuteChat, RemoveChatMember, TransferOwnership,
+    UpdateChat, ValidJson,
+};
 
 /// List all chats in the workspace of the user.
+///
+/// - Use `expand=members` to include the members' profiles of each chat.
+/// - The response has a weak ETag, pass it as If-None-Match to get 304 while nothing changed.
 #[utoipa::path(
     get,
     path = "/api/chats",
+    params(
+        ListChats
+    ),
     responses(
-        (status = 200, description = "List of chats", body = Vec<Chat>)
+        (status = 200, description = "List of chats", body = Vec<Chat>),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
     ),
     security(
         ("token" = [])
@@ -22,17 +34,43 @@ use crate::{AppError, AppState, CreateChat, ErrorOutput, UpdateChat};
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListChats>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.fetch_chats(user.id as _, user.ws_id as _).await?;
+    let chat = state
+        .fetch_chats(user.id as _, user.ws_id as _, input)
+        .await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
+/// List public channels in my workspace that I haven't joined.
+#[utoipa::path(
+    get,
+    path = "/api/chats/public",
+    responses(
+        (status = 200, description = "List of public channels", body = Vec<Chat>)
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_public_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let chats = state
+        .fetch_public_chats(user.id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(chats))
+}
+
 /// Create a new chat in the workspace of the user.
 #[utoipa::path(
     post,
     path = "/api/chats",
     responses(
-        (status = 201, description = "Chat created", body = Chat)
+        (status = 201, description = "Chat created", body = Chat),
+        (status = 422, description = "Invalid chat name or members", body = ErrorOutput),
+        (status = 409, description = "Chat already exists", body = ErrorOutput),
     ),
     security(
         ("token" = [])
@@ -41,7 +79,7 @@ pub(crate) async fn list_chat_handler(
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
-    Json(input): Json<CreateChat>,
+    ValidJson(input): ValidJson<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
     let chat = state
         .create_chat(input, user.id as _, user.ws_id as _)
@@ -50,6 +88,8 @@ pub(crate) async fn create_chat_handler(
 }
 
 /// Get the chat info by id.
+///
+/// - The response has a weak ETag, pass it as If-None-Match to get 304 while nothing changed.
 #[utoipa::path(
     get,
     path = "/api/chats/{id}",
@@ -58,6 +98,7 @@ pub(crate) async fn create_chat_handler(
     ),
     responses(
         (status = 200, description = "Chat found", body = Chat),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
         (status = 404, description = "Chat not found", body = ErrorOutput),
     ),
     security(
@@ -65,14 +106,9 @@ pub(crate) async fn create_chat_handler(
     )
 )]
 pub(crate) async fn get_chat_handler(
-    State(state): State<AppState>,
-    Path(id): Path<u64>,
+    Extension(chat): Extension<Chat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.get_chat_by_id(id).await?;
-    match chat {
-        Some(chat) => Ok(Json(chat)),
-        None => Err(AppError::NotFound(format!("Chat id {id}"))),
-    }
+    Ok(Json(chat))
 }
 
 /// Update the chat info by id.
@@ -85,6 +121,7 @@ pub(crate) async fn get_chat_handler(
     responses(
         (status = 200, description = "Chat updated", body = Chat),
         (status = 404, description = "Chat not found", body = ErrorOutput),
+        (status = 422, description = "Invalid chat name or members", body = ErrorOutput),
     ),
     security(
         ("token" = [])
@@ -93,12 +130,284 @@ pub(crate) async fn get_chat_handler(
 pub(crate) async fn update_chat_handler(
     State(state): State<AppState>,
     Path(id): Path<u64>,
-    Json(input): Json<UpdateChat>,
+    ValidJson(input): ValidJson<UpdateChat>,
 ) -> Result<impl IntoResponse, AppError> {
     let chat = state.update_chat_by_id(id, input).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
+/// Add a member to the chat.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/members",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Member added", body = Chat),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "Chat or user not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn add_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<AddChatMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .add_chat_member(id, input.user_id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(chat))
+}
+
+/// Remove a member from the chat and optionally ban them, only the chat owner can do it.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/members/{user_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("user_id" = u64, Path, description = "User id of the member"),
+        RemoveChatMember
+    ),
+    responses(
+        (status = 200, description = "Member removed", body = Chat),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+        (status = 404, description = "Member not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn remove_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, member_id)): Path<(u64, u64)>,
+    Query(input): Query<RemoveChatMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .kick_chat_member(input, id, member_id, user.id as _)
+        .await?;
+    Ok(Json(chat))
+}
+
+/// List the members of the chat with an open event stream, online or away.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/online",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Presence of the connected members", body = Vec<Presence>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_chat_online_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.list_chat_online(id).await?;
+    Ok(Json(presence))
+}
+
+/// List users banned from the chat, only the chat owner can do it.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/bans",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "List of bans", body = Vec<ChatBan>),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_chat_bans_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let bans = state.list_chat_bans(id, user.id as _).await?;
+    Ok(Json(bans))
+}
+
+/// Lift the ban of a user, only the chat owner can do it.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/bans/{user_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("user_id" = u64, Path, description = "User id of the banned user")
+    ),
+    responses(
+        (status = 200, description = "Ban lifted"),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+        (status = 404, description = "Ban not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unban_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, member_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unban_chat_member(id, member_id, user.id as _).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Transfer the ownership of the chat to another member, only the chat owner can do it.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/transfer_ownership",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Ownership transferred", body = Chat),
+        (status = 400, description = "New owner is not a member", body = ErrorOutput),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn transfer_chat_ownership_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<TransferOwnership>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .transfer_chat_ownership(id, input.new_owner_id as _, user.id as _)
+        .await?;
+    Ok(Json(chat))
+}
+
+/// Join a public channel of my workspace.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/join",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Joined the chat", body = Chat),
+        (status = 400, description = "Already a member", body = ErrorOutput),
+        (status = 404, description = "Public channel not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn join_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_chat(id, user.id as _, user.ws_id as _).await?;
+    Ok(Json(chat))
+}
+
+/// Leave a group or channel.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/leave",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Left the chat", body = Chat),
+        (status = 400, description = "Single chat or chat owner", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.leave_chat(id, user.id as _).await?;
+    Ok(Json(chat))
+}
+
+/// Upload a new avatar image for the chat, only the chat owner can do it.
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/avatar",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    request_body(content_type = "multipart/form-data", description = "The avatar image file"),
+    responses(
+        (status = 200, description = "Avatar updated", body = Chat),
+        (status = 400, description = "Missing or non image file", body = ErrorOutput),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+        (status = 413, description = "Image too large", body = ErrorOutput),
+        (status = 429, description = "Too many uploads, retry after the Retry-After seconds", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn upload_chat_avatar_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    // checked before the upload is stored
+    if chat.owner_id != user.id {
+        return Err(AppError::PermissionDenied(format!(
+            "User {} is not the owner of chat {}",
+            user.id, id
+        )));
+    }
+    let Some(field) = multipart.next_field().await? else {
+        return Err(AppError::ChatFileError("Missing avatar file".to_string()));
+    };
+    let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+        return Err(AppError::ChatFileError("Missing avatar file".to_string()));
+    };
+    let mime = field
+        .content_type()
+        .map(|mime| mime.to_string())
+        .unwrap_or_else(|| {
+            mime_guess::from_path(&filename)
+                .first_or_octet_stream()
+                .to_string()
+        });
+    if !mime.starts_with("image/") {
+        return Err(AppError::ChatFileError(format!(
+            "Avatar must be an image, but got {mime}"
+        )));
+    }
+    let data = field.bytes().await?;
+
+    let attachment = state
+        .save_upload(user.ws_id as _, user.id as _, &filename, Some(mime), &data)
+        .await?;
+    let chat = state
+        .update_chat_avatar(id, &attachment.url, user.id as _)
+        .await?;
+    Ok(Json(chat))
+}
+
 /// Delete the chat by id.
 #[utoipa::path(
     delete,
@@ -121,3 +430,163 @@ pub(crate) async fn delete_chat_handler(
     state.delete_chat_by_id(id).await?;
     Ok(StatusCode::OK)
 }
+
+/// Mark messages in the chat as read up to the given message.
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Read state updated", body = ReadState),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mark_chat_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let read = state.mark_chat_read(input, id, user.id as _).await?;
+    Ok(Json(read))
+}
+
+/// Mute the chat for me, new messages no longer push notifications until it expires.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/mute",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat muted", body = ChatSettings),
+        (status = 400, description = "Invalid duration", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mute_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MuteChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.mute_chat(input, id, user.id as _).await?;
+    Ok(Json(settings))
+}
+
+/// Unmute the chat for me.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/mute",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Chat unmuted", body = ChatSettings),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unmute_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.unmute_chat(id, user.id as _).await?;
+    Ok(Json(settings))
+}
+
+/// Unread message counts of all my chats in the workspace.
+#[utoipa::path(
+    get,
+    path = "/api/chats/unread",
+    responses(
+        (status = 200, description = "Unread counts per chat", body = Vec<ChatUnread>)
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_unread_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let unread = state
+        .fetch_unread_counts(user.id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(unread))
+}
+
+/// Unread and mention badges of all my chats in the workspace, with the totals.
+#[utoipa::path(
+    get,
+    path = "/api/badges",
+    responses(
+        (status = 200, description = "Badges per chat and in total", body = Badges)
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_badges_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let badges = state.fetch_badges(user.id as _, user.ws_id as _).await?;
+    Ok(Json(badges))
+}
+
+/// Export the full history of the chat, only the chat owner can export it.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/export",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ExportChat
+    ),
+    responses(
+        (status = 200, description = "Chat history streamed as json, csv or ndjson"),
+        (status = 403, description = "Not the chat owner", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn export_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<ExportChat>,
+) -> Result<impl IntoResponse, AppError> {
+    if chat.owner_id != user.id {
+        return Err(AppError::PermissionDenied(format!(
+            "User {} is not the owner of chat {}",
+            user.id, id
+        )));
+    }
+
+    let format = input.format;
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"chat-{}.{}\"",
+                id,
+                format.extension()
+            ),
+        ),
+    ];
+    let body = Body::from_stream(state.export_chat(id, format));
+    Ok((headers, body))
+}
@@ -0,0 +1,78 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{AppError, AppState, Device, ErrorOutput, RegisterDevice};
+
+/// Register a device of the current user for push notifications.
+///
+/// - Registering the same token again updates the device and marks it as seen,
+///   clients should do it on every start.
+/// - Devices not seen for a while are pruned.
+#[utoipa::path(
+    post,
+    path = "/api/devices",
+    request_body = RegisterDevice,
+    responses(
+        (status = 200, description = "Device registered", body = Device),
+        (status = 422, description = "Invalid token or name", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn register_device_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<RegisterDevice>,
+) -> Result<impl IntoResponse, AppError> {
+    let device = state.register_device(user.id as _, &input).await?;
+    Ok(Json(device))
+}
+
+/// List the devices of the current user, most recently seen first.
+#[utoipa::path(
+    get,
+    path = "/api/devices",
+    responses(
+        (status = 200, description = "List of devices", body = Vec<Device>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_devices_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let devices = state.list_devices(user.id as _).await?;
+    Ok(Json(devices))
+}
+
+/// Unregister a device of the current user, e.g. on signout.
+#[utoipa::path(
+    delete,
+    path = "/api/devices/{id}",
+    params(
+        ("id" = u64, Path, description = "Device id")
+    ),
+    responses(
+        (status = 204, description = "Device unregistered"),
+        (status = 404, description = "Device not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unregister_device_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unregister_device(user.id as _, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,25 +1,37 @@
 use axum::{
+    body::Body,
     extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Message, User};
-use tokio::fs::{self};
-use tracing::{info, warn};
+use chat_core::{Attachment, DeliveryState, Message, ScanStatus, UploadStage, User};
+use chrono::Utc;
+use tracing::warn;
 
-use crate::{AppError, AppState, ChatFile, CreateMessage, ErrorOutput, ListMessages};
+use crate::{
+    AppError, AppState, ChatFile, CreateMessage, ErrorOutput, GetFile, ListFiles, ListMessages,
+    MediaKind, MessageStatus, Page, RenderOptions, SavedMessage, ScheduledMessage, SearchMessages,
+    SearchResult, SignFile, SignedFile, SignedFileUrl, UpdateMessage, UploadParams, ValidJson,
+};
 
 /// Send a new message in the chat.
+///
+/// - If `send_at` is set, the message is scheduled and delivered at that time instead.
+/// - The markdown content is sanitized, invalid content returns 422 with the details.
 #[utoipa::path(
     post,
     path = "/api/chats/{id}",
     params(
-        ("id" = u64, Path, description = "Chat ID")
+        ("id" = u64, Path, description = "Chat ID"),
+        RenderOptions
     ),
     responses(
         (status = 201, description = "Message send", body = Message),
+        (status = 202, description = "Message scheduled", body = ScheduledMessage),
         (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 422, description = "Invalid content", body = ErrorOutput),
+        (status = 429, description = "Too many messages, retry after the Retry-After seconds", body = ErrorOutput),
     ),
     security(
         ("token" = [])
@@ -29,22 +41,162 @@ pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
-    Json(input): Json<CreateMessage>,
+    Query(opts): Query<RenderOptions>,
+    ValidJson(input): ValidJson<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(input, id, user.id as _).await?;
-    Ok((StatusCode::CREATED, Json(msg)))
+    if input.send_at.is_some() {
+        let scheduled = state.schedule_message(input, id, user.id as _).await?;
+        return Ok((StatusCode::ACCEPTED, Json(scheduled)).into_response());
+    }
+    let mut msg = state.create_message(input, id, user.id as _).await?;
+    opts.render(std::slice::from_mut(&mut msg));
+    Ok((StatusCode::CREATED, Json(msg)).into_response())
 }
 
-/// List all messages in the chat.
+/// Edit a message in the chat.
+///
+/// - Only the sender can edit the message, otherwise it will return 403.
+/// - Messages can only be edited within the configured edit window.
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/messages/{message_id}",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID"),
+        RenderOptions
+    ),
+    responses(
+        (status = 200, description = "Message updated", body = Message),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 422, description = "Invalid content", body = ErrorOutput),
+        (status = 403, description = "Not the sender", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Query(opts): Query<RenderOptions>,
+    ValidJson(input): ValidJson<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut msg = state
+        .update_message(input, id, message_id, user.id as _)
+        .await?;
+    opts.render(std::slice::from_mut(&mut msg));
+    Ok(Json(msg))
+}
+
+/// Delete a message in the chat.
+///
+/// - Only the sender or the chat owner can delete the message, otherwise it will return 403.
+/// - The message is kept as a tombstone, its content and files are no longer returned.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{message_id}",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 200, description = "Message deleted"),
+        (status = 403, description = "Not allowed to delete", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_message(id, message_id, user.id as _).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Ack delivery of a message, all earlier messages of the chat are acked as well.
 #[utoipa::path(
     post,
+    path = "/api/chats/{id}/messages/{message_id}/ack",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 200, description = "Delivery state", body = DeliveryState),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn ack_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let delivery = state
+        .ack_message_delivery(id, message_id, user.id as _)
+        .await?;
+    Ok(Json(delivery))
+}
+
+/// Get the delivery status of a message I sent.
+///
+/// - `sent`: not yet delivered to every other member.
+/// - `delivered`: delivered to every other member.
+/// - `read`: read by every other member.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{message_id}/status",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 200, description = "Message status", body = MessageStatus),
+        (status = 403, description = "Not the sender", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_message_status_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = state
+        .get_message_status(id, message_id, user.id as _)
+        .await?;
+    Ok(Json(status))
+}
+
+/// List messages in the chat, newest first.
+///
+/// - Replies are not included, use the thread endpoint to list them.
+/// - Pass the `older` or `newer` cursor of a page as `cursor` to page through the chat.
+/// - `limit` defaults to 20 and is capped at 100.
+/// - Use `format=html` to also get the rendered-safe html of each message.
+/// - Messages of users I blocked have `senderBlocked` set.
+/// - The page has a weak ETag, pass it as If-None-Match to get 304 while nothing changed.
+#[utoipa::path(
+    get,
     path = "/api/chats/{id}/messages",
     params(
         ("id" = u64, Path, description = "Chat ID"),
-        ListMessages
+        ListMessages,
+        RenderOptions
     ),
     responses(
-        (status = 200, description = "List of messages", body = Vec<Message>),
+        (status = 200, description = "List of messages", body = Page<Message>),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
         (status = 400, description = "Invalid input", body = ErrorOutput),
     ),
     security(
@@ -52,66 +204,833 @@ pub(crate) async fn send_message_handler(
     )
 )]
 pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
     Query(input): Query<ListMessages>,
+    Query(opts): Query<RenderOptions>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut page = state.list_messages(input, id).await?;
+    state
+        .flag_blocked_senders(user.id as _, &mut page.items)
+        .await?;
+    opts.render(&mut page.items);
+    Ok(Json(page))
+}
+
+/// List replies in the thread of a message, newest first.
+///
+/// - The page has a weak ETag, pass it as If-None-Match to get 304 while nothing changed.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{message_id}/thread",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID"),
+        ListMessages,
+        RenderOptions
+    ),
+    responses(
+        (status = 200, description = "List of replies", body = Page<Message>),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
+        (status = 404, description = "Thread not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_thread_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Query(input): Query<ListMessages>,
+    Query(opts): Query<RenderOptions>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msgs = state.list_messages(input, id).await?;
+    let mut page = state.list_thread_messages(input, id, message_id).await?;
+    state
+        .flag_blocked_senders(user.id as _, &mut page.items)
+        .await?;
+    opts.render(&mut page.items);
+    Ok(Json(page))
+}
+
+/// List messages mentioning me across my chats, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/mentions",
+    params(
+        ListMessages
+    ),
+    responses(
+        (status = 200, description = "List of messages mentioning me", body = Page<Message>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_mentions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let msgs = state
+        .list_mentions(input, user.id as _, user.ws_id as _)
+        .await?;
     Ok(Json(msgs))
 }
 
+/// Search messages in the chat, best match first.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/search",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        SearchMessages
+    ),
+    responses(
+        (status = 200, description = "Matched messages", body = Vec<SearchResult>),
+        (status = 400, description = "Invalid query", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn search_chat_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let ret = state
+        .search_messages(input, user.id as _, user.ws_id as _, Some(id))
+        .await?;
+    Ok(Json(ret))
+}
+
+/// Search messages in all my chats of the workspace, best match first.
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    params(
+        SearchMessages
+    ),
+    responses(
+        (status = 200, description = "Matched messages", body = Vec<SearchResult>),
+        (status = 400, description = "Invalid query", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn search_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let ret = state
+        .search_messages(input, user.id as _, user.ws_id as _, None)
+        .await?;
+    Ok(Json(ret))
+}
+
+/// Save a message for later.
+///
+/// - I must be a member of the chat the message belongs to, otherwise it will return 404.
+#[utoipa::path(
+    post,
+    path = "/api/messages/{id}/save",
+    params(
+        ("id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 201, description = "Message saved", body = SavedMessage),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn save_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let saved = state.save_message(id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(saved)))
+}
+
+/// Remove a message from my saved items.
+#[utoipa::path(
+    delete,
+    path = "/api/messages/{id}/save",
+    params(
+        ("id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 200, description = "Message unsaved"),
+        (status = 404, description = "Saved message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unsave_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unsave_message(id, user.id as _).await?;
+    Ok(StatusCode::OK)
+}
+
+/// List my saved messages across chats, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/saved",
+    params(
+        ListMessages
+    ),
+    responses(
+        (status = 200, description = "List of saved messages", body = Page<SavedMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_saved_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let saved = state.list_saved_messages(input, user.id as _).await?;
+    Ok(Json(saved))
+}
+
+/// List my pending scheduled messages, the next one to be sent first.
+#[utoipa::path(
+    get,
+    path = "/api/scheduled",
+    responses(
+        (status = 200, description = "List of scheduled messages", body = Vec<ScheduledMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state.list_scheduled_messages(user.id as _).await?;
+    Ok(Json(scheduled))
+}
+
+/// Cancel one of my pending scheduled messages.
+#[utoipa::path(
+    delete,
+    path = "/api/scheduled/{id}",
+    params(
+        ("id" = u64, Path, description = "Scheduled message ID")
+    ),
+    responses(
+        (status = 200, description = "Scheduled message cancelled"),
+        (status = 404, description = "Scheduled message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn cancel_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.cancel_scheduled_message(id, user.id as _).await?;
+    Ok(StatusCode::OK)
+}
+
+/// List files uploaded to my workspace, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/files",
+    params(
+        ListFiles
+    ),
+    responses(
+        (status = 200, description = "List of files", body = Vec<Attachment>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_files_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    let files = state.list_attachments(input, user.ws_id as _).await?;
+    Ok(Json(files))
+}
+
 pub(crate) async fn file_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
+    Query(input): Query<GetFile>,
+    req_headers: HeaderMap,
 ) -> Result<impl IntoResponse, AppError> {
     if user.ws_id != ws_id {
         return Err(AppError::NotFound(
             "File not found or you don't have access".to_string(),
         ));
     }
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
+    let file: ChatFile = format!("/files/{}/{}", ws_id, path)
+        .parse()
+        .map_err(|_| AppError::NotFound("File not found".to_string()))?;
+
+    // files are content addressed, so they never change
+    let cache_control = "private, max-age=31536000, immutable";
+    serve_file(&state, &file, &input, cache_control, &req_headers).await
+}
+
+/// Delete a file of my workspace, only its uploader or an admin can.
+///
+/// Files still used by messages or as avatars can't be deleted.
+#[utoipa::path(
+    delete,
+    path = "/api/files/{ws_id}/{path}",
+    params(
+        ("ws_id" = u64, Path, description = "Workspace id"),
+        ("path" = String, Path, description = "Path of the file")
+    ),
+    responses(
+        (status = 204, description = "File deleted"),
+        (status = 403, description = "Not the uploader nor an admin", body = ErrorOutput),
+        (status = 404, description = "File not found", body = ErrorOutput),
+        (status = 409, description = "File is in use", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .delete_file(&user, &format!("/files/{ws_id}/{path}"))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sign the url of a file of my workspace, to fetch it without the token until it expires.
+///
+/// The signed url is under `/api`, e.g. for `<img>` tags or external viewers.
+#[utoipa::path(
+    post,
+    path = "/api/files/sign",
+    request_body = SignFile,
+    responses(
+        (status = 200, description = "Signed url", body = SignedFileUrl),
+        (status = 404, description = "Signed urls are disabled or the file is not in the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn sign_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SignFile>,
+) -> Result<impl IntoResponse, AppError> {
+    let signed = state.sign_file_url(user.ws_id as _, &input)?;
+    Ok(Json(signed))
+}
+
+/// Get a file with a signed url, no token needed.
+#[utoipa::path(
+    get,
+    path = "/api/files/signed/{ws_id}/{path}",
+    params(
+        ("ws_id" = u64, Path, description = "Workspace id"),
+        ("path" = String, Path, description = "Path of the file"),
+        SignedFile
+    ),
+    responses(
+        (status = 200, description = "The file"),
+        (status = 401, description = "Invalid or expired url", body = ErrorOutput),
+        (status = 404, description = "File not found", body = ErrorOutput),
+    )
+)]
+pub(crate) async fn signed_file_handler(
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    Query(input): Query<SignedFile>,
+    req_headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let file: ChatFile = format!("/files/{}/{}", ws_id, path)
+        .parse()
+        .map_err(|_| AppError::NotFound("File not found".to_string()))?;
+    state.verify_file_signature(&file, &input)?;
+
+    // not cached past the expiry of the url
+    let max_age = (input.expires - Utc::now().timestamp()).max(0);
+    let cache_control = format!("private, max-age={max_age}");
+    let input = GetFile {
+        size: input.size,
+        rendition: input.rendition,
+    };
+    serve_file(&state, &file, &input, &cache_control, &req_headers).await
+}
+
+pub(crate) async fn serve_file(
+    state: &AppState,
+    file: &ChatFile,
+    input: &GetFile,
+    cache_control: &str,
+    req_headers: &HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body), AppError> {
+    // the hash is a strong etag
+    let etag = match (input.rendition, input.size) {
+        (Some(rendition), _) => format!("\"{}-{}\"", file.hash, rendition.as_str()),
+        (None, Some(size)) => format!("\"{}-{}\"", file.hash, size.as_str()),
+        (None, None) => format!("\"{}\"", file.hash),
+    };
+
+    // the type and name given at upload, guessed from the key for files stored without them
+    let (mime, filename) = match state.get_attachment_by_url(&file.url()).await? {
+        Some(attachment) => {
+            match attachment.scan_status {
+                ScanStatus::Clean => {}
+                ScanStatus::Infected => {
+                    return Err(AppError::PermissionDenied(format!(
+                        "File {} is quarantined",
+                        attachment.filename
+                    )))
+                }
+                ScanStatus::Pending | ScanStatus::Failed => {
+                    return Err(AppError::ScanPending(format!(
+                        "File {} is not scanned yet",
+                        attachment.filename
+                    )))
+                }
+            }
+            (attachment.mime, attachment.filename)
+        }
+        None => {
+            let mime = mime_guess::from_path(file.url()).first_or_octet_stream();
+            (mime.to_string(), format!("{}.{}", file.hash, file.ext))
+        }
+    };
+
+    let key = match input.rendition {
+        Some(rendition) => {
+            let key = file.rendition_key(rendition);
+            let found = state.is_file_referenced(file).await? && state.storage.exists(&key).await?;
+            found.then_some(key)
+        }
+        None => state.locate_file(file, input.size).await?,
+    };
+    let Some(key) = key else {
         return Err(AppError::NotFound("File not found".to_string()));
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, etag.parse()?);
+    headers.insert(header::CACHE_CONTROL, cache_control.parse()?);
+    if req_headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|v| v.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, headers, Body::empty()));
     }
 
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-    // TODO: streaming
-    let body = fs::read(path).await?;
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", mime.to_string().parse().unwrap());
+    let Some(body) = state.storage.stream(&key).await? else {
+        return Err(AppError::NotFound("File not found".to_string()));
+    };
+    // thumbnails are always jpeg, renditions mp4
+    let mime = match (input.rendition, input.size) {
+        (Some(_), _) => MediaKind::of(&mime)
+            .map_or("video/mp4", |kind| kind.rendition_mime())
+            .to_string(),
+        (None, Some(_)) => "image/jpeg".to_string(),
+        (None, None) => mime,
+    };
+    headers.insert(header::CONTENT_TYPE, mime.parse()?);
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition(&mime, &filename).parse()?,
+    );
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".parse()?);
+
+    Ok((StatusCode::OK, headers, body))
+}
 
-    Ok((headers, body))
+/// Media the browsers display safely are shown inline, anything else, e.g. html or svg
+/// that could run scripts on our origin, is downloaded
+fn content_disposition(mime: &str, filename: &str) -> String {
+    let inline = match mime.split_once('/') {
+        Some(("image", subtype)) => subtype != "svg+xml",
+        Some(("audio" | "video", _)) => true,
+        _ => matches!(mime, "application/pdf" | "text/plain"),
+    };
+    let disposition = if inline { "inline" } else { "attachment" };
+
+    // RFC 6266: a plain ascii fallback and the utf-8 name for the clients that know it
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::with_capacity(filename.len());
+    for b in filename.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
 }
 
+/// Upload files to my workspace, their urls are returned in order.
+///
+/// With an `upload_id`, the bytes received and the processing of each file (scan,
+/// thumbnails, transcoding) are sent to me as UploadProgress events of the notify server.
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
+    let total = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let mut tracker = params.tracker(user.id as _, total)?;
     let mut files = vec![];
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let filename = field.file_name().map(|name| name.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                let e = AppError::from(e);
+                state.report_upload_failed(tracker.as_ref(), &e).await;
+                return Err(e);
+            }
+        };
+        let Some(filename) = field.file_name().map(|name| name.to_string()) else {
             warn!("Failed to read multipart field");
             continue;
         };
+        let mime = field.content_type().map(|mime| mime.to_string());
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.filename = filename.clone();
+        }
 
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("File path parent should exists")).await?;
-            fs::write(path, data).await?;
+        // the rest of the body can't be read once a field fails, e.g. over the size limit
+        let mut data = vec![];
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    data.extend_from_slice(&chunk);
+                    if let Some(t) = tracker.as_mut() {
+                        if t.receive(chunk.len() as _) {
+                            state
+                                .report_upload_stage(Some(t), UploadStage::Receiving)
+                                .await;
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read multipart field {}: {}", filename, e);
+                    let e = AppError::from(e);
+                    state.report_upload_failed(tracker.as_ref(), &e).await;
+                    return Err(e);
+                }
+            }
         }
 
-        files.push(file.url());
+        let attachment = match state
+            .save_tracked_upload(
+                ws_id,
+                user.id as _,
+                &filename,
+                mime,
+                &data,
+                tracker.as_ref(),
+            )
+            .await
+        {
+            Ok(attachment) => attachment,
+            Err(e) => {
+                state.report_upload_failed(tracker.as_ref(), &e).await;
+                return Err(e);
+            }
+        };
+        files.push(attachment.url);
     }
 
     Ok(Json(files))
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MessageFormat;
+    use anyhow::Result;
+    use axum::extract::{FromRequest, Request};
+    use http_body_util::BodyExt as _;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn signed_file_url_should_work_without_token() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        state.update_config(|config| config.files.signed_urls = true);
+        let attachment = state.save_upload(1, 1, "notes.txt", None, b"hello").await?;
+        let input = SignFile {
+            url: attachment.url.clone(),
+            size: None,
+            rendition: None,
+        };
+        let signed = state.sign_file_url(1, &input)?;
+
+        let app = crate::get_router(state).await?;
+        let get = |url: &str| {
+            axum::extract::Request::builder()
+                .uri(format!("/api{url}"))
+                .body(Body::empty())
+        };
+        let ret = app.clone().oneshot(get(&signed.url)?).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(
+            ret.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"notes.txt\"; filename*=UTF-8''notes.txt"
+        );
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"hello");
+
+        // a tampered signature, and the unsigned url without a token
+        let tampered = signed.url.replace("sig=", "sig=0");
+        let ret = app.clone().oneshot(get(&tampered)?).await?;
+        assert_eq!(ret.status(), StatusCode::UNAUTHORIZED);
+        let ret = app.oneshot(get(&attachment.url)?).await?;
+        assert_eq!(ret.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_refuse_files_pending_scan() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let file = ChatFile::new(1, "pending.txt", b"not scanned yet");
+        state
+            .storage
+            .put(&file.key(), b"not scanned yet".to_vec())
+            .await?;
+        let attachment = state
+            .create_attachment(&file, "pending.txt", 15, "text/plain", 1)
+            .await?;
+        assert_eq!(attachment.scan_status, ScanStatus::Pending);
+
+        let path = file.url().trim_start_matches("/files/1/").to_string();
+        let ret = file_handler(
+            Extension(user),
+            State(state),
+            Path((1, path)),
+            Query(GetFile::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_disposition_should_work() {
+        assert_eq!(
+            content_disposition("image/png", "cat.png"),
+            "inline; filename=\"cat.png\"; filename*=UTF-8''cat.png"
+        );
+        assert_eq!(
+            content_disposition("text/html", "a \"b\".html"),
+            "attachment; filename=\"a _b_.html\"; filename*=UTF-8''a%20%22b%22.html"
+        );
+        assert_eq!(
+            content_disposition("image/svg+xml", "报告.svg"),
+            "attachment; filename=\"__.svg\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A.svg"
+        );
+    }
+
+    #[tokio::test]
+    async fn file_handler_should_keep_type_and_name() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let attachment = state
+            .save_upload(1, 1, "Q3 report.pdf", None, b"numbers")
+            .await?;
+        let path = attachment
+            .url
+            .strip_prefix("/files/1/")
+            .expect("url should be in ws 1")
+            .to_string();
+
+        let ret = file_handler(
+            Extension(user),
+            State(state),
+            Path((1, path)),
+            Query(GetFile::default()),
+            HeaderMap::new(),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let headers = ret.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "application/pdf");
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            "inline; filename=\"Q3 report.pdf\"; filename*=UTF-8''Q3%20report.pdf"
+        );
+        assert_eq!(headers[header::ETAG], format!("\"{}\"", attachment.hash));
+
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"numbers");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_handler_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+
+        let ret = send_message_handler(
+            Extension(user),
+            State(state),
+            Path(1),
+            Query(RenderOptions::default()),
+            ValidJson(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let body = ret.into_body().collect().await?.to_bytes();
+        let msg: Message = serde_json::from_slice(&body)?;
+        assert_eq!(msg.content, "hello");
+        assert_eq!(msg.chat_id, 1);
+        assert_eq!(msg.sender_id, 1);
+        assert_eq!(msg.html, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_with_empty_content_should_422() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let req = Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"content":" ","files":[]}"#))?;
+        let Err(ret) = ValidJson::<CreateMessage>::from_request(req, &state).await else {
+            panic!("expect the content to be rejected");
+        };
+        assert_eq!(ret.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.error, "invalid content: content is empty");
+        assert_eq!(ret.details[0].field, "content");
+        assert_eq!(ret.details[0].code, "empty");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_handler_with_html_format_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let input = CreateMessage {
+            content: "**hello** <script>alert(1)</script>".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        let opts = RenderOptions {
+            format: MessageFormat::Html,
+        };
+
+        let ret = send_message_handler(
+            Extension(user),
+            State(state),
+            Path(1),
+            Query(opts),
+            ValidJson(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let body = ret.into_body().collect().await?.to_bytes();
+        let msg: Message = serde_json::from_slice(&body)?;
+        assert_eq!(msg.content, "**hello**");
+        assert_eq!(msg.html.as_deref(), Some("<p><strong>hello</strong></p>\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_handler_with_unsafe_content_should_422() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let input = CreateMessage {
+            content: "<script>alert(1)</script>".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+
+        let ret = send_message_handler(
+            Extension(user),
+            State(state),
+            Path(1),
+            Query(RenderOptions::default()),
+            ValidJson(input),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.details.len(), 1);
+        assert_eq!(ret.details[0].field, "content");
+        assert_eq!(ret.details[0].code, "empty");
+
+        Ok(())
+    }
+}
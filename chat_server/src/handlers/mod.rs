@@ -1,13 +1,23 @@
+mod admin;
 mod auth;
 mod chat;
+mod device;
 mod messages;
+mod poll;
+mod reaction;
+mod user;
 mod workspace;
 
 use axum::response::IntoResponse;
 
+pub(crate) use admin::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use device::*;
 pub(crate) use messages::*;
+pub(crate) use poll::*;
+pub(crate) use reaction::*;
+pub(crate) use user::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
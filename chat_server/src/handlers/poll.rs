@@ -0,0 +1,65 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Poll, User};
+
+use crate::{AppError, AppState, CreatePoll, ErrorOutput, ValidJson, VotePoll};
+
+/// Post a poll in the chat.
+///
+/// - The poll is sent as a message whose content is the question.
+/// - A poll has 2 to 10 unique options.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/polls",
+    params(
+        ("id" = u64, Path, description = "Chat ID")
+    ),
+    responses(
+        (status = 201, description = "Poll created", body = Poll),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 422, description = "Invalid question or options", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_poll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    ValidJson(input): ValidJson<CreatePoll>,
+) -> Result<impl IntoResponse, AppError> {
+    let poll = state.create_poll(input, id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(poll)))
+}
+
+/// Vote on a poll, voting again changes my vote.
+#[utoipa::path(
+    post,
+    path = "/api/polls/{id}/vote",
+    params(
+        ("id" = u64, Path, description = "Poll ID")
+    ),
+    responses(
+        (status = 200, description = "Latest poll results", body = Poll),
+        (status = 400, description = "Invalid option or expired poll", body = ErrorOutput),
+        (status = 403, description = "Not a member of the chat", body = ErrorOutput),
+        (status = 404, description = "Poll not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn vote_poll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<VotePoll>,
+) -> Result<impl IntoResponse, AppError> {
+    let poll = state.vote_poll(input, id, user.id as _).await?;
+    Ok(Json(poll))
+}
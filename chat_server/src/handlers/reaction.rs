@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Reaction, User};
+
+use crate::{AppError, AppState, CreateReaction, ErrorOutput, ValidJson};
+
+/// React to a message with an emoji.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/messages/{message_id}/reactions",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 201, description = "Reaction added", body = Reaction),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+        (status = 422, description = "Invalid emoji", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    ValidJson(input): ValidJson<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reaction = state
+        .add_reaction(input, id, message_id, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(reaction)))
+}
+
+/// Remove my emoji reaction from a message.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{message_id}/reactions",
+    params(
+        ("id" = u64, Path, description = "Chat ID"),
+        ("message_id" = u64, Path, description = "Message ID"),
+        CreateReaction
+    ),
+    responses(
+        (status = 200, description = "Reaction removed"),
+        (status = 404, description = "Reaction not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(u64, u64)>,
+    Query(input): Query<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .remove_reaction(input, id, message_id, user.id as _)
+        .await?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,379 @@
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use axum_extra::extract::CookieJar;
+use chat_core::{ChatUser, Presence, User};
+
+use crate::{
+    auth_response, serve_file, AckEvents, AppError, AppState, AuthOutput, ChangePassword,
+    ErrorOutput, GetFile, ListEvents, ListPresence, NotificationPreferences, SetEmailDigest,
+    SetNotifyLevel, SetPresence, SetUserStatus, UserEvents, ValidJson,
+};
+
+/// Change the password of the current user.
+///
+/// - The current password must be given.
+/// - All the tokens issued before are revoked, a new one is returned for this session.
+#[utoipa::path(
+    post,
+    path = "/api/users/me/password",
+    responses(
+        (status = 200, description = "Password changed", body = AuthOutput),
+        (status = 403, description = "Invalid current password", body = ErrorOutput),
+        (status = 422, description = "Invalid new password", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn change_password_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ValidJson(input): ValidJson<ChangePassword>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.change_password(&user, &input).await?;
+    auth_response(&state, jar, user).await
+}
+
+/// Upload a new avatar for the current user, it's cropped and resized to a fixed size.
+#[utoipa::path(
+    post,
+    path = "/api/users/me/avatar",
+    request_body(content_type = "multipart/form-data", description = "The avatar image file"),
+    responses(
+        (status = 200, description = "Avatar updated", body = User),
+        (status = 400, description = "Missing or invalid image file", body = ErrorOutput),
+        (status = 413, description = "Image too large", body = ErrorOutput),
+        (status = 429, description = "Too many uploads, retry after the Retry-After seconds", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn upload_user_avatar_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(AppError::ChatFileError("Missing avatar file".to_string()));
+    };
+    let data = field.bytes().await?;
+
+    let user = state.update_user_avatar(&user, &data).await?;
+    Ok(Json(user))
+}
+
+/// The avatar of a user, to the members of any of the user's workspaces
+pub(crate) async fn user_avatar_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((user_id, path)): Path<(u64, String)>,
+    Query(input): Query<GetFile>,
+    req_headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let url = format!("/avatars/{user_id}/{path}");
+    let Some(file) = state
+        .get_user_avatar(user.ws_id as _, user_id, &url)
+        .await?
+    else {
+        return Err(AppError::NotFound("Avatar not found".to_string()));
+    };
+
+    // the url changes with the content
+    let cache_control = "private, max-age=31536000, immutable";
+    serve_file(&state, &file, &input, cache_control, &req_headers).await
+}
+
+/// Delete the account of the current user.
+///
+/// - All the tokens are revoked at once.
+/// - The profile is anonymized and the uploaded files are deleted in the background,
+///   the messages stay under the anonymized author.
+/// - Owners must transfer their workspaces first.
+#[utoipa::path(
+    delete,
+    path = "/api/users/me",
+    responses(
+        (status = 200, description = "Account deleted", body = User),
+        (status = 403, description = "The user still owns a workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.delete_user(user.id as _).await?;
+    state.spawn_user_purge(user.id as _);
+    Ok(Json(user))
+}
+
+/// Delete the account of a user of the current workspace, admin only.
+///
+/// Only accounts whose home is this workspace and with a lower role than the caller can be deleted.
+#[utoipa::path(
+    delete,
+    path = "/api/workspace/users/{id}",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "Account deleted", body = User),
+        (status = 403, description = "Not allowed to delete the account", body = ErrorOutput),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn admin_delete_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.admin_delete_user(&user, id).await?;
+    state.spawn_user_purge(user.id as _);
+    Ok(Json(user))
+}
+
+/// Get the presence of users of the current workspace.
+#[utoipa::path(
+    get,
+    path = "/api/users/presence",
+    params(
+        ListPresence
+    ),
+    responses(
+        (status = 200, description = "Presence of the users", body = Vec<Presence>),
+        (status = 422, description = "Invalid ids", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListPresence>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.list_presence(user.ws_id as _, &input).await?;
+    Ok(Json(presence))
+}
+
+/// List the events sent to the current user after a given one, to catch up after being
+/// offline.
+///
+/// - Events are kept for `retention.event_days`, `resync` tells the client some are gone.
+/// - `seq` is also in the data of the events of the event stream.
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    params(
+        ListEvents
+    ),
+    responses(
+        (status = 200, description = "Events after the given one", body = UserEvents),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_user_events_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListEvents>,
+) -> Result<impl IntoResponse, AppError> {
+    let events = state.list_user_events(user.id as _, input).await?;
+    Ok(Json(events))
+}
+
+/// Acknowledge the critical events the client handled, e.g. `MemberAdded`.
+///
+/// - Critical events are sent again at the start of each event stream until acked, and
+///   listed under `unacked` by `GET /api/events`.
+#[utoipa::path(
+    post,
+    path = "/api/events/ack",
+    request_body = AckEvents,
+    responses(
+        (status = 204, description = "Events acknowledged"),
+        (status = 422, description = "Too many events", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn ack_events_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<AckEvents>,
+) -> Result<impl IntoResponse, AppError> {
+    state.ack_events(user.id as _, input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Set the current user online or away, the user must have an open event stream.
+#[utoipa::path(
+    put,
+    path = "/api/users/me/presence",
+    responses(
+        (status = 200, description = "Presence updated", body = Presence),
+        (status = 403, description = "No open event stream", body = ErrorOutput),
+        (status = 422, description = "Invalid status", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SetPresence>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.set_presence(user.id as _, &input).await?;
+    Ok(Json(presence))
+}
+
+/// Set the custom status of the current user, an empty emoji and text clear it.
+#[utoipa::path(
+    put,
+    path = "/api/users/me/status",
+    responses(
+        (status = 200, description = "Status updated", body = ChatUser),
+        (status = 422, description = "Invalid status", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_user_status_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SetUserStatus>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.set_user_status(user.id as _, input).await?;
+    Ok(Json(user))
+}
+
+/// Get my notification levels, the default one and the ones of my chats.
+#[utoipa::path(
+    get,
+    path = "/api/preferences/notifications",
+    responses(
+        (status = 200, description = "Notification levels", body = NotificationPreferences),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_notification_preferences_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.get_notification_preferences(user.id as _).await?;
+    Ok(Json(prefs))
+}
+
+/// Set which new messages I get `NewMessage` events for, by default or in a chat.
+///
+/// - `all`, `mentions` for the messages mentioning me only, or `nothing`.
+/// - A chat without a level follows the default, muting a chat still silences it.
+#[utoipa::path(
+    put,
+    path = "/api/preferences/notifications",
+    responses(
+        (status = 200, description = "Notification levels", body = NotificationPreferences),
+        (status = 403, description = "Not a member of the chat", body = ErrorOutput),
+        (status = 422, description = "Missing default level", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_notify_level_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SetNotifyLevel>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.set_notify_level(input, user.id as _).await?;
+    Ok(Json(prefs))
+}
+
+/// Turn on or off the emails of the mentions and direct messages I missed while offline.
+#[utoipa::path(
+    put,
+    path = "/api/preferences/digest",
+    responses(
+        (status = 200, description = "Notification levels", body = NotificationPreferences),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_email_digest_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SetEmailDigest>,
+) -> Result<impl IntoResponse, AppError> {
+    let prefs = state.set_email_digest(input, user.id as _).await?;
+    Ok(Json(prefs))
+}
+
+/// Block a user.
+///
+/// - They can no longer start a single chat with me.
+/// - Their messages are flagged with `senderBlocked` and I get no `NewMessage` events for them.
+#[utoipa::path(
+    post,
+    path = "/api/users/{id}/block",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 201, description = "User blocked"),
+        (status = 404, description = "User not found", body = ErrorOutput),
+        (status = 422, description = "Cannot block myself", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn block_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.block_user(user.id as _, id).await?;
+    Ok(StatusCode::CREATED)
+}
+
+/// Unblock a user.
+#[utoipa::path(
+    delete,
+    path = "/api/users/{id}/block",
+    params(
+        ("id" = u64, Path, description = "User id")
+    ),
+    responses(
+        (status = 200, description = "User unblocked"),
+        (status = 404, description = "User not blocked", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unblock_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unblock_user(user.id as _, id).await?;
+    Ok(StatusCode::OK)
+}
@@ -1,14 +1,26 @@
-use axum::{extract::State, response::IntoResponse, Extension, Json};
-use chat_core::{ChatUser, User};
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use axum_extra::extract::CookieJar;
+use chat_core::{ChatUser, User, Workspace};
 
-use crate::{AppError, AppState};
+use super::{auth_response, AuthOutput};
+use crate::{
+    AppError, AppState, ErrorOutput, ListUsers, MyWorkspace, Page, TransferOwnership,
+    UpdateWorkspaceSettings, WorkspaceSettings, WorkspaceStats,
+};
 
-/// List all users in the workspace.
+/// List users in the workspace by id, optionally searching by name or email prefix.
 #[utoipa::path(
     get,
     path = "/api/users",
+    params(
+        ListUsers
+    ),
     responses(
-        (status = 200, description = "List of ws users", body = Vec<ChatUser>)
+        (status = 200, description = "Page of ws users", body = Page<ChatUser>)
     ),
     security(
         ("token" = [])
@@ -17,7 +29,192 @@ use crate::{AppError, AppState};
 pub(crate) async fn list_chat_users_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListUsers>,
 ) -> Result<impl IntoResponse, AppError> {
-    let users = state.fetch_chat_users(user.ws_id as _).await?;
+    let users = state.fetch_chat_users(user.ws_id as _, input).await?;
     Ok(Json(users))
 }
+
+/// List all workspaces the user belongs to, with my role and counts in each.
+#[utoipa::path(
+    get,
+    path = "/api/workspaces",
+    responses(
+        (status = 200, description = "List of my workspaces", body = Vec<MyWorkspace>)
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.fetch_workspaces(user.id as _).await?;
+    Ok(Json(workspaces))
+}
+
+/// Switch to another workspace the user belongs to.
+///
+/// - It returns a new token scoped to the workspace, the current token keeps its own scope.
+/// - If the user is not a member of the workspace, it will return 404.
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/switch",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Token scoped to the workspace", body = AuthOutput),
+        (status = 404, description = "Workspace not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn switch_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.switch_workspace(user, id).await?;
+    auth_response(&state, jar, user).await
+}
+
+/// Delete the workspace, owner only.
+///
+/// - The members lose access at once and get a `WorkspaceDeleted` event.
+/// - Chats, messages and files of the workspace are purged in the background.
+#[utoipa::path(
+    delete,
+    path = "/api/workspaces/{id}",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Workspace deleted", body = Workspace),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+        (status = 404, description = "Workspace not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.delete_workspace(id, user.id as _).await?;
+    state.spawn_workspace_purge(id);
+    Ok(Json(ws))
+}
+
+/// Transfer the ownership of the workspace to another member, owner only.
+///
+/// - The previous owner becomes an admin.
+/// - The new owner must be a member of the workspace, otherwise it will return 422.
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/transfer_ownership",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    request_body = TransferOwnership,
+    responses(
+        (status = 200, description = "Ownership transferred", body = Workspace),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+        (status = 404, description = "Workspace not found", body = ErrorOutput),
+        (status = 422, description = "New owner is not a member", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn transfer_workspace_ownership_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<TransferOwnership>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state
+        .transfer_workspace_ownership(id, input.new_owner_id as _, user.id as _)
+        .await?;
+    Ok(Json(ws))
+}
+
+/// Get the usage statistics of the workspace, admin only.
+///
+/// - The statistics are cached for a minute.
+#[utoipa::path(
+    get,
+    path = "/api/workspaces/{id}/stats",
+    params(
+        ("id" = u64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Workspace statistics", body = WorkspaceStats),
+        (status = 403, description = "Not an admin of the workspace", body = ErrorOutput),
+        (status = 404, description = "Workspace not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_workspace_stats_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let stats = state.get_workspace_stats(id, user.id as _).await?;
+    Ok(Json(stats))
+}
+
+/// Get the settings of the workspace, admin only.
+#[utoipa::path(
+    get,
+    path = "/api/workspace/settings",
+    responses(
+        (status = 200, description = "Workspace settings", body = WorkspaceSettings),
+        (status = 403, description = "Not an admin of the workspace"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_workspace_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.get_workspace_settings(user.ws_id as _).await?;
+    Ok(Json(settings))
+}
+
+/// Update the settings of the workspace, admin only.
+///
+/// - Only the given fields are changed.
+/// - Default channels must be public channels of the workspace, otherwise it will return 422.
+#[utoipa::path(
+    patch,
+    path = "/api/workspace/settings",
+    request_body = UpdateWorkspaceSettings,
+    responses(
+        (status = 200, description = "Workspace settings updated", body = WorkspaceSettings),
+        (status = 403, description = "Not an admin of the workspace"),
+        (status = 422, description = "Invalid settings", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspaceSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state
+        .update_workspace_settings(user.ws_id as _, input)
+        .await?;
+    Ok(Json(settings))
+}
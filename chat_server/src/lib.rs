@@ -1,32 +1,60 @@
 mod config;
+mod digest;
 mod error;
+mod file_gc;
 mod handlers;
+mod media;
 mod middlewares;
 mod models;
 mod openapi;
+mod push;
+mod reload;
+mod retention;
+mod scan;
+mod scheduler;
+mod storage;
+mod validate;
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use axum::{
-    http::Method,
-    middleware::from_fn_with_state,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use chat_core::{
-    middlewares::{set_layer, verify_token, TokenVerify},
-    DecodingKey, EncodingKey, User,
+    is_token_denied,
+    middlewares::{
+        ip_acl, rate_limit, require_role, set_layer, verify_token, RateLimiter, RouteClass,
+        RouteRateLimit, RouteRateLimiter, TokenVerify,
+    },
+    DecodingKey, EncodingKey, User, WorkspaceRole,
 };
+use dashmap::DashMap;
 use handlers::*;
-use middlewares::verify_chat;
+use middlewares::{
+    etag, limit_upload, payload_too_large, rate_limit_auth, restrict_bot, verify_chat,
+    verify_workspace,
+};
 use openapi::OpenApiRouter;
 use sqlx::PgPool;
-use std::{fmt, ops::Deref, sync::Arc};
-use tokio::fs;
-use tower_http::cors::{self, CorsLayer};
+use std::{fmt, ops::Deref, sync::Arc, time::Instant};
+pub(crate) use validate::ValidJson;
 
 pub use config::AppConfig;
-pub use error::{AppError, ErrorOutput};
+pub use digest::spawn_email_digest;
+pub use error::{AppError, ErrorOutput, ValidationIssue};
+pub use file_gc::spawn_file_gc;
+pub use media::{MediaKind, Transcoder};
 pub use models::*;
+pub use push::spawn_device_prune;
+pub use reload::spawn_config_reload;
+pub use retention::{spawn_purge_sweep, spawn_retention_purge};
+pub use scan::{spawn_rescan, ScanVerdict, Scanner};
+pub use scheduler::spawn_scheduler;
+pub use storage::{Storage, StoredObject};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -35,54 +63,240 @@ pub struct AppState {
 
 #[allow(unused)]
 pub struct AppStateInner {
-    pub(crate) config: AppConfig,
+    pub(crate) config: ArcSwap<AppConfig>,
     pub(crate) ek: EncodingKey,
     pub(crate) dk: DecodingKey,
     pub(crate) pool: PgPool,
+    pub(crate) http: reqwest::Client,
+    pub(crate) mailer: Mailer,
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) scanner: Arc<dyn Scanner>,
+    pub(crate) transcoder: Arc<dyn Transcoder>,
+    // workspace stats with the time they were computed
+    pub(crate) stats_cache: DashMap<u64, (Instant, WorkspaceStats)>,
+    // signin and signup attempts per client IP
+    pub(crate) ip_limiter: RateLimiter,
+    // signin and signup attempts per email, apart so an IP can't use up their budget
+    pub(crate) email_limiter: RateLimiter,
+    // requests to the other routes per user or client IP
+    pub(crate) route_limiter: Arc<RouteRateLimiter>,
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
+    let limit = RouteRateLimit::new(
+        state.route_limiter.clone(),
+        &state.config().rate_limit.routes,
+    );
+    // the uploads get their own limit, checked on the content length before they're read
+    let upload_limit = (
+        DefaultBodyLimit::max(state.config().server.max_multipart_body),
+        from_fn_with_state(state.clone(), limit_upload),
+        from_fn_with_state(limit.class(RouteClass::Upload), rate_limit),
+    );
     let chat = Router::new()
         .route(
             "/:id",
-            get(get_chat_handler)
+            get(get_chat_handler.layer(from_fn(etag)))
                 .patch(update_chat_handler)
                 .delete(delete_chat_handler)
-                .post(send_message_handler),
+                .post(send_message_handler.layer(from_fn_with_state(
+                    limit.class(RouteClass::Send),
+                    rate_limit,
+                ))),
+        )
+        .route(
+            "/:id/avatar",
+            put(upload_chat_avatar_handler).layer(upload_limit.clone()),
+        )
+        .route("/:id/members", post(add_chat_member_handler))
+        .route("/:id/members/:user_id", delete(remove_chat_member_handler))
+        .route("/:id/online", get(list_chat_online_handler))
+        .route("/:id/bans", get(list_chat_bans_handler))
+        .route("/:id/bans/:user_id", delete(unban_chat_member_handler))
+        .route("/:id/leave", post(leave_chat_handler))
+        .route(
+            "/:id/transfer_ownership",
+            post(transfer_chat_ownership_handler),
+        )
+        .route(
+            "/:id/mute",
+            post(mute_chat_handler).delete(unmute_chat_handler),
+        )
+        .route("/:id/read", put(mark_chat_read_handler))
+        .route("/:id/export", get(export_chat_handler))
+        .route("/:id/polls", post(create_poll_handler))
+        .route(
+            "/:id/messages",
+            get(list_message_handler.layer(from_fn(etag))),
+        )
+        .route("/:id/messages/search", get(search_chat_messages_handler))
+        .route(
+            "/:id/messages/:message_id",
+            patch(update_message_handler).delete(delete_message_handler),
+        )
+        .route(
+            "/:id/messages/:message_id/thread",
+            get(list_thread_handler.layer(from_fn(etag))),
+        )
+        .route("/:id/messages/:message_id/ack", post(ack_message_handler))
+        .route(
+            "/:id/messages/:message_id/status",
+            get(get_message_status_handler),
+        )
+        .route(
+            "/:id/messages/:message_id/reactions",
+            post(add_reaction_handler).delete(remove_reaction_handler),
         )
-        .route("/:id/messages", get(list_message_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
-
-    let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([
-            Method::GET,
-            Method::POST,
-            Method::PATCH,
-            Method::DELETE,
-            Method::PUT,
-        ])
-        .allow_origin(cors::Any)
-        .allow_headers(cors::Any);
+        .route(
+            "/",
+            get(list_chat_handler.layer(from_fn(etag))).post(create_chat_handler),
+        )
+        .route("/unread", get(list_unread_handler))
+        .route("/public", get(list_public_chat_handler))
+        // not a member yet, join_chat checks the chat is a public channel of the workspace
+        .route("/:id/join", post(join_chat_handler));
+
+    // workspace administration, the role is checked against the current workspace
+    let admin = Router::new()
+        .route(
+            "/workspace/settings",
+            get(get_workspace_settings_handler).patch(update_workspace_settings_handler),
+        )
+        .route("/workspace/users/:id", delete(admin_delete_user_handler))
+        .route(
+            "/apikeys",
+            get(list_api_keys_handler).post(create_api_key_handler),
+        )
+        .route("/apikeys/:id", delete(revoke_api_key_handler))
+        .route("/admin/users", get(admin_list_users_handler))
+        .route(
+            "/admin/users/:id/deactivate",
+            post(admin_deactivate_user_handler),
+        )
+        .route(
+            "/admin/users/:id/reactivate",
+            post(admin_reactivate_user_handler),
+        )
+        .route(
+            "/admin/users/:id/password_reset",
+            post(admin_reset_password_handler),
+        )
+        .route("/admin/users/:id/role", put(admin_set_user_role_handler))
+        .layer(from_fn(|req, next| {
+            require_role(WorkspaceRole::Admin, req, next)
+        }))
+        .layer(from_fn_with_state(
+            Arc::new(state.config().acl.admin.clone()),
+            ip_acl,
+        ));
+    // credentials are checked here, the attempts are limited
+    let auth = Router::new()
+        .route("/signin", post(signin_handler))
+        .route("/signup", post(signup_handler))
+        .layer(from_fn_with_state(state.clone(), rate_limit_auth));
+    let public = Router::new()
+        .route("/token/refresh", post(refresh_token_handler))
+        .route("/verify_email/:token", get(verify_email_handler))
+        // the signature stands in for the token
+        .route("/files/signed/:ws_id/*path", get(signed_file_handler))
+        .layer(from_fn_with_state(limit.clone(), rate_limit));
+    // the token is enough, a user whose workspace is gone can still sign out
+    let signout = Router::new()
+        .route("/signout", post(signout_handler))
+        .layer(from_fn_with_state(limit.clone(), rate_limit))
+        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>));
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route("/users/me", delete(delete_user_handler))
+        .route(
+            "/users/me/avatar",
+            post(upload_user_avatar_handler).layer(upload_limit.clone()),
+        )
+        .route("/users/me/password", post(change_password_handler))
+        .route("/avatars/:user_id/:path", get(user_avatar_handler))
+        .route("/users/me/presence", put(set_presence_handler))
+        .route("/users/me/status", put(set_user_status_handler))
+        .route("/users/presence", get(list_presence_handler))
+        .route(
+            "/users/:id/block",
+            post(block_user_handler).delete(unblock_user_handler),
+        )
+        .route(
+            "/verify_email/resend",
+            post(resend_verification_email_handler),
+        )
+        .route(
+            "/devices",
+            get(list_devices_handler).post(register_device_handler),
+        )
+        .route("/devices/:id", delete(unregister_device_handler))
+        .route("/workspaces", get(list_workspaces_handler))
+        .route("/workspaces/:id", delete(delete_workspace_handler))
+        .route("/workspaces/:id/switch", post(switch_workspace_handler))
+        .route("/workspaces/:id/stats", get(get_workspace_stats_handler))
+        .route(
+            "/workspaces/:id/transfer_ownership",
+            post(transfer_workspace_ownership_handler),
+        )
+        .merge(admin)
         .nest("/chats", chat)
-        .route("/upload", post(upload_handler))
-        .route("/files/:ws_id/*path", get(file_handler))
+        .route("/badges", get(list_badges_handler))
+        .route("/events", get(list_user_events_handler))
+        .route("/events/ack", post(ack_events_handler))
+        .route(
+            "/preferences/notifications",
+            get(get_notification_preferences_handler).put(set_notify_level_handler),
+        )
+        .route("/preferences/digest", put(set_email_digest_handler))
+        .route("/mentions", get(list_mentions_handler))
+        .route("/search", get(search_messages_handler))
+        .route(
+            "/messages/:id/save",
+            post(save_message_handler).delete(unsave_message_handler),
+        )
+        .route("/saved", get(list_saved_handler))
+        .route("/polls/:id/vote", post(vote_poll_handler))
+        .route("/scheduled", get(list_scheduled_handler))
+        .route("/scheduled/:id", delete(cancel_scheduled_handler))
+        .route("/upload", post(upload_handler).layer(upload_limit))
+        .route("/files", get(list_files_handler))
+        .route("/files/sign", post(sign_file_handler))
+        .route(
+            "/files/:ws_id/*path",
+            get(file_handler).delete(delete_file_handler),
+        )
+        // per user, once the token is verified
+        .layer(from_fn_with_state(limit.clone(), rate_limit))
+        .layer(from_fn(restrict_bot))
+        .layer(from_fn_with_state(state.clone(), verify_workspace))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         // routes doesn't need token verification
-        .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler))
-        .layer(cors);
+        .merge(auth)
+        .merge(signout)
+        .merge(public)
+        .layer(DefaultBodyLimit::max(state.config().server.max_json_body));
 
+    let trusted_proxies = state.config().server.trusted_proxies.clone();
+    let cors = state.config().cors.clone();
+    let compression = state.config().compression.clone();
+    let acl = state.config().acl.all.clone();
+    let metrics = state.config().metrics.clone();
     let app = Router::new()
         .openapi()
         .route("/", get(index_handler))
         .nest("/api", api)
+        .layer(from_fn(payload_too_large))
         .with_state(state);
 
-    Ok(set_layer(app))
+    Ok(set_layer(
+        app,
+        &acl,
+        &metrics,
+        &trusted_proxies,
+        &cors,
+        &compression,
+    ))
 }
 
 // 调用 state.config => state.inner.config
@@ -97,27 +311,56 @@ impl Deref for AppState {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        if token.starts_with(API_KEY_PREFIX) {
+            return self.verify_api_key(token).await;
+        }
+        let token = self.dk.verify_access_token(token)?;
+        if let Some(jti) = &token.jti {
+            if is_token_denied(&self.pool, jti).await? {
+                return Err(AppError::Unauthorized("Token has been revoked".to_string()));
+            }
+        }
+        Ok(token.user)
     }
 }
 
 impl AppState {
+    /// The config, it changes when reloaded so it's read again rather than kept
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.load_full()
+    }
+
     pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
-        fs::create_dir_all(&config.server.base_dir)
-            .await
-            .context("Create base url failed")?;
-        let ek = EncodingKey::load(&config.auth.sk).context("Failed to load private key")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("Failed to load public key")?;
+        let ek = EncodingKey::load_with_config(&config.auth.sk, config.auth.jwt.clone())
+            .context("Failed to load private key")?;
+        let dk = DecodingKey::load_with_config(config.auth.pks(), config.auth.jwt.clone())
+            .context("Failed to load public keys")?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("Failed to connect to database")?;
+        let http = http_client(&config.preview)?;
+        let mailer = mailer(&config.mail)?;
+        let storage = storage::storage(&config.storage, &config.server.base_dir)
+            .await
+            .context("Failed to create the storage")?;
+        let scanner = scan::scanner(&config.scan);
+        let transcoder = media::transcoder(&config.media);
         Ok(Self {
             inner: Arc::new(AppStateInner {
-                config,
+                config: ArcSwap::from_pointee(config),
                 ek,
                 dk,
                 pool,
+                http,
+                mailer,
+                storage,
+                scanner,
+                transcoder,
+                stats_cache: DashMap::new(),
+                ip_limiter: RateLimiter::default(),
+                email_limiter: RateLimiter::default(),
+                route_limiter: Arc::default(),
             }),
         })
     }
@@ -141,23 +384,46 @@ mod test_util {
     impl AppState {
         pub async fn try_new_for_test() -> Result<(sqlx_db_tester::TestPg, Self), AppError> {
             let config = AppConfig::try_load()?;
-            let ek = EncodingKey::load(&config.auth.sk).context("Failed to load private key")?;
-            let dk = DecodingKey::load(&config.auth.pk).context("Failed to load public key")?;
+            let ek = EncodingKey::load_with_config(&config.auth.sk, config.auth.jwt.clone())
+                .context("Failed to load private key")?;
+            let dk = DecodingKey::load_with_config(config.auth.pks(), config.auth.jwt.clone())
+                .context("Failed to load public keys")?;
             // let post = config.server.db_url.rfind('/').expect("Invalid db_url");
             // let server_url = &config.server.db_url[..post];
             // println!("server_url: {}", server_url);
             let (tdb, pool) = get_test_pool(Some(config.server.db_url.as_ref())).await;
+            let http = http_client(&config.preview)?;
+            let mailer = mailer(&config.mail)?;
+            let storage = storage::storage(&config.storage, &config.server.base_dir).await?;
+            let scanner = scan::scanner(&config.scan);
+            let transcoder = media::transcoder(&config.media);
             let state = Self {
                 inner: Arc::new(AppStateInner {
-                    config,
+                    config: ArcSwap::from_pointee(config),
                     ek,
                     dk,
                     pool,
+                    http,
+                    mailer,
+                    storage,
+                    scanner,
+                    transcoder,
+                    stats_cache: DashMap::new(),
+                    ip_limiter: RateLimiter::default(),
+                    email_limiter: RateLimiter::default(),
+                    route_limiter: Arc::default(),
                 }),
             };
 
             Ok((tdb, state))
         }
+
+        /// Change some settings of the config, as a reload would
+        pub fn update_config(&self, f: impl FnOnce(&mut AppConfig)) {
+            let mut config = AppConfig::clone(&self.config());
+            f(&mut config);
+            self.config.store(Arc::new(config));
+        }
     }
 
     pub async fn get_test_pool(url: Option<&str>) -> (TestPg, PgPool) {
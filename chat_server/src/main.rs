@@ -1,23 +1,29 @@
 use anyhow::Result;
-use chat_server::{get_router, AppConfig, AppState};
-use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use chat_core::{init_logging, serve, ReloadableRouter};
+use chat_server::{
+    get_router, spawn_config_reload, spawn_device_prune, spawn_email_digest, spawn_file_gc,
+    spawn_purge_sweep, spawn_rescan, spawn_retention_purge, spawn_scheduler, AppConfig, AppState,
+};
+use std::{env, future};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
-
-    let config = AppConfig::try_load()?;
-    let addr = format!("0.0.0.0:{}", config.server.port);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = AppConfig::try_load_with_args(args.clone())?;
+    init_logging(&config.log)?;
+    let addr = config.server.listen_addr();
 
     let state = AppState::try_new(config).await?;
-    let app = get_router(state).await?;
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
-
-    axum::serve(listener, app.into_make_service()).await?;
+    spawn_scheduler(state.clone());
+    spawn_retention_purge(state.clone());
+    spawn_purge_sweep(state.clone());
+    spawn_device_prune(state.clone());
+    spawn_file_gc(state.clone());
+    spawn_rescan(state.clone());
+    spawn_email_digest(state.clone());
+    let router = ReloadableRouter::new(get_router(state.clone()).await?);
+    spawn_config_reload(state, router.clone(), args);
+    serve(router.router(), &addr, future::pending()).await?;
 
     Ok(())
 }
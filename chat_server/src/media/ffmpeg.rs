@@ -0,0 +1,257 @@
+use async_trait::async_trait;
+use chat_core::MediaInfo;
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use std::{path::PathBuf, process::Stdio, time::Duration};
+use tokio::{process::Command, sync::Semaphore, time};
+
+use super::{MediaKind, Transcoder};
+use crate::{config::FfmpegConfig, AppError};
+
+/// ffprobe and ffmpeg, run on temporary copies of the files
+#[derive(Debug)]
+pub struct FfmpegTranscoder {
+    config: FfmpegConfig,
+    /// a transcoding takes the CPU, a few of them at once are enough to starve the server
+    jobs: Semaphore,
+}
+
+/// what's read from the json output of ffprobe
+#[derive(Debug, Deserialize)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    disposition: Option<ProbeDisposition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeDisposition {
+    /// cover art of an audio
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFormat {
+    /// seconds, as a string
+    duration: Option<String>,
+}
+
+/// a temporary file, removed when dropped
+struct TempFile(PathBuf);
+
+impl FfmpegTranscoder {
+    pub fn new(config: FfmpegConfig) -> Self {
+        let jobs = Semaphore::new(config.max_jobs.max(1));
+        Self { config, jobs }
+    }
+
+    /// Run one of the binaries once `max_jobs` allows it, its stdout if it succeeded
+    async fn run(&self, program: &str, args: &[&str]) -> Result<Vec<u8>, AppError> {
+        let _permit = self
+            .jobs
+            .acquire()
+            .await
+            .map_err(|e| AppError::MediaError(e.to_string()))?;
+        let mut cmd = Command::new(program);
+        cmd.args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let timeout = Duration::from_millis(self.config.timeout);
+        let output = time::timeout(timeout, cmd.output())
+            .await
+            .map_err(|_| AppError::MediaError(format!("{program} timed out")))?
+            .map_err(|e| AppError::MediaError(format!("{program} failed to start: {e}")))?;
+        if !output.status.success() {
+            return Err(AppError::MediaError(format!(
+                "{program} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+}
+
+#[async_trait]
+impl Transcoder for FfmpegTranscoder {
+    async fn probe(&self, data: &[u8]) -> Result<MediaInfo, AppError> {
+        let input = TempFile::create(data).await?;
+        let output = self
+            .run(
+                &self.config.ffprobe,
+                &[
+                    "-v",
+                    "error",
+                    "-print_format",
+                    "json",
+                    "-show_format",
+                    "-show_streams",
+                    input.path()?,
+                ],
+            )
+            .await?;
+        parse_probe(&output)
+    }
+
+    async fn transcode(&self, data: &[u8], kind: MediaKind) -> Result<Option<Vec<u8>>, AppError> {
+        let input = TempFile::create(data).await?;
+        let output = TempFile::new("mp4");
+        let codecs: &[&str] = match kind {
+            MediaKind::Audio => &["-vn", "-c:a", "aac", "-b:a", "128k"],
+            MediaKind::Video => &[
+                "-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p",
+                "-c:a", "aac", "-b:a", "128k",
+            ],
+        };
+        let mut args = vec!["-y", "-v", "error", "-i", input.path()?];
+        args.extend(codecs);
+        // playable before it's fully downloaded
+        args.extend(["-movflags", "+faststart", output.path()?]);
+        self.run(&self.config.ffmpeg, &args).await?;
+
+        Ok(Some(tokio::fs::read(&output.0).await?))
+    }
+}
+
+impl TempFile {
+    fn new(ext: &str) -> Self {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let name = format!("chat-media-{}.{ext}", hex::encode(bytes));
+        Self(std::env::temp_dir().join(name))
+    }
+
+    async fn create(data: &[u8]) -> Result<Self, AppError> {
+        // ffprobe and ffmpeg look at the content, not at the extension
+        let file = Self::new("bin");
+        tokio::fs::write(&file.0, data).await?;
+        Ok(file)
+    }
+
+    fn path(&self) -> Result<&str, AppError> {
+        self.0
+            .to_str()
+            .ok_or_else(|| AppError::MediaError("Invalid temporary path".to_string()))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Duration and dimensions from the json output of ffprobe, the dimensions are the ones of
+/// the first video stream that isn't a cover art
+fn parse_probe(output: &[u8]) -> Result<MediaInfo, AppError> {
+    let probe: Probe = serde_json::from_slice(output)?;
+    let duration = probe
+        .format
+        .and_then(|format| format.duration)
+        .and_then(|duration| duration.parse().ok());
+    let video = probe.streams.into_iter().find(|stream| {
+        stream.codec_type.as_deref() == Some("video")
+            && stream.disposition.as_ref().map_or(0, |d| d.attached_pic) == 0
+    });
+
+    Ok(MediaInfo {
+        duration,
+        width: video.as_ref().and_then(|video| video.width),
+        height: video.and_then(|video| video.height),
+        rendition: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn parse_probe_should_work() -> Result<()> {
+        let output = br#"{
+            "streams": [
+                {"codec_type": "audio", "codec_name": "opus"},
+                {"codec_type": "video", "codec_name": "vp9", "width": 1280, "height": 720,
+                    "disposition": {"default": 1, "attached_pic": 0}}
+            ],
+            "format": {"format_name": "matroska,webm", "duration": "12.480000"}
+        }"#;
+        let info = parse_probe(output)?;
+        assert_eq!(info.duration, Some(12.48));
+        assert_eq!((info.width, info.height), (Some(1280), Some(720)));
+
+        // the cover art of a song is not a video
+        let output = br#"{
+            "streams": [
+                {"codec_type": "audio"},
+                {"codec_type": "video", "width": 600, "height": 600,
+                    "disposition": {"attached_pic": 1}}
+            ],
+            "format": {"duration": "180.5"}
+        }"#;
+        let info = parse_probe(output)?;
+        assert_eq!(info.duration, Some(180.5));
+        assert_eq!((info.width, info.height), (None, None));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ffmpeg_transcoder_should_run_the_binaries() -> Result<()> {
+        let script = |name: &str, body: &str| -> Result<PathBuf> {
+            let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            std::fs::write(&path, format!("#!/bin/sh\n{body}\n"))?;
+            std::process::Command::new("chmod")
+                .arg("+x")
+                .arg(&path)
+                .status()?;
+            Ok(path)
+        };
+        // the output file is the last argument of ffmpeg
+        let ffmpeg = script(
+            "fake-ffmpeg",
+            r#"for last; do :; done; printf rendition > "$last""#,
+        )?;
+        let ffprobe = script(
+            "fake-ffprobe",
+            r#"sleep 0.2; echo '{"streams": [], "format": {"duration": "3.5"}}'"#,
+        )?;
+        let transcoder = FfmpegTranscoder::new(FfmpegConfig {
+            ffmpeg: ffmpeg.to_string_lossy().to_string(),
+            ffprobe: ffprobe.to_string_lossy().to_string(),
+            timeout: 10_000,
+            max_jobs: 1,
+        });
+
+        // one at a time
+        let start = std::time::Instant::now();
+        let (first, second) = tokio::join!(transcoder.probe(b"audio"), transcoder.probe(b"audio"));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(first?.duration, Some(3.5));
+        assert_eq!(second?.duration, Some(3.5));
+        let rendition = transcoder.transcode(b"audio", MediaKind::Audio).await?;
+        assert_eq!(rendition.as_deref(), Some(&b"rendition"[..]));
+
+        let failing = FfmpegTranscoder::new(FfmpegConfig {
+            ffprobe: "false".to_string(),
+            ..Default::default()
+        });
+        let ret = failing.probe(b"audio").await;
+        assert!(matches!(ret, Err(AppError::MediaError(_))));
+
+        std::fs::remove_file(ffmpeg)?;
+        std::fs::remove_file(ffprobe)?;
+        Ok(())
+    }
+}
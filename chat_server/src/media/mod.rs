@@ -0,0 +1,84 @@
+mod ffmpeg;
+
+use async_trait::async_trait;
+use chat_core::MediaInfo;
+use std::{fmt, sync::Arc};
+
+use crate::{config::MediaConfig, AppError};
+
+pub use ffmpeg::FfmpegTranscoder;
+
+/// types every browser plays, they're served as uploaded
+const BROWSER_TYPES: [&str; 5] = [
+    "audio/aac",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/wav",
+    "video/mp4",
+];
+
+/// probes the audios and videos and transcodes them to renditions browsers play
+#[async_trait]
+pub trait Transcoder: fmt::Debug + Send + Sync {
+    /// Duration and dimensions of an audio or a video
+    async fn probe(&self, data: &[u8]) -> Result<MediaInfo, AppError>;
+
+    /// An mp4 of the file, h264 and aac for a video and aac for an audio, None when the
+    /// transcoder doesn't make renditions
+    async fn transcode(&self, data: &[u8], kind: MediaKind) -> Result<Option<Vec<u8>>, AppError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    /// The kind of the files of a mime type, None for anything else than audios and videos
+    pub fn of(mime: &str) -> Option<Self> {
+        if mime.starts_with("audio/") {
+            Some(Self::Audio)
+        } else if mime.starts_with("video/") {
+            Some(Self::Video)
+        } else {
+            None
+        }
+    }
+
+    /// mime type of the renditions of this kind
+    pub fn rendition_mime(&self) -> &'static str {
+        match self {
+            Self::Audio => "audio/mp4",
+            Self::Video => "video/mp4",
+        }
+    }
+}
+
+/// Whether every browser plays the files of a mime type, so they need no rendition
+pub fn plays_in_browsers(mime: &str) -> bool {
+    BROWSER_TYPES.contains(&mime)
+}
+
+/// knows nothing about the files, for when there's no transcoder
+#[derive(Debug, Default)]
+pub struct NoopTranscoder;
+
+#[async_trait]
+impl Transcoder for NoopTranscoder {
+    async fn probe(&self, _data: &[u8]) -> Result<MediaInfo, AppError> {
+        Ok(MediaInfo::default())
+    }
+
+    async fn transcode(&self, _data: &[u8], _kind: MediaKind) -> Result<Option<Vec<u8>>, AppError> {
+        Ok(None)
+    }
+}
+
+/// Create the transcoder of the config
+pub fn transcoder(config: &MediaConfig) -> Arc<dyn Transcoder> {
+    match config {
+        MediaConfig::None => Arc::new(NoopTranscoder),
+        MediaConfig::Ffmpeg(config) => Arc::new(FfmpegTranscoder::new(config.clone())),
+    }
+}
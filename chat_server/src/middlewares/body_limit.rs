@@ -0,0 +1,116 @@
+use axum::{
+    body::to_bytes,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::{AppError, AppState};
+
+/// longest plain text rejection kept as the error message
+const MAX_REJECTION_LEN: usize = 1024;
+
+/// Reject the uploads announcing a content length over the limit before reading them, the
+/// connection is closed rather than the rest of the body read
+pub async fn limit_upload(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let max = state.config().server.max_multipart_body;
+    let len = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if let Some(len) = len.filter(|len| *len > max) {
+        warn!("Rejected an upload of {} bytes", len);
+        let msg = format!("the body is larger than {max} bytes");
+        let mut resp = AppError::PayloadTooLarge(msg).into_response();
+        resp.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        return resp;
+    }
+
+    next.run(req).await
+}
+
+/// Give the bodies the extractors reject for their size an ErrorOutput like the other errors
+pub async fn payload_too_large(req: Request, next: Next) -> Response {
+    let resp = next.run(req).await;
+    let is_json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if resp.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return resp;
+    }
+
+    let body = to_bytes(resp.into_body(), MAX_REJECTION_LEN)
+        .await
+        .unwrap_or_default();
+    AppError::PayloadTooLarge(String::from_utf8_lossy(&body).into_owned()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorOutput;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        extract::DefaultBodyLimit,
+        middleware::{from_fn, from_fn_with_state},
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    async fn error(resp: Response) -> Result<ErrorOutput> {
+        let body = to_bytes(resp.into_body(), usize::MAX).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_should_get_error_output() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let max = state.config().server.max_multipart_body;
+        let app = Router::new()
+            .route("/json", post(|Json(v): Json<Value>| async { Json(v) }))
+            .route(
+                "/upload",
+                post(|| async { "uploaded" })
+                    .layer(from_fn_with_state(state.clone(), limit_upload)),
+            )
+            .layer(DefaultBodyLimit::max(16))
+            .layer(from_fn(payload_too_large))
+            .with_state(state);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/json")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"content": "longer than the limit"}"#))?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(error(resp).await?.error.starts_with("payload too large"));
+
+        // rejected on its content length, the body isn't read
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header(header::CONTENT_LENGTH, max + 1)
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(resp.headers()[header::CONNECTION], "close");
+        assert!(error(resp).await?.error.contains(&max.to_string()));
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+}
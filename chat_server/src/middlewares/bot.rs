@@ -0,0 +1,110 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::{User, WorkspaceRole};
+use tracing::warn;
+
+use crate::AppError;
+
+/// what a bot can do with its API key, in the chats it's a member of
+const BOT_ROUTES: &[(Method, &str)] = &[
+    (Method::POST, "/api/chats/:id"),
+    (Method::GET, "/api/chats/:id/messages"),
+];
+
+/// Keep bots to the routes of `BOT_ROUTES`, it must run after `verify_workspace`
+pub async fn restrict_bot(req: Request, next: Next) -> Response {
+    let is_bot = req
+        .extensions()
+        .get::<User>()
+        .is_some_and(|user| user.role == WorkspaceRole::Bot);
+    if !is_bot {
+        return next.run(req).await;
+    }
+
+    let path = req.extensions().get::<MatchedPath>().map(|p| p.as_str());
+    let allowed = BOT_ROUTES
+        .iter()
+        .any(|(method, route)| req.method() == method && path == Some(*route));
+    if !allowed {
+        let msg = format!("Bots can't use {} {}", req.method(), req.uri().path());
+        warn!(msg);
+        return AppError::PermissionDenied(msg).into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middlewares::verify_workspace, AppState, CreateApiKey};
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{header, StatusCode},
+        middleware::{from_fn, from_fn_with_state},
+        routing::get,
+        Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "OK")
+    }
+
+    #[tokio::test]
+    async fn test_restrict_bot_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mut admin = state.find_user_by_id(1).await?.expect("user should exist");
+        admin.role = WorkspaceRole::Owner;
+        let input = CreateApiKey {
+            name: "CI".to_string(),
+            chat_ids: vec![2],
+        };
+        let key = state.create_api_key(&admin, &input).await?.key;
+        let token = state.ek.sign(admin)?;
+
+        let api = Router::new()
+            .route("/chats/:id", get(handler).post(handler))
+            .route("/chats/:id/messages", get(handler))
+            .route("/users", get(handler))
+            .layer(from_fn(restrict_bot))
+            .layer(from_fn_with_state(state.clone(), verify_workspace))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>));
+        let app = Router::new().nest("/api", api).with_state(state);
+
+        for (method, uri, status) in [
+            (Method::POST, "/api/chats/2", StatusCode::OK),
+            (Method::GET, "/api/chats/2/messages", StatusCode::OK),
+            (Method::GET, "/api/chats/2", StatusCode::FORBIDDEN),
+            (Method::GET, "/api/users", StatusCode::FORBIDDEN),
+        ] {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", key))
+                .body(Body::empty())?;
+            let resp = app.clone().oneshot(req).await?;
+            assert_eq!(resp.status(), status, "{uri}");
+            if status == StatusCode::FORBIDDEN {
+                assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+            }
+        }
+
+        // users are left alone
+        let req = Request::builder()
+            .uri("/api/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        Ok(())
+    }
+}
@@ -1,31 +1,61 @@
 use axum::{
     extract::{FromRequestParts, Path, Request, State},
+    http::Method,
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use chat_core::User;
+use chat_core::{ChatType, User};
+use serde::Deserialize;
 
 use crate::{AppError, AppState};
 
+// chat routes may carry more path params (e.g. message_id), we only need the chat id
+#[derive(Debug, Deserialize)]
+struct ChatPath {
+    id: u64,
+}
+
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
-        .await
-        .unwrap();
+    let chat_id = match Path::<ChatPath>::from_request_parts(&mut parts, &state).await {
+        Ok(Path(path)) => path.id,
+        Err(e) => return e.into_response(),
+    };
 
     let user = parts.extensions.get::<User>().unwrap();
-    if !state
-        .is_chat_member(chat_id, user.id as _)
-        .await
-        .unwrap_or_default()
-    {
+    // chats from other workspaces are reported as not found, so ids don't leak
+    let chat = match state.get_chat_by_id(chat_id).await {
+        Ok(Some(chat)) if chat.ws_id == user.ws_id => chat,
+        Ok(_) => return AppError::NotFound(format!("Chat id {chat_id}")).into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let is_member = chat.members.contains(&user.id);
+    // public channels can be read by everyone in the workspace
+    let can_read = parts.method == Method::GET && chat.r#type == ChatType::PublicChannel;
+    if !is_member && !can_read {
         let err = AppError::CreateMessageError(format!(
             "User {} is not a member of chat {}",
             user.id, chat_id
         ));
         return err.into_response();
     }
+    // a user banned from the channel loses the public read access too
+    if !is_member {
+        match state.is_chat_banned(chat_id, user.id as u64).await {
+            Ok(false) => {}
+            Ok(true) => {
+                let err = AppError::PermissionDenied(format!(
+                    "User {} is banned from chat {}",
+                    user.id, chat_id
+                ));
+                return err.into_response();
+            }
+            Err(e) => return e.into_response(),
+        }
+    }
 
+    parts.extensions.insert(chat);
     let req = Request::from_parts(parts, body);
 
     next.run(req).await
@@ -35,15 +65,20 @@ pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next
 mod tests {
 
     use super::*;
+    use crate::CreateChat;
     use anyhow::Result;
     use axum::{
         body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
     };
-    use chat_core::middlewares::verify_token;
+    use chat_core::{middlewares::verify_token, Chat};
     use tower::ServiceExt;
 
-    async fn handler(_req: Request) -> impl IntoResponse {
-        (StatusCode::OK, "OK")
+    async fn handler(req: Request) -> impl IntoResponse {
+        // the loaded chat should be available to downstream handlers
+        match req.extensions().get::<Chat>() {
+            Some(_) => (StatusCode::OK, "OK"),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, "chat not loaded"),
+        }
     }
 
     #[tokio::test]
@@ -54,10 +89,11 @@ mod tests {
         let token = state.ek.sign(user)?;
 
         let app = Router::new()
-            .route("/chats/:id/messages", get(handler))
+            .route("/chats/:id/messages", get(handler).post(handler))
+            .route("/chats/:id/messages/:message_id", get(handler))
             .layer(from_fn_with_state(state.clone(), verify_chat))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
-            .with_state(state);
+            .with_state(state.clone());
 
         // user in chat
         let req = Request::builder()
@@ -67,14 +103,70 @@ mod tests {
         let resp = app.clone().oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        // user not in chat
+        // user in chat, with extra path params
+        let req = Request::builder()
+            .uri("/chats/1/messages/1")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // chat not exists
         let req = Request::builder()
             .uri("/chats/5/messages")
             .header("Authorization", format!("Bearer {}", token))
             .body(Body::empty())?;
-        let resp = app.oneshot(req).await?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // user not in chat
+        let other = state.find_user_by_id(4).await?.expect("user should exists");
+        let other_token = state.ek.sign(other)?;
+        let req = Request::builder()
+            .uri("/chats/2/messages")
+            .header("Authorization", format!("Bearer {}", other_token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // chat in another workspace
+        let mut foreign = state.find_user_by_id(1).await?.expect("user should exists");
+        foreign.ws_id = 2;
+        let foreign_token = state.ek.sign(foreign)?;
+        let req = Request::builder()
+            .uri("/chats/1/messages")
+            .header("Authorization", format!("Bearer {}", foreign_token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // user not in public channel, can read but not write
+        let input = CreateChat::new("random", &[2, 3], true);
+        let chat = state.create_chat(input, 2, 1).await?;
+        let req = Request::builder()
+            .uri(format!("/chats/{}/messages", chat.id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/chats/{}/messages", chat.id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
+        // banned workspace member can't read the public channel
+        state.ban_chat_member(chat.id as _, 1, 2).await?;
+        let req = Request::builder()
+            .uri(format!("/chats/{}/messages", chat.id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
         Ok(())
     }
 }
@@ -0,0 +1,103 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+/// Give the GET responses a weak ETag, a hash of the body, and answer 304 to a client that
+/// already has it. The body is still built, but not sent again to the clients polling for
+/// changes. The bodies are the user's own, so the shared caches must not keep them.
+pub async fn etag(req: Request, next: Next) -> Response {
+    let is_get = req.method() == Method::GET;
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    let resp = next.run(req).await;
+    if !is_get || resp.status() != StatusCode::OK || resp.headers().contains_key(header::ETAG) {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read the body to tag: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let hash = hmac_sha256::Hash::hash(&body);
+    let etag = format!("W/\"{}\"", hex::encode(&hash[..16]));
+    parts.headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).expect("hex is a valid header value"),
+    );
+    parts
+        .headers
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("private"));
+    if if_none_match.is_some_and(|v| is_match(&v, &etag)) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// If-None-Match is `*` or a list of tags, compared weakly i.e. without the `W/`
+fn is_match(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(tags) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    tags.split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{middleware::from_fn, routing::get, Json, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn etag_should_answer_not_modified() -> Result<()> {
+        let app = Router::new()
+            .route(
+                "/chats",
+                get(|| async { Json(vec!["general"]) }).post(|| async { "created" }),
+            )
+            .layer(from_fn(etag));
+        let send = |method: &str, if_none_match: Option<&str>| {
+            let mut req = Request::builder().method(method).uri("/chats");
+            if let Some(v) = if_none_match {
+                req = req.header(header::IF_NONE_MATCH, v);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        let resp = send("GET", None).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tag = resp.headers()[header::ETAG].to_str()?.to_string();
+        assert!(tag.starts_with("W/\""));
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "private");
+        let body = to_bytes(resp.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], br#"["general"]"#);
+
+        for v in [tag.as_str(), &tag[2..], &format!("W/\"other\", {tag}"), "*"] {
+            let resp = send("GET", Some(v)).await?;
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{v}");
+            assert_eq!(resp.headers()[header::ETAG], tag.as_str());
+            assert_eq!(resp.headers()[header::CACHE_CONTROL], "private");
+            assert!(to_bytes(resp.into_body(), usize::MAX).await?.is_empty());
+        }
+        assert_eq!(send("GET", Some("W/\"other\"")).await?.status(), 200);
+
+        // only the GET responses are tagged
+        let resp = send("POST", Some("*")).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(header::ETAG));
+        Ok(())
+    }
+}
@@ -1,3 +1,13 @@
+mod body_limit;
+mod bot;
 mod chat;
+mod etag;
+mod rate_limit;
+mod workspace;
 
+pub use body_limit::{limit_upload, payload_too_large};
+pub use bot::restrict_bot;
 pub use chat::verify_chat;
+pub use etag::etag;
+pub use rate_limit::rate_limit_auth;
+pub use workspace::verify_workspace;
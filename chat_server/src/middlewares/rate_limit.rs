@@ -0,0 +1,110 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chat_core::middlewares::ClientIp;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{AppError, AppState};
+
+/// the credentials are small, larger bodies are rejected by the handlers anyway
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    email: String,
+}
+
+/// Limit the signin and signup attempts per client IP and per email, to slow down
+/// credential stuffing
+pub async fn rate_limit_auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let limits = &state.config().rate_limit;
+    // only known when served with connect info, i.e. not in the tests
+    let ip = ClientIp::of(&req)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+    state.ip_limiter.check(&ip, &limits.ip).map_err(|secs| {
+        warn!("Too many auth attempts from {}", ip);
+        AppError::TooManyRequests(secs)
+    })?;
+
+    let (parts, body) = req.into_parts();
+    let bytes = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(e) => return Err(AppError::PayloadTooLarge(e.to_string())),
+    };
+    // bodies without an email are rejected by the handlers
+    if let Ok(credentials) = serde_json::from_slice::<Credentials>(&bytes) {
+        let email = credentials.email.trim().to_lowercase();
+        state
+            .email_limiter
+            .check(&email, &limits.email)
+            .map_err(|secs| {
+                warn!("Too many auth attempts for {}", email);
+                AppError::TooManyRequests(secs)
+            })?;
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        http::{header, StatusCode},
+        middleware::from_fn_with_state,
+        response::IntoResponse,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "OK")
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_auth_should_limit_emails() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let burst = state.config().rate_limit.email.burst;
+
+        let app = Router::new()
+            .route("/signin", post(handler))
+            .layer(from_fn_with_state(state.clone(), rate_limit_auth))
+            .with_state(state);
+        let signin = |email: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/signin")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    r#"{{"email":"{email}","password":"hunter42"}}"#
+                )))
+        };
+
+        for _ in 0..burst {
+            let resp = app.clone().oneshot(signin("tchen@acme.org")?).await?;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        // the email is normalized
+        let resp = app.clone().oneshot(signin("TChen@acme.org ")?).await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+        let resp = app.oneshot(signin("alice@acme.org")?).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        Ok(())
+    }
+}
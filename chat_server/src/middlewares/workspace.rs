@@ -0,0 +1,192 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::User;
+
+use crate::{AppError, AppState, ValidationIssue};
+
+/// the workspace the client acts in, it must be the one the token is scoped to
+pub const WORKSPACE_ID_HEADER: &str = "x-workspace-id";
+
+pub async fn verify_workspace(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let user = req.extensions().get::<User>().unwrap();
+    let ws_id = user.ws_id as u64;
+    // tokens are scoped to one workspace, `POST /api/workspaces/:id/switch` mints one for another
+    if let Some(value) = req.headers().get(WORKSPACE_ID_HEADER) {
+        match value.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(id) if id == ws_id => {}
+            Some(id) => {
+                let msg = format!("Token is scoped to workspace {ws_id}, not {id}");
+                return AppError::PermissionDenied(msg).into_response();
+            }
+            None => {
+                let issue =
+                    ValidationIssue::new(WORKSPACE_ID_HEADER, "invalid", "must be a workspace id");
+                return AppError::ValidationError(vec![issue]).into_response();
+            }
+        }
+    }
+
+    match state.is_token_revoked(user).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return AppError::Unauthorized("Token has been revoked".to_string()).into_response()
+        }
+        Err(e) => return e.into_response(),
+    }
+
+    // the role is looked up on every request so changes apply to issued tokens,
+    // a revoked membership revokes the token as well
+    let role = match state.get_workspace_role(ws_id, user.id as _).await {
+        Ok(Some(role)) => role,
+        Ok(None) => return AppError::NotFound(format!("Workspace id {ws_id}")).into_response(),
+        Err(e) => return e.into_response(),
+    };
+    match state.is_member_deactivated(ws_id, user.id as _).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let msg = format!("User is deactivated in workspace {ws_id}");
+            return AppError::Unauthorized(msg).into_response();
+        }
+        Err(e) => return e.into_response(),
+    }
+    req.extensions_mut().get_mut::<User>().unwrap().role = role;
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorOutput;
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Extension,
+        Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn handler(Extension(user): Extension<User>) -> impl IntoResponse {
+        format!("{}:{:?}", user.ws_id, user.role)
+    }
+
+    #[tokio::test]
+    async fn test_workspace_middleware_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let token = state.ek.sign(user)?;
+
+        let app = Router::new()
+            .route("/users", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_workspace))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+
+        // no header, the default workspace
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "1:Owner");
+
+        // the token is scoped to acme
+        state.add_workspace_member(2, 1).await?;
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(WORKSPACE_ID_HEADER, "2")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(
+            ret.error,
+            "permission denied: Token is scoped to workspace 1, not 2"
+        );
+
+        // a token scoped to foo
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let user = state.switch_workspace(user, 2).await?;
+        let foo_token = state.ek.sign(user)?;
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", foo_token))
+            .header(WORKSPACE_ID_HEADER, "2")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "2:Member");
+
+        // not a member anymore
+        sqlx::query("DELETE FROM workspace_members WHERE ws_id = 2 AND user_id = 1")
+            .execute(&state.pool)
+            .await?;
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", foo_token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(WORKSPACE_ID_HEADER, "foo")
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.details[0].field, WORKSPACE_ID_HEADER);
+
+        // deactivated by an admin
+        sqlx::query("UPDATE workspace_members SET deactivated_at = NOW() WHERE ws_id = 1")
+            .execute(&state.pool)
+            .await?;
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.clone().oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(
+            ret.error,
+            "unauthorized: User is deactivated in workspace 1"
+        );
+        sqlx::query("UPDATE workspace_members SET deactivated_at = NULL WHERE ws_id = 1")
+            .execute(&state.pool)
+            .await?;
+
+        // revoked by a password change
+        sqlx::query("UPDATE users SET token_version = token_version + 1 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let req = Request::builder()
+            .uri("/users")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.error, "unauthorized: Token has been revoked");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{begin_tagged, User, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::token::hash_token;
+use crate::{AppError, AppState, ValidationIssue};
+
+/// API keys are told apart from JWTs by this prefix
+pub const API_KEY_PREFIX: &str = "ck_";
+const MAX_API_KEY_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: i64,
+    pub ws_id: i64,
+    /// the bot user acting with the key
+    pub user_id: i64,
+    pub created_by: i64,
+    pub name: String,
+    /// start of the key, to tell the keys apart
+    pub prefix: String,
+    /// the chats the bot can post to
+    pub chat_ids: Vec<i64>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// a new key, the key itself is only returned once
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct CreateApiKey {
+    /// Name of the key, also the full name of its bot user
+    pub name: String,
+    /// Group chats and channels of the workspace the bot is added to
+    #[serde(default)]
+    pub chat_ids: Vec<i64>,
+}
+
+impl AppState {
+    /// Create a key along with the bot user acting with it, the bot joins the given chats
+    pub async fn create_api_key(
+        &self,
+        admin: &User,
+        input: &CreateApiKey,
+    ) -> Result<CreatedApiKey, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LEN {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "name",
+                "length",
+                format!("Name must be 1 to {MAX_API_KEY_NAME_LEN} characters"),
+            )]));
+        }
+
+        let mut chat_ids = input.chat_ids.clone();
+        chat_ids.sort_unstable();
+        chat_ids.dedup();
+        let found: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*)
+            FROM chats
+            WHERE id = ANY($1) AND ws_id = $2 AND type != 'single'
+            "#,
+        )
+        .bind(&chat_ids)
+        .bind(admin.ws_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if found != chat_ids.len() as i64 {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "chat_ids",
+                "not_found",
+                "Chats must be group chats or channels of the workspace",
+            )]));
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let key = format!("{API_KEY_PREFIX}{}", hex::encode(bytes));
+        let prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        // bots can't sign in, their password hash matches no password
+        let user_id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO users (ws_id, email, full_name, password_hash, email_verified)
+            VALUES ($1, $2, $3, '', TRUE)
+            RETURNING id
+            "#,
+        )
+        .bind(admin.ws_id)
+        .bind(format!(
+            "bot-{}@bots.invalid",
+            &prefix[API_KEY_PREFIX.len()..]
+        ))
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = 'bot'
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(admin.ws_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_append(members, $1)
+            WHERE id = ANY($2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&chat_ids)
+        .execute(&mut *tx)
+        .await?;
+        let api_key: ApiKey = sqlx::query_as(
+            r#"
+            INSERT INTO api_keys (ws_id, user_id, created_by, name, prefix, key_hash, chat_ids)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, ws_id, user_id, created_by, name, prefix, chat_ids, created_at,
+                last_used_at, revoked_at
+            "#,
+        )
+        .bind(admin.ws_id)
+        .bind(user_id)
+        .bind(admin.id)
+        .bind(name)
+        .bind(&prefix)
+        .bind(hash_token(&key))
+        .bind(&chat_ids)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(CreatedApiKey { api_key, key })
+    }
+
+    /// Keys of the workspace, newest first
+    pub async fn list_api_keys(&self, ws_id: u64) -> Result<Vec<ApiKey>, AppError> {
+        let keys = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, user_id, created_by, name, prefix, chat_ids, created_at,
+                last_used_at, revoked_at
+            FROM api_keys
+            WHERE ws_id = $1
+            ORDER BY id DESC
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    /// Revoke the key, its bot is deactivated so it's left out of the member lists
+    pub async fn revoke_api_key(&self, ws_id: u64, id: u64) -> Result<ApiKey, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let api_key: Option<ApiKey> = sqlx::query_as(
+            r#"
+            UPDATE api_keys
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND ws_id = $2 AND revoked_at IS NULL
+            RETURNING id, ws_id, user_id, created_by, name, prefix, chat_ids, created_at,
+                last_used_at, revoked_at
+            "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(api_key) = api_key else {
+            return Err(AppError::NotFound(format!("API key id {id}")));
+        };
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET deactivated_at = CURRENT_TIMESTAMP
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(api_key.user_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(api_key)
+    }
+
+    /// The bot user of a valid key, scoped to the workspace of the key
+    pub async fn verify_api_key(&self, key: &str) -> Result<User, AppError> {
+        let row: Option<(i64, i64)> = sqlx::query_as(
+            r#"
+            UPDATE api_keys
+            SET last_used_at = CURRENT_TIMESTAMP
+            WHERE key_hash = $1 AND revoked_at IS NULL
+            RETURNING user_id, ws_id
+            "#,
+        )
+        .bind(hash_token(key))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let invalid = || AppError::Unauthorized("API key is invalid or revoked".to_string());
+        let (user_id, ws_id) = row.ok_or_else(invalid)?;
+        let user = self.find_user_by_id(user_id).await?.ok_or_else(invalid)?;
+        let user = self.switch_workspace(user, ws_id as _).await?;
+        if user.role != WorkspaceRole::Bot {
+            return Err(invalid());
+        }
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::middlewares::TokenVerify;
+
+    #[tokio::test]
+    async fn test_api_key_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mut admin = state.find_user_by_id(1).await?.expect("user should exist");
+        admin.role = WorkspaceRole::Owner;
+        let input = CreateApiKey {
+            name: "CI".to_string(),
+            chat_ids: vec![2],
+        };
+        let created = state.create_api_key(&admin, &input).await?;
+        assert!(created.key.starts_with(API_KEY_PREFIX));
+        assert!(created.key.starts_with(&created.api_key.prefix));
+
+        // the key is accepted as a token, acting as its bot
+        let bot = state.verify(&created.key).await?;
+        assert_eq!(bot.id, created.api_key.user_id);
+        assert_eq!(bot.full_name, "CI");
+        assert_eq!(bot.role, WorkspaceRole::Bot);
+        let chat = state.get_chat_by_id(2).await?.expect("chat should exist");
+        assert!(chat.members.contains(&bot.id));
+
+        let keys = state.list_api_keys(1).await?;
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].last_used_at.is_some());
+
+        state.revoke_api_key(1, created.api_key.id as _).await?;
+        let ret = state.verify(&created.key).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+        let ret = state.revoke_api_key(1, created.api_key.id as _).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_api_key_should_check_chats() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mut admin = state.find_user_by_id(1).await?.expect("user should exist");
+        admin.role = WorkspaceRole::Owner;
+        // a single chat, and a chat that doesn't exist
+        for chat_ids in [vec![3], vec![42]] {
+            let input = CreateApiKey {
+                name: "CI".to_string(),
+                chat_ids,
+            };
+            let ret = state.create_api_key(&admin, &input).await;
+            assert!(matches!(ret, Err(AppError::ValidationError(_))));
+        }
+        let ret = state.create_api_key(&admin, &CreateApiKey::default()).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+
+        Ok(())
+    }
+}
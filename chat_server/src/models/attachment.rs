@@ -0,0 +1,1133 @@
+use std::io::Cursor;
+
+use chat_core::{Attachment, MediaInfo, ScanStatus, Thumbnails, UploadStage, User, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    config::ScanConfig,
+    media::{plays_in_browsers, MediaKind},
+    AppError, AppState, ChatFile, ScanVerdict, UploadTracker, ValidationIssue,
+};
+
+use super::{messages::page_limit, search::escape_like};
+
+/// longest mime type stored, the size of the column
+const MAX_MIME_LEN: usize = 128;
+/// quality of the re-encoded jpeg images, high enough for photos
+const JPEG_QUALITY: u8 = 90;
+/// infected files are moved under this prefix of the storage, the garbage collection
+/// leaves them alone
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// downscaled versions of the uploaded images
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailSize {
+    #[serde(alias = "thumb")]
+    Small,
+    Medium,
+}
+
+/// versions of the audios and videos transcoded for the browsers
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rendition {
+    /// an mp4 every browser plays
+    Web,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct GetFile {
+    /// A thumbnail of the image instead of the image itself
+    #[serde(default)]
+    pub size: Option<ThumbnailSize>,
+    /// A rendition of the audio or the video instead of the file itself
+    #[serde(default)]
+    pub rendition: Option<Rendition>,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListFiles {
+    /// Only files uploaded by this user
+    #[serde(default)]
+    pub uploader_id: Option<u64>,
+    /// Only files whose mime type starts with this, e.g. `image/`
+    #[serde(default)]
+    pub mime: Option<String>,
+    /// Only files whose original name contains this, case-insensitively
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Only return files older than this id
+    #[serde(default)]
+    pub last_id: Option<u64>,
+    /// Page size - defaults to 20 when 0 or missing, capped at 100
+    #[serde(default)]
+    pub limit: u64,
+}
+
+impl AppState {
+    /// Store an uploaded file under the workspace and record its metadata
+    pub async fn save_upload(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        filename: &str,
+        mime: Option<String>,
+        data: &[u8],
+    ) -> Result<Attachment, AppError> {
+        self.save_tracked_upload(ws_id, uploader_id, filename, mime, data, None)
+            .await
+    }
+
+    /// Store an uploaded file like [`Self::save_upload`], telling the uploader about the
+    /// scan, the thumbnails and the transcoding as they happen.
+    ///
+    /// Failures past the bytes received are told by the caller for the ones returned, and
+    /// here for the ones of the background tasks.
+    pub async fn save_tracked_upload(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        filename: &str,
+        mime: Option<String>,
+        data: &[u8],
+        tracker: Option<&UploadTracker>,
+    ) -> Result<Attachment, AppError> {
+        self.ensure_email_verified(uploader_id).await?;
+        let settings = self.get_workspace_settings(ws_id).await?;
+        let max_size = settings.max_file_size;
+        if max_size > 0 && data.len() as u64 > max_size {
+            return Err(AppError::ChatFileError(format!(
+                "File {} is larger than {} bytes",
+                filename, max_size
+            )));
+        }
+
+        let mut mime = mime.unwrap_or_else(|| {
+            mime_guess::from_path(filename)
+                .first_or_octet_stream()
+                .to_string()
+        });
+        if mime.len() > MAX_MIME_LEN {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "mime",
+                "length",
+                format!("Mime type must be at most {MAX_MIME_LEN} bytes"),
+            )]));
+        }
+        // the sanitized image is the file stored, its hash included
+        let sanitized;
+        let data = if settings.sanitize_images && mime.starts_with("image/") {
+            let max_pixels = self.config().files.max_image_pixels;
+            let raw = data.to_vec();
+            let (image, format) =
+                tokio::task::spawn_blocking(move || sanitize_image(&raw, max_pixels))
+                    .await
+                    .map_err(|e| AppError::ChatFileError(e.to_string()))??;
+            mime = format.to_mime_type().to_string();
+            sanitized = image;
+            &sanitized[..]
+        } else {
+            data
+        };
+
+        let file = ChatFile::new(ws_id, filename, data);
+        let key = file.key();
+        if self.storage.exists(&key).await? {
+            info!("File {} already exists: {}", filename, key);
+        } else {
+            self.storage.put(&key, data.to_vec()).await?;
+        }
+
+        let attachment = self
+            .create_attachment(&file, filename, data.len() as _, &mime, uploader_id)
+            .await?;
+        // without a scanner the no-op one clears the file right away, previews included
+        let (attachment, scanned) = match attachment.scan_status {
+            ScanStatus::Pending | ScanStatus::Failed
+                if matches!(self.config().scan, ScanConfig::None) =>
+            {
+                let attachment = self
+                    .scan_attachment(&file, attachment, data, tracker)
+                    .await?;
+                (attachment, true)
+            }
+            _ => (attachment, false),
+        };
+        // the same content may have been uploaded and scanned before
+        match attachment.scan_status {
+            ScanStatus::Clean if scanned => Ok(attachment),
+            ScanStatus::Clean => self.ensure_previews(&file, attachment, data, tracker).await,
+            ScanStatus::Infected => {
+                // stored again by this upload
+                self.remove_blob_files(&file.hash).await;
+                Err(AppError::PermissionDenied(format!(
+                    "File {} is quarantined: {}",
+                    filename,
+                    attachment.scan_result.unwrap_or_default()
+                )))
+            }
+            ScanStatus::Pending | ScanStatus::Failed => {
+                // the file is served once the scan cleared it
+                self.report_upload_stage(tracker, UploadStage::Scanning)
+                    .await;
+                let state = self.clone();
+                let pending = attachment.clone();
+                let data = data.to_vec();
+                let tracker = tracker.cloned();
+                tokio::spawn(async move {
+                    let tracker = tracker.as_ref();
+                    let error = match state
+                        .scan_attachment(&file, attachment, &data, tracker)
+                        .await
+                    {
+                        Ok(scanned) => match scanned.scan_status {
+                            ScanStatus::Infected => format!(
+                                "File {} is quarantined: {}",
+                                scanned.filename,
+                                scanned.scan_result.unwrap_or_default()
+                            ),
+                            ScanStatus::Failed => {
+                                format!("File {} couldn't be scanned", scanned.filename)
+                            }
+                            _ => return,
+                        },
+                        Err(e) => {
+                            warn!("Failed to scan file {}: {}", file.url(), e);
+                            e.to_string()
+                        }
+                    };
+                    state.report_upload_failed(tracker, error).await;
+                });
+                Ok(pending)
+            }
+        }
+    }
+
+    /// Scan an uploaded file, quarantine it if it's infected and create its thumbnails
+    /// if it's clean
+    async fn scan_attachment(
+        &self,
+        file: &ChatFile,
+        mut attachment: Attachment,
+        data: &[u8],
+        tracker: Option<&UploadTracker>,
+    ) -> Result<Attachment, AppError> {
+        let (status, result) = match self.scanner.scan(data).await {
+            Ok(ScanVerdict::Clean) => (ScanStatus::Clean, None),
+            Ok(ScanVerdict::Infected(name)) => {
+                warn!("File {} is infected: {}", attachment.url, name);
+                self.quarantine_file(file, data).await?;
+                (ScanStatus::Infected, Some(name))
+            }
+            Err(e) => {
+                warn!("Failed to scan file {}: {}", attachment.url, e);
+                (ScanStatus::Failed, None)
+            }
+        };
+
+        sqlx::query(
+            r#"
+            UPDATE attachments SET scan_status = $2, scan_result = $3, scanned_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+        )
+            .bind(attachment.id)
+            .bind(status)
+            .bind(&result)
+            .execute(&self.pool)
+            .await?;
+        attachment.scan_status = status;
+        attachment.scan_result = result;
+
+        match status {
+            ScanStatus::Clean => self.ensure_previews(file, attachment, data, tracker).await,
+            _ => Ok(attachment),
+        }
+    }
+
+    /// Scan again the files still pending or failed since before `stale_before`, e.g. the
+    /// server stopped while scanning them or the scanner was down. Each server claims the
+    /// files it scans, the number of files scanned is returned.
+    pub async fn rescan_stale_attachments(
+        &self,
+        stale_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<u64, AppError> {
+        let attachments: Vec<Attachment> = sqlx::query_as(
+            r#"
+            UPDATE attachments SET scanned_at = CURRENT_TIMESTAMP
+            WHERE id IN (
+                SELECT id FROM attachments
+                WHERE scan_status IN ('pending', 'failed')
+                    AND COALESCE(scanned_at, created_at) < $1
+                ORDER BY COALESCE(scanned_at, created_at)
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, ws_id, uploader_id, url, hash, filename, size, mime, thumbnails, media,
+                scan_status, scan_result, created_at
+            "#,
+        )
+        .bind(stale_before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut scanned = 0;
+        for attachment in attachments {
+            let url = attachment.url.clone();
+            let file: ChatFile = url.parse()?;
+            let Some(data) = self.storage.get(&file.key()).await? else {
+                warn!("File {} to scan again is gone", url);
+                continue;
+            };
+            match self.scan_attachment(&file, attachment, &data, None).await {
+                Ok(_) => scanned += 1,
+                Err(e) => warn!("Failed to scan file {} again: {}", url, e),
+            }
+        }
+        Ok(scanned)
+    }
+
+    /// Move the file out of the files served, for the compliance teams to look at
+    async fn quarantine_file(&self, file: &ChatFile, data: &[u8]) -> Result<(), AppError> {
+        let key = format!("{QUARANTINE_PREFIX}{}", file.key());
+        self.storage.put(&key, data.to_vec()).await?;
+        // the content is the same for the other workspaces
+        self.remove_blob_files(&file.hash).await;
+        Ok(())
+    }
+
+    /// Create what the clients show of a clean file, the thumbnails of an image or the
+    /// metadata and rendition of an audio or a video
+    async fn ensure_previews(
+        &self,
+        file: &ChatFile,
+        attachment: Attachment,
+        data: &[u8],
+        tracker: Option<&UploadTracker>,
+    ) -> Result<Attachment, AppError> {
+        let attachment = if attachment.mime.starts_with("image/")
+            && attachment.thumbnails == Thumbnails::default()
+        {
+            self.report_upload_stage(tracker, UploadStage::Thumbnailing)
+                .await;
+            self.create_thumbnails(file, attachment, data).await?
+        } else {
+            match MediaKind::of(&attachment.mime) {
+                // done once the rendition is, if there's one to transcode
+                Some(kind) if attachment.media == MediaInfo::default() => {
+                    return self
+                        .probe_media(file, attachment, kind, data, tracker)
+                        .await;
+                }
+                _ => attachment,
+            }
+        };
+        self.report_upload_done(tracker, &attachment.url).await;
+        Ok(attachment)
+    }
+
+    /// Record the duration and dimensions of an audio or a video, its rendition is transcoded
+    /// in the background when browsers may not play it
+    async fn probe_media(
+        &self,
+        file: &ChatFile,
+        mut attachment: Attachment,
+        kind: MediaKind,
+        data: &[u8],
+        tracker: Option<&UploadTracker>,
+    ) -> Result<Attachment, AppError> {
+        match self.transcoder.probe(data).await {
+            Ok(media) => attachment.media = media,
+            Err(e) => warn!("Failed to probe {}: {}", attachment.url, e),
+        }
+        // the same content may have been transcoded for another file
+        let transcode = !plays_in_browsers(&attachment.mime)
+            && !self
+                .storage
+                .exists(&file.rendition_key(Rendition::Web))
+                .await?;
+        if !plays_in_browsers(&attachment.mime) && !transcode {
+            attachment.media.rendition = Some(file.rendition_url(Rendition::Web));
+        }
+
+        if attachment.media != MediaInfo::default() {
+            sqlx::query("UPDATE attachments SET media = $2 WHERE id = $1")
+                .bind(attachment.id)
+                .bind(sqlx::types::Json(&attachment.media))
+                .execute(&self.pool)
+                .await?;
+        }
+
+        if transcode {
+            self.report_upload_stage(tracker, UploadStage::Transcoding)
+                .await;
+            let state = self.clone();
+            let file = file.clone();
+            let id = attachment.id;
+            let data = data.to_vec();
+            let tracker = tracker.cloned();
+            tokio::spawn(async move {
+                let tracker = tracker.as_ref();
+                match state.create_rendition(&file, id, kind, &data).await {
+                    Ok(()) => state.report_upload_done(tracker, &file.url()).await,
+                    Err(e) => {
+                        warn!("Failed to transcode file {}: {}", file.url(), e);
+                        state.report_upload_failed(tracker, e).await;
+                    }
+                }
+            });
+        } else {
+            self.report_upload_done(tracker, &attachment.url).await;
+        }
+        Ok(attachment)
+    }
+
+    async fn create_rendition(
+        &self,
+        file: &ChatFile,
+        id: i64,
+        kind: MediaKind,
+        data: &[u8],
+    ) -> Result<(), AppError> {
+        let Some(rendition) = self.transcoder.transcode(data, kind).await? else {
+            return Ok(());
+        };
+        self.storage
+            .put(&file.rendition_key(Rendition::Web), rendition)
+            .await?;
+        sqlx::query(
+            r#"
+            UPDATE attachments
+            SET media = jsonb_set(media, '{rendition}', to_jsonb($2::text))
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(file.rendition_url(Rendition::Web))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store the thumbnails of an image and record their urls, images the server can't
+    /// decode are left without thumbnails
+    async fn create_thumbnails(
+        &self,
+        file: &ChatFile,
+        mut attachment: Attachment,
+        data: &[u8],
+    ) -> Result<Attachment, AppError> {
+        let data = data.to_vec();
+        let max_pixels = self.config().files.max_image_pixels;
+        let thumbnails =
+            match tokio::task::spawn_blocking(move || resize_thumbnails(&data, max_pixels))
+                .await
+                .map_err(|e| AppError::ChatFileError(e.to_string()))?
+            {
+                Ok(thumbnails) => thumbnails,
+                Err(e) => {
+                    warn!("No thumbnails for {}: {}", attachment.url, e);
+                    return Ok(attachment);
+                }
+            };
+
+        for (size, thumbnail) in thumbnails {
+            let url = match thumbnail {
+                Some(data) => {
+                    self.storage.put(&file.thumbnail_key(size), data).await?;
+                    file.thumbnail_url(size)
+                }
+                None => file.url(),
+            };
+            match size {
+                ThumbnailSize::Small => attachment.thumbnails.small = Some(url),
+                ThumbnailSize::Medium => attachment.thumbnails.medium = Some(url),
+            }
+        }
+
+        sqlx::query("UPDATE attachments SET thumbnails = $2 WHERE id = $1")
+            .bind(attachment.id)
+            .bind(sqlx::types::Json(&attachment.thumbnails))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(attachment)
+    }
+
+    /// Record metadata of an uploaded file, the first upload of the same content wins.
+    ///
+    /// New files are pending until scanned.
+    pub async fn create_attachment(
+        &self,
+        file: &ChatFile,
+        filename: &str,
+        size: u64,
+        mime: &str,
+        uploader_id: u64,
+    ) -> Result<Attachment, AppError> {
+        let attachment = sqlx::query_as(
+            r#"
+            INSERT INTO attachments (ws_id, uploader_id, url, hash, filename, size, mime, scan_status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 'pending')
+            ON CONFLICT (url) DO UPDATE SET url = EXCLUDED.url
+            RETURNING id, ws_id, uploader_id, url, hash, filename, size, mime, thumbnails, media,
+                scan_status, scan_result, created_at
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(uploader_id as i64)
+        .bind(file.url())
+        .bind(&file.hash)
+        .bind(filename)
+        .bind(size as i64)
+        .bind(mime)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(attachment)
+    }
+
+    /// Metadata of a stored file, files stored before it was recorded have none
+    pub async fn get_attachment_by_url(&self, url: &str) -> Result<Option<Attachment>, AppError> {
+        let attachment = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, uploader_id, url, hash, filename, size, mime, thumbnails, media,
+                scan_status, scan_result, created_at
+            FROM attachments
+            WHERE url = $1
+            "#,
+        )
+        .bind(url)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(attachment)
+    }
+
+    /// Delete a file of the workspace, only its uploader and the admins may delete it.
+    ///
+    /// Files still referenced by messages or used as avatars are kept.
+    pub async fn delete_file(&self, user: &User, url: &str) -> Result<(), AppError> {
+        let not_found = || AppError::NotFound(format!("File {url}"));
+        let file: ChatFile = url.parse().map_err(|_| not_found())?;
+        if file.ws_id != user.ws_id as u64 {
+            return Err(not_found());
+        }
+        let attachment = self
+            .get_attachment_by_url(url)
+            .await?
+            .ok_or_else(not_found)?;
+        if attachment.uploader_id != user.id && user.role < WorkspaceRole::Admin {
+            return Err(AppError::PermissionDenied(format!(
+                "Only the uploader or an admin can delete file {url}"
+            )));
+        }
+
+        let hash: Option<String> = sqlx::query_scalar(
+            r#"
+            DELETE FROM attachments a
+            WHERE a.id = $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM messages m
+                    WHERE m.files @> ARRAY[a.url] AND m.deleted_at IS NULL
+                )
+                AND NOT EXISTS (SELECT 1 FROM scheduled_messages s WHERE s.files @> ARRAY[a.url])
+                AND NOT EXISTS (
+                    SELECT 1 FROM users u
+                    WHERE u.avatar_url IN (a.url, '/avatars/' || a.uploader_id || '/' || a.hash || '.png')
+                )
+                AND NOT EXISTS (SELECT 1 FROM chats c WHERE c.avatar_url = a.url)
+            RETURNING a.hash
+            "#,
+        )
+        .bind(attachment.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(hash) = hash else {
+            return Err(AppError::FileInUse(format!(
+                "File {url} is used by messages or as an avatar"
+            )));
+        };
+
+        // the content goes with the last file referring to it
+        self.release_blobs(&[hash]).await
+    }
+
+    /// List files of the workspace, newest first
+    pub async fn list_attachments(
+        &self,
+        input: ListFiles,
+        ws_id: u64,
+    ) -> Result<Vec<Attachment>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+
+        let attachments = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, uploader_id, url, hash, filename, size, mime, thumbnails, media,
+                scan_status, scan_result, created_at
+            FROM attachments
+            WHERE ws_id = $1 AND id < $2
+                AND ($3::bigint IS NULL OR uploader_id = $3)
+                AND ($4::text IS NULL OR starts_with(mime, $4))
+                AND ($5::text IS NULL OR filename ILIKE '%' || $5 || '%' ESCAPE '\')
+            ORDER BY id DESC
+            LIMIT $6
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(last_id as i64)
+        .bind(input.uploader_id.map(|v| v as i64))
+        .bind(input.mime)
+        .bind(input.filename.as_deref().map(escape_like))
+        .bind(page_limit(input.limit))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(attachments)
+    }
+}
+
+impl ThumbnailSize {
+    pub const ALL: [ThumbnailSize; 2] = [ThumbnailSize::Small, ThumbnailSize::Medium];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+        }
+    }
+
+    /// the thumbnail fits in a square of this size
+    fn max_dimension(&self) -> u32 {
+        match self {
+            Self::Small => 160,
+            Self::Medium => 640,
+        }
+    }
+}
+
+impl Rendition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Web => "web",
+        }
+    }
+}
+
+/// a jpeg thumbnail, None when the image already fits in the size
+type Thumbnail = (ThumbnailSize, Option<Vec<u8>>);
+
+/// jpeg thumbnails of the image for each size
+fn resize_thumbnails(data: &[u8], max_pixels: u64) -> Result<Vec<Thumbnail>, AppError> {
+    let (img, _) = decode_image(data, max_pixels)?;
+
+    let mut thumbnails = vec![];
+    for size in ThumbnailSize::ALL {
+        let max = size.max_dimension();
+        if img.width() <= max && img.height() <= max {
+            thumbnails.push((size, None));
+            continue;
+        }
+        // jpeg has no alpha channel
+        let thumbnail = img.resize(max, max, FilterType::Lanczos3).into_rgb8();
+        let mut buf = Cursor::new(Vec::new());
+        thumbnail
+            .write_to(&mut buf, ImageFormat::Jpeg)
+            .map_err(|e| AppError::ChatFileError(e.to_string()))?;
+        thumbnails.push((size, Some(buf.into_inner())));
+    }
+    Ok(thumbnails)
+}
+
+/// Decode an image the right way up, images of more than `max_pixels` pixels are refused
+/// before they're decoded
+fn decode_image(data: &[u8], max_pixels: u64) -> Result<(DynamicImage, ImageFormat), AppError> {
+    let invalid = |e: image::ImageError| AppError::ChatFileError(format!("Invalid image: {e}"));
+    let reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| AppError::ChatFileError(e.to_string()))?;
+    let Some(format) = reader.format() else {
+        return Err(AppError::ChatFileError(
+            "Unsupported image format".to_string(),
+        ));
+    };
+    let mut decoder = reader.into_decoder().map_err(invalid)?;
+    let (width, height) = decoder.dimensions();
+    if width as u64 * height as u64 > max_pixels {
+        return Err(AppError::ChatFileError(format!(
+            "Image of {width}x{height} pixels is larger than {max_pixels} pixels"
+        )));
+    }
+    let orientation = decoder.orientation().map_err(invalid)?;
+    let mut img = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    img.apply_orientation(orientation);
+    Ok((img, format))
+}
+
+/// Re-encode an image without its metadata, in its own format when it's a safe one
+fn sanitize_image(data: &[u8], max_pixels: u64) -> Result<(Vec<u8>, ImageFormat), AppError> {
+    let (img, format) = decode_image(data, max_pixels)?;
+    let mut buf = Cursor::new(Vec::new());
+    let ret = match format {
+        // jpeg has no alpha channel
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.into_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY)),
+        ImageFormat::Png => img.write_to(&mut buf, ImageFormat::Png),
+        _ => {
+            return Err(AppError::ChatFileError(format!(
+                "Unsupported image format: {format:?}"
+            )))
+        }
+    };
+    ret.map_err(|e| AppError::ChatFileError(e.to_string()))?;
+    Ok((buf.into_inner(), format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use image::ImageEncoder;
+    use std::sync::Arc;
+
+    /// flags the data containing "EICAR"
+    #[derive(Debug)]
+    struct TestScanner;
+
+    #[async_trait::async_trait]
+    impl crate::Scanner for TestScanner {
+        async fn scan(&self, data: &[u8]) -> Result<ScanVerdict, AppError> {
+            if data.windows(5).any(|w| w == b"EICAR") {
+                return Ok(ScanVerdict::Infected("Eicar-Test".to_string()));
+            }
+            Ok(ScanVerdict::Clean)
+        }
+    }
+
+    /// knows the duration of everything and "transcodes" by prefixing the data
+    #[derive(Debug)]
+    struct TestTranscoder;
+
+    #[async_trait::async_trait]
+    impl crate::Transcoder for TestTranscoder {
+        async fn probe(&self, _data: &[u8]) -> Result<MediaInfo, AppError> {
+            Ok(MediaInfo {
+                duration: Some(2.5),
+                width: Some(640),
+                height: Some(360),
+                rendition: None,
+            })
+        }
+
+        async fn transcode(
+            &self,
+            data: &[u8],
+            _kind: MediaKind,
+        ) -> Result<Option<Vec<u8>>, AppError> {
+            Ok(Some([b"mp4:", data].concat()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_upload_should_quarantine_infected_files() -> Result<()> {
+        let (_tdb, mut state) = AppState::try_new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .scanner = Arc::new(TestScanner);
+
+        let data = b"X5O!P%@AP EICAR-STANDARD-ANTIVIRUS-TEST-FILE quarantine";
+        let ret = state.save_upload(1, 1, "eicar.com", None, data).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let file = ChatFile::new(1, "eicar.com", data);
+        let attachment = state
+            .get_attachment_by_url(&file.url())
+            .await?
+            .expect("attachment should exist");
+        assert_eq!(attachment.scan_status, ScanStatus::Infected);
+        assert_eq!(attachment.scan_result.as_deref(), Some("Eicar-Test"));
+        assert!(!state.storage.exists(&file.key()).await?);
+        let quarantined = format!("{QUARANTINE_PREFIX}{}", file.key());
+        assert!(state.storage.exists(&quarantined).await?);
+        state.storage.delete(&quarantined).await?;
+
+        // uploading it again doesn't bring it back
+        let ret = state.save_upload(1, 2, "again.com", None, data).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        assert!(!state.storage.exists(&file.key()).await?);
+
+        let clean = state
+            .save_upload(1, 1, "clean.txt", None, b"nothing to see")
+            .await?;
+        assert_eq!(clean.scan_status, ScanStatus::Clean);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rescan_stale_attachments_should_work() -> Result<()> {
+        let (_tdb, mut state) = AppState::try_new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .scanner = Arc::new(TestScanner);
+
+        // left pending by a server that stopped, and failed while the scanner was down
+        let mut files = vec![];
+        for (name, data) in [
+            ("clean.txt", &b"nothing to see"[..]),
+            ("eicar.com", b"EICAR"),
+        ] {
+            let file = ChatFile::new(1, name, data);
+            state.storage.put(&file.key(), data.to_vec()).await?;
+            state
+                .create_attachment(&file, name, data.len() as _, "text/plain", 1)
+                .await?;
+            files.push(file);
+        }
+        sqlx::query("UPDATE attachments SET scan_status = 'failed' WHERE url = $1")
+            .bind(files[1].url())
+            .execute(&state.pool)
+            .await?;
+
+        // too recent to be taken for stale
+        let stale_before = Utc::now() - chrono::Duration::minutes(10);
+        assert_eq!(state.rescan_stale_attachments(stale_before, 10).await?, 0);
+
+        sqlx::query("UPDATE attachments SET created_at = now() - interval '1 hour'")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.rescan_stale_attachments(stale_before, 10).await?, 2);
+        for (file, status) in files.iter().zip([ScanStatus::Clean, ScanStatus::Infected]) {
+            let attachment = state.get_attachment_by_url(&file.url()).await?;
+            assert_eq!(
+                attachment.expect("attachment should exist").scan_status,
+                status
+            );
+        }
+        assert_eq!(state.rescan_stale_attachments(Utc::now(), 10).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_upload_should_probe_and_transcode_media() -> Result<()> {
+        let (_tdb, mut state) = AppState::try_new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .transcoder = Arc::new(TestTranscoder);
+
+        let clip = state
+            .save_upload(1, 1, "clip.webm", None, b"webm clip")
+            .await?;
+        assert_eq!(clip.media.duration, Some(2.5));
+        assert_eq!(
+            (clip.media.width, clip.media.height),
+            (Some(640), Some(360))
+        );
+
+        // transcoded in the background
+        let file: ChatFile = clip.url.parse()?;
+        let mut rendition = None;
+        for _ in 0..50 {
+            let found = state.get_attachment_by_url(&clip.url).await?;
+            rendition = found.and_then(|clip| clip.media.rendition);
+            if rendition.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(rendition, Some(file.rendition_url(Rendition::Web)));
+        let data = state
+            .storage
+            .get(&file.rendition_key(Rendition::Web))
+            .await?;
+        assert_eq!(data.as_deref(), Some(&b"mp4:webm clip"[..]));
+
+        // browsers play mp3 as is
+        let song = state
+            .save_upload(1, 1, "song.mp3", None, b"mp3 song")
+            .await?;
+        assert_eq!(song.media.duration, Some(2.5));
+        assert_eq!(song.media.rendition, None);
+        let file: ChatFile = song.url.parse()?;
+        assert!(
+            !state
+                .storage
+                .exists(&file.rendition_key(Rendition::Web))
+                .await?
+        );
+
+        Ok(())
+    }
+
+    /// the next progress told to the notify server, to user 1
+    async fn next_progress(
+        listener: &mut sqlx::postgres::PgListener,
+    ) -> Result<chat_core::UploadProgress> {
+        let timeout = std::time::Duration::from_secs(5);
+        let notif = tokio::time::timeout(timeout, listener.recv()).await??;
+        let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+        assert_eq!(payload["members"], serde_json::json!([1]));
+        Ok(serde_json::from_value(payload["progress"].clone())?)
+    }
+
+    #[tokio::test]
+    async fn test_save_tracked_upload_should_report_progress() -> Result<()> {
+        let (_tdb, mut state) = AppState::try_new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .transcoder = Arc::new(TestTranscoder);
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen("upload_progress").await?;
+        let params = crate::UploadParams {
+            upload_id: Some("up-1".to_string()),
+        };
+        // the storage outlives the test, a rendition of known content is found there
+        let data = format!("tracked webm {}", chrono::Utc::now().timestamp_micros());
+        let total = data.len() as u64;
+        let mut tracker = params
+            .tracker(1, Some(total))?
+            .expect("tracker should exist");
+        tracker.filename = "clip.webm".to_string();
+        tracker.receive(total);
+        let clip = state
+            .save_tracked_upload(1, 1, "clip.webm", None, data.as_bytes(), Some(&tracker))
+            .await?;
+
+        let progress = next_progress(&mut listener).await?;
+        assert_eq!(progress.upload_id, "up-1");
+        assert_eq!(progress.filename, "clip.webm");
+        assert_eq!(progress.stage, UploadStage::Transcoding);
+        assert_eq!((progress.received, progress.total), (total, Some(total)));
+        // done once the rendition is
+        let progress = next_progress(&mut listener).await?;
+        assert_eq!(progress.stage, UploadStage::Done);
+        assert_eq!(progress.url, Some(clip.url));
+
+        // untracked uploads tell nobody
+        state.save_upload(1, 1, "a.txt", None, b"untracked").await?;
+        tracker.filename = "b.txt".to_string();
+        let text = state
+            .save_tracked_upload(1, 1, "b.txt", None, b"tracked", Some(&tracker))
+            .await?;
+        let progress = next_progress(&mut listener).await?;
+        assert_eq!(progress.stage, UploadStage::Done);
+        assert_eq!(progress.url, Some(text.url));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_upload_with_a_long_mime_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mime = format!("text/{}", "x".repeat(MAX_MIME_LEN));
+        let ret = state
+            .save_upload(1, 1, "notes.txt", Some(mime), b"notes")
+            .await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_file_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let uploader = state.find_user_by_id(1).await?.expect("user should exist");
+        let other = state.find_user_by_id(2).await?.expect("user should exist");
+
+        let notes = state
+            .save_upload(1, 1, "notes.txt", None, b"notes to delete")
+            .await?;
+        let ret = state.delete_file(&other, &notes.url).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // used by a message until the message is deleted
+        let input = crate::CreateMessage {
+            content: "my notes".to_string(),
+            files: vec![notes.url.clone()],
+            parent_id: None,
+            send_at: None,
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        let ret = state.delete_file(&uploader, &notes.url).await;
+        assert!(matches!(ret, Err(AppError::FileInUse(_))));
+        state.delete_message(1, message.id as _, 1).await?;
+
+        state.delete_file(&uploader, &notes.url).await?;
+        let file: ChatFile = notes.url.parse()?;
+        assert!(!state.storage.exists(&file.key()).await?);
+        assert!(state.get_attachment_by_url(&notes.url).await?.is_none());
+        let ret = state.delete_file(&uploader, &notes.url).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_upload_should_sanitize_images() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        state.update_config(|config| config.files.max_image_pixels = 500_000);
+        let input = crate::UpdateWorkspaceSettings {
+            sanitize_images: Some(true),
+            ..Default::default()
+        };
+        state.update_workspace_settings(1, input).await?;
+
+        // a photo taken sideways, with its position
+        let mut exif = b"MM\0*\0\0\0\x08\0\x01".to_vec();
+        exif.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0]);
+        exif.extend(b"GPS 48.8584N 2.2945E");
+        let mut data = vec![];
+        let mut encoder = JpegEncoder::new(&mut data);
+        encoder.set_exif_metadata(exif)?;
+        let img = image::RgbImage::new(80, 40);
+        encoder.encode_image(&img)?;
+        assert!(data.windows(3).any(|w| w == b"GPS"));
+
+        let photo = state.save_upload(1, 1, "photo.png", None, &data).await?;
+        assert_eq!(photo.mime, "image/jpeg");
+        let file: ChatFile = photo.url.parse()?;
+        let stored = state
+            .storage
+            .get(&file.key())
+            .await?
+            .expect("file should exist");
+        assert!(!stored.windows(3).any(|w| w == b"GPS"));
+        // rotated according to the metadata before they're dropped
+        let stored = image::load_from_memory(&stored)?;
+        assert_eq!((stored.width(), stored.height()), (40, 80));
+
+        let ret = state
+            .save_upload(1, 1, "fake.png", None, b"not an image")
+            .await;
+        assert!(matches!(ret, Err(AppError::ChatFileError(_))));
+
+        // decompression bombs are refused before they're decoded
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(1000, 1000).write_to(&mut data, ImageFormat::Png)?;
+        let ret = state
+            .save_upload(1, 1, "bomb.png", None, data.get_ref())
+            .await;
+        assert!(matches!(ret, Err(AppError::ChatFileError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_upload_should_create_thumbnails() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(800, 400).write_to(&mut data, ImageFormat::Png)?;
+        let photo = state
+            .save_upload(1, 1, "photo.png", None, data.get_ref())
+            .await?;
+        let file: ChatFile = photo.url.parse()?;
+        assert_eq!(
+            photo.thumbnails.small,
+            Some(file.thumbnail_url(ThumbnailSize::Small))
+        );
+        assert_eq!(
+            photo.thumbnails.medium,
+            Some(file.thumbnail_url(ThumbnailSize::Medium))
+        );
+        let small = state
+            .storage
+            .get(&file.thumbnail_key(ThumbnailSize::Small))
+            .await?
+            .expect("thumbnail should exist");
+        let small = image::load_from_memory(&small)?;
+        assert_eq!((small.width(), small.height()), (160, 80));
+
+        // small images are their own thumbnails
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(300, 200).write_to(&mut data, ImageFormat::Png)?;
+        let icon = state
+            .save_upload(1, 1, "icon.png", None, data.get_ref())
+            .await?;
+        let file: ChatFile = icon.url.parse()?;
+        assert_eq!(
+            icon.thumbnails.small,
+            Some(file.thumbnail_url(ThumbnailSize::Small))
+        );
+        assert_eq!(icon.thumbnails.medium, Some(icon.url.clone()));
+        let found = state.get_attachment_by_url(&icon.url).await?;
+        assert_eq!(found, Some(icon));
+
+        // not an image the server can decode
+        let fake = state
+            .save_upload(1, 1, "fake.png", None, b"not an image")
+            .await?;
+        assert_eq!(fake.thumbnails, Thumbnails::default());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list_attachments_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        let cat = state
+            .create_attachment(&file, "cat.png", 4, "image/png", 1)
+            .await?;
+        assert_eq!(cat.url, file.url());
+        assert_eq!(cat.size, 4);
+        // uploading the same content again keeps the first metadata
+        let again = state
+            .create_attachment(&file, "other.png", 4, "image/png", 2)
+            .await?;
+        assert_eq!(again, cat);
+
+        let file = ChatFile::new(1, "Report.pdf", b"numbers");
+        state
+            .create_attachment(&file, "Report.pdf", 7, "application/pdf", 2)
+            .await?;
+
+        let files = state.list_attachments(ListFiles::default(), 1).await?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].filename, "Report.pdf");
+
+        let found = state.get_attachment_by_url(&cat.url).await?;
+        assert_eq!(found, Some(cat.clone()));
+
+        let input = ListFiles {
+            mime: Some("image/".to_string()),
+            ..Default::default()
+        };
+        let files = state.list_attachments(input, 1).await?;
+        assert_eq!(files, vec![cat.clone()]);
+
+        let input = ListFiles {
+            uploader_id: Some(2),
+            filename: Some("report".to_string()),
+            ..Default::default()
+        };
+        let files = state.list_attachments(input, 1).await?;
+        assert_eq!(files.len(), 1);
+        // the wildcards are matched as written
+        let input = ListFiles {
+            filename: Some("c_t".to_string()),
+            ..Default::default()
+        };
+        assert!(state.list_attachments(input, 1).await?.is_empty());
+
+        assert!(state
+            .list_attachments(ListFiles::default(), 2)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+}
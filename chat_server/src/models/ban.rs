@@ -0,0 +1,199 @@
+use chat_core::Chat;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct RemoveChatMember {
+    /// Also ban the user, so that they can't be added back or join again
+    #[serde(default)]
+    pub ban: bool,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ChatBan {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub banned_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Remove a member from the chat and optionally ban them, only the chat owner can do it.
+    ///
+    /// Banning works for users who already left the chat as well.
+    pub async fn kick_chat_member(
+        &self,
+        input: RemoveChatMember,
+        chat_id: u64,
+        member_id: u64,
+        user_id: u64,
+    ) -> Result<Chat, AppError> {
+        if !input.ban {
+            return self.remove_chat_member(chat_id, member_id, user_id).await;
+        }
+
+        self.ban_chat_member(chat_id, member_id, user_id).await?;
+        match self.remove_chat_member(chat_id, member_id, user_id).await {
+            Err(AppError::NotFound(_)) => self
+                .get_chat_by_id(chat_id)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("Chat id {chat_id}"))),
+            ret => ret,
+        }
+    }
+
+    pub async fn ban_chat_member(
+        &self,
+        chat_id: u64,
+        member_id: u64,
+        user_id: u64,
+    ) -> Result<ChatBan, AppError> {
+        let chat = self.get_owned_chat(chat_id, user_id).await?;
+        if chat.owner_id == member_id as i64 {
+            return Err(AppError::UpdateChatError(
+                "The chat owner cannot be banned".to_string(),
+            ));
+        }
+
+        let ban = sqlx::query_as(
+            r#"
+            INSERT INTO chat_bans (chat_id, user_id, banned_by)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE SET banned_by = chat_bans.banned_by
+            RETURNING chat_id, user_id, banned_by, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(ban)
+    }
+
+    /// Bans of the chat, newest first, only the chat owner can see them
+    pub async fn list_chat_bans(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<ChatBan>, AppError> {
+        self.get_owned_chat(chat_id, user_id).await?;
+
+        let bans = sqlx::query_as(
+            r#"
+            SELECT chat_id, user_id, banned_by, created_at
+            FROM chat_bans
+            WHERE chat_id = $1
+            ORDER BY created_at DESC, user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(bans)
+    }
+
+    pub async fn unban_chat_member(
+        &self,
+        chat_id: u64,
+        member_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        self.get_owned_chat(chat_id, user_id).await?;
+
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM chat_bans
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "Ban of user {member_id} in chat {chat_id}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub async fn is_chat_banned(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let banned = sqlx::query(
+            r#"
+            SELECT 1
+            FROM chat_bans
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(banned.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_kick_and_ban_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // only the owner can ban
+        let input = RemoveChatMember { ban: true };
+        let err = state.kick_chat_member(input, 1, 5, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let input = RemoveChatMember { ban: true };
+        let chat = state.kick_chat_member(input, 1, 5, 1).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+        assert!(state.is_chat_banned(1, 5).await?);
+
+        // banned users can't join the public channel again
+        let err = state.join_chat(1, 5, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let bans = state.list_chat_bans(1, 1).await?;
+        assert_eq!(bans.len(), 1);
+        assert_eq!((bans[0].user_id, bans[0].banned_by), (5, 1));
+
+        state.unban_chat_member(1, 5, 1).await?;
+        assert!(state.list_chat_bans(1, 1).await?.is_empty());
+        let chat = state.join_chat(1, 5, 1).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4, 5]);
+
+        let err = state.unban_chat_member(1, 5, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ban_non_member_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // user 4 is not in chat 2
+        let input = RemoveChatMember { ban: true };
+        let chat = state.kick_chat_member(input, 2, 4, 1).await?;
+        assert_eq!(chat.members, vec![1, 2, 3]);
+
+        let err = state.add_chat_member(2, 4, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+
+use super::file::{blob_key, blob_rendition_key, blob_thumbnail_key, hash_from_blob_key};
+use crate::{AppError, AppState, ChatFile, PurgeStats, Rendition, StoredObject, ThumbnailSize};
+
+/// keys looked up at once by the garbage collection
+const GC_BATCH: usize = 1000;
+
+impl AppState {
+    /// Drop the blobs no attachment refers to anymore, their content and thumbnails are
+    /// removed from the storage
+    pub(crate) async fn release_blobs(&self, hashes: &[String]) -> Result<(), AppError> {
+        // the files moved from a per workspace key may only be referred to by messages
+        let released: Vec<String> = sqlx::query_scalar(
+            r#"
+            DELETE FROM blobs b
+            WHERE b.hash = ANY($1) AND b.ref_count <= 0
+                AND NOT EXISTS (
+                    SELECT 1 FROM messages m, unnest(m.files) f
+                    WHERE m.deleted_at IS NULL AND f LIKE '%/' || substr(b.hash, 1, 3) || '/' || substr(b.hash, 4, 3)
+                        || '/' || substr(b.hash, 7) || '.%'
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM scheduled_messages s, unnest(s.files) f
+                    WHERE f LIKE '%/' || substr(b.hash, 1, 3) || '/' || substr(b.hash, 4, 3)
+                        || '/' || substr(b.hash, 7) || '.%'
+                )
+            RETURNING b.hash
+            "#,
+        )
+        .bind(hashes)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for hash in released {
+            self.remove_blob_files(&hash).await;
+        }
+        Ok(())
+    }
+
+    /// Remove the content of a blob, its thumbnails and its rendition from the storage,
+    /// whoever refers to it
+    pub(crate) async fn remove_blob_files(&self, hash: &str) {
+        let thumbnails = ThumbnailSize::ALL.map(|size| blob_thumbnail_key(hash, size));
+        let rendition = blob_rendition_key(hash, Rendition::Web);
+        for key in std::iter::once(blob_key(hash))
+            .chain(thumbnails)
+            .chain([rendition])
+        {
+            if let Err(e) = self.storage.delete(&key).await {
+                warn!("Failed to remove file {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Key of the content of the file or of its thumbnail in the storage, files stored
+    /// per workspace are found until the garbage collection moved them.
+    ///
+    /// The blobs are shared by the workspaces, so one is only found for a file referred to in
+    /// the workspace of its url, a hash alone doesn't give access to the content.
+    pub async fn locate_file(
+        &self,
+        file: &ChatFile,
+        size: Option<ThumbnailSize>,
+    ) -> Result<Option<String>, AppError> {
+        let (key, legacy_key) = match size {
+            Some(size) => (file.thumbnail_key(size), file.legacy_thumbnail_key(size)),
+            None => (file.key(), file.legacy_key()),
+        };
+        if self.is_file_referenced(file).await? && self.storage.exists(&key).await? {
+            return Ok(Some(key));
+        }
+        if self.storage.exists(&legacy_key).await? {
+            return Ok(Some(legacy_key));
+        }
+        Ok(None)
+    }
+
+    /// Whether an attachment or a message of the workspace of the url refers to the file
+    pub(crate) async fn is_file_referenced(&self, file: &ChatFile) -> Result<bool, AppError> {
+        let referenced = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM attachments WHERE url = $1)
+                OR EXISTS (SELECT 1 FROM messages WHERE files @> ARRAY[$1] AND deleted_at IS NULL)
+                OR EXISTS (SELECT 1 FROM scheduled_messages WHERE files @> ARRAY[$1])
+            "#,
+        )
+        .bind(file.url())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(referenced)
+    }
+
+    /// Remove the stored blobs, thumbnails and renditions included, that have no row anymore, if they
+    /// were last modified before the cutoff.
+    ///
+    /// Files still stored per workspace are moved to their blob when referenced, and removed
+    /// like the blobs otherwise.
+    pub async fn collect_file_garbage(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Result<PurgeStats, AppError> {
+        let mut stats = PurgeStats::default();
+        let mut blobs = vec![];
+        let mut legacy = vec![];
+        for object in self.storage.list("").await? {
+            if let Some(hash) = hash_from_blob_key(&object.key) {
+                if object.modified_at < cutoff {
+                    blobs.push((object.key, hash));
+                }
+            } else if let Ok((file, size)) = ChatFile::from_legacy_key(&object.key) {
+                legacy.push((object, file, size));
+            }
+            // anything else in the storage isn't ours
+        }
+
+        for batch in blobs.chunks(GC_BATCH) {
+            let hashes: Vec<_> = batch.iter().map(|(_, hash)| hash.as_str()).collect();
+            let known: Vec<String> =
+                sqlx::query_scalar("SELECT hash FROM blobs WHERE hash = ANY($1)")
+                    .bind(&hashes)
+                    .fetch_all(&self.pool)
+                    .await?;
+            let known: HashSet<_> = known.into_iter().collect();
+
+            for (key, hash) in batch {
+                if known.contains(hash) {
+                    continue;
+                }
+                match self.storage.delete(key).await {
+                    Ok(()) => stats.files += 1,
+                    Err(e) => warn!("Failed to remove file {}: {}", key, e),
+                }
+            }
+        }
+
+        let mut moved = 0;
+        for batch in legacy.chunks(GC_BATCH) {
+            let mut urls: Vec<_> = batch.iter().map(|(_, file, _)| file.url()).collect();
+            urls.dedup();
+            let referenced: Vec<String> = sqlx::query_scalar(
+                r#"
+                SELECT u.url
+                FROM unnest($1::text[]) AS u(url)
+                WHERE EXISTS (SELECT 1 FROM attachments a WHERE a.url = u.url)
+                    OR EXISTS (SELECT 1 FROM messages m WHERE m.files @> ARRAY[u.url])
+                    OR EXISTS (SELECT 1 FROM scheduled_messages s WHERE s.files @> ARRAY[u.url])
+                "#,
+            )
+            .bind(&urls)
+            .fetch_all(&self.pool)
+            .await?;
+            let referenced: HashSet<_> = referenced.into_iter().collect();
+
+            for (object, file, size) in batch {
+                if referenced.contains(&file.url()) {
+                    match self.move_legacy_file(object, file, *size).await {
+                        Ok(()) => moved += 1,
+                        Err(e) => warn!("Failed to move file {}: {}", object.key, e),
+                    }
+                } else if object.modified_at < cutoff {
+                    match self.storage.delete(&object.key).await {
+                        Ok(()) => stats.files += 1,
+                        Err(e) => warn!("Failed to remove file {}: {}", object.key, e),
+                    }
+                }
+            }
+        }
+        if moved > 0 {
+            info!("Moved {} files stored per workspace to their blobs", moved);
+        }
+
+        Ok(stats)
+    }
+
+    /// Move a file stored per workspace to its blob, the copies of the other workspaces
+    /// are dropped as they get there
+    async fn move_legacy_file(
+        &self,
+        object: &StoredObject,
+        file: &ChatFile,
+        size: Option<ThumbnailSize>,
+    ) -> Result<(), AppError> {
+        let Some(data) = self.storage.get(&object.key).await? else {
+            return Ok(());
+        };
+        let key = match size {
+            Some(size) => file.thumbnail_key(size),
+            None => {
+                // kept by the garbage collection even when only messages refer to it
+                sqlx::query(
+                    r#"
+                    INSERT INTO blobs (hash, size)
+                    VALUES ($1, $2)
+                    ON CONFLICT (hash) DO NOTHING
+                    "#,
+                )
+                .bind(&file.hash)
+                .bind(data.len() as i64)
+                .execute(&self.pool)
+                .await?;
+                file.key()
+            }
+        };
+        if !self.storage.exists(&key).await? {
+            self.storage.put(&key, data).await?;
+        }
+        self.storage.delete(&object.key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use anyhow::Result;
+    use sqlx_db_tester::TestPg;
+    use std::{path::PathBuf, sync::Arc};
+
+    /// a state with a storage of its own, the storage of the other tests is none of this
+    /// database's business
+    async fn state_with_own_storage(name: &str) -> Result<(TestPg, AppState, PathBuf)> {
+        let (tdb, mut state) = AppState::try_new_for_test().await?;
+        let dir = std::env::temp_dir().join(format!("chat-{}-{}", name, std::process::id()));
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .storage = Arc::new(LocalStorage::try_new(&dir).await?);
+        Ok((tdb, state, dir))
+    }
+
+    async fn ref_count(state: &AppState, hash: &str) -> Result<Option<i32>> {
+        let count = sqlx::query_scalar("SELECT ref_count FROM blobs WHERE hash = $1")
+            .bind(hash)
+            .fetch_optional(&state.pool)
+            .await?;
+        Ok(count)
+    }
+
+    #[tokio::test]
+    async fn test_blobs_should_be_shared_by_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let mut admin = state.find_user_by_id(1).await?.expect("user should exist");
+        admin.role = chat_core::WorkspaceRole::Admin;
+
+        let data = b"shared by two workspaces";
+        let first = state.save_upload(1, 1, "a.txt", None, data).await?;
+        // uploading it again to the same url adds no reference
+        state.save_upload(1, 1, "a.txt", None, data).await?;
+        let file = ChatFile::new(2, "b.txt", data);
+        state
+            .create_attachment(&file, "b.txt", data.len() as _, "text/plain", 1)
+            .await?;
+        assert_eq!(ref_count(&state, &file.hash).await?, Some(2));
+
+        state.delete_file(&admin, &first.url).await?;
+        assert_eq!(ref_count(&state, &file.hash).await?, Some(1));
+        assert!(state.locate_file(&file, None).await?.is_some());
+
+        admin.ws_id = 2;
+        state.delete_file(&admin, &file.url()).await?;
+        assert_eq!(ref_count(&state, &file.hash).await?, None);
+        assert!(!state.storage.exists(&file.key()).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_file_garbage_should_keep_referenced_files() -> Result<()> {
+        let (_tdb, state, dir) = state_with_own_storage("gc").await?;
+
+        let kept = state.save_upload(1, 1, "kept.txt", None, b"kept").await?;
+        // a file without metadata nor message, e.g. a failed upload
+        let orphan = ChatFile::new(1, "orphan.txt", b"orphan");
+        state.storage.put(&orphan.key(), b"orphan".to_vec()).await?;
+
+        // too recent
+        state
+            .collect_file_garbage(Utc::now() - chrono::Duration::hours(1))
+            .await?;
+        assert!(state.storage.exists(&orphan.key()).await?);
+
+        let stats = state
+            .collect_file_garbage(Utc::now() + chrono::Duration::seconds(1))
+            .await?;
+        assert_eq!(stats.files, 1);
+        assert!(!state.storage.exists(&orphan.key()).await?);
+        let kept: ChatFile = kept.url.parse()?;
+        assert!(state.storage.exists(&kept.key()).await?);
+
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_file_garbage_should_move_legacy_files() -> Result<()> {
+        let (_tdb, state, dir) = state_with_own_storage("legacy").await?;
+
+        // stored per workspace and referenced by a message only
+        let file = ChatFile::new(1, "legacy.txt", b"legacy");
+        state
+            .storage
+            .put(&file.legacy_key(), b"legacy".to_vec())
+            .await?;
+        let thumbnail = file.legacy_thumbnail_key(ThumbnailSize::Small);
+        state.storage.put(&thumbnail, b"thumb".to_vec()).await?;
+        let input = crate::CreateMessage {
+            content: "legacy".to_string(),
+            files: vec![file.url()],
+            parent_id: None,
+            send_at: None,
+        };
+        state.create_message(input, 1, 1).await?;
+        assert_eq!(
+            state.locate_file(&file, None).await?,
+            Some(file.legacy_key())
+        );
+        let stale = ChatFile::new(1, "stale.txt", b"stale");
+        state
+            .storage
+            .put(&stale.legacy_key(), b"stale".to_vec())
+            .await?;
+
+        let stats = state
+            .collect_file_garbage(Utc::now() + chrono::Duration::seconds(1))
+            .await?;
+        assert_eq!(stats.files, 1);
+        assert!(!state.storage.exists(&stale.legacy_key()).await?);
+        assert_eq!(state.locate_file(&file, None).await?, Some(file.key()));
+        assert!(!state.storage.exists(&file.legacy_key()).await?);
+        let small = state.locate_file(&file, Some(ThumbnailSize::Small)).await?;
+        assert_eq!(small, Some(file.thumbnail_key(ThumbnailSize::Small)));
+        assert_eq!(ref_count(&state, &file.hash).await?, Some(0));
+
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blobs_of_other_workspaces_should_not_be_found() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let data = b"only for workspace 1";
+        let upload = state.save_upload(1, 1, "a.txt", None, data).await?;
+        let file: ChatFile = upload.url.parse()?;
+        assert_eq!(state.locate_file(&file, None).await?, Some(file.key()));
+
+        // the same hash through the url of another workspace
+        let foreign = ChatFile::new(2, "a.txt", data);
+        assert_eq!(state.locate_file(&foreign, None).await?, None);
+        let input = crate::CreateMessage {
+            content: "foreign".to_string(),
+            files: vec![foreign.url()],
+            parent_id: None,
+            send_at: None,
+        };
+        assert!(state.create_message(input, 1, 1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blobs_referred_to_by_messages_should_be_kept() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let mut admin = state.find_user_by_id(1).await?.expect("user should exist");
+        admin.role = chat_core::WorkspaceRole::Admin;
+
+        let data = b"in a legacy message";
+        let upload = state.save_upload(1, 1, "a.txt", None, data).await?;
+        let file: ChatFile = upload.url.parse()?;
+        // a message of another workspace kept the url of a file moved to the blob
+        let legacy = ChatFile::new(2, "a.txt", data);
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content, files) VALUES (1, 1, 'legacy', $1)",
+        )
+        .bind(vec![legacy.url()])
+        .execute(&state.pool)
+        .await?;
+
+        state.delete_file(&admin, &file.url()).await?;
+        assert_eq!(ref_count(&state, &file.hash).await?, Some(0));
+        assert!(state.storage.exists(&file.key()).await?);
+        assert_eq!(state.locate_file(&legacy, None).await?, Some(file.key()));
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use chat_core::Message;
+
+use crate::{AppError, AppState, ValidationIssue};
+
+impl AppState {
+    /// Block a user: they can't start single chats with the blocker anymore,
+    /// and their messages are flagged for the blocker
+    pub async fn block_user(&self, user_id: u64, blocked_id: u64) -> Result<(), AppError> {
+        if user_id == blocked_id {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "id",
+                "self",
+                "Users cannot block themselves",
+            )]));
+        }
+        if self.find_user_by_id(blocked_id as _).await?.is_none() {
+            return Err(AppError::NotFound(format!("User id {blocked_id}")));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_blocks (blocker_id, blocked_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(blocked_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn unblock_user(&self, user_id: u64, blocked_id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM user_blocks
+            WHERE blocker_id = $1 AND blocked_id = $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(blocked_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("Blocked user id {blocked_id}")));
+        }
+
+        Ok(())
+    }
+
+    pub async fn is_user_blocked(&self, user_id: u64, blocked_id: u64) -> Result<bool, AppError> {
+        let blocked = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_blocks
+                WHERE blocker_id = $1 AND blocked_id = $2
+            )
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(blocked_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(blocked)
+    }
+
+    /// Flag the messages whose sender the reader blocked
+    pub async fn flag_blocked_senders(
+        &self,
+        user_id: u64,
+        messages: &mut [Message],
+    ) -> Result<(), AppError> {
+        let blocked: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT blocked_id
+            FROM user_blocks
+            WHERE blocker_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for message in messages.iter_mut() {
+            message.sender_blocked = blocked.contains(&message.sender_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateChat, ListMessages};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_block_user_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let ret = state.block_user(1, 1).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+        let ret = state.block_user(1, 42).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.block_user(1, 4).await?;
+        // blocking twice is fine
+        state.block_user(1, 4).await?;
+        assert!(state.is_user_blocked(1, 4).await?);
+        assert!(!state.is_user_blocked(4, 1).await?);
+
+        // user 4 can't start a single chat with user 1 anymore
+        let single = CreateChat::new("", &[1, 4], false);
+        let ret = state.create_chat(single.clone(), 4, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 100,
+        };
+        let mut page = state.list_messages(input, 1).await?;
+        state.flag_blocked_senders(1, &mut page.items).await?;
+        for message in &page.items {
+            assert_eq!(message.sender_blocked, message.sender_id == 4);
+        }
+
+        state.unblock_user(1, 4).await?;
+        state.create_chat(single, 4, 1).await?;
+        let ret = state.unblock_user(1, 4).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+}
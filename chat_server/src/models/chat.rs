@@ -1,23 +1,66 @@
-use chat_core::{Chat, ChatType};
+use std::borrow::Cow;
+
+use chat_core::{begin_tagged, Chat, ChatType};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidateArgs, ValidationError};
 
-use crate::{AppError, AppState};
+use crate::{config::ChatConfig, AppConfig, AppError, AppState, ChatFile};
 
-#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+/// the name and the members are checked against `chat` in the config
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+#[validate(schema(
+    function = "validate_create_chat",
+    use_context,
+    skip_on_field_errors = false
+))]
 pub struct CreateChat {
+    #[validate(custom(function = "validate_chat_name", use_context))]
     pub name: Option<String>,
+    #[validate(custom(function = "validate_chat_members", use_context))]
     pub members: Vec<i64>,
     pub public: bool,
 }
 
-#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+/// the name and the members are checked against `chat` in the config
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+#[validate(schema(
+    function = "validate_update_chat",
+    use_context,
+    skip_on_field_errors = false
+))]
 pub struct UpdateChat {
     pub r#type: ChatType,
+    #[validate(custom(function = "validate_chat_name", use_context))]
     pub name: Option<String>,
+    #[validate(custom(function = "validate_chat_members", use_context))]
     pub members: Vec<i64>,
 }
 
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatExpand {
+    /// include the profiles of the members
+    Members,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListChats {
+    pub expand: Option<ChatExpand>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct AddChatMember {
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct TransferOwnership {
+    pub new_owner_id: i64,
+}
+
 #[allow(dead_code)]
 impl AppState {
     pub async fn create_chat(
@@ -26,31 +69,14 @@ impl AppState {
         user_id: u64,
         ws_id: u64,
     ) -> Result<Chat, AppError> {
+        self.ensure_email_verified(user_id).await?;
         let len = input.members.len();
-        if len < 2 {
-            return Err(AppError::CreateChatError(format!(
-                "Members must be at least 2, but got {}",
-                len
-            )));
-        }
         // if user id is not in members, reject
         if !input.members.contains(&(user_id as i64)) {
             return Err(AppError::CreateChatError(
                 "User must be in the chat members".to_string(),
             ));
         }
-        if let Some(name) = &input.name {
-            if name.len() < 3 {
-                return Err(AppError::CreateChatError(
-                    "Chat name must have at least 3 characters".to_string(),
-                ));
-            }
-        }
-        if len > 8 && input.name.is_none() {
-            return Err(AppError::CreateChatError(
-                "Group chat with more than 8 members must have a name".to_string(),
-            ));
-        }
 
         // verify if all members exist
         let users = self.fetch_chat_users_by_ids(&input.members).await?;
@@ -72,29 +98,141 @@ impl AppState {
             }
         };
 
+        // direct messages between the same two users reuse the existing chat
+        if chat_type == ChatType::Single {
+            if let Some(chat) = self.find_single_chat(ws_id, &input.members).await? {
+                return Ok(chat);
+            }
+            let other = input.members.iter().find(|id| **id != user_id as i64);
+            if let Some(&other) = other {
+                if self.is_user_blocked(other as _, user_id).await? {
+                    return Err(AppError::PermissionDenied(format!(
+                        "User {other} blocked user {user_id}"
+                    )));
+                }
+            }
+        }
+
+        // a concurrent request may have created the single chat meanwhile, the unique index keeps only one
+        let mut tx = begin_tagged(&self.pool).await?;
         let chat = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            INSERT INTO chats (ws_id, name, type, members, owner_id)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT DO NOTHING
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
             "#,
         )
         .bind(ws_id as i64)
         .bind(input.name)
-        .bind(chat_type)
-        .bind(input.members)
-        .fetch_one(&self.pool)
+        .bind(&chat_type)
+        .bind(&input.members)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None => self
+                .find_single_chat(ws_id, &input.members)
+                .await?
+                .ok_or_else(|| AppError::CreateChatError("Failed to create chat".to_string())),
+        }
+    }
+
+    /// The single chat between the two members in the workspace, in any order
+    pub async fn find_single_chat(
+        &self,
+        ws_id: u64,
+        members: &[i64],
+    ) -> Result<Option<Chat>, AppError> {
+        let [a, b] = members else {
+            return Ok(None);
+        };
+
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            FROM chats
+            WHERE ws_id = $1 AND type = 'single'
+                AND LEAST(members[1], members[2]) = LEAST($2, $3)
+                AND GREATEST(members[1], members[2]) = GREATEST($2, $3)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(a)
+        .bind(b)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(chat)
     }
 
-    pub async fn fetch_chats(&self, user_id: u64, ws_id: u64) -> Result<Vec<Chat>, AppError> {
+    /// Chats of the user with the latest message preview, most recently active first
+    pub async fn fetch_chats(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+        input: ListChats,
+    ) -> Result<Vec<Chat>, AppError> {
+        let expand_members = input.expand == Some(ChatExpand::Members);
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT c.id, c.ws_id, c.name, c.avatar_url, c.type, c.members, c.owner_id, c.created_at,
+                lm.content AS last_message, lm.created_at AS last_message_at,
+                CASE WHEN $3 THEN COALESCE((
+                    SELECT json_agg(
+                        json_build_object('id', u.id, 'fullName', u.full_name, 'email', u.email, 'avatarUrl', u.avatar_url, 'status', active_user_status(u.status))
+                        ORDER BY array_position(c.members, u.id)
+                    )
+                    FROM users u
+                    WHERE u.id = ANY(c.members)
+                ), '[]') ELSE 'null' END AS member_details
+            FROM chats c
+            LEFT JOIN LATERAL (
+                SELECT left(m.content, 100) AS content, m.created_at
+                FROM messages m
+                WHERE m.chat_id = c.id AND m.parent_id IS NULL AND m.deleted_at IS NULL
+                ORDER BY m.created_at DESC
+                LIMIT 1
+            ) lm ON true
+            WHERE c.ws_id = $1 and $2 = ANY(c.members)
+            ORDER BY COALESCE(lm.created_at, c.created_at) DESC, c.id DESC
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(expand_members)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// The chat if the user owns it, used to guard the admin operations
+    pub async fn get_owned_chat(&self, chat_id: u64, user_id: u64) -> Result<Chat, AppError> {
+        match self.get_chat_by_id(chat_id).await? {
+            Some(chat) if chat.owner_id == user_id as i64 => Ok(chat),
+            Some(_) => Err(AppError::PermissionDenied(format!(
+                "User {user_id} is not the owner of chat {chat_id}"
+            ))),
+            None => Err(AppError::NotFound(format!("Chat id {chat_id}"))),
+        }
+    }
+
+    /// Public channels of the workspace that the user hasn't joined yet
+    pub async fn fetch_public_chats(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, avatar_url, type, members, owner_id, created_at
             FROM chats
-            WHERE ws_id = $1 and $2 = ANY(members)
+            WHERE ws_id = $1 AND type = 'public_channel' AND NOT ($2 = ANY(members))
+            ORDER BY id
             "#,
         )
         .bind(ws_id as i64)
@@ -108,7 +246,7 @@ impl AppState {
     pub async fn get_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, avatar_url, type, members, owner_id, created_at
             FROM chats
             WHERE id = $1
             "#,
@@ -136,21 +274,25 @@ impl AppState {
         Ok(is_member.is_some())
     }
 
+    pub async fn is_public_chat(&self, chat_id: u64, ws_id: u64) -> Result<bool, AppError> {
+        let is_public = sqlx::query(
+            r#"
+            SELECT 1
+            FROM chats
+            WHERE id = $1 AND ws_id = $2 AND type = 'public_channel'
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(is_public.is_some())
+    }
+
     pub async fn update_chat_by_id(&self, id: u64, input: UpdateChat) -> Result<Chat, AppError> {
         let len = input.members.len();
 
-        if len < 2 {
-            return Err(AppError::UpdateChatError(format!(
-                "Members must be at least 2, but got {}",
-                len
-            )));
-        }
-        if len > 8 && input.name.is_none() {
-            return Err(AppError::UpdateChatError(
-                "Group chat with more than 8 members must have a name".to_string(),
-            ));
-        }
-
         if input.r#type == ChatType::Single && input.members.len() != 2 {
             return Err(AppError::UpdateChatError(
                 "Chat type cannot be changed for [single] with {len} members (must 2)".to_string(),
@@ -165,25 +307,292 @@ impl AppState {
             ));
         }
 
+        if input.r#type == ChatType::Single {
+            let Some(chat) = self.get_chat_by_id(id).await? else {
+                return Err(AppError::NotFound(format!("Chat id {id}")));
+            };
+            let existing = self
+                .find_single_chat(chat.ws_id as _, &input.members)
+                .await?;
+            if existing.is_some_and(|existing| existing.id != chat.id) {
+                return Err(AppError::UpdateChatError(
+                    "A single chat between these members already exists".to_string(),
+                ));
+            }
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
         let chat = sqlx::query_as(
             r#"
             UPDATE chats
             SET type = $1, name = $2, members = $3
             WHERE id = $4
-            RETURNING id, ws_id, name, type, members, created_at
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
             "#,
         )
         .bind(input.r#type)
         .bind(input.name)
         .bind(input.members)
         .bind(id as i64)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Add a single member to the chat, the array is changed in place so concurrent changes are not lost
+    pub async fn add_chat_member(
+        &self,
+        chat_id: u64,
+        member_id: u64,
+        ws_id: u64,
+    ) -> Result<Chat, AppError> {
+        // only users of the same workspace can join the chat
+        if !self.is_workspace_member(ws_id, member_id).await? {
+            return Err(AppError::NotFound(format!("User id {member_id}")));
+        }
+
+        // the chat rules are checked against the members at the time of the update
+        let app_config = self.config();
+        let config = &app_config.chat;
+        let max_members = match config.max_members {
+            0 => i32::MAX,
+            v => v as i32,
+        };
+        let mut tx = begin_tagged(&self.pool).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_append(members, $2)
+            WHERE id = $1 AND type != 'single' AND NOT ($2 = ANY(members))
+                AND cardinality(members) < $3
+                AND (name IS NOT NULL OR cardinality(members) < $4)
+                AND NOT EXISTS (SELECT 1 FROM chat_bans WHERE chat_id = $1 AND user_id = $2)
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .bind(max_members)
+        .bind(config.unnamed_max_members as i32)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None => match self.get_chat_by_id(chat_id).await? {
+                Some(chat) if chat.r#type == ChatType::Single => Err(AppError::UpdateChatError(
+                    "Cannot add members to a single chat".to_string(),
+                )),
+                Some(chat) if chat.members.contains(&(member_id as i64)) => {
+                    Err(AppError::UpdateChatError(format!(
+                        "User {member_id} is already a member of chat {chat_id}"
+                    )))
+                }
+                Some(chat) => {
+                    if self.is_chat_banned(chat_id, member_id).await? {
+                        return Err(AppError::PermissionDenied(format!(
+                            "User {member_id} is banned from chat {chat_id}"
+                        )));
+                    }
+                    let mut members = chat.members;
+                    members.push(member_id as i64);
+                    let input = CreateChat {
+                        name: chat.name,
+                        members,
+                        public: false,
+                    };
+                    input.validate_with_args(&app_config)?;
+                    Err(AppError::UpdateChatError(format!(
+                        "Failed to add user {member_id} to chat {chat_id}"
+                    )))
+                }
+                None => Err(AppError::NotFound(format!("Chat id {chat_id}"))),
+            },
+        }
+    }
+
+    /// Join a public channel of my workspace by myself
+    pub async fn join_chat(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Chat, AppError> {
+        if !self.is_public_chat(chat_id, ws_id).await? {
+            return Err(AppError::NotFound(format!("Public channel id {chat_id}")));
+        }
+        self.add_chat_member(chat_id, user_id, ws_id).await
+    }
+
+    /// Remove a single member from the chat, only the chat owner can do it and the owner cannot be removed
+    pub async fn remove_chat_member(
+        &self,
+        chat_id: u64,
+        member_id: u64,
+        user_id: u64,
+    ) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("Chat id {chat_id}")));
+        };
+        if chat.owner_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} is not the owner of chat {chat_id}"
+            )));
+        }
+        if chat.owner_id == member_id as i64 {
+            return Err(AppError::UpdateChatError(
+                "The chat owner cannot be removed".to_string(),
+            ));
+        }
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot remove members from a single chat".to_string(),
+            ));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2)
+            WHERE id = $1 AND $2 = ANY(members) AND cardinality(members) > 2
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None if self.is_chat_member(chat_id, member_id).await? => Err(
+                AppError::UpdateChatError("Chat must keep at least 2 members".to_string()),
+            ),
+            None => Err(AppError::NotFound(format!(
+                "User {member_id} in chat {chat_id}"
+            ))),
+        }
+    }
+
+    /// Remove myself from a group or channel, the owner has to transfer the ownership first
+    pub async fn leave_chat(&self, chat_id: u64, user_id: u64) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("Chat id {chat_id}")));
+        };
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::UpdateChatError(
+                "Cannot leave a single chat".to_string(),
+            ));
+        }
+        if chat.owner_id == user_id as i64 {
+            return Err(AppError::UpdateChatError(
+                "The chat owner must transfer the ownership before leaving".to_string(),
+            ));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2)
+            WHERE id = $1 AND $2 = ANY(members)
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        chat.ok_or_else(|| AppError::NotFound(format!("User {user_id} in chat {chat_id}")))
+    }
+
+    /// Hand the chat over to another member, only the current owner can do it
+    pub async fn transfer_chat_ownership(
+        &self,
+        chat_id: u64,
+        new_owner_id: u64,
+        user_id: u64,
+    ) -> Result<Chat, AppError> {
+        // owner and membership are checked in the same statement, so a concurrent change can't slip in
+        let mut tx = begin_tagged(&self.pool).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET owner_id = $3
+            WHERE id = $1 AND owner_id = $2 AND $3 = ANY(members)
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(new_owner_id as i64)
+        .fetch_optional(&mut *tx)
         .await?;
+        tx.commit().await?;
+
+        match chat {
+            Some(chat) => Ok(chat),
+            None => match self.get_chat_by_id(chat_id).await? {
+                None => Err(AppError::NotFound(format!("Chat id {chat_id}"))),
+                Some(chat) if chat.owner_id != user_id as i64 => Err(AppError::PermissionDenied(
+                    format!("User {user_id} is not the owner of chat {chat_id}"),
+                )),
+                Some(_) => Err(AppError::UpdateChatError(format!(
+                    "User {new_owner_id} is not a member of chat {chat_id}"
+                ))),
+            },
+        }
+    }
+
+    /// Set the avatar of the chat to an uploaded file, only the chat owner can do it
+    pub async fn update_chat_avatar(
+        &self,
+        chat_id: u64,
+        url: &str,
+        user_id: u64,
+    ) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("Chat id {chat_id}")));
+        };
+        if chat.owner_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} is not the owner of chat {chat_id}"
+            )));
+        }
+        let file: ChatFile = url.parse()?;
+        if file.ws_id != chat.ws_id as u64 {
+            return Err(AppError::ChatFileError(format!(
+                "File {url} doesn't belong to the chat's workspace"
+            )));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET avatar_url = $2
+            WHERE id = $1
+            RETURNING id, ws_id, name, avatar_url, type, members, owner_id, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(url)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
 
     pub async fn delete_chat_by_id(&self, id: u64) -> Result<(), AppError> {
+        let mut tx = begin_tagged(&self.pool).await?;
         sqlx::query(
             r#"
             DELETE FROM chats
@@ -191,13 +600,70 @@ impl AppState {
             "#,
         )
         .bind(id as i64)
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(())
     }
 }
 
+fn validate_chat_name(name: &str, config: &AppConfig) -> Result<(), ValidationError> {
+    let ChatConfig {
+        name_min_length,
+        name_max_length,
+        ..
+    } = config.chat;
+    let len = name.chars().count();
+    if len < name_min_length {
+        let message = format!("name must have at least {name_min_length} characters");
+        return Err(ValidationError::new("too_short").with_message(Cow::Owned(message)));
+    }
+    if len > name_max_length {
+        let message = format!("name must have at most {name_max_length} characters");
+        return Err(ValidationError::new("too_long").with_message(Cow::Owned(message)));
+    }
+    Ok(())
+}
+
+fn validate_chat_members(members: &[i64], config: &AppConfig) -> Result<(), ValidationError> {
+    let len = members.len();
+    if len < 2 {
+        let message = format!("members must be at least 2, but got {len}");
+        return Err(ValidationError::new("too_few").with_message(Cow::Owned(message)));
+    }
+    let max_members = config.chat.max_members;
+    if max_members > 0 && len > max_members {
+        let message = format!("members must be at most {max_members}, but got {len}");
+        return Err(ValidationError::new("too_many").with_message(Cow::Owned(message)));
+    }
+    Ok(())
+}
+
+fn validate_create_chat(input: &CreateChat, config: &AppConfig) -> Result<(), ValidationError> {
+    validate_chat_named(input.name.as_deref(), input.members.len(), config)
+}
+
+fn validate_update_chat(input: &UpdateChat, config: &AppConfig) -> Result<(), ValidationError> {
+    validate_chat_named(input.name.as_deref(), input.members.len(), config)
+}
+
+/// A chat with many members must have a name
+fn validate_chat_named(
+    name: Option<&str>,
+    members: usize,
+    config: &AppConfig,
+) -> Result<(), ValidationError> {
+    let unnamed_max_members = config.chat.unnamed_max_members;
+    if name.is_none() && members > unnamed_max_members {
+        let message = format!("chat with more than {unnamed_max_members} members must have a name");
+        let mut e = ValidationError::new("required").with_message(Cow::Owned(message));
+        e.add_param(Cow::Borrowed("field"), &"name");
+        return Err(e);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 impl CreateChat {
     pub fn new(name: &str, members: &[i64], public: bool) -> Self {
@@ -234,8 +700,47 @@ impl UpdateChat {
 mod tests {
 
     use super::*;
+    use crate::CreateMessage;
     use anyhow::Result;
 
+    #[tokio::test]
+    async fn chat_rules_should_follow_config() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        state.update_config(|config| {
+            config.chat = ChatConfig {
+                max_members: 10,
+                unnamed_max_members: 3,
+                name_min_length: 3,
+                name_max_length: 5,
+            }
+        });
+        let config = state.config();
+        let input = |name: &str, members: usize| {
+            let members: Vec<i64> = (1..=members as i64).collect();
+            CreateChat::new(name, &members, false)
+        };
+        assert!(input("", 3).validate_with_args(&config).is_ok());
+        assert!(input("abc", 10).validate_with_args(&config).is_ok());
+
+        let codes = |name, members| {
+            let Err(AppError::ValidationError(issues)) = input(name, members)
+                .validate_with_args(&config)
+                .map_err(AppError::from)
+            else {
+                panic!("expect validation error");
+            };
+            issues
+                .into_iter()
+                .map(|v| format!("{}.{}", v.field, v.code))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(codes("", 1), vec!["members.too_few"]);
+        assert_eq!(codes("", 4), vec!["name.required"]);
+        assert_eq!(codes("ab", 11), vec!["members.too_many", "name.too_short"]);
+        assert_eq!(codes("abcdef", 2), vec!["name.too_long"]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_create_single_chat_should_work() -> Result<()> {
         let (_tdb, state) = AppState::try_new_for_test().await?;
@@ -248,11 +753,35 @@ mod tests {
 
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 2);
+        assert_eq!(chat.owner_id, 1);
         assert_eq!(chat.r#type, ChatType::Single);
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_create_single_chat_should_reuse_existing() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // chat 3 is the single chat between 1 and 2
+        let input = CreateChat::new("", &[2, 1], false);
+        let chat = state.create_chat(input, 1, 1).await?;
+        assert_eq!(chat.id, 3);
+
+        let input = CreateChat::new("", &[1, 4], false);
+        let chat1 = state.create_chat(input, 1, 1).await?;
+        let input = CreateChat::new("", &[4, 1], false);
+        let chat2 = state.create_chat(input, 4, 1).await?;
+        assert_eq!(chat1.id, chat2.id);
+
+        // can't turn another chat into a duplicate
+        let update = UpdateChat::new(ChatType::Single, "", &[1, 2]);
+        let err = state.update_chat_by_id(4, update).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_create_public_named_chat_should_work() -> Result<()> {
         let (_tdb, state) = AppState::try_new_for_test().await?;
@@ -293,12 +822,54 @@ mod tests {
         let (_tdb, state) = AppState::try_new_for_test().await?;
 
         let chats = state
-            .fetch_chats(1, 1)
+            .fetch_chats(1, 1, ListChats::default())
             .await
             .expect("Failed to fetch all chats");
 
         assert_eq!(chats.len(), 4);
 
+        let input = CreateMessage {
+            content: "latest".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        state.create_message(input, 2, 1).await?;
+
+        // the chat with the newest message comes first
+        let chats = state.fetch_chats(1, 1, ListChats::default()).await?;
+        assert_eq!(chats[0].id, 2);
+        assert_eq!(chats[0].last_message.as_deref(), Some("latest"));
+        assert!(chats[0].last_message_at.is_some());
+        let chat = chats
+            .iter()
+            .find(|c| c.id == 3)
+            .expect("chat 3 should exist");
+        assert!(chat.last_message.is_none());
+        assert!(chat.member_details.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_chats_with_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = ListChats {
+            expand: Some(ChatExpand::Members),
+        };
+        let chats = state.fetch_chats(1, 1, input).await?;
+        assert_eq!(chats.len(), 4);
+
+        let chat = chats
+            .iter()
+            .find(|c| c.id == 4)
+            .expect("chat 4 should exist");
+        let members = chat.member_details.as_ref().expect("members should be set");
+        let ids: Vec<_> = members.iter().map(|u| u.id).collect();
+        assert_eq!(ids, chat.members);
+        assert_eq!(members[0].full_name, "Tyr Chen");
+
         Ok(())
     }
 
@@ -350,6 +921,136 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_chat_add_member_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // private channel [1, 2, 3]
+        let chat = state.add_chat_member(2, 4, 1).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+
+        let err = state.add_chat_member(2, 4, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        // single chat can't grow
+        let err = state.add_chat_member(3, 3, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        // user 6 doesn't exist
+        let err = state.add_chat_member(2, 6, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_remove_member_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // group [1, 3, 4] owned by 1
+        let err = state.remove_chat_member(4, 4, 3).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let err = state.remove_chat_member(4, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        let chat = state.remove_chat_member(4, 4, 1).await?;
+        assert_eq!(chat.members, vec![1, 3]);
+
+        let err = state.remove_chat_member(4, 4, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // at least 2 members are kept
+        let err = state.remove_chat_member(4, 3, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_leave_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let chat = state.leave_chat(2, 3).await?;
+        assert_eq!(chat.members, vec![1, 2]);
+        assert!(!state.is_chat_member(2, 3).await?);
+
+        let err = state.leave_chat(2, 3).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // owner can't leave
+        let err = state.leave_chat(2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        // single chat can't be left
+        let err = state.leave_chat(3, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_update_avatar_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let url = ChatFile::new(1, "avatar.png", b"avatar").url();
+        let chat = state.update_chat_avatar(1, &url, 1).await?;
+        assert_eq!(chat.avatar_url.as_deref(), Some(url.as_str()));
+
+        // only the owner can change it
+        let err = state.update_chat_avatar(1, &url, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // file of another workspace
+        let url = ChatFile::new(2, "avatar.png", b"avatar").url();
+        let err = state.update_chat_avatar(1, &url, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::ChatFileError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_public_chats_should_be_listed_and_joined() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreateChat::new("random", &[1, 2], true);
+        let chat = state.create_chat(input, 1, 1).await?;
+
+        let chats = state.fetch_public_chats(3, 1).await?;
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].id, chat.id);
+        assert!(state.fetch_public_chats(1, 1).await?.is_empty());
+
+        let joined = state.join_chat(chat.id as _, 3, 1).await?;
+        assert_eq!(joined.members, vec![1, 2, 3]);
+        assert!(state.fetch_public_chats(3, 1).await?.is_empty());
+
+        // private channel can't be joined
+        let err = state.join_chat(2, 4, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_transfer_ownership_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // user 4 is not a member of chat 2
+        let err = state.transfer_chat_ownership(2, 4, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        let chat = state.transfer_chat_ownership(2, 3, 1).await?;
+        assert_eq!(chat.owner_id, 3);
+
+        // the previous owner can't do it anymore, but can leave now
+        let err = state.transfer_chat_ownership(2, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        state.leave_chat(2, 1).await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_chat_is_member_should_work() -> Result<()> {
         let (_tdb, state) = AppState::try_new_for_test().await?;
@@ -0,0 +1,157 @@
+use std::{borrow::Cow, sync::LazyLock};
+
+use chat_core::Message;
+use pulldown_cmark::{html, Options, Parser};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::ValidationError;
+
+use crate::{config::MessageConfig, AppConfig, AppError, ValidationIssue};
+
+static UNSAFE_BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<(script|style|iframe|object|embed)\b[^>]*>.*?</(script|style|iframe|object|embed)\s*>")
+        .expect("valid regex")
+});
+static UNSAFE_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)</?(script|style|iframe|object|embed)\b[^>]*>").expect("valid regex")
+});
+static UNSAFE_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\]\(\s*(javascript|vbscript|data):(?:[^()\s]|\([^()]*\))*\)")
+        .expect("valid regex")
+});
+static MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(^|[^\w@])[@＠]([\w.-]+)").expect("valid regex"));
+
+/// How message content is returned, `html` adds the rendered-safe html of the markdown
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Markdown,
+    Html,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct RenderOptions {
+    /// `markdown` (default) or `html`
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
+impl RenderOptions {
+    /// Fill in the html of the messages if the client asked for it
+    pub fn render(&self, messages: &mut [Message]) {
+        if self.format != MessageFormat::Html {
+            return;
+        }
+        for message in messages.iter_mut() {
+            if message.deleted_at.is_none() {
+                message.html = Some(render_html(&message.content));
+            }
+        }
+    }
+}
+
+/// The rule of the content of a message, not blank and at most `message.max_length` characters
+pub(crate) fn validate_content(content: &str, config: &AppConfig) -> Result<(), ValidationError> {
+    if content.trim().is_empty() {
+        return Err(ValidationError::new("empty").with_message(Cow::Borrowed("content is empty")));
+    }
+    let max_length = config.message.max_length;
+    let len = content.chars().count();
+    if max_length > 0 && len > max_length {
+        let message = format!("content is {len} characters, at most {max_length} allowed");
+        return Err(ValidationError::new("too_long").with_message(Cow::Owned(message)));
+    }
+    Ok(())
+}
+
+/// Sanitize the markdown content of a message before it is stored.
+///
+/// Scripts and other active html are stripped and mentions are normalized, the length is
+/// checked by `validate_content` beforehand.
+pub(crate) fn process_content(content: &str, config: &MessageConfig) -> Result<String, AppError> {
+    let content = if config.sanitize {
+        sanitize_markdown(content)
+    } else {
+        content.to_string()
+    };
+    if content.trim().is_empty() {
+        return Err(AppError::ValidationError(vec![ValidationIssue::new(
+            "content",
+            "empty",
+            "content is empty after removing unsafe markup",
+        )]));
+    }
+    Ok(content)
+}
+
+/// Render markdown to html that is safe to be inserted into a page
+pub fn render_html(content: &str) -> String {
+    let parser = Parser::new_ext(
+        content,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    );
+    let mut output = String::new();
+    html::push_html(&mut output, parser);
+    ammonia::clean(&output)
+}
+
+fn sanitize_markdown(content: &str) -> String {
+    let content = content.replace("\r\n", "\n");
+    let content = UNSAFE_BLOCK.replace_all(&content, "");
+    let content = UNSAFE_TAG.replace_all(&content, "");
+    let content = UNSAFE_LINK.replace_all(&content, "](#)");
+    let content = MENTION.replace_all(&content, |cap: &regex::Captures| {
+        format!("{}@{}", &cap[1], cap[2].to_lowercase())
+    });
+    content.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_content_should_sanitize_markdown() {
+        let config = MessageConfig::default();
+        let content = "hi ＠Alice and @BOB.smith\r\n<script>alert(1)</script>**bold** \
+            [click](javascript:alert(1)) <iframe src=\"x\">";
+        let ret = process_content(content, &config).unwrap();
+        assert_eq!(ret, "hi @alice and @bob.smith\n**bold** [click](#)");
+    }
+
+    #[test]
+    fn process_content_should_return_validation_errors() {
+        let config = MessageConfig::default();
+        let err = process_content("<script>alert('hello world')</script>", &config).unwrap_err();
+        let AppError::ValidationError(issues) = err else {
+            panic!("expect validation error");
+        };
+        let codes: Vec<_> = issues.iter().map(|v| v.code.as_str()).collect();
+        assert_eq!(codes, vec!["empty"]);
+    }
+
+    #[tokio::test]
+    async fn validate_content_should_follow_config() -> anyhow::Result<()> {
+        let (_tdb, state) = crate::AppState::try_new_for_test().await?;
+        state.update_config(|config| config.message.max_length = 10);
+        let config = state.config();
+        assert!(validate_content("hello", &config).is_ok());
+
+        let code = |content| validate_content(content, &config).unwrap_err().code;
+        assert_eq!(code(" \n"), "empty");
+        assert_eq!(code("hello world"), "too_long");
+        Ok(())
+    }
+
+    #[test]
+    fn render_html_should_be_safe() {
+        let html = render_html("**hi** <img src=x onerror=alert(1)> [a](javascript:alert(1))");
+        assert_eq!(
+            html,
+            "<p><strong>hi</strong> <img src=\"x\"> <a rel=\"noopener noreferrer\">a</a></p>\n"
+        );
+    }
+}
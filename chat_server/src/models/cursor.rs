@@ -0,0 +1,165 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac_sha256::HMAC;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// keeps cursors signed for message listing from being valid anywhere else
+const CURSOR_DOMAIN: &[u8] = b"chat_server:cursor:";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PageDirection {
+    Older,
+    Newer,
+}
+
+/// Position in a listing sorted by a descending key, only handed out signed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Cursor {
+    #[serde(rename = "k")]
+    pub key: i64,
+    #[serde(rename = "d")]
+    pub direction: PageDirection,
+}
+
+/// One page of a listing, newest first
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor of the next page of older items, missing when there are none
+    pub older: Option<String>,
+    /// Cursor of the page of newer items, the page may be empty until new items arrive
+    pub newer: Option<String>,
+}
+
+impl Cursor {
+    /// The first page, starting from the newest item
+    pub fn latest() -> Self {
+        Self {
+            key: i64::MAX,
+            direction: PageDirection::Older,
+        }
+    }
+
+    /// SQL comparison operator and sort order of the page
+    pub fn sql(&self) -> (&'static str, &'static str) {
+        match self.direction {
+            PageDirection::Older => ("<", "DESC"),
+            PageDirection::Newer => (">", "ASC"),
+        }
+    }
+}
+
+impl AppState {
+    pub(crate) fn encode_cursor(&self, cursor: Cursor) -> String {
+        let payload = serde_json::to_vec(&cursor).expect("cursor should serialize");
+        let tag = self.cursor_tag(&payload);
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(tag)
+        )
+    }
+
+    /// Decode and verify a cursor token, no token means the latest page
+    pub(crate) fn decode_cursor(&self, token: Option<&str>) -> Result<Cursor, AppError> {
+        let Some(token) = token else {
+            return Ok(Cursor::latest());
+        };
+        let invalid = || AppError::InvalidCursor(token.to_string());
+
+        let (payload, tag) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| invalid())?;
+        if !constant_time_eq(&tag, &self.cursor_tag(&payload)) {
+            return Err(invalid());
+        }
+
+        serde_json::from_slice(&payload).map_err(|_| invalid())
+    }
+
+    /// Turn `limit + 1` rows fetched in the order of `cursor` into a newest first page
+    pub(crate) fn paginate<T>(
+        &self,
+        mut items: Vec<T>,
+        cursor: Cursor,
+        limit: i64,
+        key: impl Fn(&T) -> i64,
+    ) -> Page<T> {
+        let has_more = items.len() as i64 > limit;
+        items.truncate(limit as usize);
+        if cursor.direction == PageDirection::Newer {
+            items.reverse();
+        }
+
+        let (Some(first), Some(last)) = (items.first(), items.last()) else {
+            // keep polling from the same position until newer items arrive
+            let newer =
+                (cursor.direction == PageDirection::Newer).then(|| self.encode_cursor(cursor));
+            return Page {
+                items,
+                older: None,
+                newer,
+            };
+        };
+
+        let older = (has_more || cursor.direction == PageDirection::Newer).then(|| {
+            self.encode_cursor(Cursor {
+                key: key(last),
+                direction: PageDirection::Older,
+            })
+        });
+        let newer = Some(self.encode_cursor(Cursor {
+            key: key(first),
+            direction: PageDirection::Newer,
+        }));
+
+        Page {
+            items,
+            older,
+            newer,
+        }
+    }
+
+    fn cursor_tag(&self, payload: &[u8]) -> [u8; 32] {
+        let mut mac = HMAC::new(self.config().auth.sk.as_bytes());
+        mac.update(CURSOR_DOMAIN);
+        mac.update(payload);
+        mac.finalize()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn cursor_should_round_trip_and_reject_tampering() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let cursor = Cursor {
+            key: 42,
+            direction: PageDirection::Newer,
+        };
+        let token = state.encode_cursor(cursor);
+        assert_eq!(state.decode_cursor(Some(&token))?, cursor);
+        assert_eq!(state.decode_cursor(None)?, Cursor::latest());
+
+        // a fabricated cursor is rejected
+        let payload = URL_SAFE_NO_PAD.encode(br#"{"k":1,"d":"older"}"#);
+        let (_, tag) = token.split_once('.').expect("token should have a tag");
+        let forged = format!("{}.{}", payload, tag);
+        let err = state.decode_cursor(Some(&forged)).unwrap_err();
+        assert!(matches!(err, AppError::InvalidCursor(_)));
+        assert!(state.decode_cursor(Some("42")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use chat_core::{begin_tagged, DeliveryState};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// not yet delivered to every other member
+    Sent,
+    /// delivered to every other member
+    Delivered,
+    /// read by every other member
+    Read,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct MessageStatus {
+    pub message_id: i64,
+    pub status: DeliveryStatus,
+    /// members (other than the sender) the message was delivered to, including readers
+    pub delivered_to: Vec<i64>,
+    /// members (other than the sender) who have read the message
+    pub read_by: Vec<i64>,
+}
+
+#[derive(Debug, FromRow)]
+struct MemberStatus {
+    user_id: i64,
+    delivered: bool,
+    read: bool,
+}
+
+impl AppState {
+    /// Ack delivery of messages of the chat up to and including `message_id`,
+    /// the delivery marker never moves backwards
+    pub async fn ack_message_delivery(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<DeliveryState, AppError> {
+        if self.get_message_by_id(chat_id, message_id).await?.is_none() {
+            return Err(AppError::NotFound(format!("Message id {message_id}")));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let state = sqlx::query_as(
+            r#"
+            INSERT INTO message_status (chat_id, user_id, last_delivered_message_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_delivered_message_id = GREATEST(message_status.last_delivered_message_id, EXCLUDED.last_delivered_message_id),
+                delivered_at = CURRENT_TIMESTAMP
+            RETURNING chat_id, user_id, last_delivered_message_id, delivered_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(message_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(state)
+    }
+
+    /// Aggregate delivery status of a message, only visible to its sender
+    pub async fn get_message_status(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<MessageStatus, AppError> {
+        let message = match self.get_message_by_id(chat_id, message_id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Err(AppError::NotFound(format!("Message id {message_id}"))),
+        };
+        if message.sender_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not the sender of message {}",
+                user_id, message_id
+            )));
+        }
+
+        // reading a message implies it was delivered
+        let members: Vec<MemberStatus> = sqlx::query_as(
+            r#"
+            SELECT u.user_id,
+                GREATEST(COALESCE(ms.last_delivered_message_id, 0), COALESCE(cm.last_read_message_id, 0)) >= $2 AS delivered,
+                COALESCE(cm.last_read_message_id, 0) >= $2 AS read
+            FROM chats c
+            CROSS JOIN LATERAL unnest(c.members) AS u(user_id)
+            LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = u.user_id
+            LEFT JOIN message_status ms ON ms.chat_id = c.id AND ms.user_id = u.user_id
+            WHERE c.id = $1 AND u.user_id <> $3
+            ORDER BY u.user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let delivered_to: Vec<i64> = members
+            .iter()
+            .filter(|m| m.delivered)
+            .map(|m| m.user_id)
+            .collect();
+        let read_by: Vec<i64> = members
+            .iter()
+            .filter(|m| m.read)
+            .map(|m| m.user_id)
+            .collect();
+        let status = match members.len() {
+            0 => DeliveryStatus::Sent,
+            n if read_by.len() == n => DeliveryStatus::Read,
+            n if delivered_to.len() == n => DeliveryStatus::Delivered,
+            _ => DeliveryStatus::Sent,
+        };
+
+        Ok(MessageStatus {
+            message_id: message.id,
+            status,
+            delivered_to,
+            read_by,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarkRead;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_message_status_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // chat 1 has members 1 to 5, message 1 is sent by user 1
+        let status = state.get_message_status(1, 1, 1).await?;
+        assert_eq!(status.status, DeliveryStatus::Sent);
+        assert!(status.delivered_to.is_empty());
+
+        let ack = state.ack_message_delivery(1, 3, 2).await?;
+        assert_eq!(ack.last_delivered_message_id, 3);
+        // acking an older message never moves the marker backwards
+        let ack = state.ack_message_delivery(1, 2, 2).await?;
+        assert_eq!(ack.last_delivered_message_id, 3);
+
+        state
+            .mark_chat_read(MarkRead { message_id: 1 }, 1, 3)
+            .await?;
+        let status = state.get_message_status(1, 1, 1).await?;
+        assert_eq!(status.status, DeliveryStatus::Sent);
+        assert_eq!(status.delivered_to, vec![2, 3]);
+        assert_eq!(status.read_by, vec![3]);
+
+        for user_id in [4, 5] {
+            state.ack_message_delivery(1, 1, user_id).await?;
+        }
+        let status = state.get_message_status(1, 1, 1).await?;
+        assert_eq!(status.status, DeliveryStatus::Delivered);
+
+        for user_id in [2, 4, 5] {
+            state
+                .mark_chat_read(MarkRead { message_id: 1 }, 1, user_id)
+                .await?;
+        }
+        let status = state.get_message_status(1, 1, 1).await?;
+        assert_eq!(status.status, DeliveryStatus::Read);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_status_should_only_be_visible_to_sender() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let err = state.get_message_status(1, 1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let err = state.ack_message_delivery(1, 1000, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        Ok(())
+    }
+}
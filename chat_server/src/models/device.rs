@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState, ValidationIssue};
+
+/// WebPush subscriptions are JSON documents, they're longer than the native tokens. The
+/// tokens are unique, a btree index entry can't take much more than 2kB
+const MAX_DEVICE_TOKEN_LEN: usize = 2048;
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "device_platform", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePlatform {
+    Apns,
+    Fcm,
+    Webpush,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: i64,
+    pub user_id: i64,
+    pub platform: DevicePlatform,
+    pub token: String,
+    pub name: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// when the device was last registered, devices not seen for a while are pruned
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct RegisterDevice {
+    pub platform: DevicePlatform,
+    /// APNs device token, FCM registration token or WebPush subscription
+    pub token: String,
+    /// Name of the device as shown to the user, e.g. "Alice's iPhone"
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl AppState {
+    /// Register the push token of a device of the user, registering it again marks the
+    /// device as seen. A token registered by another user is moved to this one.
+    pub async fn register_device(
+        &self,
+        user_id: u64,
+        input: &RegisterDevice,
+    ) -> Result<Device, AppError> {
+        let token = input.token.trim();
+        let name = input
+            .name
+            .as_deref()
+            .map(str::trim)
+            .filter(|n| !n.is_empty());
+        let mut issues = vec![];
+        if token.is_empty() || token.len() > MAX_DEVICE_TOKEN_LEN {
+            issues.push(ValidationIssue::new(
+                "token",
+                "length",
+                format!("Token must be 1 to {MAX_DEVICE_TOKEN_LEN} bytes"),
+            ));
+        }
+        if name.is_some_and(|n| n.chars().count() > MAX_DEVICE_NAME_LEN) {
+            issues.push(ValidationIssue::new(
+                "name",
+                "length",
+                format!("Name must be at most {MAX_DEVICE_NAME_LEN} characters"),
+            ));
+        }
+        if !issues.is_empty() {
+            return Err(AppError::ValidationError(issues));
+        }
+
+        let device = sqlx::query_as(
+            r#"
+            INSERT INTO devices (user_id, platform, token, name)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (platform, token) DO UPDATE
+            SET user_id = EXCLUDED.user_id, name = EXCLUDED.name, last_seen_at = NOW()
+            RETURNING id, user_id, platform, token, name, created_at, last_seen_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.platform)
+        .bind(token)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(device)
+    }
+
+    /// Devices of the user, most recently seen first
+    pub async fn list_devices(&self, user_id: u64) -> Result<Vec<Device>, AppError> {
+        let devices = sqlx::query_as(
+            r#"
+            SELECT id, user_id, platform, token, name, created_at, last_seen_at
+            FROM devices
+            WHERE user_id = $1
+            ORDER BY last_seen_at DESC
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(devices)
+    }
+
+    pub async fn unregister_device(&self, user_id: u64, id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM devices WHERE id = $1 AND user_id = $2")
+            .bind(id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("Device id {id}")));
+        }
+
+        Ok(())
+    }
+
+    /// Drop the devices not seen since the cutoff, their tokens are most likely expired
+    pub async fn prune_stale_devices(&self, cutoff: DateTime<Utc>) -> Result<u64, AppError> {
+        let ret = sqlx::query("DELETE FROM devices WHERE last_seen_at < $1")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(ret.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rand::distributions::{Alphanumeric, DistString};
+
+    fn register(token: &str) -> RegisterDevice {
+        RegisterDevice {
+            platform: DevicePlatform::Fcm,
+            token: token.to_string(),
+            name: Some("Pixel".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_device_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let device = state.register_device(1, &register("token-1")).await?;
+        assert_eq!(device.user_id, 1);
+        assert_eq!(device.platform, DevicePlatform::Fcm);
+        assert_eq!(device.name.as_deref(), Some("Pixel"));
+
+        // registering again marks the device as seen
+        let again = state.register_device(1, &register("token-1")).await?;
+        assert_eq!(again.id, device.id);
+        assert!(again.last_seen_at >= device.last_seen_at);
+
+        // the token moves to the user signed in on the device
+        let moved = state.register_device(2, &register("token-1")).await?;
+        assert_eq!(moved.id, device.id);
+        assert!(state.list_devices(1).await?.is_empty());
+        assert_eq!(state.list_devices(2).await?.len(), 1);
+
+        let ret = state.register_device(1, &register(" ")).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+        // random, it doesn't get smaller in the index
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), MAX_DEVICE_TOKEN_LEN);
+        state.register_device(1, &register(&token)).await?;
+        let token = "t".repeat(4096);
+        let ret = state.register_device(1, &register(&token)).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unregister_and_prune_devices_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let device = state.register_device(1, &register("token-1")).await?;
+        state.register_device(1, &register("token-2")).await?;
+        // only the owner can unregister a device
+        let ret = state.unregister_device(2, device.id as _).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        state.unregister_device(1, device.id as _).await?;
+        assert_eq!(state.list_devices(1).await?.len(), 1);
+
+        assert_eq!(state.prune_stale_devices(Utc::now()).await?, 1);
+        assert!(state.list_devices(1).await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use chat_core::ChatType;
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+use std::fmt::Write as _;
+use tracing::warn;
+
+use crate::{AppError, AppState};
+
+/// longest message quoted in a digest, the rest is cut
+const MAX_QUOTE_CHARS: usize = 200;
+
+/// a user away for long enough, with what they missed since
+#[derive(Debug, FromRow)]
+struct DigestRecipient {
+    id: i64,
+    email: String,
+    full_name: String,
+    since: DateTime<Utc>,
+    digest_sent_at: Option<DateTime<Utc>>,
+}
+
+/// an unread message mentioning the user or sent to them directly
+#[derive(Debug, FromRow)]
+struct DigestItem {
+    chat_name: Option<String>,
+    chat_type: ChatType,
+    sender: String,
+    content: String,
+    mentioned: bool,
+}
+
+impl AppState {
+    /// Mail the users offline since before `offline_before` the unread mentions and direct
+    /// messages they got since, each message is only in one digest. The number of digests
+    /// sent is returned.
+    pub async fn send_digests(
+        &self,
+        offline_before: DateTime<Utc>,
+        max_items: i64,
+    ) -> Result<u64, AppError> {
+        let now = Utc::now();
+        let recipients: Vec<DigestRecipient> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.email, u.full_name, u.digest_sent_at,
+                GREATEST(COALESCE(p.last_connected_at, u.created_at), u.digest_sent_at) AS since
+            FROM users u
+            LEFT JOIN user_presence p ON p.user_id = u.id
+            WHERE u.email_digest AND u.email_verified AND u.deleted_at IS NULL
+                AND COALESCE(p.connections, 0) = 0
+                AND COALESCE(p.last_connected_at, u.created_at) < $1
+            "#,
+        )
+        .bind(offline_before)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        for recipient in recipients {
+            let items = self
+                .digest_items(recipient.id, recipient.since, now, max_items + 1)
+                .await?;
+            if items.is_empty() {
+                continue;
+            }
+            // another server may be sending the same digest, the claim holds the row until
+            // the mail is sent and is rolled back if it can't be, for the next run to retry
+            let mut tx = self.pool.begin().await?;
+            let claimed = sqlx::query(
+                r#"
+                UPDATE users SET digest_sent_at = $2
+                WHERE id = $1 AND digest_sent_at IS NOT DISTINCT FROM $3
+                "#,
+            )
+            .bind(recipient.id)
+            .bind(now)
+            .bind(recipient.digest_sent_at)
+            .execute(&mut *tx)
+            .await?;
+            if claimed.rows_affected() == 0 {
+                continue;
+            }
+
+            let body = digest_body(&recipient.full_name, &items, max_items as usize);
+            let subject = "Messages you missed";
+            match self.send_mail(&recipient.email, subject, body).await {
+                Ok(()) => {
+                    tx.commit().await?;
+                    sent += 1;
+                }
+                Err(e) => warn!("Failed to send digest to user {}: {}", recipient.id, e),
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Unread messages sent to the user between the two times, the ones of the chats they
+    /// muted or only want mentions of aside
+    async fn digest_items(
+        &self,
+        user_id: i64,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DigestItem>, AppError> {
+        let items = sqlx::query_as(
+            r#"
+            SELECT c.name AS chat_name, c.type AS chat_type, u.full_name AS sender, m.content,
+                $1 = ANY(m.mentions) AS mentioned
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            JOIN users u ON u.id = m.sender_id
+            JOIN users me ON me.id = $1
+            LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $1
+            LEFT JOIN chat_settings s ON s.chat_id = c.id AND s.user_id = $1
+            WHERE $1 = ANY(c.members) AND m.sender_id <> $1 AND m.deleted_at IS NULL
+                AND m.created_at > $2 AND m.created_at <= $3
+                AND m.id > COALESCE(cm.last_read_message_id, 0)
+                AND NOT (COALESCE(s.muted, FALSE)
+                    AND (s.muted_until IS NULL OR s.muted_until > CURRENT_TIMESTAMP))
+                AND NOT EXISTS (
+                    SELECT 1 FROM user_blocks b WHERE b.blocker_id = $1 AND b.blocked_id = m.sender_id
+                )
+                AND CASE COALESCE(s.notify_level, me.notify_level)
+                    WHEN 'nothing' THEN FALSE
+                    WHEN 'mentions' THEN $1 = ANY(m.mentions)
+                    ELSE $1 = ANY(m.mentions) OR c.type = 'single'
+                END
+            ORDER BY m.id
+            LIMIT $4
+            "#,
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(items)
+    }
+}
+
+/// The plain text of a digest, the items past `max_items` are only counted
+fn digest_body(full_name: &str, items: &[DigestItem], max_items: usize) -> String {
+    let mut body = format!("Hi {full_name},\n\nHere is what you missed while you were away:\n");
+    for item in items.iter().take(max_items) {
+        let place = match (&item.chat_type, &item.chat_name) {
+            (ChatType::Single, _) => "in a direct message".to_string(),
+            (_, Some(name)) => format!("in #{name}"),
+            (_, None) => "in a group chat".to_string(),
+        };
+        let what = if item.mentioned {
+            "mentioned you"
+        } else {
+            "wrote"
+        };
+        let _ = write!(
+            body,
+            "\n{} {} {}:\n  {}\n",
+            item.sender,
+            what,
+            place,
+            quote(&item.content)
+        );
+    }
+    if items.len() > max_items {
+        body.push_str("\nAnd more.\n");
+    }
+    body.push_str(
+        "\nOpen the chat to reply. You can turn these emails off in your notification preferences.\n",
+    );
+    body
+}
+
+fn quote(content: &str) -> String {
+    let content = content.replace('\n', "\n  ");
+    match content.char_indices().nth(MAX_QUOTE_CHARS) {
+        Some((i, _)) => format!("{}…", &content[..i]),
+        None => content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SetEmailDigest;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    async fn away_since(state: &AppState, user_id: i64, hours: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO user_presence (user_id, status, connections, last_connected_at)
+            VALUES ($1, 'offline', 0, now() - make_interval(hours => $2::int))
+            "#,
+        )
+        .bind(user_id)
+        .bind(hours)
+        .execute(&state.pool)
+        .await?;
+        sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = $1")
+            .bind(user_id)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn send(state: &AppState, chat_id: i64, content: &str, mentions: &[i64]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content, mentions) VALUES ($1, 1, $2, $3)",
+        )
+        .bind(chat_id)
+        .bind(content)
+        .bind(mentions)
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_digests_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        away_since(&state, 2, 2).await?;
+        // still within the window
+        away_since(&state, 3, 0).await?;
+
+        // a direct message, a mention and a message to the channel
+        send(&state, 3, "are you there?", &[]).await?;
+        send(&state, 2, "@2 @3 look", &[2, 3]).await?;
+        send(&state, 1, "nothing for you", &[]).await?;
+
+        let offline_before = Utc::now() - Duration::hours(1);
+        let items = state
+            .digest_items(2, offline_before - Duration::hours(2), Utc::now(), 10)
+            .await?;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].chat_type, ChatType::Single);
+        assert!(items[1].mentioned);
+        let body = digest_body("Bob", &items, 1);
+        assert!(body.contains("in a direct message:\n  are you there?"));
+        assert!(body.contains("And more."));
+
+        assert_eq!(state.send_digests(offline_before, 10).await?, 1);
+        // each message is only in one digest
+        assert_eq!(state.send_digests(offline_before, 10).await?, 0);
+
+        send(&state, 3, "hello again", &[]).await?;
+        state
+            .set_email_digest(SetEmailDigest { enabled: false }, 2)
+            .await?;
+        assert_eq!(state.send_digests(offline_before, 10).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_digests_should_retry_failed_mails() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        away_since(&state, 2, 2).await?;
+        send(&state, 3, "are you there?", &[]).await?;
+        let offline_before = Utc::now() - Duration::hours(1);
+
+        sqlx::query("UPDATE users SET email = 'not an address' WHERE id = 2")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.send_digests(offline_before, 10).await?, 0);
+        let sent_at: Option<DateTime<Utc>> =
+            sqlx::query_scalar("SELECT digest_sent_at FROM users WHERE id = 2")
+                .fetch_one(&state.pool)
+                .await?;
+        assert!(sent_at.is_none());
+
+        sqlx::query("UPDATE users SET email = 'alice@acme.org' WHERE id = 2")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.send_digests(offline_before, 10).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_digests_should_include_users_never_connected() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        sqlx::query(
+            "UPDATE users SET email_verified = TRUE, created_at = now() - interval '1 day' WHERE id = 3",
+        )
+        .execute(&state.pool)
+        .await?;
+        send(&state, 4, "@3 welcome", &[3]).await?;
+
+        let offline_before = Utc::now() - Duration::hours(1);
+        assert_eq!(state.send_digests(offline_before, 10).await?, 1);
+        Ok(())
+    }
+}
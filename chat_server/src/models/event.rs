@@ -0,0 +1,258 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState, ValidationIssue};
+
+use super::messages::page_limit;
+
+/// most events acked at once
+const MAX_ACKS: usize = 1000;
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListEvents {
+    /// Sequence number of the last event the client got, 0 for all the ones still kept
+    #[serde(default)]
+    pub since: i64,
+    #[serde(default)]
+    pub limit: u64,
+}
+
+/// an event sent to the user, as it went through the event stream
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEvent {
+    pub seq: i64,
+    /// the event, its name under `event`
+    #[schema(value_type = Object)]
+    pub event: serde_json::Value,
+    /// the client must ack it, it's sent again on each new event stream until then
+    pub critical: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEvents {
+    /// the events after `since`, oldest first
+    pub events: Vec<UserEvent>,
+    /// sequence number of the latest event of the user, there are more to fetch until the
+    /// last event listed has it
+    pub last_seq: i64,
+    /// some events after `since` were purged, the client has to fetch its chats again
+    pub resync: bool,
+    /// the critical events no client acked yet, `since` aside
+    pub unacked: Vec<UserEvent>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct AckEvents {
+    /// sequence numbers of the events the client handled
+    pub seqs: Vec<i64>,
+}
+
+impl AppState {
+    /// Events sent to the user after the given one, for the clients back from offline
+    pub async fn list_user_events(
+        &self,
+        user_id: u64,
+        input: ListEvents,
+    ) -> Result<UserEvents, AppError> {
+        let limit = page_limit(input.limit);
+        let since = input.since.max(0);
+        let last_seq: i64 = sqlx::query_scalar("SELECT event_seq FROM users WHERE id = $1")
+            .bind(user_id as i64)
+            .fetch_optional(&self.pool)
+            .await?
+            .unwrap_or_default();
+        let events: Vec<UserEvent> = sqlx::query_as(
+            r#"
+            SELECT seq, event, critical, created_at
+            FROM user_events
+            WHERE user_id = $1 AND seq > $2
+            ORDER BY seq
+            LIMIT $3
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let unacked = sqlx::query_as(
+            r#"
+            SELECT seq, event, critical, created_at
+            FROM user_events
+            WHERE user_id = $1 AND critical AND acked_at IS NULL
+            ORDER BY seq
+            LIMIT $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        // the one right after `since` is gone
+        let next = events.first().map_or(last_seq, |e| e.seq - 1);
+        let resync = since < next;
+        Ok(UserEvents {
+            events,
+            last_seq,
+            resync,
+            unacked,
+        })
+    }
+
+    /// Acknowledge the critical events the client got, they're no longer sent again. The
+    /// number of events newly acked is returned.
+    pub async fn ack_events(&self, user_id: u64, input: AckEvents) -> Result<u64, AppError> {
+        if input.seqs.len() > MAX_ACKS {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "seqs",
+                "too_many",
+                format!("At most {MAX_ACKS} events can be acked at once"),
+            )]));
+        }
+        let ret = sqlx::query(
+            r#"
+            UPDATE user_events SET acked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND seq = ANY($2) AND critical AND acked_at IS NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&input.seqs)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+
+    /// Delete the events sent before `cutoff`, the clients offline since then resync
+    pub async fn purge_events_before(&self, cutoff: DateTime<Utc>) -> Result<u64, AppError> {
+        let ret = sqlx::query("DELETE FROM user_events WHERE created_at < $1")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+
+    async fn log_event(state: &AppState, user_id: i64, name: &str) -> Result<i64> {
+        let seq = sqlx::query_scalar(
+            r#"
+            WITH seqs AS (
+                UPDATE users SET event_seq = event_seq + 1 WHERE id = $1 RETURNING event_seq
+            )
+            INSERT INTO user_events (user_id, seq, event, critical)
+            SELECT $1, event_seq, $2, $3 FROM seqs
+            RETURNING seq
+            "#,
+        )
+        .bind(user_id)
+        .bind(json!({ "event": name }))
+        .bind(name == "MemberAdded")
+        .fetch_one(&state.pool)
+        .await?;
+        Ok(seq)
+    }
+
+    #[tokio::test]
+    async fn list_user_events_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let first = log_event(&state, 1, "NewChat").await?;
+        let second = log_event(&state, 1, "NewMessage").await?;
+        log_event(&state, 2, "NewMessage").await?;
+
+        let ret = state.list_user_events(1, ListEvents::default()).await?;
+        assert_eq!(ret.events.len(), 2);
+        assert_eq!(ret.events[1].event["event"], "NewMessage");
+        assert_eq!(ret.last_seq, second);
+        assert!(!ret.resync);
+
+        let input = ListEvents {
+            since: first,
+            limit: 1,
+        };
+        let ret = state.list_user_events(1, input).await?;
+        assert_eq!(ret.events.len(), 1);
+        assert_eq!(ret.events[0].seq, second);
+
+        // the first event is purged, a client that missed it resyncs
+        sqlx::query("UPDATE user_events SET created_at = now() - interval '2 days' WHERE user_id = 1 AND seq = $1")
+            .bind(first)
+            .execute(&state.pool)
+            .await?;
+        let purged = state
+            .purge_events_before(Utc::now() - chrono::Duration::days(1))
+            .await?;
+        assert_eq!(purged, 1);
+        let ret = state.list_user_events(1, ListEvents::default()).await?;
+        assert!(ret.resync);
+        let input = ListEvents {
+            since: first,
+            limit: 0,
+        };
+        assert!(!state.list_user_events(1, input).await?.resync);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ack_events_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let added = log_event(&state, 1, "MemberAdded").await?;
+        let message = log_event(&state, 1, "NewMessage").await?;
+
+        // listed whatever the client got already
+        let input = ListEvents {
+            since: message,
+            limit: 0,
+        };
+        let ret = state.list_user_events(1, input.clone()).await?;
+        assert!(ret.events.is_empty());
+        assert_eq!(ret.unacked.len(), 1);
+        assert_eq!(ret.unacked[0].seq, added);
+        assert!(ret.unacked[0].critical);
+
+        // only the critical events of the user are acked
+        let ack = AckEvents {
+            seqs: vec![added, message],
+        };
+        assert_eq!(state.ack_events(2, ack.clone()).await?, 0);
+        assert_eq!(state.ack_events(1, ack.clone()).await?, 1);
+        assert_eq!(state.ack_events(1, ack).await?, 0);
+        assert!(state.list_user_events(1, input).await?.unacked.is_empty());
+
+        let ack = AckEvents {
+            seqs: vec![1; MAX_ACKS + 1],
+        };
+        let err = state.ack_events(1, ack).await.unwrap_err();
+        assert!(matches!(err, AppError::ValidationError(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_should_be_logged_with_the_change() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        sqlx::query("INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 1, 'hello')")
+            .execute(&state.pool)
+            .await?;
+
+        let ret = state.list_user_events(2, ListEvents::default()).await?;
+        assert_eq!(ret.events.len(), 1);
+        let event = &ret.events[0].event;
+        assert_eq!(event["event"], "NewMessage");
+        assert_eq!(event["chatId"], 1);
+        assert_eq!(event["content"], "hello");
+        assert!(event.get("searchVector").is_none());
+        assert_eq!(ret.last_seq, ret.events[0].seq);
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+/// number of exported rows buffered before the client reads them
+const EXPORT_BUFFER: usize = 64;
+const CSV_HEADER: &str =
+    "id,sender_id,sender_name,sender_email,parent_id,content,files,created_at,updated_at\n";
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// one json array
+    #[default]
+    Json,
+    Csv,
+    /// one json object per line
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ExportChat {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub sender_id: i64,
+    pub sender_name: String,
+    pub sender_email: String,
+    pub parent_id: Option<i64>,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl AppState {
+    /// Stream the whole history of the chat, oldest first, deleted messages are left out.
+    ///
+    /// Rows are read from the database as the client consumes the stream, so the
+    /// history is never buffered in memory.
+    pub fn export_chat(
+        &self,
+        chat_id: u64,
+        format: ExportFormat,
+    ) -> ReceiverStream<Result<String, AppError>> {
+        let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut rows = sqlx::query_as::<_, ExportedMessage>(
+                r#"
+                SELECT m.id, m.sender_id, u.full_name AS sender_name, u.email AS sender_email,
+                    m.parent_id, m.content, m.files, m.created_at, m.updated_at
+                FROM messages m
+                JOIN users u ON u.id = m.sender_id
+                WHERE m.chat_id = $1 AND m.deleted_at IS NULL
+                ORDER BY m.id
+                "#,
+            )
+            .bind(chat_id as i64)
+            .fetch(&state.pool);
+
+            let head = match format {
+                ExportFormat::Json => "[",
+                ExportFormat::Csv => CSV_HEADER,
+                ExportFormat::Ndjson => "",
+            };
+            if tx.send(Ok(head.to_string())).await.is_err() {
+                return;
+            }
+
+            let mut first = true;
+            while let Some(row) = rows.next().await {
+                let chunk = row
+                    .map_err(AppError::from)
+                    .and_then(|row| format_row(&row, format, first));
+                let failed = chunk.is_err();
+                if let Err(e) = &chunk {
+                    warn!("Failed to export chat {}: {}", chat_id, e);
+                }
+                // the client went away or the export failed, stop reading rows
+                if tx.send(chunk).await.is_err() || failed {
+                    return;
+                }
+                first = false;
+            }
+
+            if format == ExportFormat::Json {
+                let _ = tx.send(Ok("]".to_string())).await;
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+fn format_row(
+    row: &ExportedMessage,
+    format: ExportFormat,
+    first: bool,
+) -> Result<String, AppError> {
+    let chunk = match format {
+        ExportFormat::Json => {
+            let sep = if first { "" } else { "," };
+            format!("{}{}", sep, serde_json::to_string(row)?)
+        }
+        ExportFormat::Ndjson => format!("{}\n", serde_json::to_string(row)?),
+        ExportFormat::Csv => {
+            let fields = [
+                row.id.to_string(),
+                row.sender_id.to_string(),
+                csv_field(&row.sender_name),
+                csv_field(&row.sender_email),
+                row.parent_id.map(|v| v.to_string()).unwrap_or_default(),
+                csv_field(&row.content),
+                csv_field(&row.files.join(" ")),
+                row.created_at.to_rfc3339(),
+                row.updated_at.map(|v| v.to_rfc3339()).unwrap_or_default(),
+            ];
+            format!("{}\n", fields.join(","))
+        }
+    };
+    Ok(chunk)
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn csv_field_should_be_escaped() {
+        assert_eq!(csv_field("hello"), "hello");
+        assert_eq!(csv_field("a, \"b\"\nc"), "\"a, \"\"b\"\"\nc\"");
+    }
+
+    #[tokio::test]
+    async fn export_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let chunks: Vec<_> = state.export_chat(1, ExportFormat::Json).collect().await;
+        let json = chunks.into_iter().collect::<Result<String, _>>()?;
+        let messages: Vec<ExportedMessage> = serde_json::from_str(&json)?;
+        assert_eq!(messages.len(), 10);
+        assert_eq!(messages[0].id, 1);
+        assert_eq!(messages[0].sender_name, "Tyr Chen");
+
+        let chunks: Vec<_> = state.export_chat(1, ExportFormat::Csv).collect().await;
+        let csv = chunks.into_iter().collect::<Result<String, _>>()?;
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 11);
+        assert!(lines[1].starts_with("1,1,Tyr Chen,tchen@acme.org,,\"Hello, world!\","));
+
+        let chunks: Vec<_> = state.export_chat(1, ExportFormat::Ndjson).collect().await;
+        let ndjson = chunks.into_iter().collect::<Result<String, _>>()?;
+        assert_eq!(ndjson.lines().count(), 10);
+
+        Ok(())
+    }
+}
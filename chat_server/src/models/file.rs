@@ -1,14 +1,14 @@
-use std::{
-    path::{Path, PathBuf},
-    str::FromStr,
-};
+use std::str::FromStr;
 
 use sha1::{Digest, Sha1};
 
-use crate::AppError;
+use crate::{AppError, Rendition, ThumbnailSize};
 
 use super::ChatFile;
 
+/// the content of the files is stored under this prefix, once whatever the workspace
+pub(crate) const BLOB_PREFIX: &str = "blobs/";
+
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
         let hash = Sha1::digest(data);
@@ -24,18 +24,86 @@ impl ChatFile {
         format!("/files/{}", self.hash_to_path())
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+    /// Key of the content of the file in the storage, shared by the workspaces
+    pub fn key(&self) -> String {
+        blob_key(&self.hash)
+    }
+
+    pub fn thumbnail_url(&self, size: ThumbnailSize) -> String {
+        format!("{}?size={}", self.url(), size.as_str())
+    }
+
+    /// Key of a thumbnail in the storage, next to the content
+    pub fn thumbnail_key(&self, size: ThumbnailSize) -> String {
+        blob_thumbnail_key(&self.hash, size)
+    }
+
+    pub fn rendition_url(&self, rendition: Rendition) -> String {
+        format!("{}?rendition={}", self.url(), rendition.as_str())
+    }
+
+    /// Key of a rendition in the storage, next to the content
+    pub fn rendition_key(&self, rendition: Rendition) -> String {
+        blob_rendition_key(&self.hash, rendition)
+    }
+
+    /// Key the file was stored under before the content was shared by the workspaces
+    pub fn legacy_key(&self) -> String {
+        self.hash_to_path()
+    }
+
+    pub fn legacy_thumbnail_key(&self, size: ThumbnailSize) -> String {
+        format!("{}.{}.jpeg", self.hash_to_path(), size.as_str())
+    }
+
+    /// The file of a legacy key in the storage, and the size if it's a thumbnail key
+    pub fn from_legacy_key(key: &str) -> Result<(Self, Option<ThumbnailSize>), AppError> {
+        let mut file: Self = format!("/files/{key}").parse()?;
+        for size in ThumbnailSize::ALL {
+            let suffix = format!(".{}.jpeg", size.as_str());
+            if let Some(ext) = file.ext.strip_suffix(&suffix) {
+                file.ext = ext.to_string();
+                return Ok((file, Some(size)));
+            }
+        }
+        Ok((file, None))
     }
 
-    // split hash into 3 parts, first 2 with 3 chars
     fn hash_to_path(&self) -> String {
-        let (part1, part2) = self.hash.split_at(3);
-        let (part2, part3) = part2.split_at(3);
-        format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
+        format!("{}/{}.{}", self.ws_id, split_hash(&self.hash), self.ext)
     }
 }
 
+pub(crate) fn blob_key(hash: &str) -> String {
+    format!("{BLOB_PREFIX}{}", split_hash(hash))
+}
+
+pub(crate) fn blob_thumbnail_key(hash: &str, size: ThumbnailSize) -> String {
+    format!("{}.{}.jpeg", blob_key(hash), size.as_str())
+}
+
+pub(crate) fn blob_rendition_key(hash: &str, rendition: Rendition) -> String {
+    format!("{}.{}.mp4", blob_key(hash), rendition.as_str())
+}
+
+/// The hash of a blob key in the storage, thumbnail keys included
+pub(crate) fn hash_from_blob_key(key: &str) -> Option<String> {
+    let parts: Vec<_> = key.strip_prefix(BLOB_PREFIX)?.split('/').collect();
+    let [part1, part2, part3] = parts[..] else {
+        return None;
+    };
+    let part3 = part3.split('.').next().unwrap_or_default();
+    let hash = format!("{part1}{part2}{part3}");
+    (hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit())).then_some(hash)
+}
+
+// split hash into 3 parts, first 2 with 3 chars
+fn split_hash(hash: &str) -> String {
+    let (part1, part2) = hash.split_at(3);
+    let (part2, part3) = part2.split_at(3);
+    format!("{}/{}/{}", part1, part2, part3)
+}
+
 impl FromStr for ChatFile {
     type Err = AppError;
 
@@ -88,4 +156,33 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_chat_file_keys_should_work() -> Result<()> {
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        // the same content in another workspace shares the key
+        let other = ChatFile::new(2, "kitty.png", b"meow");
+        assert_eq!(file.key(), other.key());
+        assert_ne!(file.url(), other.url());
+
+        assert_eq!(hash_from_blob_key(&file.key()), Some(file.hash.clone()));
+        let thumbnail = file.thumbnail_key(ThumbnailSize::Medium);
+        assert_eq!(hash_from_blob_key(&thumbnail), Some(file.hash.clone()));
+        let rendition = file.rendition_key(Rendition::Web);
+        assert_eq!(hash_from_blob_key(&rendition), Some(file.hash.clone()));
+        assert_eq!(hash_from_blob_key(&file.legacy_key()), None);
+        assert_eq!(hash_from_blob_key("blobs/README.md"), None);
+
+        let (legacy, size) = ChatFile::from_legacy_key(&file.legacy_key())?;
+        assert_eq!((legacy.url(), size), (file.url(), None));
+        let thumbnail = file.legacy_thumbnail_key(ThumbnailSize::Small);
+        let (legacy, size) = ChatFile::from_legacy_key(&thumbnail)?;
+        assert_eq!(
+            (legacy.url(), size),
+            (file.url(), Some(ThumbnailSize::Small))
+        );
+        assert!(ChatFile::from_legacy_key("README.md").is_err());
+
+        Ok(())
+    }
 }
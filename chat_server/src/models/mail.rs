@@ -0,0 +1,53 @@
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::info;
+
+use crate::{config::MailConfig, AppError, AppState};
+
+/// sends the mails of the server, without a smtp url they are only logged
+pub(crate) struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+}
+
+pub(crate) fn mailer(config: &MailConfig) -> Result<Mailer, AppError> {
+    let transport = if config.smtp_url.is_empty() {
+        None
+    } else {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+            .map_err(|e| AppError::MailError(e.to_string()))?
+            .build();
+        Some(transport)
+    };
+    let from = config
+        .from
+        .parse()
+        .map_err(|e| AppError::MailError(format!("Invalid sender {}: {e}", config.from)))?;
+
+    Ok(Mailer { transport, from })
+}
+
+impl AppState {
+    /// Send a plain text mail
+    pub async fn send_mail(&self, to: &str, subject: &str, body: String) -> Result<(), AppError> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| AppError::MailError(format!("Invalid recipient {to}: {e}")))?;
+        let Some(transport) = &self.mailer.transport else {
+            info!("Mail to {} ({}):\n{}", to, subject, body);
+            return Ok(());
+        };
+
+        let message = Message::builder()
+            .from(self.mailer.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| AppError::MailError(e.to_string()))?;
+        transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::MailError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use chat_core::{User, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{messages::page_limit, search::escape_like};
+use crate::{AppError, AppState, Page, ValidationIssue};
+
+/// a user of the workspace as its admins see it
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceMember {
+    pub id: i64,
+    pub full_name: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub role: WorkspaceRole,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    /// when the user joined the workspace
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListWorkspaceMembers {
+    /// Prefix of the full name or the email, case-insensitively
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub role: Option<WorkspaceRole>,
+    /// Only the deactivated members if true, only the active ones if false
+    #[serde(default)]
+    pub deactivated: Option<bool>,
+    /// Opaque cursor from the `older` or `newer` field of a previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Page size - defaults to 20 when 0 or missing, capped at 100
+    #[serde(default)]
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SetMemberRole {
+    /// `member` or `admin`, the owner changes with an ownership transfer and bots come with API keys
+    pub role: WorkspaceRole,
+}
+
+impl AppState {
+    /// Members of the admin's workspace matching the filters, newest first
+    pub async fn list_workspace_members(
+        &self,
+        ws_id: u64,
+        input: ListWorkspaceMembers,
+    ) -> Result<Page<WorkspaceMember>, AppError> {
+        let cursor = self.decode_cursor(input.cursor.as_deref())?;
+        let (op, order) = cursor.sql();
+        let limit = page_limit(input.limit);
+        let q = input.q.filter(|q| !q.trim().is_empty());
+
+        let sql = format!(
+            r#"
+            SELECT u.id, u.full_name, u.email, u.email_verified, wm.role, wm.deactivated_at,
+                wm.created_at AS joined_at
+            FROM users u
+            JOIN workspace_members wm ON wm.user_id = u.id
+            WHERE wm.ws_id = $1 AND u.id {op} $2
+                AND ($4::text IS NULL OR u.full_name ILIKE $4 || '%' ESCAPE '\'
+                    OR u.email ILIKE $4 || '%' ESCAPE '\')
+                AND ($5::workspace_role IS NULL OR wm.role = $5)
+                AND ($6::boolean IS NULL OR (wm.deactivated_at IS NOT NULL) = $6)
+            ORDER BY u.id {order}
+            LIMIT $3
+            "#
+        );
+        let members: Vec<WorkspaceMember> = sqlx::query_as(&sql)
+            .bind(ws_id as i64)
+            .bind(cursor.key)
+            .bind(limit + 1)
+            .bind(q.map(|q| escape_like(q.trim())))
+            .bind(input.role)
+            .bind(input.deactivated)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(self.paginate(members, cursor, limit, |m| m.id))
+    }
+
+    /// Role of a member the admin may manage, i.e. one with a lower role than the admin
+    pub async fn get_managed_role(
+        &self,
+        admin: &User,
+        user_id: u64,
+    ) -> Result<WorkspaceRole, AppError> {
+        let Some(role) = self.get_workspace_role(admin.ws_id as _, user_id).await? else {
+            return Err(AppError::NotFound(format!("User id {user_id}")));
+        };
+        if role >= admin.role {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} is a workspace {role:?}"
+            )));
+        }
+        Ok(role)
+    }
+
+    /// Deactivate or reactivate a member of the admin's workspace, a deactivated member's
+    /// tokens are rejected by the workspace and it's left out of the member lists
+    pub async fn set_member_deactivated(
+        &self,
+        admin: &User,
+        user_id: u64,
+        deactivated: bool,
+    ) -> Result<WorkspaceMember, AppError> {
+        self.get_managed_role(admin, user_id).await?;
+
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET deactivated_at = CASE
+                WHEN NOT $3 THEN NULL
+                ELSE COALESCE(deactivated_at, CURRENT_TIMESTAMP)
+            END
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(admin.ws_id)
+        .bind(user_id as i64)
+        .bind(deactivated)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_workspace_member(admin.ws_id as _, user_id).await
+    }
+
+    /// Change the role of a member of the admin's workspace, admins can make members
+    /// admins but only the owner can demote an admin
+    pub async fn set_member_role(
+        &self,
+        admin: &User,
+        user_id: u64,
+        input: &SetMemberRole,
+    ) -> Result<WorkspaceMember, AppError> {
+        if input.role == WorkspaceRole::Owner {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "role",
+                "owner",
+                "The owner can only be changed by transferring the ownership",
+            )]));
+        }
+        // bots are the service identities of API keys
+        let role = self.get_managed_role(admin, user_id).await?;
+        if input.role == WorkspaceRole::Bot || role == WorkspaceRole::Bot {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "role",
+                "bot",
+                "Bots are managed with API keys",
+            )]));
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = $3
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(admin.ws_id)
+        .bind(user_id as i64)
+        .bind(input.role)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_workspace_member(admin.ws_id as _, user_id).await
+    }
+
+    pub async fn is_member_deactivated(&self, ws_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let deactivated = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM workspace_members
+                WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NOT NULL
+            )
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(deactivated)
+    }
+
+    async fn get_workspace_member(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let member: Option<WorkspaceMember> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.full_name, u.email, u.email_verified, wm.role, wm.deactivated_at,
+                wm.created_at AS joined_at
+            FROM users u
+            JOIN workspace_members wm ON wm.user_id = u.id
+            WHERE wm.ws_id = $1 AND u.id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        member.ok_or_else(|| AppError::NotFound(format!("User id {user_id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListUsers;
+    use anyhow::Result;
+
+    async fn user_with_role(state: &AppState, id: i64, role: WorkspaceRole) -> Result<User> {
+        let mut user = state.find_user_by_id(id).await?.expect("user should exist");
+        user.role = role;
+        Ok(user)
+    }
+
+    #[tokio::test]
+    async fn test_list_workspace_members_should_filter() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let owner = user_with_role(&state, 1, WorkspaceRole::Owner).await?;
+
+        let input = SetMemberRole {
+            role: WorkspaceRole::Admin,
+        };
+        state.set_member_role(&owner, 4, &input).await?;
+        state.set_member_deactivated(&owner, 3, true).await?;
+
+        let input = ListWorkspaceMembers {
+            role: Some(WorkspaceRole::Admin),
+            ..Default::default()
+        };
+        let page = state.list_workspace_members(1, input).await?;
+        let ids: Vec<_> = page.items.iter().map(|m| m.id).collect();
+        assert_eq!(ids, [4, 2]);
+
+        let input = ListWorkspaceMembers {
+            deactivated: Some(true),
+            ..Default::default()
+        };
+        let page = state.list_workspace_members(1, input).await?;
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].id, 3);
+        assert!(page.items[0].deactivated_at.is_some());
+
+        let input = ListWorkspaceMembers {
+            q: Some("daisy".to_string()),
+            deactivated: Some(false),
+            ..Default::default()
+        };
+        let page = state.list_workspace_members(1, input).await?;
+        let ids: Vec<_> = page.items.iter().map(|m| m.id).collect();
+        assert_eq!(ids, [5]);
+        // the wildcards are matched as written
+        let input = ListWorkspaceMembers {
+            q: Some("%".to_string()),
+            ..Default::default()
+        };
+        assert!(state
+            .list_workspace_members(1, input)
+            .await?
+            .items
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_member_deactivated_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let admin = user_with_role(&state, 2, WorkspaceRole::Admin).await?;
+
+        let member = state.set_member_deactivated(&admin, 3, true).await?;
+        assert!(member.deactivated_at.is_some());
+        assert!(state.is_member_deactivated(1, 3).await?);
+        // deactivated members are not offered in the member list
+        let users = state.fetch_chat_users(1, ListUsers::default()).await?.items;
+        assert!(users.iter().all(|u| u.id != 3));
+
+        let member = state.set_member_deactivated(&admin, 3, false).await?;
+        assert!(member.deactivated_at.is_none());
+        assert!(!state.is_member_deactivated(1, 3).await?);
+
+        // admins can't deactivate themselves, nor users outside of the workspace
+        let ret = state.set_member_deactivated(&admin, 2, true).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.set_member_deactivated(&admin, 42, true).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_member_role_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let admin = user_with_role(&state, 2, WorkspaceRole::Admin).await?;
+
+        let input = SetMemberRole {
+            role: WorkspaceRole::Admin,
+        };
+        let member = state.set_member_role(&admin, 4, &input).await?;
+        assert_eq!(member.role, WorkspaceRole::Admin);
+
+        // only the owner can demote an admin
+        let input = SetMemberRole {
+            role: WorkspaceRole::Member,
+        };
+        let ret = state.set_member_role(&admin, 4, &input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let owner = user_with_role(&state, 1, WorkspaceRole::Owner).await?;
+        let member = state.set_member_role(&owner, 4, &input).await?;
+        assert_eq!(member.role, WorkspaceRole::Member);
+
+        let input = SetMemberRole {
+            role: WorkspaceRole::Owner,
+        };
+        let ret = state.set_member_role(&owner, 4, &input).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use chat_core::Message;
+
+use crate::{AppError, AppState, ListMessages, Page};
+
+use super::messages::page_limit;
+
+impl AppState {
+    /// Resolve `@name` / `@id` tokens in the content to ids of the chat members.
+    ///
+    /// `name` matches the local part of the member's email, case-insensitively.
+    /// The sender is never mentioned.
+    pub async fn resolve_mentions(
+        &self,
+        content: &str,
+        chat_id: u64,
+        sender_id: u64,
+    ) -> Result<Vec<i64>, AppError> {
+        let tokens = parse_mentions(content);
+        if tokens.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Ok(vec![]);
+        };
+        let users = self.fetch_chat_users_by_ids(&chat.members).await?;
+
+        let mut mentions = vec![];
+        for token in tokens {
+            let user = match token.parse::<i64>() {
+                Ok(id) => users.iter().find(|u| u.id == id),
+                Err(_) => users.iter().find(|u| {
+                    u.email
+                        .split('@')
+                        .next()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(token))
+                }),
+            };
+            if let Some(user) = user {
+                if user.id != sender_id as i64 && !mentions.contains(&user.id) {
+                    mentions.push(user.id);
+                }
+            }
+        }
+
+        Ok(mentions)
+    }
+
+    /// List messages mentioning the user in the chats the user is still a member of
+    pub async fn list_mentions(
+        &self,
+        input: ListMessages,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Page<Message>, AppError> {
+        let cursor = self.decode_cursor(input.cursor.as_deref())?;
+        let (op, order) = cursor.sql();
+        let limit = page_limit(input.limit);
+
+        let sql = format!(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.parent_id, m.content, m.files, m.mentions,
+                m.created_at, m.updated_at, m.deleted_at
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE $1 = ANY(m.mentions) AND $1 = ANY(c.members) AND c.ws_id = $2
+                AND m.deleted_at IS NULL AND m.id {op} $3
+            ORDER BY m.id {order}
+            LIMIT $4
+            "#
+        );
+        let messages: Vec<Message> = sqlx::query_as(&sql)
+            .bind(user_id as i64)
+            .bind(ws_id as i64)
+            .bind(cursor.key)
+            .bind(limit + 1)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(self.paginate(messages, cursor, limit, |m| m.id))
+    }
+}
+
+/// Extract `@token` mentions from the content, `foo@bar.com` is not a mention
+fn parse_mentions(content: &str) -> Vec<&str> {
+    let mut tokens = HashSet::new();
+    let mut ret = vec![];
+    let mut prev = ' ';
+    for (i, c) in content.char_indices() {
+        if c == '@' && !prev.is_alphanumeric() {
+            let rest = &content[i + 1..];
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || matches!(c, '.' | '_' | '-')))
+                .unwrap_or(rest.len());
+            let token = rest[..end].trim_end_matches('.');
+            if !token.is_empty() && tokens.insert(token) {
+                ret.push(token);
+            }
+        }
+        prev = c;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse_mentions_should_work() {
+        let tokens = parse_mentions("hi @alice and @3, mail bob@acme.org. thanks @alice.");
+        assert_eq!(tokens, vec!["alice", "3"]);
+
+        assert!(parse_mentions("no mentions @ all").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_mentions_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // user 4 is not in chat 2, user 1 is the sender
+        let input = CreateMessage {
+            content: "@Alice @3 @4 @tchen please check".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        let message = state.create_message(input, 2, 1).await?;
+        assert_eq!(message.mentions, vec![2, 3]);
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 10,
+        };
+        let mentions = state.list_mentions(input, 2, 1).await?.items;
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].id, message.id);
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 10,
+        };
+        assert!(state.list_mentions(input, 4, 1).await?.items.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_mentions_should_skip_the_blocking_users() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // alice blocked the sender, bob is told about the mention alone
+        state.block_user(2, 1).await?;
+        let input = CreateMessage {
+            content: "@alice @bob please check".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        let message = state.create_message(input, 2, 1).await?;
+        assert_eq!(message.mentions, vec![2, 3]);
+
+        let mentioned: Vec<i64> = sqlx::query_scalar(
+            "SELECT user_id FROM user_events WHERE event->>'event' = 'Mention' ORDER BY user_id",
+        )
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(mentioned, vec![3]);
+
+        Ok(())
+    }
+}
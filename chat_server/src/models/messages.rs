@@ -1,20 +1,47 @@
-use chat_core::Message;
+use chat_core::{begin_tagged, Message};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 use std::str::FromStr;
 use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
 
-use crate::{AppError, AppState, ChatFile};
+use super::content::{process_content, validate_content};
+use crate::{AppConfig, AppError, AppState, ChatFile, Page};
 
-#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+const DEFAULT_LIST_LIMIT: i64 = 20;
+const MAX_LIST_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
 pub struct CreateMessage {
+    /// Markdown, at most `message.max_length` characters
+    #[validate(custom(function = "validate_content", use_context))]
     pub content: String,
     pub files: Vec<String>,
+    /// Reply in the thread of this message
+    #[serde(default)]
+    pub parent_id: Option<u64>,
+    /// Deliver the message at this time instead of now
+    #[serde(default)]
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+pub struct UpdateMessage {
+    /// Markdown, at most `message.max_length` characters
+    #[validate(custom(function = "validate_content", use_context))]
+    pub content: String,
 }
 
 #[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
 pub struct ListMessages {
+    /// Opaque cursor from the `older` or `newer` field of a previous page,
+    /// the latest page is returned without it
     #[serde(default)]
-    pub last_id: Option<u64>,
+    pub cursor: Option<String>,
+    /// Page size - defaults to 20 when 0 or missing, capped at 100
     #[serde(default)]
     pub limit: u64,
 }
@@ -27,18 +54,48 @@ impl AppState {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.config.server.base_dir;
-        // verify content - not empty
-        if input.content.is_empty() {
-            return Err(AppError::CreateMessageError(
-                "Content cannot be empty".to_string(),
-            ));
-        }
+        let (input, mentions) = self.prepare_message(input, chat_id, user_id).await?;
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let message = insert_message(&mut tx, input, mentions, chat_id, user_id).await?;
+        tx.commit().await?;
+
+        self.spawn_link_previews(&message);
+
+        Ok(message)
+    }
+
+    /// Validate a new message and resolve its mentions, for `insert_message`
+    pub(crate) async fn prepare_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<(CreateMessage, Vec<i64>), AppError> {
+        let input = self.validate_message(input, chat_id).await?;
+        let mentions = self
+            .resolve_mentions(&input.content, chat_id, user_id)
+            .await?;
+        Ok((input, mentions))
+    }
+
+    /// Sanitize the content, verify the files and the parent message of a new message,
+    /// returns the message with its content sanitized
+    pub(crate) async fn validate_message(
+        &self,
+        mut input: CreateMessage,
+        chat_id: u64,
+    ) -> Result<CreateMessage, AppError> {
+        input.content = process_content(&input.content, &self.config().message)?;
 
-        // verify files exist
+        // verify files exist in the workspace of the chat
+        let ws_id = match self.get_chat_by_id(chat_id).await? {
+            Some(chat) => chat.ws_id as u64,
+            None => return Err(AppError::NotFound(format!("Chat id {chat_id}"))),
+        };
         for s in &input.files {
             let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+            if file.ws_id != ws_id || self.locate_file(&file, None).await?.is_none() {
                 return Err(AppError::CreateMessageError(format!(
                     "File {} not found",
                     s
@@ -46,59 +103,276 @@ impl AppState {
             }
         }
 
-        // create message
-        let message: Message = sqlx::query_as(
+        // verify parent is a top-level message in the same chat
+        if let Some(parent_id) = input.parent_id {
+            match self.get_message_by_id(chat_id, parent_id).await? {
+                Some(parent) if parent.deleted_at.is_none() && parent.parent_id.is_none() => {}
+                _ => {
+                    return Err(AppError::CreateMessageError(format!(
+                        "Cannot reply to message {}",
+                        parent_id
+                    )))
+                }
+            }
+        }
+
+        Ok(input)
+    }
+
+    pub async fn get_message_by_id(
+        &self,
+        chat_id: u64,
+        id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        let message = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content, files)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
+            SELECT id, chat_id, sender_id, parent_id, content, files, mentions, created_at, updated_at, deleted_at
+            FROM messages
+            WHERE chat_id = $1 AND id = $2
             "#,
         )
         .bind(chat_id as i64)
-        .bind(user_id as i64)
-        .bind(input.content)
-        .bind(input.files)
-        .fetch_one(&self.pool)
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(message)
     }
 
-    pub async fn list_messages(
+    pub async fn update_message(
         &self,
-        input: ListMessages,
+        input: UpdateMessage,
         chat_id: u64,
-    ) -> Result<Vec<Message>, AppError> {
-        let last_id = input.last_id.unwrap_or(i64::MAX as _);
-        let limit = match input.limit {
-            0 => i64::MAX,
-            1..=100 => input.limit as _,
-            _ => 100,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let content = process_content(&input.content, &self.config().message)?;
+
+        let message = match self.get_message_by_id(chat_id, id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Err(AppError::NotFound(format!("Message id {id}"))),
         };
 
-        let messages: Vec<Message> = sqlx::query_as(
+        // only the sender can edit the message
+        if message.sender_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not the sender of message {}",
+                user_id, id
+            )));
+        }
+
+        let edit_window = Duration::seconds(self.config().message.edit_window as _);
+        if message.created_at + edit_window < Utc::now() {
+            return Err(AppError::UpdateMessageError(format!(
+                "Message {} can no longer be edited",
+                id
+            )));
+        }
+
+        let mentions = self.resolve_mentions(&content, chat_id, user_id).await?;
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let message = sqlx::query_as(
             r#"
-            SELECT id, chat_id, sender_id, content, files, created_at
-            FROM messages
-            WHERE chat_id = $1 AND id < $2
-            ORDER BY id DESC
-            LIMIT $3
+            UPDATE messages
+            SET content = $1, mentions = $3, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $2
+            RETURNING id, chat_id, sender_id, parent_id, content, files, mentions, created_at, updated_at, deleted_at
             "#,
         )
-        .bind(chat_id as i64)
-        .bind(last_id as i64)
-        .bind(limit)
-        .fetch_all(&self.pool)
+        .bind(content)
+        .bind(id as i64)
+        .bind(mentions)
+        .fetch_one(&mut *tx)
         .await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    /// Soft delete a message, only the sender or the chat owner can do it
+    pub async fn delete_message(
+        &self,
+        chat_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        let message = match self.get_message_by_id(chat_id, id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Err(AppError::NotFound(format!("Message id {id}"))),
+        };
+
+        if message.sender_id != user_id as i64 {
+            let is_owner = self
+                .get_chat_by_id(chat_id)
+                .await?
+                .is_some_and(|chat| chat.owner_id == user_id as i64);
+            if !is_owner {
+                return Err(AppError::PermissionDenied(format!(
+                    "User {} cannot delete message {}",
+                    user_id, id
+                )));
+            }
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        sqlx::query(
+            r#"
+            UPDATE messages
+            SET deleted_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
 
-        Ok(messages)
+    /// List top-level messages of the chat, replies are listed by `list_thread_messages`
+    pub async fn list_messages(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+    ) -> Result<Page<Message>, AppError> {
+        self.fetch_messages(input, chat_id, None).await
+    }
+
+    /// List replies in the thread of a top-level message
+    pub async fn list_thread_messages(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+        message_id: u64,
+    ) -> Result<Page<Message>, AppError> {
+        match self.get_message_by_id(chat_id, message_id).await? {
+            Some(message) if message.parent_id.is_none() => {}
+            _ => {
+                return Err(AppError::NotFound(format!(
+                    "Thread of message {message_id}"
+                )))
+            }
+        }
+
+        self.fetch_messages(input, chat_id, Some(message_id)).await
+    }
+
+    async fn fetch_messages(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+        parent_id: Option<u64>,
+    ) -> Result<Page<Message>, AppError> {
+        let cursor = self.decode_cursor(input.cursor.as_deref())?;
+        let (op, order) = cursor.sql();
+        let limit = page_limit(input.limit);
+
+        let sql = format!(
+            r#"
+            SELECT id, chat_id, sender_id, parent_id,
+                CASE WHEN deleted_at IS NULL THEN content ELSE '' END AS content,
+                CASE WHEN deleted_at IS NULL THEN files ELSE '{{}}' END AS files,
+                mentions, created_at, updated_at, deleted_at,
+                COALESCE((
+                    SELECT json_agg(a ORDER BY array_position(messages.files, a.url))
+                    FROM attachments a
+                    WHERE a.url = ANY(messages.files) AND messages.deleted_at IS NULL
+                ), '[]') AS attachments,
+                COALESCE((
+                    SELECT json_agg(r)
+                    FROM (
+                        SELECT emoji, count(*) AS count
+                        FROM reactions
+                        WHERE message_id = messages.id
+                        GROUP BY emoji
+                        ORDER BY min(created_at), emoji
+                    ) r
+                ), '[]') AS reactions,
+                (
+                    SELECT count(*)
+                    FROM messages replies
+                    WHERE replies.parent_id = messages.id AND replies.deleted_at IS NULL
+                ) AS reply_count,
+                ARRAY(
+                    SELECT user_id
+                    FROM chat_members
+                    WHERE chat_members.chat_id = messages.chat_id
+                        AND chat_members.last_read_message_id >= messages.id
+                        AND chat_members.user_id <> messages.sender_id
+                    ORDER BY user_id
+                ) AS read_by,
+                COALESCE((
+                    SELECT json_agg(p ORDER BY p.created_at)
+                    FROM link_previews p
+                    WHERE p.message_id = messages.id AND messages.deleted_at IS NULL
+                ), '[]') AS previews,
+                COALESCE((
+                    SELECT row_to_json(r)
+                    FROM poll_results r
+                    WHERE r.message_id = messages.id AND messages.deleted_at IS NULL
+                ), 'null') AS poll
+            FROM messages
+            WHERE chat_id = $1 AND id {op} $2 AND parent_id IS NOT DISTINCT FROM $4
+            ORDER BY id {order}
+            LIMIT $3
+            "#
+        );
+        let messages: Vec<Message> = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(cursor.key)
+            .bind(limit + 1)
+            .bind(parent_id.map(|v| v as i64))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(self.paginate(messages, cursor, limit, |m| m.id))
+    }
+}
+
+/// Page size of message listings, 0 means the default
+pub(crate) fn page_limit(limit: u64) -> i64 {
+    match limit as i64 {
+        0 => DEFAULT_LIST_LIMIT,
+        v @ 1..=MAX_LIST_LIMIT => v,
+        _ => MAX_LIST_LIMIT,
     }
 }
 
+/// Insert a message prepared by `prepare_message`, its link previews are fetched by the
+/// caller once the transaction is committed
+pub(crate) async fn insert_message(
+    tx: &mut PgConnection,
+    input: CreateMessage,
+    mentions: Vec<i64>,
+    chat_id: u64,
+    user_id: u64,
+) -> Result<Message, AppError> {
+    let message = sqlx::query_as(
+        r#"
+        INSERT INTO messages (chat_id, sender_id, content, files, parent_id, mentions)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, chat_id, sender_id, parent_id, content, files, mentions, created_at, updated_at, deleted_at
+        "#,
+    )
+    .bind(chat_id as i64)
+    .bind(user_id as i64)
+    .bind(input.content)
+    .bind(input.files)
+    .bind(input.parent_id.map(|v| v as i64))
+    .bind(mentions)
+    .fetch_one(tx)
+    .await?;
+
+    Ok(message)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
+    use sqlx::Connection;
 
     #[tokio::test]
     async fn test_create_message_should_work() -> Result<()> {
@@ -107,6 +381,8 @@ mod tests {
         let input = CreateMessage {
             content: "Hello World".to_string(),
             files: vec![],
+            parent_id: None,
+            send_at: None,
         };
 
         let message = state
@@ -119,14 +395,18 @@ mod tests {
         let input = CreateMessage {
             content: "Hello World".to_string(),
             files: vec!["invalid_file".to_string()],
+            parent_id: None,
+            send_at: None,
         };
         assert!(state.create_message(input, 1, 1).await.is_err());
 
         // invalid files should work
-        let url = upload_dummy_file(&state)?;
+        let url = upload_dummy_file(&state).await?;
         let input = CreateMessage {
             content: "Hello World".to_string(),
             files: vec![url],
+            parent_id: None,
+            send_at: None,
         };
         let message = state
             .create_message(input, 1, 1)
@@ -143,31 +423,230 @@ mod tests {
         let (_tdb, state) = AppState::try_new_for_test().await?;
 
         let input = ListMessages {
-            last_id: None,
+            cursor: None,
             limit: 6,
         };
 
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 6);
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(page.items.len(), 6);
+        assert_eq!(page.items[0].id, 10);
 
-        let last_id = messages.last().expect("last message should exists").id;
+        let input = ListMessages {
+            cursor: page.older.clone(),
+            limit: 6,
+        };
+        let older = state.list_messages(input, 1).await?;
+        assert_eq!(older.items.len(), 4);
+        assert_eq!(older.items[0].id, 4);
+        assert!(older.older.is_none());
 
+        // paging back to newer messages, still newest first
         let input = ListMessages {
-            last_id: Some(last_id as _),
+            cursor: older.newer,
+            limit: 3,
+        };
+        let newer = state.list_messages(input, 1).await?;
+        let ids: Vec<_> = newer.items.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![7, 6, 5]);
+        assert!(newer.older.is_some());
+
+        // nothing newer than the latest page yet
+        let input = ListMessages {
+            cursor: page.newer,
             limit: 6,
         };
+        let latest = state.list_messages(input, 1).await?;
+        assert!(latest.items.is_empty());
+        assert!(latest.newer.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_messages_should_use_default_and_max_limit() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        for _ in 0..120 {
+            let input = CreateMessage {
+                content: "Hello World".to_string(),
+                files: vec![],
+                parent_id: None,
+                send_at: None,
+            };
+            state.create_message(input, 1, 1).await?;
+        }
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 0,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), DEFAULT_LIST_LIMIT as usize);
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 1000,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), MAX_LIST_LIMIT as usize);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = UpdateMessage {
+            content: "Hello again".to_string(),
+        };
+        let message = state.update_message(input, 1, 1, 1).await?;
+        assert_eq!(message.content, "Hello again");
+        assert!(message.updated_at.is_some());
+
+        // message 2 was sent by user 2
+        let input = UpdateMessage {
+            content: "Hello again".to_string(),
+        };
+        let ret = state.update_message(input, 1, 2, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // message 1 is not in chat 2
+        let input = UpdateMessage {
+            content: "Hello again".to_string(),
+        };
+        let ret = state.update_message(input, 2, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
 
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 4);
+    #[tokio::test]
+    async fn test_delete_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // sender can delete own message
+        state.delete_message(1, 10, 1).await?;
+
+        // user 3 is neither the sender of message 2 nor the owner of chat 1
+        let ret = state.delete_message(1, 2, 3).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // chat owner can delete others' messages
+        state.delete_message(1, 2, 1).await?;
+
+        // deleted message can't be deleted or edited again
+        let ret = state.delete_message(1, 2, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let input = UpdateMessage {
+            content: "Hello again".to_string(),
+        };
+        let ret = state.update_message(input, 1, 10, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // deleted messages are listed as tombstones
+        let input = ListMessages {
+            cursor: None,
+            limit: 10,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), 10);
+        let deleted: Vec<_> = messages.iter().filter(|m| m.deleted_at.is_some()).collect();
+        assert_eq!(deleted.len(), 2);
+        assert!(deleted.iter().all(|m| m.content.is_empty()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_notifications_should_carry_request_id() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_message_created").await?;
+
+        // what begin_tagged does, the id is gone once the transaction is over
+        let mut conn = state.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query("SELECT set_config('app.request_id', $1, true)")
+            .bind("req-1")
+            .execute(&mut *tx)
+            .await?;
+        let insert = "INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 1, 'hi')";
+        sqlx::query(insert).execute(&mut *tx).await?;
+        tx.commit().await?;
+        sqlx::query(insert).execute(&mut *conn).await?;
+
+        let timeout = std::time::Duration::from_secs(5);
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let notif = tokio::time::timeout(timeout, listener.recv()).await??;
+            let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+            ids.push(payload["request_id"].clone());
+        }
+        assert_eq!(ids, [serde_json::json!("req-1"), serde_json::Value::Null]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_thread_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        for i in 0..3 {
+            let input = CreateMessage {
+                content: format!("Reply {}", i),
+                files: vec![],
+                parent_id: Some(1),
+                send_at: None,
+            };
+            let message = state.create_message(input, 1, 2).await?;
+            assert_eq!(message.parent_id, Some(1));
+        }
+
+        // replies are not listed in the chat, but counted on the parent
+        let input = ListMessages {
+            cursor: None,
+            limit: 100,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), 10);
+        let parent = messages.iter().find(|m| m.id == 1).expect("message 1");
+        assert_eq!(parent.reply_count, 3);
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 100,
+        };
+        let replies = state.list_thread_messages(input, 1, 1).await?.items;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].content, "Reply 2");
+
+        // can't reply to a reply
+        let input = CreateMessage {
+            content: "nested".to_string(),
+            files: vec![],
+            parent_id: Some(replies[0].id as _),
+            send_at: None,
+        };
+        assert!(state.create_message(input, 1, 1).await.is_err());
+
+        // can't reply to a message in another chat
+        let input = CreateMessage {
+            content: "wrong chat".to_string(),
+            files: vec![],
+            parent_id: Some(1),
+            send_at: None,
+        };
+        assert!(state.create_message(input, 2, 1).await.is_err());
 
         Ok(())
     }
 
-    fn upload_dummy_file(state: &AppState) -> Result<String> {
+    async fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "dummy.txt", b"Hello World");
-        let file_path = file.path(&state.config.server.base_dir);
-        std::fs::create_dir_all(file_path.parent().expect("parent dir should exists"))?;
-        std::fs::write(&file_path, b"Hello World")?;
+        state
+            .storage
+            .put(&file.key(), b"Hello World".to_vec())
+            .await?;
 
         Ok(file.url())
     }
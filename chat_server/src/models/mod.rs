@@ -1,14 +1,75 @@
+mod api_key;
+mod attachment;
+mod ban;
+mod blob;
+mod block;
 mod chat;
+mod content;
+mod cursor;
+mod delivery;
+mod device;
+mod digest;
+mod event;
+mod export;
 mod file;
+mod mail;
+mod member;
+mod mention;
 mod messages;
+mod poll;
+mod presence;
+mod preview;
+mod reaction;
+mod read_state;
+mod retention;
+mod saved;
+mod scheduled;
+mod search;
+mod settings;
+mod signed_url;
+mod stats;
+mod token;
+mod upload;
 mod user;
+mod verification;
 mod workspace;
 
 use serde::{Deserialize, Serialize};
 
-pub use chat::{CreateChat, UpdateChat};
-pub use messages::{CreateMessage, ListMessages};
-pub use user::{CreateUser, SigninUser};
+pub(crate) use mail::{mailer, Mailer};
+pub(crate) use preview::http_client;
+
+pub use api_key::{ApiKey, CreateApiKey, CreatedApiKey, API_KEY_PREFIX};
+pub use attachment::{GetFile, ListFiles, Rendition, ThumbnailSize};
+pub use ban::{ChatBan, RemoveChatMember};
+pub use chat::{AddChatMember, ChatExpand, CreateChat, ListChats, TransferOwnership, UpdateChat};
+pub use content::{render_html, MessageFormat, RenderOptions};
+pub use cursor::Page;
+pub use delivery::{DeliveryStatus, MessageStatus};
+pub use device::{Device, DevicePlatform, RegisterDevice};
+pub use event::{AckEvents, ListEvents, UserEvent, UserEvents};
+pub use export::{ExportChat, ExportFormat, ExportedMessage};
+pub use member::{ListWorkspaceMembers, SetMemberRole, WorkspaceMember};
+pub use messages::{CreateMessage, ListMessages, UpdateMessage};
+pub use poll::{CreatePoll, VotePoll};
+pub use presence::{ListPresence, SetPresence};
+pub use reaction::CreateReaction;
+pub use read_state::{Badges, ChatBadge, ChatUnread, MarkRead};
+pub use retention::PurgeStats;
+pub use saved::SavedMessage;
+pub use scheduled::ScheduledMessage;
+pub use search::{SearchMessages, SearchResult};
+pub use settings::{
+    ChatNotifyLevel, ChatSettings, MuteChat, NotificationPreferences, NotifyLevel, SetEmailDigest,
+    SetNotifyLevel,
+};
+pub use signed_url::{SignFile, SignedFile, SignedFileUrl};
+pub use stats::{DailyMessages, WorkspaceStats};
+pub(crate) use token::REFRESH_TOKEN_TTL;
+pub use token::{RefreshToken, Signout};
+pub use upload::{UploadParams, UploadTracker};
+pub use user::{ChangePassword, CreateUser, ListUsers, SetUserStatus, SigninUser};
+pub use workspace::{InvitePolicy, MyWorkspace, UpdateWorkspaceSettings, WorkspaceSettings};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatFile {
@@ -0,0 +1,284 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use chat_core::{begin_tagged, Poll};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
+
+use super::content::{process_content, validate_content};
+use crate::{AppConfig, AppError, AppState};
+
+const MIN_POLL_OPTIONS: u64 = 2;
+const MAX_POLL_OPTIONS: u64 = 10;
+const MAX_OPTION_LEN: usize = 100;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+pub struct CreatePoll {
+    #[validate(custom(function = "validate_content", use_context))]
+    pub question: String,
+    /// 2 to 10 unique options of at most 100 characters
+    #[validate(
+        length(
+            min = MIN_POLL_OPTIONS,
+            max = MAX_POLL_OPTIONS,
+            code = "length",
+            message = "Poll must have 2 to 10 options"
+        ),
+        custom(function = "validate_poll_options")
+    )]
+    pub options: Vec<String>,
+    /// Stop accepting votes at this time, polls without it never expire
+    #[serde(default)]
+    #[validate(custom(function = "validate_poll_expires_at"))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct VotePoll {
+    /// Index of the chosen option, voting again changes the vote
+    pub option: u32,
+}
+
+impl AppState {
+    /// Post a poll in the chat, the poll message content is the question
+    pub async fn create_poll(
+        &self,
+        input: CreatePoll,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Poll, AppError> {
+        let question = process_content(&input.question, &self.config().message)?;
+        let options: Vec<String> = input
+            .options
+            .iter()
+            .map(|option| option.trim().to_string())
+            .collect();
+
+        // the message and the poll are created in one statement
+        let mut tx = begin_tagged(&self.pool).await?;
+        let poll = sqlx::query_as(
+            r#"
+            WITH message AS (
+                INSERT INTO messages (chat_id, sender_id, content)
+                VALUES ($1, $2, $3)
+                RETURNING id
+            ), poll AS (
+                INSERT INTO polls (message_id, chat_id, creator_id, question, options, expires_at)
+                SELECT id, $1, $2, $3, $4, $5
+                FROM message
+                RETURNING id, message_id, chat_id, creator_id, question, options, expires_at, created_at
+            )
+            SELECT poll.*, array_fill(0::bigint, ARRAY[cardinality(poll.options)]) AS votes
+            FROM poll
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(question)
+        .bind(options)
+        .bind(input.expires_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(poll)
+    }
+
+    /// Get a poll with its results, polls of deleted messages are not found
+    pub async fn get_poll_by_id(&self, id: u64) -> Result<Option<Poll>, AppError> {
+        let poll = sqlx::query_as(
+            r#"
+            SELECT r.id, r.message_id, r.chat_id, r.creator_id, r.question, r.options,
+                r.expires_at, r.created_at, r.votes
+            FROM poll_results r
+            JOIN messages m ON m.id = r.message_id
+            WHERE r.id = $1 AND m.deleted_at IS NULL
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(poll)
+    }
+
+    /// Vote on a poll of a chat the user is a member of, returns the latest results
+    pub async fn vote_poll(
+        &self,
+        input: VotePoll,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Poll, AppError> {
+        let Some(poll) = self.get_poll_by_id(id).await? else {
+            return Err(AppError::NotFound(format!("Poll id {id}")));
+        };
+        if !self.is_chat_member(poll.chat_id as _, user_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "User {} is not a member of chat {}",
+                user_id, poll.chat_id
+            )));
+        }
+        if poll.expires_at.is_some_and(|v| v <= Utc::now()) {
+            return Err(AppError::PollError(format!("Poll {id} has expired")));
+        }
+        if input.option as usize >= poll.options.len() {
+            return Err(AppError::PollError(format!(
+                "Option {} does not exist",
+                input.option
+            )));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO poll_votes (poll_id, user_id, option)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (poll_id, user_id) DO UPDATE SET option = EXCLUDED.option, created_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .bind(input.option as i32)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        self.get_poll_by_id(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Poll id {id}")))
+    }
+}
+
+fn validate_poll_options(options: &[String]) -> Result<(), ValidationError> {
+    let options: Vec<_> = options.iter().map(|option| option.trim()).collect();
+    if options
+        .iter()
+        .any(|option| option.is_empty() || option.chars().count() > MAX_OPTION_LEN)
+    {
+        let message = format!("Options must have 1 to {MAX_OPTION_LEN} characters");
+        return Err(ValidationError::new("length").with_message(Cow::Owned(message)));
+    }
+    if options.iter().collect::<HashSet<_>>().len() != options.len() {
+        let message = Cow::Borrowed("Options must be unique");
+        return Err(ValidationError::new("unique").with_message(message));
+    }
+    Ok(())
+}
+
+fn validate_poll_expires_at(expires_at: &DateTime<Utc>) -> Result<(), ValidationError> {
+    if *expires_at <= Utc::now() {
+        let message = Cow::Borrowed("expires_at must be in the future");
+        return Err(ValidationError::new("in_past").with_message(message));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+impl CreatePoll {
+    pub fn new(question: &str, options: &[&str]) -> Self {
+        Self {
+            question: question.to_string(),
+            options: options.iter().map(|v| v.to_string()).collect(),
+            expires_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListMessages;
+    use anyhow::Result;
+    use chrono::Duration;
+    use validator::ValidateArgs;
+
+    #[tokio::test]
+    async fn test_create_poll_and_vote_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreatePoll::new("Lunch?", &["pizza", "sushi", "salad"]);
+        let poll = state.create_poll(input, 1, 1).await?;
+        assert_eq!(poll.chat_id, 1);
+        assert_eq!(poll.creator_id, 1);
+        assert_eq!(poll.votes, vec![0, 0, 0]);
+
+        let poll = state
+            .vote_poll(VotePoll { option: 1 }, poll.id as _, 2)
+            .await?;
+        assert_eq!(poll.votes, vec![0, 1, 0]);
+        let poll = state
+            .vote_poll(VotePoll { option: 1 }, poll.id as _, 3)
+            .await?;
+        assert_eq!(poll.votes, vec![0, 2, 0]);
+        // voting again changes the vote
+        let poll = state
+            .vote_poll(VotePoll { option: 0 }, poll.id as _, 3)
+            .await?;
+        assert_eq!(poll.votes, vec![1, 1, 0]);
+
+        // the poll is returned with its message
+        let input = ListMessages {
+            cursor: None,
+            limit: 1,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages[0].id, poll.message_id);
+        assert_eq!(messages[0].content, "Lunch?");
+        assert_eq!(messages[0].poll.as_ref(), Some(&poll));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_poll_with_invalid_options_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let config = state.config();
+
+        let input = CreatePoll::new("Lunch?", &["pizza"]);
+        assert!(input.validate_with_args(&config).is_err());
+        let input = CreatePoll::new("Lunch?", &["pizza", " pizza "]);
+        let err = AppError::from(input.validate_with_args(&config).unwrap_err());
+        assert_eq!(err.to_string(), "invalid content: Options must be unique");
+
+        let mut input = CreatePoll::new("Lunch?", &["pizza", "sushi"]);
+        input.expires_at = Some(Utc::now() - Duration::minutes(1));
+        assert!(input.validate_with_args(&config).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vote_poll_should_be_checked() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreatePoll::new("Lunch?", &["pizza", "sushi"]);
+        let poll = state.create_poll(input, 3, 1).await?;
+
+        // only chat members can vote
+        let err = state
+            .vote_poll(VotePoll { option: 0 }, poll.id as _, 3)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let err = state
+            .vote_poll(VotePoll { option: 2 }, poll.id as _, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "poll error: Option 2 does not exist");
+
+        sqlx::query("UPDATE polls SET expires_at = now() WHERE id = $1")
+            .bind(poll.id)
+            .execute(&state.pool)
+            .await?;
+        let err = state
+            .vote_poll(VotePoll { option: 0 }, poll.id as _, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PollError(_)));
+
+        Ok(())
+    }
+}
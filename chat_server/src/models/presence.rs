@@ -0,0 +1,213 @@
+use chat_core::{begin_tagged, Presence, PresenceStatus};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState, ValidationIssue};
+
+/// max number of users whose presence is fetched at once
+const MAX_PRESENCE_IDS: usize = 200;
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListPresence {
+    /// Comma separated user ids, e.g. `1,2,3`
+    pub ids: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SetPresence {
+    /// `online` or `away`, offline follows from closing all the event streams
+    pub status: PresenceStatus,
+}
+
+impl ListPresence {
+    fn parse_ids(&self) -> Result<Vec<i64>, AppError> {
+        let ids = self
+            .ids
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                AppError::ValidationError(vec![ValidationIssue::new(
+                    "ids",
+                    "invalid",
+                    "Ids must be comma separated numbers",
+                )])
+            })?;
+        if ids.len() > MAX_PRESENCE_IDS {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "ids",
+                "too_many",
+                format!("At most {MAX_PRESENCE_IDS} ids"),
+            )]));
+        }
+        Ok(ids)
+    }
+}
+
+impl AppState {
+    /// Presence of the given users, users outside the workspace are left out
+    pub async fn list_presence(
+        &self,
+        ws_id: u64,
+        input: &ListPresence,
+    ) -> Result<Vec<Presence>, AppError> {
+        let ids = input.parse_ids()?;
+        let presence = sqlx::query_as(
+            r#"
+            SELECT wm.user_id, COALESCE(p.status, 'offline') AS status, p.updated_at
+            FROM workspace_members wm
+            LEFT JOIN user_presence p ON p.user_id = wm.user_id
+            WHERE wm.ws_id = $1 AND wm.user_id = ANY($2)
+            ORDER BY wm.user_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(presence)
+    }
+
+    /// Presence of the members of a chat with an open event stream
+    pub async fn list_chat_online(&self, chat_id: u64) -> Result<Vec<Presence>, AppError> {
+        let presence = sqlx::query_as(
+            r#"
+            SELECT p.user_id, p.status, p.updated_at
+            FROM chats c
+            JOIN user_presence p ON p.user_id = ANY(c.members)
+            WHERE c.id = $1 AND p.connections > 0
+            ORDER BY p.user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(presence)
+    }
+
+    /// Switch a connected user between online and away
+    pub async fn set_presence(
+        &self,
+        user_id: u64,
+        input: &SetPresence,
+    ) -> Result<Presence, AppError> {
+        if input.status == PresenceStatus::Offline {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "status",
+                "invalid",
+                "Status must be online or away",
+            )]));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let presence: Option<Presence> = sqlx::query_as(
+            r#"
+            UPDATE user_presence
+            SET status = $2,
+                updated_at = CASE WHEN status = $2 THEN updated_at ELSE CURRENT_TIMESTAMP END
+            WHERE user_id = $1 AND connections > 0
+            RETURNING user_id, status, updated_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.status)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        presence.ok_or_else(|| {
+            AppError::PermissionDenied(format!("User {user_id} has no open event stream"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_presence_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = SetPresence {
+            status: PresenceStatus::Away,
+        };
+        let ret = state.set_presence(1, &input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // what notify_server does on connect
+        sqlx::query(
+            "INSERT INTO user_presence (user_id, status, connections) VALUES (1, 'online', 1)",
+        )
+        .execute(&state.pool)
+        .await?;
+        let presence = state.set_presence(1, &input).await?;
+        assert_eq!(presence.status, PresenceStatus::Away);
+
+        let input = ListPresence {
+            ids: "1, 2,42".to_string(),
+        };
+        let presence = state.list_presence(1, &input).await?;
+        assert_eq!(presence.len(), 2);
+        assert_eq!(presence[0].status, PresenceStatus::Away);
+        assert_eq!(presence[1].status, PresenceStatus::Offline);
+        assert!(presence[1].updated_at.is_none());
+
+        let input = ListPresence {
+            ids: "1,foo".to_string(),
+        };
+        let ret = state.list_presence(1, &input).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_online_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_presence_changed").await?;
+        let chat_id: i64 = sqlx::query_scalar(
+            "SELECT id FROM chats WHERE members @> ARRAY[1, 2]::bigint[] ORDER BY id LIMIT 1",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO user_presence (user_id, status, connections) VALUES (2, 'online', 1)",
+        )
+        .execute(&state.pool)
+        .await?;
+        // a second stream changes nothing to the roster
+        sqlx::query("UPDATE user_presence SET connections = 2 WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let presence = state.list_chat_online(chat_id as _).await?;
+        let online: Vec<_> = presence.iter().map(|p| p.user_id).collect();
+        assert_eq!(online, [2]);
+
+        sqlx::query("UPDATE user_presence SET connections = 0 WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        assert!(state.list_chat_online(chat_id as _).await?.is_empty());
+
+        // a notification when they joined, then when they left, the chats are looked up
+        let mut joined = vec![];
+        for _ in 0..2 {
+            let timeout = std::time::Duration::from_secs(5);
+            let notif = tokio::time::timeout(timeout, listener.recv()).await??;
+            let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+            assert_eq!(payload["user_id"], 2);
+            assert!(payload.get("members").is_none());
+            joined.push(payload["joined"].as_bool());
+        }
+        assert_eq!(joined, [Some(true), Some(false)]);
+
+        Ok(())
+    }
+}
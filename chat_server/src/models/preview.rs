@@ -0,0 +1,320 @@
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use chat_core::{LinkPreview, Message};
+use regex::Regex;
+use reqwest::{header, redirect, Client, Url};
+use tracing::{info, warn};
+
+use crate::{config::PreviewConfig, AppError, AppState};
+
+/// max bytes of a page read to look for its metadata
+const MAX_PAGE_SIZE: usize = 512 * 1024;
+const MAX_REDIRECTS: usize = 3;
+
+static META_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<meta\s[^>]*>").expect("valid regex"));
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)([a-z][a-z0-9:_-]*)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex")
+});
+static TITLE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("valid regex"));
+
+/// OpenGraph / twitter card metadata of a page
+#[derive(Debug, Default, PartialEq)]
+struct PageMeta {
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    site_name: Option<String>,
+}
+
+/// Outbound client used to fetch link previews, redirects must stay on allowed hosts
+pub(crate) fn http_client(config: &PreviewConfig) -> Result<Client, AppError> {
+    let allowed_hosts = config.allowed_hosts.clone();
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_allowed_url(attempt.url(), &allowed_hosts) {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+
+    let client = Client::builder()
+        .timeout(Duration::from_millis(config.timeout))
+        .redirect(policy)
+        .user_agent(concat!("chat-server/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    Ok(client)
+}
+
+impl AppState {
+    /// Fetch previews of the allowed urls in the message in the background.
+    ///
+    /// Each stored preview is pushed to the chat members by the database trigger.
+    pub(crate) fn spawn_link_previews(&self, message: &Message) {
+        let config = &self.config().preview;
+        let urls = extract_urls(&message.content, &config.allowed_hosts, config.max_links);
+        if urls.is_empty() {
+            return;
+        }
+
+        let state = self.clone();
+        let message_id = message.id as u64;
+        let chat_id = message.chat_id as u64;
+        tokio::spawn(async move {
+            for url in urls {
+                match state.fetch_page_meta(&url).await {
+                    Ok(Some(meta)) => {
+                        if let Err(e) = state
+                            .save_link_preview(message_id, chat_id, url.as_str(), meta)
+                            .await
+                        {
+                            warn!("Failed to save link preview of {}: {}", url, e);
+                        }
+                    }
+                    Ok(None) => info!("No link preview for {}", url),
+                    Err(e) => warn!("Failed to fetch link preview of {}: {}", url, e),
+                }
+            }
+        });
+    }
+
+    async fn fetch_page_meta(&self, url: &Url) -> Result<Option<PageMeta>, AppError> {
+        let mut res = self
+            .http
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let is_html = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+        if !is_html {
+            return Ok(None);
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_PAGE_SIZE {
+                break;
+            }
+        }
+
+        let mut meta = parse_page_meta(&String::from_utf8_lossy(&body));
+        // relative image urls are resolved against the final page url
+        meta.image = meta
+            .image
+            .and_then(|image| res.url().join(&image).ok())
+            .map(|image| image.to_string());
+
+        if meta == PageMeta::default() {
+            return Ok(None);
+        }
+        Ok(Some(meta))
+    }
+
+    async fn save_link_preview(
+        &self,
+        message_id: u64,
+        chat_id: u64,
+        url: &str,
+        meta: PageMeta,
+    ) -> Result<Option<LinkPreview>, AppError> {
+        let preview = sqlx::query_as(
+            r#"
+            INSERT INTO link_previews (message_id, chat_id, url, title, description, image, site_name)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (message_id, url) DO NOTHING
+            RETURNING message_id, chat_id, url, title, description, image, site_name, created_at
+            "#,
+        )
+        .bind(message_id as i64)
+        .bind(chat_id as i64)
+        .bind(url)
+        .bind(meta.title)
+        .bind(meta.description)
+        .bind(meta.image)
+        .bind(meta.site_name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(preview)
+    }
+}
+
+/// Find distinct http(s) urls on allowed hosts in the content, at most `max` of them
+fn extract_urls(content: &str, allowed_hosts: &[String], max: usize) -> Vec<Url> {
+    let mut urls: Vec<Url> = vec![];
+    for word in content.split_whitespace() {
+        if urls.len() >= max {
+            break;
+        }
+        let word = word
+            .trim_start_matches(['(', '<', '[', '"', '\''])
+            .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', ']', '"', '\'']);
+        if !word.starts_with("http://") && !word.starts_with("https://") {
+            continue;
+        }
+        if let Ok(url) = Url::parse(word) {
+            if is_allowed_url(&url, allowed_hosts) && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+    urls
+}
+
+fn is_allowed_url(url: &Url, allowed_hosts: &[String]) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    allowed_hosts.iter().any(|allowed| {
+        host.eq_ignore_ascii_case(allowed)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", allowed.to_ascii_lowercase()))
+    })
+}
+
+/// Read OpenGraph and twitter card `<meta>` tags, falling back to `<title>` and `description`
+fn parse_page_meta(html: &str) -> PageMeta {
+    let mut tags = HashMap::new();
+    for tag in META_TAG.find_iter(html) {
+        let mut attrs = HashMap::new();
+        for cap in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = cap.get(2).or_else(|| cap.get(3)).map_or("", |m| m.as_str());
+            attrs.insert(cap[1].to_ascii_lowercase(), value);
+        }
+        let key = attrs.get("property").or_else(|| attrs.get("name"));
+        if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+            let content = decode_entities(content.trim());
+            if !content.is_empty() {
+                tags.entry(key.to_ascii_lowercase()).or_insert(content);
+            }
+        }
+    }
+
+    let pick = |keys: &[&str]| keys.iter().find_map(|key| tags.get(*key).cloned());
+    let title = pick(&["og:title", "twitter:title"]).or_else(|| {
+        TITLE_TAG
+            .captures(html)
+            .map(|cap| decode_entities(cap[1].trim()))
+            .filter(|title| !title.is_empty())
+    });
+
+    PageMeta {
+        title,
+        description: pick(&["og:description", "twitter:description", "description"]),
+        image: pick(&[
+            "og:image",
+            "og:image:url",
+            "twitter:image",
+            "twitter:image:src",
+        ]),
+        site_name: pick(&["og:site_name"]),
+    }
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListMessages;
+    use anyhow::Result;
+
+    #[test]
+    fn extract_urls_should_only_return_allowed_hosts() {
+        let allowed = vec!["github.com".to_string()];
+        let content = "see (https://github.com/rust-lang/rust), https://docs.github.com/en. \
+            and https://evil.com/github.com or ftp://github.com/x https://github.com/rust-lang/rust";
+        let urls = extract_urls(content, &allowed, 3);
+        assert_eq!(
+            urls.iter().map(|u| u.as_str()).collect::<Vec<_>>(),
+            vec![
+                "https://github.com/rust-lang/rust",
+                "https://docs.github.com/en"
+            ]
+        );
+
+        assert_eq!(extract_urls(content, &allowed, 1).len(), 1);
+        assert!(extract_urls(content, &[], 3).is_empty());
+    }
+
+    #[test]
+    fn parse_page_meta_should_prefer_open_graph() {
+        let html = r#"
+            <html><head>
+            <title>Fallback &amp; title</title>
+            <meta name="description" content="plain description">
+            <meta name="twitter:title" content="Twitter title" />
+            <meta property="og:title" content="OG &quot;title&quot;" />
+            <meta content='/logo.png' property='og:image'>
+            <meta property="og:site_name" content="GitHub">
+            </head></html>
+        "#;
+        let meta = parse_page_meta(html);
+        assert_eq!(
+            meta,
+            PageMeta {
+                title: Some("OG \"title\"".to_string()),
+                description: Some("plain description".to_string()),
+                image: Some("/logo.png".to_string()),
+                site_name: Some("GitHub".to_string()),
+            }
+        );
+
+        let meta = parse_page_meta("<title>Only &amp; title</title>");
+        assert_eq!(meta.title.as_deref(), Some("Only & title"));
+        assert_eq!(meta.description, None);
+    }
+
+    #[tokio::test]
+    async fn save_link_preview_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let meta = PageMeta {
+            title: Some("Rust".to_string()),
+            ..Default::default()
+        };
+        let preview = state
+            .save_link_preview(1, 1, "https://github.com/rust-lang/rust", meta)
+            .await?
+            .expect("preview should be saved");
+        assert_eq!(preview.message_id, 1);
+        assert_eq!(preview.title.as_deref(), Some("Rust"));
+
+        // the same url is only previewed once per message
+        let meta = PageMeta::default();
+        let preview = state
+            .save_link_preview(1, 1, "https://github.com/rust-lang/rust", meta)
+            .await?;
+        assert!(preview.is_none());
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 0,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        let message = messages.iter().find(|m| m.id == 1).expect("message 1");
+        assert_eq!(message.previews.len(), 1);
+        assert_eq!(message.previews[0].url, "https://github.com/rust-lang/rust");
+
+        Ok(())
+    }
+}
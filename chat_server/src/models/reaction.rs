@@ -0,0 +1,175 @@
+use chat_core::{begin_tagged, Reaction};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{AppConfig, AppError, AppState};
+
+const MAX_EMOJI_LEN: u64 = 32;
+
+#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+pub struct CreateReaction {
+    /// The emoji to react with, e.g. "👍" or ":tada:"
+    #[validate(length(
+        min = 1,
+        max = MAX_EMOJI_LEN,
+        code = "length",
+        message = "Emoji must have 1 to 32 characters"
+    ))]
+    pub emoji: String,
+}
+
+impl AppState {
+    /// React to a message, adding the same reaction twice is a no-op
+    pub async fn add_reaction(
+        &self,
+        input: CreateReaction,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<Reaction, AppError> {
+        match self.get_message_by_id(chat_id, message_id).await? {
+            Some(message) if message.deleted_at.is_none() => {}
+            _ => return Err(AppError::NotFound(format!("Message id {message_id}"))),
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let reaction = sqlx::query_as(
+            r#"
+            INSERT INTO reactions (message_id, chat_id, user_id, emoji)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (message_id, user_id, emoji) DO UPDATE SET emoji = EXCLUDED.emoji
+            RETURNING message_id, chat_id, user_id, emoji, created_at
+            "#,
+        )
+        .bind(message_id as i64)
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.emoji)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(reaction)
+    }
+
+    /// Remove a reaction of the user from a message
+    pub async fn remove_reaction(
+        &self,
+        input: CreateReaction,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        let mut tx = begin_tagged(&self.pool).await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM reactions
+            WHERE chat_id = $1 AND message_id = $2 AND user_id = $3 AND emoji = $4
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .bind(user_id as i64)
+        .bind(&input.emoji)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "Reaction {} on message {}",
+                input.emoji, message_id
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CreateReaction {
+    pub fn new(emoji: &str) -> Self {
+        Self {
+            emoji: emoji.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListMessages;
+    use anyhow::Result;
+    use validator::ValidateArgs;
+
+    #[tokio::test]
+    async fn test_add_and_remove_reaction_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let reaction = state
+            .add_reaction(CreateReaction::new("👍"), 1, 1, 1)
+            .await?;
+        assert_eq!(reaction.message_id, 1);
+        assert_eq!(reaction.chat_id, 1);
+        assert_eq!(reaction.emoji, "👍");
+
+        // duplicated reaction is a no-op
+        state
+            .add_reaction(CreateReaction::new("👍"), 1, 1, 1)
+            .await?;
+        state
+            .add_reaction(CreateReaction::new("👍"), 1, 1, 2)
+            .await?;
+        state
+            .add_reaction(CreateReaction::new("🎉"), 1, 1, 2)
+            .await?;
+
+        let messages = state.list_messages(list_all(), 1).await?.items;
+        let message = messages.iter().find(|m| m.id == 1).expect("message 1");
+        assert_eq!(message.reactions.len(), 2);
+        assert_eq!(message.reactions[0].emoji, "👍");
+        assert_eq!(message.reactions[0].count, 2);
+        assert_eq!(message.reactions[1].emoji, "🎉");
+        assert_eq!(message.reactions[1].count, 1);
+
+        state
+            .remove_reaction(CreateReaction::new("👍"), 1, 1, 1)
+            .await?;
+        let ret = state
+            .remove_reaction(CreateReaction::new("👍"), 1, 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let messages = state.list_messages(list_all(), 1).await?.items;
+        let message = messages.iter().find(|m| m.id == 1).expect("message 1");
+        assert_eq!(message.reactions[0].count, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_invalid_reaction_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let ret = CreateReaction::new("").validate_with_args(&state.config());
+        assert!(matches!(
+            ret.map_err(AppError::from),
+            Err(AppError::ValidationError(_))
+        ));
+
+        // message 1 is not in chat 2
+        let ret = state.add_reaction(CreateReaction::new("👍"), 2, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    fn list_all() -> ListMessages {
+        ListMessages {
+            cursor: None,
+            limit: 100,
+        }
+    }
+}
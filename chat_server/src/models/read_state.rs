@@ -0,0 +1,227 @@
+use chat_core::{begin_tagged, ReadState};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct MarkRead {
+    /// All messages up to and including this one are marked as read
+    pub message_id: u64,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ChatUnread {
+    pub chat_id: i64,
+    /// messages sent by others after the user's read marker
+    pub unread: i64,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ChatBadge {
+    pub chat_id: i64,
+    pub unread: i64,
+    /// unread messages mentioning the user
+    pub mentions: i64,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Badges {
+    pub chats: Vec<ChatBadge>,
+    /// unread messages of all my chats in the workspace
+    pub total_unread: i64,
+    pub total_mentions: i64,
+}
+
+impl AppState {
+    /// Mark messages of the chat as read for the user, the read marker never moves backwards
+    pub async fn mark_chat_read(
+        &self,
+        input: MarkRead,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ReadState, AppError> {
+        if self
+            .get_message_by_id(chat_id, input.message_id)
+            .await?
+            .is_none()
+        {
+            return Err(AppError::NotFound(format!(
+                "Message id {}",
+                input.message_id
+            )));
+        }
+
+        let mut tx = begin_tagged(&self.pool).await?;
+        let state = sqlx::query_as(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id, last_read_message_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_read_message_id = GREATEST(chat_members.last_read_message_id, EXCLUDED.last_read_message_id),
+                read_at = CURRENT_TIMESTAMP
+            RETURNING chat_id, user_id, last_read_message_id, read_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.message_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(state)
+    }
+}
+
+impl AppState {
+    /// Unread message counts for every chat of the user in the workspace
+    pub async fn fetch_unread_counts(
+        &self,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<ChatUnread>, AppError> {
+        let unread = sqlx::query_as(
+            r#"
+            SELECT c.id AS chat_id, count(m.id) AS unread
+            FROM chats c
+            LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $1
+            LEFT JOIN messages m ON m.chat_id = c.id
+                AND m.id > COALESCE(cm.last_read_message_id, 0)
+                AND m.sender_id <> $1
+                AND m.deleted_at IS NULL
+            WHERE c.ws_id = $2 AND $1 = ANY(c.members)
+            GROUP BY c.id
+            ORDER BY c.id
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(unread)
+    }
+
+    /// Unread and mention counts of every chat of the user with the workspace totals
+    pub async fn fetch_badges(&self, user_id: u64, ws_id: u64) -> Result<Badges, AppError> {
+        let chats: Vec<ChatBadge> = sqlx::query_as(
+            r#"
+            SELECT c.id AS chat_id, count(m.id) AS unread,
+                count(m.id) FILTER (WHERE $1 = ANY(m.mentions)) AS mentions
+            FROM chats c
+            LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $1
+            LEFT JOIN messages m ON m.chat_id = c.id
+                AND m.id > COALESCE(cm.last_read_message_id, 0)
+                AND m.sender_id <> $1
+                AND m.deleted_at IS NULL
+            WHERE c.ws_id = $2 AND $1 = ANY(c.members)
+            GROUP BY c.id
+            ORDER BY c.id
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total_unread = chats.iter().map(|v| v.unread).sum();
+        let total_mentions = chats.iter().map(|v| v.mentions).sum();
+        Ok(Badges {
+            chats,
+            total_unread,
+            total_mentions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, ListMessages};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_mark_chat_read_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let read = state
+            .mark_chat_read(MarkRead { message_id: 5 }, 1, 2)
+            .await?;
+        assert_eq!(read.last_read_message_id, 5);
+
+        // read marker never moves backwards
+        let read = state
+            .mark_chat_read(MarkRead { message_id: 3 }, 1, 2)
+            .await?;
+        assert_eq!(read.last_read_message_id, 5);
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 100,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        for message in messages {
+            // user 2 sent message 2 itself
+            if message.id <= 5 && message.id != 2 {
+                assert_eq!(message.read_by, vec![2]);
+            } else {
+                assert!(message.read_by.is_empty());
+            }
+        }
+
+        // message 1 is not in chat 2
+        let ret = state.mark_chat_read(MarkRead { message_id: 1 }, 2, 2).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_unread_counts_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // user 1 sent 4 of the 10 messages in chat 1
+        let unread = state.fetch_unread_counts(1, 1).await?;
+        assert_eq!(unread.len(), 4);
+        assert_eq!(
+            unread[0],
+            ChatUnread {
+                chat_id: 1,
+                unread: 6
+            }
+        );
+        assert!(unread[1..].iter().all(|v| v.unread == 0));
+
+        state
+            .mark_chat_read(MarkRead { message_id: 5 }, 1, 1)
+            .await?;
+        let unread = state.fetch_unread_counts(1, 1).await?;
+        assert_eq!(unread[0].unread, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_badges_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreateMessage {
+            content: "hi @1".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        state.create_message(input, 4, 3).await?;
+
+        let badges = state.fetch_badges(1, 1).await?;
+        assert_eq!(badges.chats.len(), 4);
+        assert_eq!(badges.total_unread, 7);
+        assert_eq!(badges.total_mentions, 1);
+        let chat = badges.chats.iter().find(|v| v.chat_id == 4).unwrap();
+        assert_eq!((chat.unread, chat.mentions), (1, 1));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::{info, warn};
+
+use crate::{AppError, AppState};
+
+/// max number of rows deleted in one statement, keeps locks short
+const PURGE_BATCH: i64 = 1000;
+
+/// What a purge run removed
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PurgeStats {
+    pub messages: u64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, FromRow)]
+struct PurgedFile {
+    hash: String,
+    size: i64,
+}
+
+impl AppState {
+    /// Hard delete messages sent before the cutoff of their workspace, then files uploaded
+    /// before it that no message references anymore. The workspaces with their own
+    /// `retention_days` get their own cutoff, the others get `cutoff`, none keeps them.
+    ///
+    /// A message whose thread still has newer replies is kept until the replies expire.
+    pub async fn purge_before(
+        &self,
+        cutoff: Option<DateTime<Utc>>,
+    ) -> Result<PurgeStats, AppError> {
+        let mut stats = PurgeStats::default();
+
+        // replies go first so that a thread root is only deleted with or after its replies
+        loop {
+            let ret = sqlx::query(
+                r#"
+                WITH cutoffs AS (
+                    SELECT id AS ws_id, workspace_cutoff(settings, $1) AS cutoff FROM workspaces
+                )
+                DELETE FROM messages
+                WHERE id IN (
+                    SELECT m.id FROM messages m
+                    JOIN chats c ON c.id = m.chat_id
+                    JOIN cutoffs w ON w.ws_id = c.ws_id
+                    WHERE m.created_at < w.cutoff
+                        AND NOT EXISTS (
+                            SELECT 1 FROM messages r
+                            WHERE r.parent_id = m.id AND r.created_at >= w.cutoff
+                        )
+                    ORDER BY m.parent_id IS NULL, m.id
+                    LIMIT $2
+                )
+                "#,
+            )
+            .bind(cutoff)
+            .bind(PURGE_BATCH)
+            .execute(&self.pool)
+            .await?;
+
+            stats.messages += ret.rows_affected();
+            if ret.rows_affected() < PURGE_BATCH as u64 {
+                break;
+            }
+        }
+
+        loop {
+            let files: Vec<PurgedFile> = sqlx::query_as(
+                r#"
+                DELETE FROM attachments
+                WHERE id IN (
+                    SELECT a.id FROM attachments a
+                    JOIN workspaces w ON w.id = a.ws_id
+                    WHERE a.created_at < workspace_cutoff(w.settings, $1)
+                        AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.files @> ARRAY[a.url])
+                        AND NOT EXISTS (SELECT 1 FROM scheduled_messages s WHERE s.files @> ARRAY[a.url])
+                    LIMIT $2
+                )
+                RETURNING hash, size
+                "#,
+            )
+            .bind(cutoff)
+            .bind(PURGE_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+
+            let count = files.len();
+            self.release_purged_files(files, &mut stats).await?;
+            if count < PURGE_BATCH as usize {
+                break;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Purge the content of a deleted workspace in the background
+    pub(crate) fn spawn_workspace_purge(&self, ws_id: u64) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            match state.purge_workspace(ws_id).await {
+                Ok(stats) => info!(
+                    "Purged {} messages and {} files ({} bytes) of workspace {}",
+                    stats.messages, stats.files, stats.bytes, ws_id
+                ),
+                Err(e) => warn!("Failed to purge workspace {}: {}", ws_id, e),
+            }
+        });
+    }
+
+    /// Hard delete the messages, chats and files of a deleted workspace, it's marked purged
+    /// once done and the sweep resumes the purges left halfway
+    pub async fn purge_workspace(&self, ws_id: u64) -> Result<PurgeStats, AppError> {
+        let mut stats = PurgeStats::default();
+
+        // replies go first so that a thread root is only deleted with or after its replies
+        loop {
+            let ret = sqlx::query(
+                r#"
+                DELETE FROM messages
+                WHERE id IN (
+                    SELECT m.id FROM messages m
+                    JOIN chats c ON c.id = m.chat_id
+                    JOIN workspaces w ON w.id = c.ws_id
+                    WHERE c.ws_id = $1 AND w.deleted_at IS NOT NULL
+                    ORDER BY m.parent_id IS NULL, m.id
+                    LIMIT $2
+                )
+                "#,
+            )
+            .bind(ws_id as i64)
+            .bind(PURGE_BATCH)
+            .execute(&self.pool)
+            .await?;
+
+            stats.messages += ret.rows_affected();
+            if ret.rows_affected() < PURGE_BATCH as u64 {
+                break;
+            }
+        }
+
+        // the rest of the chat data is deleted by the cascades
+        sqlx::query(
+            r#"
+            DELETE FROM chats
+            WHERE ws_id = $1 AND EXISTS (
+                SELECT 1 FROM workspaces WHERE id = $1 AND deleted_at IS NOT NULL
+            )
+            "#,
+        )
+        .bind(ws_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        loop {
+            let files: Vec<PurgedFile> = sqlx::query_as(
+                r#"
+                DELETE FROM attachments
+                WHERE id IN (
+                    SELECT a.id FROM attachments a
+                    JOIN workspaces w ON w.id = a.ws_id
+                    WHERE a.ws_id = $1 AND w.deleted_at IS NOT NULL
+                    LIMIT $2
+                )
+                RETURNING hash, size
+                "#,
+            )
+            .bind(ws_id as i64)
+            .bind(PURGE_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+
+            let count = files.len();
+            self.release_purged_files(files, &mut stats).await?;
+            if count < PURGE_BATCH as usize {
+                break;
+            }
+        }
+
+        sqlx::query(
+            "UPDATE workspaces SET purged_at = CURRENT_TIMESTAMP WHERE id = $1 AND deleted_at IS NOT NULL",
+        )
+        .bind(ws_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(stats)
+    }
+
+    /// Anonymize a deleted account in the background
+    pub(crate) fn spawn_user_purge(&self, user_id: u64) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            match state.purge_user(user_id).await {
+                Ok(stats) => info!(
+                    "Purged {} files ({} bytes) of user {}",
+                    stats.files, stats.bytes, user_id
+                ),
+                Err(e) => warn!("Failed to purge user {}: {}", user_id, e),
+            }
+        });
+    }
+
+    /// Anonymize the profile of a deleted account and delete the files it uploaded.
+    ///
+    /// The messages are kept so that threads stay intact, they show the anonymized author.
+    /// The account is marked purged once done, the sweep resumes the purges left halfway.
+    pub async fn purge_user(&self, user_id: u64) -> Result<PurgeStats, AppError> {
+        let mut stats = PurgeStats::default();
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE users
+            SET full_name = 'Deleted User',
+                email = 'deleted-' || id || '@deleted.invalid',
+                password_hash = '',
+                avatar_url = NULL,
+                status = NULL,
+                email_verified = FALSE
+            WHERE id = $1 AND deleted_at IS NOT NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(stats);
+        }
+
+        // private data the other members never see
+        for sql in [
+            "DELETE FROM email_verifications WHERE user_id = $1",
+            "DELETE FROM refresh_tokens WHERE user_id = $1",
+            "DELETE FROM devices WHERE user_id = $1",
+            "DELETE FROM saved_messages WHERE user_id = $1",
+            "DELETE FROM chat_settings WHERE user_id = $1",
+            "DELETE FROM scheduled_messages WHERE sender_id = $1",
+        ] {
+            sqlx::query(sql)
+                .bind(user_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        loop {
+            let files: Vec<PurgedFile> = sqlx::query_as(
+                r#"
+                DELETE FROM attachments
+                WHERE id IN (
+                    SELECT id FROM attachments
+                    WHERE uploader_id = $1
+                    LIMIT $2
+                )
+                RETURNING hash, size
+                "#,
+            )
+            .bind(user_id as i64)
+            .bind(PURGE_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+
+            let count = files.len();
+            self.release_purged_files(files, &mut stats).await?;
+            if count < PURGE_BATCH as usize {
+                break;
+            }
+        }
+
+        sqlx::query("UPDATE users SET purged_at = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(stats)
+    }
+
+    /// Purge the deleted workspaces and accounts not purged yet, e.g. a server stopped while
+    /// purging them. The number of workspaces and accounts purged is returned.
+    pub async fn resume_purges(&self) -> Result<u64, AppError> {
+        let ws_ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT id FROM workspaces WHERE deleted_at IS NOT NULL AND purged_at IS NULL ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut purged = 0;
+        for ws_id in ws_ids {
+            match self.purge_workspace(ws_id as _).await {
+                Ok(_) => purged += 1,
+                Err(e) => warn!("Failed to purge workspace {}: {}", ws_id, e),
+            }
+        }
+
+        let user_ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT id FROM users WHERE deleted_at IS NOT NULL AND purged_at IS NULL ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        for user_id in user_ids {
+            match self.purge_user(user_id as _).await {
+                Ok(_) => purged += 1,
+                Err(e) => warn!("Failed to purge user {}: {}", user_id, e),
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Drop the content of the purged files no other file refers to
+    async fn release_purged_files(
+        &self,
+        files: Vec<PurgedFile>,
+        stats: &mut PurgeStats,
+    ) -> Result<(), AppError> {
+        let mut hashes = Vec::with_capacity(files.len());
+        for file in files {
+            stats.files += 1;
+            stats.bytes += file.size as u64;
+            hashes.push(file.hash);
+        }
+        self.release_blobs(&hashes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatFile, CreateMessage, ListMessages, UpdateWorkspaceSettings};
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_purge_before_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // message 1 keeps a fresh reply, message 2 has an old reply
+        for parent_id in [1, 2] {
+            let input = CreateMessage {
+                content: "reply".to_string(),
+                files: vec![],
+                parent_id: Some(parent_id),
+                send_at: None,
+            };
+            state.create_message(input, 1, 3).await?;
+        }
+        sqlx::query(
+            "UPDATE messages SET created_at = now() - interval '10 days' WHERE id <= 10 OR parent_id = 2",
+        )
+        .execute(&state.pool)
+        .await?;
+
+        let file = ChatFile::new(1, "old.txt", b"old");
+        state
+            .create_attachment(&file, "old.txt", 3, "text/plain", 1)
+            .await?;
+        sqlx::query("UPDATE attachments SET created_at = now() - interval '10 days'")
+            .execute(&state.pool)
+            .await?;
+
+        let stats = state
+            .purge_before(Some(Utc::now() - Duration::days(1)))
+            .await?;
+        assert_eq!(
+            stats,
+            PurgeStats {
+                messages: 10,
+                files: 1,
+                bytes: 3,
+            }
+        );
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 0,
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, 1);
+        assert_eq!(messages[0].reply_count, 1);
+
+        // nothing left to purge
+        let stats = state
+            .purge_before(Some(Utc::now() - Duration::days(1)))
+            .await?;
+        assert_eq!(stats, PurgeStats::default());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_before_should_apply_the_retention_of_the_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        sqlx::query("UPDATE messages SET created_at = now() - interval '10 days' WHERE id <= 5")
+            .execute(&state.pool)
+            .await?;
+        let stats = state.purge_before(None).await?;
+        assert_eq!(stats, PurgeStats::default());
+
+        let input = UpdateWorkspaceSettings {
+            retention_days: Some(7),
+            ..Default::default()
+        };
+        state.update_workspace_settings(1, input).await?;
+        // the workspace keeps its messages for less than the server
+        let stats = state
+            .purge_before(Some(Utc::now() - Duration::days(30)))
+            .await?;
+        assert_eq!(stats.messages, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let file = ChatFile::new(1, "test.txt", b"test");
+        state
+            .create_attachment(&file, "test.txt", 4, "text/plain", 1)
+            .await?;
+
+        // the workspace must be deleted first
+        let stats = state.purge_workspace(1).await?;
+        assert_eq!(stats, PurgeStats::default());
+
+        state.delete_workspace(1, 1).await?;
+        let stats = state.purge_workspace(1).await?;
+        assert_eq!(stats.messages, 10);
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.bytes, 4);
+        assert!(state.get_chat_by_id(1).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_user_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let file = ChatFile::new(1, "test.txt", b"test");
+        state
+            .create_attachment(&file, "test.txt", 4, "text/plain", 3)
+            .await?;
+
+        // the account must be deleted first
+        let stats = state.purge_user(3).await?;
+        assert_eq!(stats, PurgeStats::default());
+
+        sqlx::query(r#"UPDATE users SET status = '{"text": "away"}' WHERE id = 3"#)
+            .execute(&state.pool)
+            .await?;
+        state.delete_user(3).await?;
+        let stats = state.purge_user(3).await?;
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.bytes, 4);
+
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        assert_eq!(user.full_name, "Deleted User");
+        let cleared: bool = sqlx::query_scalar("SELECT status IS NULL FROM users WHERE id = 3")
+            .fetch_one(&state.pool)
+            .await?;
+        assert!(cleared);
+        assert_eq!(user.email, "deleted-3@deleted.invalid");
+        // the messages stay
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM messages WHERE sender_id = 3")
+            .fetch_one(&state.pool)
+            .await?;
+        assert!(count > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_purges_should_purge_what_is_left() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // the server stopped before purging the account and the workspace
+        state.delete_user(3).await?;
+        state.delete_workspace(1, 1).await?;
+        assert_eq!(state.resume_purges().await?, 2);
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        assert_eq!(user.full_name, "Deleted User");
+        assert!(state.get_chat_by_id(1).await?.is_none());
+        assert_eq!(state.resume_purges().await?, 0);
+
+        Ok(())
+    }
+}
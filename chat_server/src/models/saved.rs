@@ -0,0 +1,160 @@
+use chat_core::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState, ListMessages, Page};
+
+use super::messages::page_limit;
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize)]
+pub struct SavedMessage {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    pub saved_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Bookmark a message, the user must be a member of the message's chat
+    pub async fn save_message(&self, id: u64, user_id: u64) -> Result<SavedMessage, AppError> {
+        let saved = sqlx::query_as(
+            r#"
+            WITH saved AS (
+                INSERT INTO saved_messages (user_id, message_id)
+                SELECT $2, m.id
+                FROM messages m
+                JOIN chats c ON c.id = m.chat_id
+                WHERE m.id = $1 AND m.deleted_at IS NULL AND $2 = ANY(c.members)
+                ON CONFLICT (user_id, message_id) DO UPDATE SET created_at = saved_messages.created_at
+                RETURNING message_id, created_at
+            )
+            SELECT m.id, m.chat_id, m.sender_id, m.parent_id, m.content, m.files, m.mentions,
+                m.created_at, m.updated_at, m.deleted_at, saved.created_at AS saved_at
+            FROM saved
+            JOIN messages m ON m.id = saved.message_id
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        saved.ok_or_else(|| AppError::NotFound(format!("Message id {id}")))
+    }
+
+    pub async fn unsave_message(&self, id: u64, user_id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM saved_messages
+            WHERE user_id = $1 AND message_id = $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("Saved message id {id}")));
+        }
+
+        Ok(())
+    }
+
+    /// List saved messages that the user can still see, newest message first
+    pub async fn list_saved_messages(
+        &self,
+        input: ListMessages,
+        user_id: u64,
+    ) -> Result<Page<SavedMessage>, AppError> {
+        let cursor = self.decode_cursor(input.cursor.as_deref())?;
+        let (op, order) = cursor.sql();
+        let limit = page_limit(input.limit);
+
+        let sql = format!(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.parent_id, m.content, m.files, m.mentions,
+                m.created_at, m.updated_at, m.deleted_at, s.created_at AS saved_at
+            FROM saved_messages s
+            JOIN messages m ON m.id = s.message_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE s.user_id = $1 AND $1 = ANY(c.members) AND m.deleted_at IS NULL AND m.id {op} $2
+            ORDER BY m.id {order}
+            LIMIT $3
+            "#
+        );
+        let saved: Vec<SavedMessage> = sqlx::query_as(&sql)
+            .bind(user_id as i64)
+            .bind(cursor.key)
+            .bind(limit + 1)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(self.paginate(saved, cursor, limit, |s| s.message.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_save_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let saved = state.save_message(1, 4).await?;
+        assert_eq!(saved.message.id, 1);
+        state.save_message(3, 4).await?;
+        // saving twice is a no-op
+        state.save_message(3, 4).await?;
+
+        let input = ListMessages {
+            cursor: None,
+            limit: 1,
+        };
+        let page = state.list_saved_messages(input, 4).await?;
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].message.id, 3);
+
+        let input = ListMessages {
+            cursor: page.older,
+            limit: 10,
+        };
+        let saved = state.list_saved_messages(input, 4).await?.items;
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].message.id, 1);
+
+        state.unsave_message(1, 4).await?;
+        let ret = state.unsave_message(1, 4).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_message_of_other_chat_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let message = state
+            .create_message(
+                crate::CreateMessage {
+                    content: "secret".to_string(),
+                    files: vec![],
+                    parent_id: None,
+                    send_at: None,
+                },
+                2,
+                1,
+            )
+            .await?;
+
+        // user 4 is not in chat 2
+        let ret = state.save_message(message.id as _, 4).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use chat_core::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use super::messages::insert_message;
+use crate::{AppError, AppState, CreateMessage};
+
+/// max number of scheduled messages delivered in one run
+const DELIVERY_BATCH: i64 = 100;
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub parent_id: Option<i64>,
+    pub content: String,
+    pub files: Vec<String>,
+    pub send_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Store a message to be delivered at `input.send_at`, which must be in the future
+    pub async fn schedule_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage, AppError> {
+        let send_at = match input.send_at {
+            Some(send_at) if send_at > Utc::now() => send_at,
+            _ => {
+                return Err(AppError::CreateMessageError(
+                    "send_at must be in the future".to_string(),
+                ))
+            }
+        };
+
+        let input = self.validate_message(input, chat_id).await?;
+
+        let scheduled = sqlx::query_as(
+            r#"
+            INSERT INTO scheduled_messages (chat_id, sender_id, parent_id, content, files, send_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, chat_id, sender_id, parent_id, content, files, send_at, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.parent_id.map(|v| v as i64))
+        .bind(input.content)
+        .bind(input.files)
+        .bind(send_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    /// List pending scheduled messages of the user, the next one to be sent first
+    pub async fn list_scheduled_messages(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<ScheduledMessage>, AppError> {
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, parent_id, content, files, send_at, created_at
+            FROM scheduled_messages
+            WHERE sender_id = $1
+            ORDER BY send_at ASC, id ASC
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    pub async fn cancel_scheduled_message(&self, id: u64, user_id: u64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM scheduled_messages
+            WHERE id = $1 AND sender_id = $2
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("Scheduled message id {id}")));
+        }
+
+        Ok(())
+    }
+
+    /// Send all scheduled messages that are due through the normal message path.
+    ///
+    /// Messages whose sender left the chat or that no longer pass validation are dropped.
+    pub async fn deliver_scheduled_messages(&self) -> Result<Vec<Message>, AppError> {
+        let due: Vec<ScheduledMessage> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, parent_id, content, files, send_at, created_at
+            FROM scheduled_messages
+            WHERE send_at <= now()
+            ORDER BY send_at ASC, id ASC
+            LIMIT $1
+            "#,
+        )
+        .bind(DELIVERY_BATCH)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::with_capacity(due.len());
+        for scheduled in due {
+            match self.deliver_scheduled_message(&scheduled).await {
+                Ok(Some(msg)) => messages.push(msg),
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Failed to deliver scheduled message {}: {}",
+                    scheduled.id, e
+                ),
+            }
+        }
+        messages.sort_by_key(|m| m.id);
+
+        Ok(messages)
+    }
+
+    /// Deliver the message unless a concurrent run did, it's left for the next run if the
+    /// database fails
+    async fn deliver_scheduled_message(
+        &self,
+        scheduled: &ScheduledMessage,
+    ) -> Result<Option<Message>, AppError> {
+        let chat_id = scheduled.chat_id as u64;
+        let sender_id = scheduled.sender_id as u64;
+        let prepared = match self.is_chat_member(chat_id, sender_id).await? {
+            true => {
+                let input = CreateMessage {
+                    content: scheduled.content.clone(),
+                    files: scheduled.files.clone(),
+                    parent_id: scheduled.parent_id.map(|v| v as u64),
+                    send_at: None,
+                };
+                self.prepare_message(input, chat_id, sender_id).await
+            }
+            false => Err(AppError::PermissionDenied(format!(
+                "User {sender_id} is no longer a member of chat {chat_id}"
+            ))),
+        };
+
+        // claimed along with the delivery, so a message is sent once and only once it's gone
+        let mut tx = self.pool.begin().await?;
+        let claimed: Option<i64> = sqlx::query_scalar(
+            r#"
+            DELETE FROM scheduled_messages
+            WHERE id IN (
+                SELECT id FROM scheduled_messages
+                WHERE id = $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id
+            "#,
+        )
+        .bind(scheduled.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if claimed.is_none() {
+            return Ok(None);
+        }
+
+        let (input, mentions) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e @ AppError::SqlxError(_)) => return Err(e),
+            // dropped for good
+            Err(e) => {
+                tx.commit().await?;
+                return Err(e);
+            }
+        };
+        let message = insert_message(&mut tx, input, mentions, chat_id, sender_id).await?;
+        tx.commit().await?;
+
+        self.spawn_link_previews(&message);
+
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    fn scheduled_input(content: &str, send_at: DateTime<Utc>) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: Some(send_at),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schedule_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = scheduled_input("later", Utc::now() + Duration::hours(1));
+        let scheduled = state.schedule_message(input, 1, 1).await?;
+        assert_eq!(scheduled.chat_id, 1);
+        assert_eq!(scheduled.content, "later");
+
+        let list = state.list_scheduled_messages(1).await?;
+        assert_eq!(list, vec![scheduled.clone()]);
+        assert!(state.list_scheduled_messages(2).await?.is_empty());
+
+        // only the sender can cancel it
+        assert!(state
+            .cancel_scheduled_message(scheduled.id as _, 2)
+            .await
+            .is_err());
+        state.cancel_scheduled_message(scheduled.id as _, 1).await?;
+        assert!(state.list_scheduled_messages(1).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_schedule_message_in_the_past_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = scheduled_input("too late", Utc::now() - Duration::minutes(1));
+        let err = state.schedule_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: send_at must be in the future"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_scheduled_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = scheduled_input("due", Utc::now() + Duration::hours(1));
+        let due = state.schedule_message(input, 1, 1).await?;
+        let input = scheduled_input("not yet", Utc::now() + Duration::hours(1));
+        state.schedule_message(input, 1, 2).await?;
+        sqlx::query("UPDATE scheduled_messages SET send_at = now() WHERE id = $1")
+            .bind(due.id)
+            .execute(&state.pool)
+            .await?;
+
+        let messages = state.deliver_scheduled_messages().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "due");
+        assert_eq!(messages[0].sender_id, 1);
+        assert!(state.list_scheduled_messages(1).await?.is_empty());
+        assert_eq!(state.list_scheduled_messages(2).await?.len(), 1);
+
+        // nothing left to deliver
+        assert!(state.deliver_scheduled_messages().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_scheduled_messages_should_keep_the_failed_ones() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = scheduled_input("fail", Utc::now() + Duration::hours(1));
+        let failing = state.schedule_message(input, 1, 1).await?;
+        let input = scheduled_input("left", Utc::now() + Duration::hours(1));
+        state.schedule_message(input, 4, 4).await?;
+        sqlx::query("UPDATE scheduled_messages SET send_at = now()")
+            .execute(&state.pool)
+            .await?;
+        // the insert of the message fails
+        sqlx::query("ALTER TABLE messages ADD CONSTRAINT no_fail CHECK (content <> 'fail')")
+            .execute(&state.pool)
+            .await?;
+        sqlx::query("UPDATE chats SET members = array_remove(members, 4) WHERE id = 4")
+            .execute(&state.pool)
+            .await?;
+
+        assert!(state.deliver_scheduled_messages().await?.is_empty());
+        // the one of a sender who left is dropped, the other is tried again
+        assert!(state.list_scheduled_messages(4).await?.is_empty());
+        let left = state.list_scheduled_messages(1).await?;
+        assert_eq!(left.iter().map(|m| m.id).collect::<Vec<_>>(), [failing.id]);
+
+        sqlx::query("ALTER TABLE messages DROP CONSTRAINT no_fail")
+            .execute(&state.pool)
+            .await?;
+        let messages = state.deliver_scheduled_messages().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "fail");
+
+        Ok(())
+    }
+}
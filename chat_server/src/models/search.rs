@@ -0,0 +1,201 @@
+use chat_core::Message;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+use super::messages::page_limit;
+
+// ts_headline marks the hits with these, they can't be confused with the html of the content
+const MARK_START: char = '\u{2}';
+const MARK_STOP: char = '\u{3}';
+
+#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct SearchMessages {
+    /// Search query, supports `"quoted phrases"`, `or` and `-exclusions`
+    pub q: String,
+    /// Page size - defaults to 20 when 0 or missing, capped at 100
+    #[serde(default)]
+    pub limit: u64,
+    /// Number of results to skip
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize)]
+pub struct SearchResult {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    pub rank: f32,
+    /// html escaped matched content with the hits wrapped in `<mark></mark>`
+    pub snippet: String,
+}
+
+impl AppState {
+    /// Search messages in the user's chats of the workspace, or in a single chat, best match first
+    pub async fn search_messages(
+        &self,
+        input: SearchMessages,
+        user_id: u64,
+        ws_id: u64,
+        chat_id: Option<u64>,
+    ) -> Result<Vec<SearchResult>, AppError> {
+        let q = input.q.trim();
+        if q.is_empty() {
+            return Err(AppError::SearchError("Query cannot be empty".to_string()));
+        }
+
+        let mut results: Vec<SearchResult> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.parent_id, m.content, m.files, m.mentions,
+                m.created_at, m.updated_at, m.deleted_at,
+                ts_rank(m.search_vector, query) AS rank,
+                ts_headline('simple', m.content, query, $7) AS snippet
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id,
+                websearch_to_tsquery('simple', $1) query
+            WHERE m.search_vector @@ query AND m.deleted_at IS NULL
+                AND c.ws_id = $2 AND $3 = ANY(c.members)
+                AND ($4::bigint IS NULL OR m.chat_id = $4)
+            ORDER BY rank DESC, m.id DESC
+            LIMIT $5 OFFSET $6
+            "#,
+        )
+        .bind(q)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(chat_id.map(|v| v as i64))
+        .bind(page_limit(input.limit))
+        .bind(input.offset as i64)
+        .bind(format!(
+            r#"StartSel="{MARK_START}", StopSel="{MARK_STOP}", MaxFragments=2"#
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        for result in &mut results {
+            result.snippet = escape_html(&result.snippet)
+                .replace(MARK_START, "<mark>")
+                .replace(MARK_STOP, "</mark>");
+        }
+
+        Ok(results)
+    }
+}
+
+/// Escape the wildcards of a LIKE pattern, the text is matched as written
+pub(crate) fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape the html special chars, so the snippet can only carry our own `<mark>` tags
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+impl SearchMessages {
+    pub fn new(q: &str) -> Self {
+        Self {
+            q: q.to_string(),
+            limit: 0,
+            offset: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, UpdateMessage};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_search_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let ret = state
+            .search_messages(SearchMessages::new("world"), 1, 1, None)
+            .await?;
+        assert_eq!(ret.len(), 4);
+        assert!(ret[0].snippet.contains("<mark>world</mark>"));
+
+        let input = CreateMessage {
+            content: "the world is big".to_string(),
+            files: vec![],
+            parent_id: None,
+            send_at: None,
+        };
+        let message = state.create_message(input, 2, 1).await?;
+
+        // workspace wide search covers all my chats
+        let ret = state
+            .search_messages(SearchMessages::new("world"), 1, 1, None)
+            .await?;
+        assert_eq!(ret.len(), 5);
+
+        // chat search only covers the chat
+        let ret = state
+            .search_messages(SearchMessages::new("world"), 1, 1, Some(2))
+            .await?;
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].message.id, message.id);
+
+        // user 4 is not in chat 2
+        let ret = state
+            .search_messages(SearchMessages::new("big"), 4, 1, None)
+            .await?;
+        assert!(ret.is_empty());
+
+        // edited content is re-indexed
+        let input = UpdateMessage {
+            content: "the universe is bigger".to_string(),
+        };
+        state.update_message(input, 2, message.id as _, 1).await?;
+        let ret = state
+            .search_messages(SearchMessages::new("universe"), 1, 1, None)
+            .await?;
+        assert_eq!(ret.len(), 1);
+
+        // the content is escaped, only the hits are marked up
+        let input = UpdateMessage {
+            content: "<script>alert('xss')</script> the universe <img src=x onerror=alert(1)>"
+                .to_string(),
+        };
+        state.update_message(input, 2, message.id as _, 1).await?;
+        let ret = state
+            .search_messages(SearchMessages::new("universe"), 1, 1, None)
+            .await?;
+        assert_eq!(ret.len(), 1);
+        assert!(!ret[0].snippet.contains("<script>"));
+        assert!(!ret[0].snippet.contains("<img"));
+        assert!(ret[0].snippet.contains("<mark>universe</mark> &lt;img"));
+
+        let ret = state
+            .search_messages(SearchMessages::new("  "), 1, 1, None)
+            .await;
+        assert!(matches!(ret, Err(AppError::SearchError(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState, ValidationIssue};
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct MuteChat {
+    /// seconds to mute the chat for, muted until unmuted if not set
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// Per user settings of a chat, notify_server reads the mute state from it
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ChatSettings {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub muted: bool,
+    pub muted_until: Option<DateTime<Utc>>,
+    /// overrides the default of the user, none follows it
+    pub notify_level: Option<NotifyLevel>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Which new messages the user is notified of
+#[derive(
+    Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, Eq, sqlx::Type,
+)]
+#[sqlx(type_name = "notify_level", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    /// only the messages mentioning the user
+    Mentions,
+    Nothing,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct SetNotifyLevel {
+    /// the chat to set the level of, the default of the user if not set
+    #[serde(default)]
+    pub chat_id: Option<u64>,
+    /// none only for a chat, it follows the default again
+    #[serde(default)]
+    pub level: Option<NotifyLevel>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct ChatNotifyLevel {
+    pub chat_id: i64,
+    pub level: NotifyLevel,
+}
+
+/// The notification levels of the user, notify_server skips the new messages they don't
+/// want to be told about
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct NotificationPreferences {
+    /// the level of the chats without one of their own
+    pub default: NotifyLevel,
+    pub chats: Vec<ChatNotifyLevel>,
+    /// mail a digest of the mentions and direct messages missed while offline
+    pub email_digest: bool,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SetEmailDigest {
+    pub enabled: bool,
+}
+
+impl AppState {
+    /// Stop NewMessage notifications of the chat for the user, messages still show up in history
+    pub async fn mute_chat(
+        &self,
+        input: MuteChat,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ChatSettings, AppError> {
+        let muted_until = match input.duration {
+            Some(0) => {
+                return Err(AppError::UpdateChatError(
+                    "Mute duration must be positive".to_string(),
+                ))
+            }
+            Some(secs) => {
+                let secs = i64::try_from(secs).map_err(|_| {
+                    AppError::UpdateChatError("Mute duration is too long".to_string())
+                })?;
+                Some(Utc::now() + Duration::seconds(secs))
+            }
+            None => None,
+        };
+
+        self.upsert_mute(chat_id, user_id, true, muted_until).await
+    }
+
+    pub async fn unmute_chat(&self, chat_id: u64, user_id: u64) -> Result<ChatSettings, AppError> {
+        self.upsert_mute(chat_id, user_id, false, None).await
+    }
+
+    pub async fn get_chat_settings(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Option<ChatSettings>, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            SELECT chat_id, user_id, muted, muted_until, notify_level, updated_at
+            FROM chat_settings
+            WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(settings)
+    }
+
+    /// The default notification level of the user and the ones of their chats
+    pub async fn get_notification_preferences(
+        &self,
+        user_id: u64,
+    ) -> Result<NotificationPreferences, AppError> {
+        let user: Option<(NotifyLevel, bool)> =
+            sqlx::query_as("SELECT notify_level, email_digest FROM users WHERE id = $1")
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some((default, email_digest)) = user else {
+            return Err(AppError::NotFound(format!("user id {user_id}")));
+        };
+        let chats = sqlx::query_as(
+            r#"
+            SELECT chat_id, notify_level AS level
+            FROM chat_settings
+            WHERE user_id = $1 AND notify_level IS NOT NULL
+            ORDER BY chat_id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(NotificationPreferences {
+            default,
+            chats,
+            email_digest,
+        })
+    }
+
+    /// Turn the email digests of the user on or off
+    pub async fn set_email_digest(
+        &self,
+        input: SetEmailDigest,
+        user_id: u64,
+    ) -> Result<NotificationPreferences, AppError> {
+        sqlx::query("UPDATE users SET email_digest = $2 WHERE id = $1")
+            .bind(user_id as i64)
+            .bind(input.enabled)
+            .execute(&self.pool)
+            .await?;
+
+        self.get_notification_preferences(user_id).await
+    }
+
+    /// Set the default notification level of the user, or the one of a chat of theirs
+    pub async fn set_notify_level(
+        &self,
+        input: SetNotifyLevel,
+        user_id: u64,
+    ) -> Result<NotificationPreferences, AppError> {
+        match (input.chat_id, input.level) {
+            (None, None) => {
+                return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                    "level",
+                    "required",
+                    "The default level can't be unset",
+                )]))
+            }
+            (None, Some(level)) => {
+                sqlx::query("UPDATE users SET notify_level = $2 WHERE id = $1")
+                    .bind(user_id as i64)
+                    .bind(level)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            (Some(chat_id), level) => {
+                if !self.is_chat_member(chat_id, user_id).await? {
+                    return Err(AppError::PermissionDenied(format!(
+                        "User {user_id} is not a member of chat {chat_id}"
+                    )));
+                }
+                sqlx::query(
+                    r#"
+                    INSERT INTO chat_settings (chat_id, user_id, notify_level)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (chat_id, user_id) DO UPDATE
+                    SET notify_level = EXCLUDED.notify_level, updated_at = CURRENT_TIMESTAMP
+                    "#,
+                )
+                .bind(chat_id as i64)
+                .bind(user_id as i64)
+                .bind(level)
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
+        self.get_notification_preferences(user_id).await
+    }
+
+    async fn upsert_mute(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        muted: bool,
+        muted_until: Option<DateTime<Utc>>,
+    ) -> Result<ChatSettings, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            INSERT INTO chat_settings (chat_id, user_id, muted, muted_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET muted = EXCLUDED.muted, muted_until = EXCLUDED.muted_until,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING chat_id, user_id, muted, muted_until, notify_level, updated_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(muted)
+        .bind(muted_until)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_mute_chat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        assert!(state.get_chat_settings(1, 2).await?.is_none());
+
+        let settings = state.mute_chat(MuteChat::default(), 1, 2).await?;
+        assert!(settings.muted);
+        assert!(settings.muted_until.is_none());
+
+        let input = MuteChat {
+            duration: Some(3600),
+        };
+        let settings = state.mute_chat(input, 1, 2).await?;
+        let until = settings.muted_until.expect("muted_until should be set");
+        assert!(until > Utc::now() + Duration::seconds(3500));
+
+        let settings = state.unmute_chat(1, 2).await?;
+        assert!(!settings.muted);
+        assert_eq!(state.get_chat_settings(1, 2).await?, Some(settings));
+
+        let input = MuteChat { duration: Some(0) };
+        let err = state.mute_chat(input, 1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::UpdateChatError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_notify_level_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let prefs = state.get_notification_preferences(2).await?;
+        assert_eq!(prefs.default, NotifyLevel::All);
+        assert!(prefs.chats.is_empty());
+        assert!(prefs.email_digest);
+        let prefs = state
+            .set_email_digest(SetEmailDigest { enabled: false }, 2)
+            .await?;
+        assert!(!prefs.email_digest);
+
+        let input = SetNotifyLevel {
+            chat_id: None,
+            level: Some(NotifyLevel::Nothing),
+        };
+        let prefs = state.set_notify_level(input, 2).await?;
+        assert_eq!(prefs.default, NotifyLevel::Nothing);
+
+        let input = SetNotifyLevel {
+            chat_id: Some(1),
+            level: Some(NotifyLevel::Mentions),
+        };
+        let prefs = state.set_notify_level(input, 2).await?;
+        let chat = ChatNotifyLevel {
+            chat_id: 1,
+            level: NotifyLevel::Mentions,
+        };
+        assert_eq!(prefs.chats, vec![chat]);
+        let settings = state.get_chat_settings(1, 2).await?.expect("settings");
+        assert!(!settings.muted);
+        assert_eq!(settings.notify_level, Some(NotifyLevel::Mentions));
+
+        // back to the default
+        let input = SetNotifyLevel {
+            chat_id: Some(1),
+            level: None,
+        };
+        assert!(state.set_notify_level(input, 2).await?.chats.is_empty());
+
+        let err = state
+            .set_notify_level(SetNotifyLevel::default(), 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::ValidationError(_)));
+        let input = SetNotifyLevel {
+            chat_id: Some(3),
+            level: Some(NotifyLevel::All),
+        };
+        let err = state.set_notify_level(input, 5).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        Ok(())
+    }
+}
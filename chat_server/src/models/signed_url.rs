@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use hmac_sha256::{Hash, HMAC};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState, ChatFile, Rendition, ThumbnailSize};
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SignFile {
+    /// Url of the file, e.g. `/files/1/dfb/d31/a22376042aef61b5df0c538dbc8f0031b9.jpeg`
+    pub url: String,
+    /// Sign the url of a thumbnail of the image instead
+    #[serde(default)]
+    pub size: Option<ThumbnailSize>,
+    /// Sign the url of a rendition of the audio or the video instead
+    #[serde(default)]
+    pub rendition: Option<Rendition>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedFileUrl {
+    /// Url of the file under `/api`, fetched without the token
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct SignedFile {
+    #[serde(default)]
+    pub size: Option<ThumbnailSize>,
+    #[serde(default)]
+    pub rendition: Option<Rendition>,
+    /// Unix timestamp the url expires at
+    pub expires: i64,
+    /// Signature of the url, in hex
+    pub sig: String,
+}
+
+impl AppState {
+    /// Sign the url of a file of the user's workspace, so it can be fetched without the token
+    /// until it expires
+    pub fn sign_file_url(&self, ws_id: u64, input: &SignFile) -> Result<SignedFileUrl, AppError> {
+        let config = &self.config().files;
+        if !config.signed_urls {
+            return Err(AppError::NotFound("Signed urls are disabled".to_string()));
+        }
+        let file: ChatFile = input.url.parse()?;
+        if file.ws_id != ws_id {
+            return Err(AppError::NotFound(
+                "File not found or you don't have access".to_string(),
+            ));
+        }
+
+        let expires_at = Utc::now() + chrono::Duration::seconds(config.signed_url_ttl as _);
+        let expires = expires_at.timestamp();
+        let sig = self.file_signature(&file, input.size, input.rendition, expires);
+        let size = input
+            .size
+            .map(|size| format!("size={}&", size.as_str()))
+            .unwrap_or_default();
+        let rendition = input
+            .rendition
+            .map(|rendition| format!("rendition={}&", rendition.as_str()))
+            .unwrap_or_default();
+        let path = file.url().replacen("/files/", "/files/signed/", 1);
+        Ok(SignedFileUrl {
+            url: format!("{path}?{size}{rendition}expires={expires}&sig={sig}"),
+            expires_at,
+        })
+    }
+
+    /// Check the signature and the expiry of a signed file url
+    pub fn verify_file_signature(
+        &self,
+        file: &ChatFile,
+        input: &SignedFile,
+    ) -> Result<(), AppError> {
+        let invalid = || AppError::Unauthorized("File url is invalid or expired".to_string());
+        if !self.config().files.signed_urls || input.expires < Utc::now().timestamp() {
+            return Err(invalid());
+        }
+        let sig = self.file_signature(file, input.size, input.rendition, input.expires);
+        // constant time, so the signature can't be guessed byte by byte
+        let diff = sig
+            .bytes()
+            .zip(input.sig.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 || sig.len() != input.sig.len() {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    fn file_signature(
+        &self,
+        file: &ChatFile,
+        size: Option<ThumbnailSize>,
+        rendition: Option<Rendition>,
+        expires: i64,
+    ) -> String {
+        let key = Hash::hash(format!("signed-file-url:{}", self.config().auth.sk).as_bytes());
+        let size = size.map(|size| size.as_str()).unwrap_or_default();
+        let rendition = rendition
+            .map(|rendition| rendition.as_str())
+            .unwrap_or_default();
+        let message = format!("{}\n{size}\n{rendition}\n{expires}", file.url());
+        hex::encode(HMAC::mac(message, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::extract::Query;
+
+    #[tokio::test]
+    async fn test_signed_file_url_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        let input = SignFile {
+            url: file.url(),
+            size: None,
+            rendition: None,
+        };
+        // disabled by default
+        let ret = state.sign_file_url(1, &input);
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.update_config(|config| config.files.signed_urls = true);
+        let ret = state.sign_file_url(2, &input);
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let signed = state.sign_file_url(1, &input)?;
+        assert!(signed.url.starts_with("/files/signed/1/"));
+        let Query(params) = Query::<SignedFile>::try_from_uri(&signed.url.parse()?)?;
+        assert!(state.verify_file_signature(&file, &params).is_ok());
+
+        // the signature covers the size, the expiry and the file
+        let thumbnail = SignedFile {
+            size: Some(ThumbnailSize::Small),
+            ..params.clone()
+        };
+        assert!(state.verify_file_signature(&file, &thumbnail).is_err());
+        let rendition = SignedFile {
+            rendition: Some(Rendition::Web),
+            ..params.clone()
+        };
+        assert!(state.verify_file_signature(&file, &rendition).is_err());
+        let later = SignedFile {
+            expires: params.expires + 1,
+            ..params.clone()
+        };
+        assert!(state.verify_file_signature(&file, &later).is_err());
+        let other = ChatFile::new(1, "dog.png", b"woof");
+        assert!(state.verify_file_signature(&other, &params).is_err());
+
+        let expired = SignedFile {
+            expires: 1,
+            sig: state.file_signature(&file, None, None, 1),
+            size: None,
+            rendition: None,
+        };
+        assert!(state.verify_file_signature(&file, &expired).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use chat_core::WorkspaceRole;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// the stats are computed with full scans, so they are reused for a while
+const STATS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// number of days covered by `messages_per_day`, today included
+const STATS_DAYS: i32 = 30;
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct DailyMessages {
+    pub date: NaiveDate,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceStats {
+    pub members: i64,
+    pub chats: i64,
+    /// messages sent each day, oldest first, days without messages included
+    #[sqlx(skip)]
+    pub messages_per_day: Vec<DailyMessages>,
+    /// total size of the uploaded files in bytes
+    pub storage_bytes: i64,
+}
+
+impl AppState {
+    /// Usage of the workspace, only its admins can see it
+    pub async fn get_workspace_stats(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceStats, AppError> {
+        match self.get_workspace_role(ws_id, user_id).await? {
+            Some(role) if role >= WorkspaceRole::Admin => {}
+            Some(_) => {
+                return Err(AppError::PermissionDenied(format!(
+                    "User {user_id} is not an admin of workspace {ws_id}"
+                )))
+            }
+            None => return Err(AppError::NotFound(format!("Workspace id {ws_id}"))),
+        }
+
+        if let Some(entry) = self.stats_cache.get(&ws_id) {
+            let (at, stats) = entry.value();
+            if at.elapsed() < STATS_CACHE_TTL {
+                return Ok(stats.clone());
+            }
+        }
+
+        let stats = self.fetch_workspace_stats(ws_id).await?;
+        self.stats_cache
+            .insert(ws_id, (Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+
+    async fn fetch_workspace_stats(&self, ws_id: u64) -> Result<WorkspaceStats, AppError> {
+        let mut stats: WorkspaceStats = sqlx::query_as(
+            r#"
+            SELECT
+                (SELECT count(*) FROM workspace_members WHERE ws_id = $1) AS members,
+                (SELECT count(*) FROM chats WHERE ws_id = $1) AS chats,
+                (SELECT COALESCE(sum(size), 0)::bigint FROM attachments WHERE ws_id = $1) AS storage_bytes
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        stats.messages_per_day = sqlx::query_as(
+            r#"
+            SELECT d.date, count(m.id) AS count
+            FROM (
+                SELECT generate_series(CURRENT_DATE - ($2 - 1), CURRENT_DATE, interval '1 day')::date AS date
+            ) d
+            LEFT JOIN messages m
+                ON m.created_at >= d.date AND m.created_at < d.date + 1
+                AND m.chat_id IN (SELECT id FROM chats WHERE ws_id = $1)
+            GROUP BY d.date
+            ORDER BY d.date
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(STATS_DAYS)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatFile;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn get_workspace_stats_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // bob is a member, not an admin
+        let ret = state.get_workspace_stats(1, 3).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.get_workspace_stats(2, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let file = ChatFile::new(1, "test.txt", b"test");
+        state
+            .create_attachment(&file, "test.txt", 4, "text/plain", 1)
+            .await?;
+
+        let stats = state.get_workspace_stats(1, 2).await?;
+        assert_eq!(stats.members, 5);
+        assert_eq!(stats.chats, 4);
+        assert_eq!(stats.storage_bytes, 4);
+        assert_eq!(stats.messages_per_day.len(), STATS_DAYS as usize);
+        let today = stats.messages_per_day.last().expect("today should exist");
+        assert_eq!(today.count, 10);
+
+        // cached for a while
+        state
+            .create_attachment(
+                &ChatFile::new(1, "a.txt", b"a"),
+                "a.txt",
+                1,
+                "text/plain",
+                1,
+            )
+            .await?;
+        let cached = state.get_workspace_stats(1, 1).await?;
+        assert_eq!(cached, stats);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{AccessToken, User};
+use chrono::{DateTime, Utc};
+use hmac_sha256::Hash;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// days a refresh token stays valid
+pub(crate) const REFRESH_TOKEN_TTL: i32 = 30;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct Signout {
+    /// Refresh token of the session, it's revoked as well when given
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshTokenRow {
+    user_id: i64,
+    ws_id: i64,
+    token_version: i32,
+    expires_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Create a refresh token for the session of the user, the access tokens it renews
+    /// are scoped to the same workspace
+    pub async fn create_refresh_token(&self, user: &User) -> Result<String, AppError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (token_hash, user_id, ws_id, token_version, expires_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+            "#,
+        )
+        .bind(hash_token(&token))
+        .bind(user.id)
+        .bind(user.ws_id)
+        .bind(user.token_version)
+        .bind(REFRESH_TOKEN_TTL)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Consume a refresh token and return the user of its session, a token can only be used once
+    pub async fn use_refresh_token(&self, token: &str) -> Result<User, AppError> {
+        let row: Option<RefreshTokenRow> = sqlx::query_as(
+            r#"
+            DELETE FROM refresh_tokens
+            WHERE token_hash = $1
+            RETURNING user_id, ws_id, token_version, expires_at
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let invalid = || AppError::Unauthorized("Refresh token is invalid or expired".to_string());
+        let row = row
+            .filter(|row| row.expires_at > Utc::now())
+            .ok_or_else(invalid)?;
+        let user = self
+            .find_user_by_id(row.user_id)
+            .await?
+            .filter(|user| user.token_version == row.token_version)
+            .ok_or_else(invalid)?;
+
+        // the membership may be gone or deactivated since the token was issued, the session
+        // moves on to another workspace of the user then
+        match self.resolve_workspace(user, row.ws_id as _).await {
+            Ok(user) => Ok(user),
+            Err(AppError::NotFound(_)) => Err(invalid()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Revoke the access token until it expires and the refresh token of the session,
+    /// other sessions of the user stay signed in
+    pub async fn signout(
+        &self,
+        user_id: u64,
+        token: &AccessToken,
+        input: &Signout,
+    ) -> Result<(), AppError> {
+        if let (Some(jti), Some(expires_at)) = (&token.jti, token.expires_at) {
+            // expired tokens are rejected anyway, their rows are dropped along the way
+            sqlx::query("DELETE FROM token_denylist WHERE expires_at < NOW()")
+                .execute(&self.pool)
+                .await?;
+            sqlx::query(
+                r#"
+                INSERT INTO token_denylist (jti, expires_at)
+                VALUES ($1, $2)
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(jti)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        if let Some(refresh_token) = &input.refresh_token {
+            sqlx::query(
+                r#"
+                DELETE FROM refresh_tokens
+                WHERE token_hash = $1 AND user_id = $2
+                "#,
+            )
+            .bind(hash_token(refresh_token))
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) fn hash_token(token: &str) -> String {
+    hex::encode(Hash::hash(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChangePassword;
+    use anyhow::Result;
+    use chat_core::middlewares::TokenVerify;
+
+    #[tokio::test]
+    async fn test_refresh_token_should_rotate() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let user = state.switch_workspace(user, 1).await?;
+        let token = state.create_refresh_token(&user).await?;
+        let refreshed = state.use_refresh_token(&token).await?;
+        assert_eq!(refreshed.id, user.id);
+        assert_eq!(refreshed.ws_id, 1);
+        assert_eq!(refreshed.ws_name, "acme");
+
+        // used tokens are gone
+        let ret = state.use_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_should_be_revoked_by_password_change() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.create_refresh_token(&user).await?;
+        let input = ChangePassword {
+            current_password: "123456".to_string(),
+            new_password: "hunter4242".to_string(),
+        };
+        state.change_password(&user, &input).await?;
+        let ret = state.use_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_should_be_refused_to_deactivated_members() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let owner = state.switch_workspace(owner, 1).await?;
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        let token = state.create_refresh_token(&user).await?;
+        state.set_member_deactivated(&owner, 3, true).await?;
+        let ret = state.use_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signout_should_revoke_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.ek.sign(user.clone())?;
+        let other = state.ek.sign(user.clone())?;
+        let refresh_token = state.create_refresh_token(&user).await?;
+        assert!(state.verify(&token).await.is_ok());
+
+        let access = state.dk.verify_access_token(&token)?;
+        let input = Signout {
+            refresh_token: Some(refresh_token.clone()),
+        };
+        state.signout(user.id as _, &access, &input).await?;
+        let ret = state.verify(&token).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+        let ret = state.use_refresh_token(&refresh_token).await;
+        assert!(matches!(ret, Err(AppError::Unauthorized(_))));
+
+        // other sessions are left alone
+        assert!(state.verify(&other).await.is_ok());
+
+        Ok(())
+    }
+}
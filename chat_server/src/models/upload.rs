@@ -0,0 +1,179 @@
+use chat_core::{middlewares::current_request_id, UploadProgress, UploadStage};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState, ValidationIssue};
+
+/// channel the notify server listens on for the progress of the uploads
+const UPLOAD_PROGRESS_CHANNEL: &str = "upload_progress";
+const MAX_UPLOAD_ID_LEN: usize = 64;
+/// without a total, the bytes received are told every this many bytes
+const RECEIVING_STEP: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct UploadParams {
+    /// The progress of the upload is sent as UploadProgress events to the uploader,
+    /// keyed to this id
+    #[serde(default)]
+    pub upload_id: Option<String>,
+}
+
+/// what is told to the uploader about an upload
+#[derive(Debug, Clone)]
+pub struct UploadTracker {
+    pub upload_id: String,
+    pub user_id: u64,
+    pub filename: String,
+    pub received: u64,
+    pub total: Option<u64>,
+}
+
+impl UploadParams {
+    /// A tracker for the upload if the client asked for its progress
+    pub fn tracker(
+        &self,
+        user_id: u64,
+        total: Option<u64>,
+    ) -> Result<Option<UploadTracker>, AppError> {
+        let Some(upload_id) = &self.upload_id else {
+            return Ok(None);
+        };
+        let valid = upload_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if upload_id.is_empty() || upload_id.len() > MAX_UPLOAD_ID_LEN || !valid {
+            return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                "upload_id",
+                "format",
+                format!("Upload id must be 1 to {MAX_UPLOAD_ID_LEN} letters, digits, '-' or '_'"),
+            )]));
+        }
+
+        Ok(Some(UploadTracker {
+            upload_id: upload_id.clone(),
+            user_id,
+            filename: String::new(),
+            received: 0,
+            total,
+        }))
+    }
+}
+
+impl UploadTracker {
+    /// Count bytes received, true when the progress moved enough to be told: a percent of
+    /// the total, or a megabyte without one
+    pub fn receive(&mut self, len: u64) -> bool {
+        let before = self.received;
+        self.received += len;
+        match self.total {
+            Some(total) if total > 0 => before * 100 / total != self.received * 100 / total,
+            _ => before / RECEIVING_STEP != self.received / RECEIVING_STEP,
+        }
+    }
+
+    pub fn progress(&self, stage: UploadStage) -> UploadProgress {
+        UploadProgress {
+            upload_id: self.upload_id.clone(),
+            filename: self.filename.clone(),
+            stage,
+            received: self.received,
+            total: self.total,
+            url: None,
+            error: None,
+        }
+    }
+}
+
+impl AppState {
+    /// Tell the uploader where the server is with an upload, the upload goes on whether
+    /// it's told or not
+    async fn report_upload(&self, tracker: &UploadTracker, progress: UploadProgress) {
+        let payload = json!({
+            "progress": progress,
+            "members": [tracker.user_id],
+            "request_id": current_request_id(),
+        });
+        let ret = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(UPLOAD_PROGRESS_CHANNEL)
+            .bind(payload.to_string())
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = ret {
+            warn!("Failed to report upload {}: {}", tracker.upload_id, e);
+        }
+    }
+
+    /// Tell the uploader the upload reached a stage, the bytes received included
+    pub(crate) async fn report_upload_stage(
+        &self,
+        tracker: Option<&UploadTracker>,
+        stage: UploadStage,
+    ) {
+        if let Some(t) = tracker {
+            self.report_upload(t, t.progress(stage)).await;
+        }
+    }
+
+    /// Tell the uploader the file is ready to be sent
+    pub(crate) async fn report_upload_done(&self, tracker: Option<&UploadTracker>, url: &str) {
+        if let Some(t) = tracker {
+            let progress = UploadProgress {
+                url: Some(url.to_string()),
+                ..t.progress(UploadStage::Done)
+            };
+            self.report_upload(t, progress).await;
+        }
+    }
+
+    /// Tell the uploader the upload failed
+    pub(crate) async fn report_upload_failed(
+        &self,
+        tracker: Option<&UploadTracker>,
+        error: impl ToString,
+    ) {
+        if let Some(t) = tracker {
+            let progress = UploadProgress {
+                error: Some(error.to_string()),
+                ..t.progress(UploadStage::Failed)
+            };
+            self.report_upload(t, progress).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn upload_tracker_should_report_every_percent() -> Result<()> {
+        let params = UploadParams {
+            upload_id: Some("up-1".to_string()),
+        };
+        let mut tracker = params
+            .tracker(1, Some(1000))?
+            .expect("tracker should exist");
+        assert!(!tracker.receive(5));
+        assert!(tracker.receive(5));
+        assert!(tracker.receive(500));
+        assert_eq!(tracker.progress(UploadStage::Receiving).received, 510);
+
+        let mut tracker = params.tracker(1, None)?.expect("tracker should exist");
+        assert!(!tracker.receive(RECEIVING_STEP - 1));
+        assert!(tracker.receive(1));
+
+        let none = UploadParams::default();
+        assert!(none.tracker(1, None)?.is_none());
+        let invalid = UploadParams {
+            upload_id: Some("../etc".to_string()),
+        };
+        assert!(matches!(
+            invalid.tracker(1, None),
+            Err(AppError::ValidationError(_))
+        ));
+        Ok(())
+    }
+}
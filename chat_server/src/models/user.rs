@@ -1,39 +1,119 @@
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHasher, PasswordVerifier, SaltString,
+    },
     Argon2, PasswordHash,
 };
-use chat_core::{ChatUser, User};
+use chat_core::{begin_tagged, is_token_outdated, ChatUser, User, UserStatus, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use image::{imageops::FilterType, ImageFormat};
 use serde::{Deserialize, Serialize};
-use std::mem;
-use utoipa::ToSchema;
+use sqlx::types::Json;
+use std::{io::Cursor, mem};
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use super::{messages::page_limit, search::escape_like};
+use crate::{
+    AppConfig, AppError, AppState, ChatFile, InvitePolicy, Page, ValidationIssue, WorkspaceSettings,
+};
+
+const MIN_PASSWORD_LEN: u64 = 8;
+const MAX_STATUS_EMOJI_LEN: usize = 16;
+const MAX_STATUS_TEXT_LEN: usize = 100;
 
-use crate::{AppError, AppState};
+/// avatars are cropped to a square of this size in pixels
+const AVATAR_SIZE: u32 = 256;
 
 /// create a user with email and password
-#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
 pub struct CreateUser {
     /// Full name of the user
+    #[validate(length(
+        min = 1,
+        max = 64,
+        code = "length",
+        message = "Full name must have 1 to 64 characters"
+    ))]
     pub full_name: String,
     /// Email of the user
+    #[validate(
+        email(code = "email", message = "Email is invalid"),
+        length(
+            max = 64,
+            code = "too_long",
+            message = "Email must have at most 64 characters"
+        )
+    )]
     pub email: String,
     /// Workspace name - if not exists, create one
+    #[validate(length(
+        min = 1,
+        max = 32,
+        code = "length",
+        message = "Workspace must have 1 to 32 characters"
+    ))]
     pub workspace: String,
-    /// Password of the user
+    /// Password of the user, at least 8 characters
+    #[validate(length(
+        min = MIN_PASSWORD_LEN,
+        code = "too_short",
+        message = "Password must have at least 8 characters"
+    ))]
     pub password: String,
 }
 
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct ListUsers {
+    /// Prefix of the full name or the email, case-insensitively
+    #[serde(default)]
+    pub q: Option<String>,
+    /// Opaque cursor from the `older` or `newer` field of a previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Page size - defaults to 20 when 0 or missing, capped at 100
+    #[serde(default)]
+    pub limit: u64,
+}
+
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct SigninUser {
     pub email: String,
     pub password: String,
 }
 
+/// an empty emoji and text clear the status
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct SetUserStatus {
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+    /// the status is cleared after this time
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, Validate)]
+#[validate(context = AppConfig)]
+pub struct ChangePassword {
+    /// The password the user signs in with now
+    pub current_password: String,
+    /// The new password, at least 8 characters
+    #[validate(length(
+        min = MIN_PASSWORD_LEN,
+        code = "too_short",
+        message = "Password must have at least 8 characters"
+    ))]
+    pub new_password: String,
+}
+
 #[allow(dead_code)]
 impl AppState {
     /// Find a user by email
     pub async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, full_name, email, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at FROM users WHERE email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -45,7 +125,7 @@ impl AppState {
     /// Find a user by id
     pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "SELECT id, ws_id, full_name, email, created_at FROM users WHERE id = $1",
+            "SELECT id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at FROM users WHERE id = $1",
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -64,9 +144,21 @@ impl AppState {
         }
 
         // check if workspace exists, if not create one
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+        let (ws, settings) = match self.find_workspace_by_name(&input.workspace).await? {
+            Some(ws) => {
+                let settings = self.get_workspace_settings(ws.id as _).await?;
+                if settings.invite_policy == InvitePolicy::InviteOnly {
+                    return Err(AppError::PermissionDenied(format!(
+                        "Workspace {} is invite only",
+                        ws.name
+                    )));
+                }
+                (ws, settings)
+            }
+            None => (
+                self.create_workspace(&input.workspace, 0).await?,
+                WorkspaceSettings::default(),
+            ),
         };
 
         let password_hash = hash_password(&input.password)?;
@@ -74,7 +166,7 @@ impl AppState {
             r#"
             INSERT INTO users (ws_id, email, full_name, password_hash)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, full_name, email, created_at
+            RETURNING id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at
             "#,
         )
         .bind(ws.id)
@@ -86,9 +178,21 @@ impl AppState {
 
         user.ws_name = ws.name.clone();
 
+        if let Err(e) = self.send_verification_email(&user).await {
+            warn!("Failed to send verification email to {}: {}", user.email, e);
+        }
+
         if ws.owner_id == 0 {
             self.update_workspace_owner(ws.id as _, user.id as _)
                 .await?;
+            user.role = WorkspaceRole::Owner;
+        }
+
+        // a default channel may have been removed or filled up meanwhile, it shouldn't fail the signup
+        for chat_id in settings.default_channels {
+            if let Err(e) = self.join_chat(chat_id as _, user.id as _, ws.id as _).await {
+                warn!("Failed to join default channel {}: {}", chat_id, e);
+            }
         }
 
         Ok(user)
@@ -97,7 +201,7 @@ impl AppState {
     /// Verify email and password
     pub async fn verify_user(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, full_name, email, password_hash, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, full_name, email, email_verified, avatar_url, token_version, password_hash, created_at FROM users WHERE email = $1 AND deleted_at IS NULL",
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
@@ -109,10 +213,9 @@ impl AppState {
                 let is_valid =
                     verify_password(&input.password, &password_hash.unwrap_or_default())?;
                 if is_valid {
-                    // load workspace name
-                    let ws = self.find_workspace_by_id(user.ws_id as _).await?.unwrap();
-                    user.ws_name = ws.name;
-                    Ok(Some(user))
+                    // the home workspace, or another one when it's gone
+                    let ws_id = user.ws_id as _;
+                    Ok(Some(self.resolve_workspace(user, ws_id).await?))
                 } else {
                     Ok(None)
                 }
@@ -121,35 +224,381 @@ impl AppState {
         }
     }
 
-    pub async fn fetch_chat_users_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
-        let users = sqlx::query_as(
+    /// Change the password and revoke all the tokens issued before,
+    /// the returned user has the new token version to sign a fresh token with
+    pub async fn change_password(
+        &self,
+        user: &User,
+        input: &ChangePassword,
+    ) -> Result<User, AppError> {
+        let password_hash: Option<String> =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+                .bind(user.id)
+                .fetch_optional(&self.pool)
+                .await?
+                .flatten();
+        let Some(password_hash) = password_hash else {
+            return Err(AppError::NotFound(format!("User id {}", user.id)));
+        };
+        if !verify_password(&input.current_password, &password_hash)? {
+            return Err(AppError::PermissionDenied(
+                "Invalid current password".to_string(),
+            ));
+        }
+
+        let password_hash = hash_password(&input.new_password)?;
+        let mut updated: User = sqlx::query_as(
             r#"
-            SELECT id, full_name, email
-            FROM users
-            WHERE id = ANY($1)
+            UPDATE users
+            SET password_hash = $2, token_version = token_version + 1
+            WHERE id = $1
+            RETURNING id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at
             "#,
         )
-        .bind(ids)
-        .fetch_all(&self.pool)
+        .bind(user.id)
+        .bind(password_hash)
+        .fetch_one(&self.pool)
         .await?;
 
-        Ok(users)
+        updated.ws_name = user.ws_name.clone();
+        updated.role = user.role;
+        Ok(updated)
+    }
+
+    /// Check the version the token was issued with against the user's current one
+    pub async fn is_token_revoked(&self, user: &User) -> Result<bool, AppError> {
+        Ok(is_token_outdated(&self.pool, user).await?)
     }
 
-    pub async fn fetch_chat_users(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
+    /// Delete the account: its tokens are revoked at once and the profile is anonymized
+    /// by the purge, see `spawn_user_purge` and `spawn_purge_sweep`
+    pub async fn delete_user(&self, user_id: u64) -> Result<User, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET deleted_at = CURRENT_TIMESTAMP, token_version = token_version + 1
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::NotFound(format!("User id {user_id}")));
+        };
+
+        // checked in the transaction, the workspaces can't be handed over to the user meanwhile
+        let owned: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM workspaces
+            WHERE owner_id = $1 AND deleted_at IS NULL
+            LIMIT 1
+            FOR UPDATE
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(ws_id) = owned {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} owns workspace {ws_id}, transfer its ownership first"
+            )));
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM workspace_members
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    /// Delete the account of a user whose home is the admin's workspace,
+    /// only users with a lower role than the admin can be deleted
+    pub async fn admin_delete_user(&self, admin: &User, user_id: u64) -> Result<User, AppError> {
+        let role = self.get_workspace_role(admin.ws_id as _, user_id).await?;
+        let user = self.find_user_by_id(user_id as _).await?;
+        let (Some(role), Some(user)) = (role, user) else {
+            return Err(AppError::NotFound(format!("User id {user_id}")));
+        };
+        if user.ws_id != admin.ws_id {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} belongs to workspace {}",
+                user.ws_id
+            )));
+        }
+        if role >= admin.role {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} is a workspace {role:?}"
+            )));
+        }
+
+        self.delete_user(user_id).await
+    }
+
+    /// Replace the password of a user of the admin's workspace with a random one mailed
+    /// to the user, all of the user's tokens are revoked
+    pub async fn reset_user_password(&self, admin: &User, user_id: u64) -> Result<(), AppError> {
+        self.get_managed_role(admin, user_id).await?;
+        let Some(user) = self.find_user_by_id(user_id as _).await? else {
+            return Err(AppError::NotFound(format!("User id {user_id}")));
+        };
+        if user.ws_id != admin.ws_id {
+            return Err(AppError::PermissionDenied(format!(
+                "User {user_id} belongs to workspace {}",
+                user.ws_id
+            )));
+        }
+
+        let mut bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut bytes);
+        let password = hex::encode(bytes);
+        let password_hash = hash_password(&password)?;
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $2, token_version = token_version + 1
+            WHERE id = $1
+            "#,
+        )
+        .bind(user.id)
+        .bind(password_hash)
+        .execute(&self.pool)
+        .await?;
+
+        let body = format!(
+            "Hi {},\n\nAn admin of {} reset your password, sign in with the temporary password below and change it:\n\n{}\n",
+            user.full_name, admin.ws_name, password
+        );
+        let state = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state
+                .send_mail(&user.email, "Your password was reset", body)
+                .await
+            {
+                warn!(
+                    "Failed to send password reset email to {}: {}",
+                    user.email, e
+                );
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Set or clear the custom status, the workspaces of the user get a `UserStatusChanged` event
+    pub async fn set_user_status(
+        &self,
+        user_id: u64,
+        input: SetUserStatus,
+    ) -> Result<ChatUser, AppError> {
+        let status = validate_user_status(input)?;
+        let mut tx = begin_tagged(&self.pool).await?;
+        let user: Option<ChatUser> = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET status = $2
+            WHERE id = $1
+            RETURNING id, full_name, email, avatar_url,
+                COALESCE(active_user_status(status), 'null') AS status
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(status.map(Json))
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        user.ok_or_else(|| AppError::NotFound(format!("User id {user_id}")))
+    }
+
+    /// Resize the uploaded image into the user's avatar and store its url on the user
+    pub async fn update_user_avatar(&self, user: &User, data: &[u8]) -> Result<User, AppError> {
+        let data = data.to_vec();
+        let avatar = tokio::task::spawn_blocking(move || resize_avatar(&data))
+            .await
+            .map_err(|e| AppError::ChatFileError(e.to_string()))??;
+        let attachment = self
+            .save_upload(
+                user.ws_id as _,
+                user.id as _,
+                "avatar.png",
+                Some("image/png".to_string()),
+                &avatar,
+            )
+            .await?;
+
+        // the attachment keeps the content, the url is the same in all the user's workspaces
+        let mut updated: User = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET avatar_url = $2
+            WHERE id = $1
+            RETURNING id, ws_id, full_name, email, email_verified, avatar_url, token_version, created_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(avatar_url(user.id, &attachment.hash))
+        .fetch_one(&self.pool)
+        .await?;
+
+        updated.ws_name = user.ws_name.clone();
+        updated.role = user.role;
+        Ok(updated)
+    }
+
+    /// The uploaded file of the avatar at the url, if its user is a member of the workspace
+    pub async fn get_user_avatar(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        url: &str,
+    ) -> Result<Option<ChatFile>, AppError> {
+        let file: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT a.url
+            FROM users u
+            JOIN attachments a ON a.uploader_id = u.id
+            WHERE u.id = $1 AND u.avatar_url = $2
+                AND u.avatar_url = '/avatars/' || u.id || '/' || a.hash || '.png'
+                AND EXISTS (
+                    SELECT 1 FROM workspace_members wm
+                    WHERE wm.user_id = u.id AND wm.ws_id = $3
+                )
+            LIMIT 1
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(url)
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        file.map(|url| url.parse()).transpose()
+    }
+
+    pub async fn fetch_chat_users_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-            SELECT id, full_name, email
+            SELECT id, full_name, email, avatar_url,
+                COALESCE(active_user_status(status), 'null') AS status
             FROM users
-            WHERE ws_id = $1
+            WHERE id = ANY($1)
             "#,
         )
-        .bind(ws_id as i64)
+        .bind(ids)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(users)
     }
+
+    /// Users of the workspace matching the search, by id
+    pub async fn fetch_chat_users(
+        &self,
+        ws_id: u64,
+        input: ListUsers,
+    ) -> Result<Page<ChatUser>, AppError> {
+        let cursor = self.decode_cursor(input.cursor.as_deref())?;
+        let (op, order) = cursor.sql();
+        let limit = page_limit(input.limit);
+        let q = input.q.filter(|q| !q.trim().is_empty());
+
+        // the cursors walk down their key, the key of a user is -id so the ids go up
+        let sql = format!(
+            r#"
+            SELECT u.id, u.full_name, u.email, u.avatar_url,
+                COALESCE(active_user_status(u.status), 'null') AS status
+            FROM users u
+            JOIN workspace_members wm ON wm.user_id = u.id
+            WHERE wm.ws_id = $1 AND wm.deactivated_at IS NULL AND -u.id {op} $2
+                AND ($4::text IS NULL OR u.full_name ILIKE $4 || '%' ESCAPE '\'
+                    OR u.email ILIKE $4 || '%' ESCAPE '\')
+            ORDER BY -u.id {order}
+            LIMIT $3
+            "#
+        );
+        let users: Vec<ChatUser> = sqlx::query_as(&sql)
+            .bind(ws_id as i64)
+            .bind(cursor.key)
+            .bind(limit + 1)
+            .bind(q.map(|q| escape_like(q.trim())))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(self.paginate(users, cursor, limit, |u| -u.id))
+    }
+}
+
+fn validate_user_status(input: SetUserStatus) -> Result<Option<UserStatus>, AppError> {
+    let emoji = input.emoji.filter(|v| !v.trim().is_empty());
+    let text = input.text.filter(|v| !v.trim().is_empty());
+    if emoji.is_none() && text.is_none() {
+        return Ok(None);
+    }
+
+    let mut issues = vec![];
+    if emoji
+        .as_ref()
+        .is_some_and(|v| v.chars().count() > MAX_STATUS_EMOJI_LEN)
+    {
+        issues.push(ValidationIssue::new(
+            "emoji",
+            "too_long",
+            format!("Emoji must have at most {MAX_STATUS_EMOJI_LEN} characters"),
+        ));
+    }
+    if text
+        .as_ref()
+        .is_some_and(|v| v.chars().count() > MAX_STATUS_TEXT_LEN)
+    {
+        issues.push(ValidationIssue::new(
+            "text",
+            "too_long",
+            format!("Text must have at most {MAX_STATUS_TEXT_LEN} characters"),
+        ));
+    }
+    if input.expires_at.is_some_and(|v| v <= Utc::now()) {
+        issues.push(ValidationIssue::new(
+            "expires_at",
+            "in_past",
+            "Expiry must be in the future",
+        ));
+    }
+    if !issues.is_empty() {
+        return Err(AppError::ValidationError(issues));
+    }
+
+    Ok(Some(UserStatus {
+        emoji,
+        text,
+        expires_at: input.expires_at,
+    }))
+}
+
+/// crop the image to a square around its center and encode it as png
+/// url of the avatar of the user, served to the members of any of their workspaces
+fn avatar_url(user_id: i64, hash: &str) -> String {
+    format!("/avatars/{user_id}/{hash}.png")
+}
+
+fn resize_avatar(data: &[u8]) -> Result<Vec<u8>, AppError> {
+    let img = image::load_from_memory(data)
+        .map_err(|e| AppError::ChatFileError(format!("Invalid avatar image: {e}")))?;
+    let img = img.resize_to_fill(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+    let mut buf = Cursor::new(Vec::new());
+    img.write_to(&mut buf, ImageFormat::Png)
+        .map_err(|e| AppError::ChatFileError(e.to_string()))?;
+    Ok(buf.into_inner())
 }
 
 fn hash_password(password: &str) -> Result<String, AppError> {
@@ -204,7 +653,9 @@ impl SigninUser {
 mod tests {
 
     use super::*;
+    use crate::UpdateWorkspaceSettings;
     use anyhow::Result;
+    use validator::ValidateArgs;
 
     #[test]
     fn test_hash_password_and_verify_should_work() -> Result<()> {
@@ -262,6 +713,34 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_create_user_should_follow_workspace_settings() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = UpdateWorkspaceSettings {
+            default_channels: Some(vec![1]),
+            ..Default::default()
+        };
+        state.update_workspace_settings(1, input).await?;
+
+        let input = CreateUser::new("acme", "rcrwhyg@sina.com", "Lyn Wong", "hunter42");
+        let user = state.create_user(&input).await?;
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert!(chat.members.contains(&user.id));
+
+        let input = UpdateWorkspaceSettings {
+            invite_policy: Some(InvitePolicy::InviteOnly),
+            ..Default::default()
+        };
+        state.update_workspace_settings(1, input).await?;
+
+        let input = CreateUser::new("acme", "rcrwhyg2@sina.com", "Lyn Wong2", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_find_user_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::try_new_for_test().await?;
@@ -274,4 +753,217 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_fetch_chat_users_should_search_and_page() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // prefix of the email or the full name, case-insensitively
+        let input = ListUsers {
+            q: Some("ALI".to_string()),
+            ..Default::default()
+        };
+        let page = state.fetch_chat_users(1, input).await?;
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].email, "alice@acme.org");
+        let input = ListUsers {
+            q: Some("chen".to_string()),
+            ..Default::default()
+        };
+        let page = state.fetch_chat_users(1, input).await?;
+        assert!(page.items.is_empty());
+        // the wildcards are matched as written
+        for q in ["%", "_"] {
+            let input = ListUsers {
+                q: Some(q.to_string()),
+                ..Default::default()
+            };
+            assert!(state.fetch_chat_users(1, input).await?.items.is_empty());
+        }
+
+        let input = ListUsers {
+            limit: 2,
+            ..Default::default()
+        };
+        let page = state.fetch_chat_users(1, input).await?;
+        let ids: Vec<_> = page.items.iter().map(|u| u.id).collect();
+        assert_eq!(ids, [1, 2]);
+        let input = ListUsers {
+            cursor: page.older,
+            limit: 2,
+            ..Default::default()
+        };
+        let page = state.fetch_chat_users(1, input).await?;
+        let ids: Vec<_> = page.items.iter().map(|u| u.id).collect();
+        assert_eq!(ids, [3, 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_user_avatar_should_resize_image() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(400, 200).write_to(&mut data, ImageFormat::Png)?;
+        let user = state.update_user_avatar(&owner, data.get_ref()).await?;
+        let url = user.avatar_url.expect("avatar url should be set");
+        assert!(url.starts_with("/avatars/1/"));
+
+        // the members of the other workspaces of the user get it too
+        let ws = state.create_workspace("other", 2).await?;
+        let elsewhere = state.create_workspace("elsewhere", 2).await?;
+        state.add_workspace_member(ws.id as _, 1).await?;
+        assert!(state.get_user_avatar(ws.id as _, 1, &url).await?.is_some());
+        assert!(state
+            .get_user_avatar(elsewhere.id as _, 1, &url)
+            .await?
+            .is_none());
+        assert!(state.get_user_avatar(1, 2, &url).await?.is_none());
+
+        let file = state
+            .get_user_avatar(1, 1, &url)
+            .await?
+            .expect("avatar should be found");
+        let data = state
+            .storage
+            .get(&file.key())
+            .await?
+            .expect("avatar should exist");
+        let avatar = image::load_from_memory(&data)?;
+        assert_eq!(
+            (avatar.width(), avatar.height()),
+            (AVATAR_SIZE, AVATAR_SIZE)
+        );
+
+        let users = state.fetch_chat_users_by_ids(&[1]).await?;
+        assert_eq!(users[0].avatar_url.as_deref(), Some(url.as_str()));
+
+        let ret = state.update_user_avatar(&owner, b"not an image").await;
+        assert!(matches!(ret, Err(AppError::ChatFileError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_change_password_should_revoke_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+
+        let input = ChangePassword {
+            current_password: "wrong-password".to_string(),
+            new_password: "hunter4242".to_string(),
+        };
+        let ret = state.change_password(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = ChangePassword {
+            current_password: "123456".to_string(),
+            new_password: "short".to_string(),
+        };
+        let ret = input.validate_with_args(&state.config());
+        assert!(matches!(
+            ret.map_err(AppError::from),
+            Err(AppError::ValidationError(_))
+        ));
+
+        let input = ChangePassword {
+            current_password: "123456".to_string(),
+            new_password: "hunter4242".to_string(),
+        };
+        let updated = state.change_password(&user, &input).await?;
+        assert_eq!(updated.token_version, user.token_version + 1);
+        assert!(state.is_token_revoked(&user).await?);
+        assert!(!state.is_token_revoked(&updated).await?);
+
+        let input = SigninUser::new(&user.email, "hunter4242");
+        assert!(state.verify_user(&input).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset_user_password_should_revoke_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let mut admin = state.find_user_by_id(2).await?.expect("user should exist");
+        admin.role = WorkspaceRole::Admin;
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        state.reset_user_password(&admin, 3).await?;
+        assert!(state.is_token_revoked(&user).await?);
+        let input = SigninUser::new(&user.email, "123456");
+        assert!(state.verify_user(&input).await?.is_none());
+
+        let ret = state.reset_user_password(&admin, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.reset_user_password(&admin, 42).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // the owner must hand over the workspace first
+        let ret = state.delete_user(1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let mut admin = state.find_user_by_id(2).await?.expect("user should exist");
+        admin.role = WorkspaceRole::Admin;
+        let ret = state.admin_delete_user(&admin, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        state.admin_delete_user(&admin, 3).await?;
+        assert!(state.is_token_revoked(&user).await?);
+        assert!(state.get_workspace_role(1, 3).await?.is_none());
+        let input = SigninUser::new(&user.email, "123456");
+        assert!(state.verify_user(&input).await?.is_none());
+
+        let ret = state.delete_user(3).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_user_status_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = SetUserStatus {
+            emoji: Some("🌴".to_string()),
+            text: Some("On vacation".to_string()),
+            expires_at: Some(Utc::now() + chrono::Duration::days(1)),
+        };
+        let user = state.set_user_status(1, input).await?;
+        let status = user.status.expect("status should be set");
+        assert_eq!(status.text.as_deref(), Some("On vacation"));
+        let users = state.fetch_chat_users(1, Default::default()).await?.items;
+        assert_eq!(users[0].status, Some(status));
+
+        // expired statuses are hidden
+        sqlx::query(
+            "UPDATE users SET status = jsonb_set(status, '{expiresAt}', to_jsonb(NOW() - interval '1 minute')) WHERE id = 1",
+        )
+        .execute(&state.pool)
+        .await?;
+        let users = state.fetch_chat_users_by_ids(&[1]).await?;
+        assert!(users[0].status.is_none());
+
+        let input = SetUserStatus {
+            text: Some("x".repeat(MAX_STATUS_TEXT_LEN + 1)),
+            expires_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        let ret = state.set_user_status(1, input).await;
+        assert!(matches!(ret, Err(AppError::ValidationError(issues)) if issues.len() == 2));
+
+        let user = state.set_user_status(1, SetUserStatus::default()).await?;
+        assert!(user.status.is_none());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,232 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use tracing::warn;
+
+use crate::{AppError, AppState};
+
+/// hours a verification link stays valid
+const EMAIL_TOKEN_TTL: i32 = 24;
+/// seconds before another verification link can be mailed to the same user
+const RESEND_INTERVAL: f64 = 60.0;
+
+impl AppState {
+    /// Create a verification token for the user and mail the link to verify the email
+    pub async fn send_verification_email(&self, user: &User) -> Result<(), AppError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        sqlx::query(
+            r#"
+            INSERT INTO email_verifications (token, user_id)
+            VALUES ($1, $2)
+            "#,
+        )
+        .bind(&token)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+
+        let link = format!(
+            "{}/api/verify_email/{}",
+            self.config().mail.public_url.trim_end_matches('/'),
+            token
+        );
+        let body = format!(
+            "Hi {},\n\nPlease verify your email by opening the link below, it expires in {} hours:\n\n{}\n",
+            user.full_name, EMAIL_TOKEN_TTL, link
+        );
+
+        // a slow or broken mail server shouldn't hold the caller
+        let state = self.clone();
+        let to = user.email.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.send_mail(&to, "Verify your email", body).await {
+                warn!("Failed to send verification email to {}: {}", to, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Mail a new verification link to the user, the links sent before stop working. Nothing
+    /// is sent once the email is verified
+    pub async fn resend_verification_email(&self, user_id: u64) -> Result<(), AppError> {
+        let user = self
+            .find_user_by_id(user_id as _)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("User id {user_id}")))?;
+        if user.email_verified {
+            return Ok(());
+        }
+
+        let wait: Option<f64> = sqlx::query_scalar(
+            r#"
+            SELECT EXTRACT(EPOCH FROM MAX(created_at) + make_interval(secs => $2) - NOW())::float8
+            FROM email_verifications
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user.id)
+        .bind(RESEND_INTERVAL)
+        .fetch_one(&self.pool)
+        .await?;
+        if let Some(wait) = wait.filter(|wait| *wait > 0.0) {
+            return Err(AppError::TooManyRequests(wait.ceil() as _));
+        }
+
+        sqlx::query("DELETE FROM email_verifications WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        self.send_verification_email(&user).await
+    }
+
+    /// Mark the email of the token's user as verified, a token can only be used once
+    pub async fn verify_email(&self, token: &str) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            WITH verification AS (
+                DELETE FROM email_verifications
+                WHERE token = $1
+                RETURNING user_id, created_at
+            )
+            UPDATE users
+            SET email_verified = TRUE
+            FROM verification v
+            WHERE users.id = v.user_id
+                AND v.created_at > NOW() - make_interval(hours => $2)
+            "#,
+        )
+        .bind(token)
+        .bind(EMAIL_TOKEN_TTL)
+        .execute(&self.pool)
+        .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(
+                "Verification token is invalid or expired".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reject unverified users when the server requires a verified email
+    pub async fn ensure_email_verified(&self, user_id: u64) -> Result<(), AppError> {
+        if !self.config().auth.require_verified_email {
+            return Ok(());
+        }
+
+        let verified: Option<bool> =
+            sqlx::query_scalar("SELECT email_verified FROM users WHERE id = $1")
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        if verified != Some(true) {
+            return Err(AppError::PermissionDenied(format!(
+                "Email of user {user_id} is not verified"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateChat, CreateUser};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_verify_email_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreateUser::new("acme", "rcrwhyg@sina.com", "Lyn Wong", "hunter42");
+        let user = state.create_user(&input).await?;
+        assert!(!user.email_verified);
+
+        let token: String =
+            sqlx::query_scalar("SELECT token FROM email_verifications WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_one(&state.pool)
+                .await?;
+        state.verify_email(&token).await?;
+        let user = state
+            .find_user_by_id(user.id)
+            .await?
+            .expect("user should exist");
+        assert!(user.email_verified);
+
+        // tokens can only be used once
+        let ret = state.verify_email(&token).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resend_verification_email_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let input = CreateUser::new("acme", "rcrwhyg@sina.com", "Lyn Wong", "hunter42");
+        let user = state.create_user(&input).await?;
+        let tokens = |user_id| {
+            sqlx::query_scalar::<_, String>(
+                "SELECT token FROM email_verifications WHERE user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_all(&state.pool)
+        };
+        let sent = tokens(user.id).await?;
+
+        // right after the signup mail
+        let ret = state.resend_verification_email(user.id as _).await;
+        assert!(matches!(ret, Err(AppError::TooManyRequests(_))));
+
+        sqlx::query(
+            "UPDATE email_verifications SET created_at = NOW() - INTERVAL '2 minutes' WHERE user_id = $1",
+        )
+        .bind(user.id)
+        .execute(&state.pool)
+        .await?;
+        state.resend_verification_email(user.id as _).await?;
+        let resent = tokens(user.id).await?;
+        assert_eq!(resent.len(), 1);
+        assert_ne!(resent, sent);
+
+        // the link sent before no longer works
+        let ret = state.verify_email(&sent[0]).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        state.verify_email(&resent[0]).await?;
+
+        // nothing is sent once verified
+        state.resend_verification_email(user.id as _).await?;
+        assert!(tokens(user.id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unverified_user_should_be_restricted() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        state.update_config(|config| config.auth.require_verified_email = true);
+
+        let input = CreateUser::new("acme", "rcrwhyg@sina.com", "Lyn Wong", "hunter42");
+        let user = state.create_user(&input).await?;
+        let input = CreateChat::new("", &[1, user.id as _], false);
+        let ret = state.create_chat(input.clone(), user.id as _, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .save_upload(1, user.id as _, "hello.txt", None, b"hello")
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = $1")
+            .bind(user.id)
+            .execute(&state.pool)
+            .await?;
+        state.create_chat(input, user.id as _, 1).await?;
+
+        Ok(())
+    }
+}
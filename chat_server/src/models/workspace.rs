@@ -1,22 +1,84 @@
-use chat_core::Workspace;
+use chat_core::{begin_tagged, User, Workspace, WorkspaceRole};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use utoipa::ToSchema;
 
-use crate::{AppError, AppState};
+use crate::{AppError, AppState, ValidationIssue};
+
+/// max number of characters of the display name
+const DISPLAY_NAME_MAX_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitePolicy {
+    /// anyone can sign up to the workspace with its name
+    #[default]
+    Open,
+    /// signing up to the workspace with its name is rejected
+    InviteOnly,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct WorkspaceSettings {
+    /// shown to the members instead of the workspace name
+    pub display_name: Option<String>,
+    /// public channels new members join when they sign up
+    pub default_channels: Vec<i64>,
+    /// max size of an uploaded file in bytes, 0 means unlimited
+    pub max_file_size: u64,
+    /// days to keep the messages, 0 means the server retention applies
+    pub retention_days: u64,
+    pub invite_policy: InvitePolicy,
+    /// re-encode the uploaded images without their metadata, e.g. the GPS position of photos,
+    /// images that can't be re-encoded are rejected
+    pub sanitize_images: bool,
+}
+
+/// a workspace the user belongs to, with what a workspace switcher shows
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MyWorkspace {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub workspace: Workspace,
+    pub role: WorkspaceRole,
+    /// number of members of the workspace
+    pub members: i64,
+    /// number of chats of the workspace the user is in
+    pub chats: i64,
+    /// number of unread messages in those chats
+    pub unread: i64,
+}
+
+/// only the given fields are changed, an empty display name clears it
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct UpdateWorkspaceSettings {
+    pub display_name: Option<String>,
+    pub default_channels: Option<Vec<i64>>,
+    pub max_file_size: Option<u64>,
+    pub retention_days: Option<u64>,
+    pub invite_policy: Option<InvitePolicy>,
+    pub sanitize_images: Option<bool>,
+}
 
 impl AppState {
     pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
+        // the name of a deleted workspace is not reused
         let ws = sqlx::query_as(
             r#"
             INSERT INTO workspaces (name, owner_id)
             VALUES ($1, $2)
+            ON CONFLICT (name) DO NOTHING
             RETURNING id, name, owner_id, created_at
             "#,
         )
         .bind(name)
         .bind(user_id as i64)
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(ws)
+        ws.ok_or_else(|| AppError::PermissionDenied(format!("Workspace {name} is not available")))
     }
 
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
@@ -24,7 +86,7 @@ impl AppState {
             r#"
             SELECT id, name, owner_id, created_at
             FROM workspaces
-            WHERE name = $1
+            WHERE name = $1 AND deleted_at IS NULL
             "#,
         )
         .bind(name)
@@ -50,23 +112,380 @@ impl AppState {
         Ok(ws)
     }
 
+    /// Workspaces the user belongs to, with the role and counts of the user
+    pub async fn fetch_workspaces(&self, user_id: u64) -> Result<Vec<MyWorkspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, w.created_at, wm.role,
+                (SELECT count(*) FROM workspace_members WHERE ws_id = w.id) AS members,
+                (SELECT count(*) FROM chats WHERE ws_id = w.id AND $1 = ANY(members)) AS chats,
+                (
+                    SELECT count(*)
+                    FROM chats c
+                    JOIN messages m ON m.chat_id = c.id
+                    LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $1
+                    WHERE c.ws_id = w.id AND $1 = ANY(c.members)
+                        AND m.id > COALESCE(cm.last_read_message_id, 0)
+                        AND m.sender_id <> $1
+                        AND m.deleted_at IS NULL
+                ) AS unread
+            FROM workspaces w
+            JOIN workspace_members wm ON wm.ws_id = w.id
+            WHERE wm.user_id = $1
+            ORDER BY w.id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    pub async fn is_workspace_member(&self, ws_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let is_member = sqlx::query(
+            r#"
+            SELECT 1
+            FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(is_member.is_some())
+    }
+
+    /// Role of the user in the workspace, None if not a member
+    pub async fn get_workspace_role(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Option<WorkspaceRole>, AppError> {
+        let role = sqlx::query_scalar(
+            r#"
+            SELECT role
+            FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(role)
+    }
+
+    /// The user acting in another workspace it is a member of, used to mint a token scoped to it
+    pub async fn switch_workspace(&self, mut user: User, ws_id: u64) -> Result<User, AppError> {
+        let role = self.get_workspace_role(ws_id, user.id as _).await?;
+        let ws = match role {
+            Some(_) => self.find_workspace_by_id(ws_id).await?,
+            None => None,
+        };
+        let (Some(role), Some(ws)) = (role, ws) else {
+            return Err(AppError::NotFound(format!("Workspace id {ws_id}")));
+        };
+
+        user.ws_id = ws.id;
+        user.ws_name = ws.name;
+        user.role = role;
+        Ok(user)
+    }
+
+    /// The user acting in the given workspace, or in the first one it is still an active
+    /// member of when that membership is gone or deactivated, so losing the home workspace
+    /// doesn't lock it out
+    pub async fn resolve_workspace(&self, user: User, ws_id: u64) -> Result<User, AppError> {
+        if !self.is_member_deactivated(ws_id, user.id as _).await? {
+            match self.switch_workspace(user.clone(), ws_id).await {
+                Err(AppError::NotFound(_)) => {}
+                ret => return ret,
+            }
+        }
+
+        let fallback: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT wm.ws_id
+            FROM workspace_members wm
+            JOIN workspaces w ON w.id = wm.ws_id
+            WHERE wm.user_id = $1 AND wm.deactivated_at IS NULL AND w.deleted_at IS NULL
+            ORDER BY wm.created_at, wm.ws_id
+            LIMIT 1
+            "#,
+        )
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match fallback {
+            Some(id) => self.switch_workspace(user, id as _).await,
+            None => Err(AppError::NotFound(format!(
+                "User {} doesn't belong to any workspace",
+                user.id
+            ))),
+        }
+    }
+
+    pub async fn add_workspace_member(&self, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members (ws_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Soft delete the workspace, the members lose access at once and the content is purged in the background,
+    /// see `spawn_workspace_purge` and `spawn_purge_sweep`
+    pub async fn delete_workspace(&self, ws_id: u64, user_id: u64) -> Result<Workspace, AppError> {
+        match self.get_workspace_role(ws_id, user_id).await? {
+            Some(WorkspaceRole::Owner) => {}
+            Some(_) => {
+                return Err(AppError::PermissionDenied(format!(
+                    "User {user_id} is not the owner of workspace {ws_id}"
+                )))
+            }
+            None => return Err(AppError::NotFound(format!("Workspace id {ws_id}"))),
+        }
+
+        // the trigger collects the members to notify before they are removed
+        let mut tx = begin_tagged(&self.pool).await?;
+        let ws: Option<Workspace> = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET deleted_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, name, owner_id, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(ws) = ws else {
+            return Err(AppError::NotFound(format!("Workspace id {ws_id}")));
+        };
+
+        sqlx::query(
+            r#"
+            DELETE FROM workspace_members
+            WHERE ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    pub async fn get_workspace_settings(&self, ws_id: u64) -> Result<WorkspaceSettings, AppError> {
+        let settings: Option<Json<WorkspaceSettings>> = sqlx::query_scalar(
+            r#"
+            SELECT settings
+            FROM workspaces
+            WHERE id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match settings {
+            Some(Json(settings)) => Ok(settings),
+            None => Err(AppError::NotFound(format!("Workspace id {ws_id}"))),
+        }
+    }
+
+    pub async fn update_workspace_settings(
+        &self,
+        ws_id: u64,
+        input: UpdateWorkspaceSettings,
+    ) -> Result<WorkspaceSettings, AppError> {
+        if let Some(channels) = &input.default_channels {
+            self.validate_default_channels(ws_id, channels).await?;
+        }
+        if let Some(name) = &input.display_name {
+            let len = name.chars().count();
+            if len > DISPLAY_NAME_MAX_LENGTH {
+                return Err(AppError::ValidationError(vec![ValidationIssue::new(
+                    "display_name",
+                    "too_long",
+                    format!(
+                        "display_name must be at most {} characters, but got {}",
+                        DISPLAY_NAME_MAX_LENGTH, len
+                    ),
+                )]));
+            }
+        }
+
+        // lock the row so concurrent updates of different fields are not lost
+        let mut tx = self.pool.begin().await?;
+        let settings: Option<Json<WorkspaceSettings>> = sqlx::query_scalar(
+            r#"
+            SELECT settings
+            FROM workspaces
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(Json(mut settings)) = settings else {
+            return Err(AppError::NotFound(format!("Workspace id {ws_id}")));
+        };
+
+        if let Some(name) = input.display_name {
+            settings.display_name = Some(name).filter(|v| !v.is_empty());
+        }
+        if let Some(channels) = input.default_channels {
+            settings.default_channels = channels;
+        }
+        if let Some(size) = input.max_file_size {
+            settings.max_file_size = size;
+        }
+        if let Some(days) = input.retention_days {
+            settings.retention_days = days;
+        }
+        if let Some(policy) = input.invite_policy {
+            settings.invite_policy = policy;
+        }
+        if let Some(sanitize) = input.sanitize_images {
+            settings.sanitize_images = sanitize;
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE workspaces
+            SET settings = $2
+            WHERE id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(Json(&settings))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(settings)
+    }
+
+    /// Default channels must be public channels of the workspace
+    async fn validate_default_channels(&self, ws_id: u64, ids: &[i64]) -> Result<(), AppError> {
+        let found: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM chats
+            WHERE id = ANY($1) AND ws_id = $2 AND type = 'public_channel'
+            "#,
+        )
+        .bind(ids)
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let invalid: Vec<_> = ids
+            .iter()
+            .filter(|id| !found.contains(id))
+            .map(|id| id.to_string())
+            .collect();
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        Err(AppError::ValidationError(vec![ValidationIssue::new(
+            "default_channels",
+            "invalid",
+            format!(
+                "default_channels must be public channels of the workspace, but got {}",
+                invalid.join(", ")
+            ),
+        )]))
+    }
+
     pub async fn update_workspace_owner(
         &self,
         id: u64,
         owner_id: u64,
     ) -> Result<Workspace, AppError> {
-        // update owner_id in two cases 1) owner_id is 0, 2) owner's ws_id = id
+        self.set_workspace_owner(id, owner_id, None)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Workspace id {id}")))
+    }
+
+    /// Hand the workspace over to another member, only the owner can do it
+    pub async fn transfer_workspace_ownership(
+        &self,
+        ws_id: u64,
+        new_owner_id: u64,
+        user_id: u64,
+    ) -> Result<Workspace, AppError> {
+        let ws = self
+            .set_workspace_owner(ws_id, new_owner_id, Some(user_id))
+            .await?;
+
+        match ws {
+            Some(ws) => Ok(ws),
+            None => match self.get_workspace_role(ws_id, user_id).await? {
+                None => Err(AppError::NotFound(format!("Workspace id {ws_id}"))),
+                Some(role) if role != WorkspaceRole::Owner => Err(AppError::PermissionDenied(
+                    format!("User {user_id} is not the owner of workspace {ws_id}"),
+                )),
+                Some(_) => Err(AppError::ValidationError(vec![ValidationIssue::new(
+                    "new_owner_id",
+                    "not_member",
+                    format!("User {new_owner_id} is not a member of workspace {ws_id}"),
+                )])),
+            },
+        }
+    }
+
+    // owner, membership and roles are changed in one statement, so a concurrent change can't slip in
+    async fn set_workspace_owner(
+        &self,
+        id: u64,
+        owner_id: u64,
+        current_owner_id: Option<u64>,
+    ) -> Result<Option<Workspace>, AppError> {
+        // the new owner must be a member of the workspace, the previous owner becomes an admin
         let ws = sqlx::query_as(
             r#"
-            UPDATE workspaces
-            SET owner_id = $1
-            WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-            RETURNING id, name, owner_id, created_at
+            WITH ws AS (
+                UPDATE workspaces
+                SET owner_id = $1
+                WHERE id = $2 AND deleted_at IS NULL
+                    AND ($3::bigint IS NULL OR owner_id = $3)
+                    AND EXISTS (SELECT 1 FROM workspace_members WHERE ws_id = $2 AND user_id = $1)
+                RETURNING id, name, owner_id, created_at
+            ), demoted AS (
+                UPDATE workspace_members
+                SET role = 'admin'
+                WHERE ws_id = $2 AND role = 'owner' AND user_id != $1
+                    AND EXISTS (SELECT 1 FROM ws)
+            ), promoted AS (
+                UPDATE workspace_members
+                SET role = 'owner'
+                WHERE ws_id = $2 AND user_id = $1
+                    AND EXISTS (SELECT 1 FROM ws)
+            )
+            SELECT id, name, owner_id, created_at FROM ws
             "#,
         )
         .bind(owner_id as i64)
         .bind(id as i64)
-        .fetch_one(&self.pool)
+        .bind(current_owner_id.map(|v| v as i64))
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(ws)
@@ -75,7 +494,7 @@ impl AppState {
 
 #[cfg(test)]
 mod tests {
-    use crate::models::CreateUser;
+    use crate::models::{CreateUser, SigninUser};
 
     use super::*;
     use anyhow::Result;
@@ -99,6 +518,195 @@ mod tests {
             .update_workspace_owner(ws.id as _, user.id as _)
             .await?;
         assert_eq!(ws.owner_id, user.id);
+        let role = state.get_workspace_role(ws.id as _, user.id as _).await?;
+        assert_eq!(role, Some(WorkspaceRole::Owner));
+
+        // the previous owner stays as an admin
+        let input = CreateUser::new(&ws.name, "rcrwhyg2@sina.com", "Lyn Wong2", password);
+        let user2 = state.create_user(&input).await?;
+        assert_eq!(user2.role, WorkspaceRole::Member);
+        let ws = state
+            .update_workspace_owner(ws.id as _, user2.id as _)
+            .await?;
+        assert_eq!(ws.owner_id, user2.id);
+        let role = state.get_workspace_role(ws.id as _, user.id as _).await?;
+        assert_eq!(role, Some(WorkspaceRole::Admin));
+        let role = state.get_workspace_role(ws.id as _, user2.id as _).await?;
+        assert_eq!(role, Some(WorkspaceRole::Owner));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspace_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // users are members of the workspace they signed up with
+        let workspaces = state.fetch_workspaces(1).await?;
+        assert_eq!(workspaces.len(), 1);
+        assert_eq!(workspaces[0].workspace.name, "acme");
+        assert_eq!(workspaces[0].role, WorkspaceRole::Owner);
+        assert_eq!(workspaces[0].members, 5);
+        assert_eq!(workspaces[0].chats, 4);
+        assert!(!state.is_workspace_member(2, 1).await?);
+
+        state.add_workspace_member(2, 1).await?;
+        assert!(state.is_workspace_member(2, 1).await?);
+        let workspaces = state.fetch_workspaces(1).await?;
+        assert_eq!(workspaces.len(), 2);
+        assert_eq!(workspaces[1].workspace.name, "foo");
+        assert_eq!(workspaces[1].role, WorkspaceRole::Member);
+        assert_eq!(workspaces[1].chats, 0);
+
+        let users = state.fetch_chat_users(2, Default::default()).await?.items;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].id, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspace_settings_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let settings = state.get_workspace_settings(1).await?;
+        assert_eq!(settings, WorkspaceSettings::default());
+
+        let input = UpdateWorkspaceSettings {
+            display_name: Some("Acme Inc.".to_string()),
+            default_channels: Some(vec![1]),
+            max_file_size: Some(1024),
+            ..Default::default()
+        };
+        let settings = state.update_workspace_settings(1, input).await?;
+        assert_eq!(settings.display_name.as_deref(), Some("Acme Inc."));
+        assert_eq!(settings.default_channels, vec![1]);
+        assert_eq!(settings.max_file_size, 1024);
+
+        // other fields are kept
+        let input = UpdateWorkspaceSettings {
+            display_name: Some("".to_string()),
+            invite_policy: Some(InvitePolicy::InviteOnly),
+            ..Default::default()
+        };
+        let settings = state.update_workspace_settings(1, input).await?;
+        assert_eq!(settings.display_name, None);
+        assert_eq!(settings.max_file_size, 1024);
+        assert_eq!(settings.invite_policy, InvitePolicy::InviteOnly);
+        assert_eq!(state.get_workspace_settings(1).await?, settings);
+
+        // chat 2 is a private channel
+        let input = UpdateWorkspaceSettings {
+            default_channels: Some(vec![1, 2]),
+            ..Default::default()
+        };
+        let ret = state.update_workspace_settings(1, input).await;
+        match ret {
+            Err(AppError::ValidationError(issues)) => {
+                assert_eq!(issues[0].field, "default_channels");
+            }
+            _ => panic!("private channels should be rejected"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // alice is an admin, not the owner
+        let ret = state.delete_workspace(1, 2).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.delete_workspace(2, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let ws = state.delete_workspace(1, 1).await?;
+        assert_eq!(ws.name, "acme");
+        assert!(!state.is_workspace_member(1, 2).await?);
+        assert!(state.fetch_workspaces(1).await?.is_empty());
+        assert!(state.find_workspace_by_name("acme").await?.is_none());
+
+        // the name is not reused
+        let ret = state.create_workspace("acme", 0).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_workspace_ownership_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        // alice is an admin, not the owner
+        let ret = state.transfer_workspace_ownership(1, 3, 2).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // user 6 is not a member of acme
+        let ret = state.transfer_workspace_ownership(1, 6, 1).await;
+        match ret {
+            Err(AppError::ValidationError(issues)) => assert_eq!(issues[0].code, "not_member"),
+            _ => panic!("non members should be rejected"),
+        }
+
+        let ws = state.transfer_workspace_ownership(1, 3, 1).await?;
+        assert_eq!(ws.owner_id, 3);
+        let role = state.get_workspace_role(1, 3).await?;
+        assert_eq!(role, Some(WorkspaceRole::Owner));
+        let role = state.get_workspace_role(1, 1).await?;
+        assert_eq!(role, Some(WorkspaceRole::Admin));
+
+        // the previous owner can't transfer it again
+        let ret = state.transfer_workspace_ownership(1, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_switch_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+        let ret = state.switch_workspace(user.clone(), 2).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.add_workspace_member(2, 1).await?;
+        let user = state.switch_workspace(user, 2).await?;
+        assert_eq!(user.ws_id, 2);
+        assert_eq!(user.ws_name, "foo");
+        assert_eq!(user.role, WorkspaceRole::Member);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_workspace_should_fall_back() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let user = state.find_user_by_id(2).await?.expect("user should exists");
+        state.add_workspace_member(2, 2).await?;
+        let token = state.create_refresh_token(&user).await?;
+
+        // removed from the home workspace
+        sqlx::query("DELETE FROM workspace_members WHERE ws_id = 1 AND user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let input = SigninUser::new(&user.email, "123456");
+        let signed_in = state
+            .verify_user(&input)
+            .await?
+            .expect("user should sign in");
+        assert_eq!(signed_in.ws_id, 2);
+        let refreshed = state.use_refresh_token(&token).await?;
+        assert_eq!(refreshed.ws_id, 2);
+        assert_eq!(refreshed.role, WorkspaceRole::Member);
+
+        sqlx::query("DELETE FROM workspace_members WHERE user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let ret = state.resolve_workspace(user, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
 
         Ok(())
     }
@@ -115,7 +723,7 @@ mod tests {
     async fn test_workspace_should_fetch_all_chat_users() -> Result<()> {
         let (_tdb, state) = AppState::try_new_for_test().await?;
 
-        let users = state.fetch_chat_users(1).await?;
+        let users = state.fetch_chat_users(1, Default::default()).await?.items;
         assert_eq!(users.len(), 5);
         // assert_eq!(users.clone().split_off(2), users);
 
@@ -132,7 +740,10 @@ mod tests {
         let input = CreateUser::new(&ws.name, email, full_name, password);
         let user2 = state.create_user(&input).await?;
 
-        let users = state.fetch_chat_users(ws.id as _).await?;
+        let users = state
+            .fetch_chat_users(ws.id as _, Default::default())
+            .await?
+            .items;
         assert_eq!(users.len(), 2);
         assert_eq!(users[0].id, user1.id);
         assert_eq!(users[1].id, user2.id);
@@ -1,5 +1,9 @@
 use axum::Router;
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    Attachment, Chat, ChatPresence, ChatType, ChatUser, DeliveryState, LinkPreview, MediaInfo,
+    Message, Poll, Presence, PresenceStatus, Reaction, ReactionCount, ReadState, ScanStatus,
+    Thumbnails, UploadProgress, UploadStage, User, UserStatus, Workspace, WorkspaceRole,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -10,7 +14,17 @@ use utoipa_swagger_ui::SwaggerUi;
 
 use crate::handlers::*;
 use crate::{
-    AppState, CreateChat, CreateMessage, CreateUser, ErrorOutput, ListMessages, SigninUser,
+    AckEvents, AddChatMember, ApiKey, AppState, Badges, ChangePassword, ChatBadge, ChatBan,
+    ChatExpand, ChatNotifyLevel, ChatSettings, ChatUnread, CreateApiKey, CreateChat, CreateMessage,
+    CreatePoll, CreateReaction, CreateUser, CreatedApiKey, DailyMessages, DeliveryStatus, Device,
+    DevicePlatform, ErrorOutput, ExportChat, ExportFormat, ExportedMessage, InvitePolicy,
+    ListChats, ListEvents, ListFiles, ListMessages, ListPresence, ListUsers, ListWorkspaceMembers,
+    MarkRead, MessageFormat, MessageStatus, MuteChat, MyWorkspace, NotificationPreferences,
+    NotifyLevel, Page, RefreshToken, RegisterDevice, RemoveChatMember, RenderOptions, Rendition,
+    SavedMessage, ScheduledMessage, SearchMessages, SearchResult, SetEmailDigest, SetMemberRole,
+    SetNotifyLevel, SetPresence, SetUserStatus, SignFile, SignedFile, SignedFileUrl, SigninUser,
+    Signout, ThumbnailSize, TransferOwnership, UpdateMessage, UpdateWorkspaceSettings, UserEvent,
+    UserEvents, ValidationIssue, VotePoll, WorkspaceMember, WorkspaceSettings, WorkspaceStats,
 };
 
 pub(crate) trait OpenApiRouter {
@@ -22,17 +36,90 @@ pub(crate) trait OpenApiRouter {
     paths(
         signup_handler,
         signin_handler,
+        refresh_token_handler,
+        signout_handler,
+        verify_email_handler,
+        resend_verification_email_handler,
+        register_device_handler,
+        list_devices_handler,
+        unregister_device_handler,
         list_chat_handler,
+        list_public_chat_handler,
         create_chat_handler,
         get_chat_handler,
         update_chat_handler,
+        upload_chat_avatar_handler,
+        add_chat_member_handler,
+        remove_chat_member_handler,
+        list_chat_online_handler,
+        list_chat_bans_handler,
+        unban_chat_member_handler,
+        transfer_chat_ownership_handler,
+        join_chat_handler,
+        leave_chat_handler,
         list_message_handler,
+        list_thread_handler,
+        list_mentions_handler,
+        search_chat_messages_handler,
+        search_messages_handler,
+        save_message_handler,
+        unsave_message_handler,
+        list_saved_handler,
+        list_files_handler,
+        delete_file_handler,
+        sign_file_handler,
+        signed_file_handler,
+        list_scheduled_handler,
+        cancel_scheduled_handler,
         delete_chat_handler,
+        export_chat_handler,
+        mark_chat_read_handler,
+        mute_chat_handler,
+        unmute_chat_handler,
+        list_unread_handler,
+        list_badges_handler,
         send_message_handler,
+        update_message_handler,
+        delete_message_handler,
+        ack_message_handler,
+        get_message_status_handler,
+        add_reaction_handler,
+        remove_reaction_handler,
+        create_poll_handler,
+        vote_poll_handler,
         list_chat_users_handler,
+        upload_user_avatar_handler,
+        change_password_handler,
+        delete_user_handler,
+        list_presence_handler,
+        list_user_events_handler,
+        ack_events_handler,
+        set_presence_handler,
+        set_user_status_handler,
+        get_notification_preferences_handler,
+        set_notify_level_handler,
+        set_email_digest_handler,
+        block_user_handler,
+        unblock_user_handler,
+        list_workspaces_handler,
+        switch_workspace_handler,
+        delete_workspace_handler,
+        transfer_workspace_ownership_handler,
+        get_workspace_stats_handler,
+        get_workspace_settings_handler,
+        admin_delete_user_handler,
+        admin_list_users_handler,
+        create_api_key_handler,
+        list_api_keys_handler,
+        revoke_api_key_handler,
+        admin_deactivate_user_handler,
+        admin_reactivate_user_handler,
+        admin_reset_password_handler,
+        admin_set_user_role_handler,
+        update_workspace_settings_handler,
     ),
     components  (
-        schemas(Chat, ChatType, ChatUser, Message, User, Workspace, CreateChat, CreateMessage, CreateUser, ErrorOutput, ListMessages, SigninUser),
+        schemas(Attachment, Chat, ChatPresence, ChatType, ChatUser, DeliveryState, LinkPreview, MediaInfo, Message, Poll, Presence, PresenceStatus, Reaction, ReactionCount, ReadState, ScanStatus, Thumbnails, UploadProgress, UploadStage, User, UserStatus, Workspace, WorkspaceRole, AckEvents, AddChatMember, ApiKey, Badges, ChangePassword, ChatBadge, ChatBan, ChatExpand, ChatNotifyLevel, ChatSettings, ChatUnread, CreateApiKey, CreateChat, CreateMessage, CreatePoll, CreateReaction, CreateUser, CreatedApiKey, DailyMessages, DeliveryStatus, Device, DevicePlatform, ErrorOutput, ExportChat, ExportFormat, ExportedMessage, InvitePolicy, ListChats, ListEvents, ListFiles, ListMessages, ListPresence, ListUsers, ListWorkspaceMembers, MarkRead, MessageFormat, MessageStatus, MuteChat, MyWorkspace, NotificationPreferences, NotifyLevel, Page<ChatUser>, Page<Message>, Page<SavedMessage>, Page<WorkspaceMember>, RefreshToken, RegisterDevice, RemoveChatMember, Rendition, RenderOptions, SavedMessage, ScheduledMessage, SearchMessages, SearchResult, SetEmailDigest, SetMemberRole, SetNotifyLevel, SetPresence, SetUserStatus, SignFile, SignedFile, SignedFileUrl, SigninUser, Signout, ThumbnailSize, TransferOwnership, UpdateMessage, UpdateWorkspaceSettings, UserEvent, UserEvents, ValidationIssue, VotePoll, WorkspaceMember, WorkspaceSettings, WorkspaceStats),
     ),
     modifiers(
         &SecurityAddon,
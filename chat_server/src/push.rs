@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically prune the devices not seen for the device TTL
+pub fn spawn_device_prune(state: AppState) -> JoinHandle<()> {
+    let days = state.config().push.device_ttl;
+    let period = Duration::from_secs(state.config().push.prune_interval.max(1));
+    tokio::spawn(async move {
+        if days == 0 {
+            info!("Device pruning is disabled");
+            return;
+        }
+
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - chrono::Duration::days(days as _);
+            match state.prune_stale_devices(cutoff).await {
+                Ok(0) => {}
+                Ok(count) => info!("Pruned {} devices not seen since {}", count, cutoff),
+                Err(e) => warn!("Failed to prune stale devices: {}", e),
+            }
+        }
+    })
+}
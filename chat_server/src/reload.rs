@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chat_core::{
+    reload_config, reload_logging, ConfigReload, KeyReload, ReloadTrigger, ReloadableRouter,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{get_router, AppConfig, AppState};
+
+/// the settings a reload applies, the others need a restart
+const RELOADABLE: &[&str] = &[
+    "log.level",
+    "log.filters",
+    "rate_limit",
+    "cors.allowed_origins",
+    "acl",
+    "server.max_json_body",
+    "server.max_multipart_body",
+    "auth.sk",
+    "auth.pk",
+    "auth.extra_pks",
+];
+
+/// Reload the config on SIGHUP and every `auth.key_reload_interval` seconds, the log level,
+/// rate limits, CORS origins, network ACLs, body limits and keys are applied without a restart.
+/// `args` are the flags the server was started with, they still override the reloaded file.
+///
+/// The keys are rotated this way:
+///
+/// 1. add the new public key to `extra_pks` of both servers, and wait for a reload
+/// 2. swap `sk` and `pk` for the new keys and move the old public key to `extra_pks`
+/// 3. drop the old public key once the tokens it signed expired
+pub fn spawn_config_reload(
+    state: AppState,
+    router: ReloadableRouter,
+    args: Vec<String>,
+) -> JoinHandle<()> {
+    let trigger = ReloadTrigger::new(state.config().auth.key_reload_interval);
+    tokio::spawn(async move {
+        loop {
+            trigger.wait().await;
+            let ret = match AppConfig::try_load_with_args(args.clone()) {
+                Ok(config) => apply_config(&state, &router, config).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = ret {
+                warn!("Failed to reload the config: {:#}", e);
+            }
+        }
+    })
+}
+
+/// Apply the changes of the reloadable settings, the changes of the others are ignored
+async fn apply_config(
+    state: &AppState,
+    router: &ReloadableRouter,
+    new: AppConfig,
+) -> Result<Vec<String>> {
+    let ConfigReload {
+        config,
+        applied,
+        rejected,
+    } = reload_config(&*state.config(), &new, RELOADABLE)?;
+    for field in &rejected {
+        warn!("Ignored the change of {}, it needs a restart", field);
+    }
+    if applied.is_empty() {
+        return Ok(applied);
+    }
+
+    config.cors.validate()?;
+    // both keys are loaded before either is swapped, a bad one keeps the pair in use
+    let keys = if applied.iter().any(|field| field.starts_with("auth.")) {
+        Some(KeyReload::load(
+            config.auth.jwt.algorithm,
+            &config.auth.sk,
+            config.auth.pks(),
+        )?)
+    } else {
+        None
+    };
+    reload_logging(&config.log)?;
+    if let Some(keys) = keys {
+        keys.apply(&state.ek, &state.dk);
+        info!(
+            "Reloaded the keys, signing with {} and verifying with {:?}",
+            state.ek.key_id(),
+            state.dk.key_ids()
+        );
+    }
+    state.config.store(Arc::new(config));
+    // the body limits, the ACLs and the CORS layer are set up with the router
+    router.store(get_router(state.clone()).await?);
+    info!("Reloaded the config, changed {}", applied.join(", "));
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Request, http::header};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn reload_should_apply_reloadable_settings_only() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let router = ReloadableRouter::new(get_router(state.clone()).await?);
+        let app = router.router();
+
+        let mut new = AppConfig::clone(&state.config());
+        new.rate_limit.ip.burst = 1;
+        new.cors.allowed_origins = vec!["https://chat.example.com".to_string()];
+        new.server.db_url = "postgres://elsewhere/chat".to_string();
+        let applied = apply_config(&state, &router, new).await?;
+        assert_eq!(applied, ["cors.allowed_origins", "rate_limit.ip.burst"]);
+
+        let config = state.config();
+        assert_eq!(config.rate_limit.ip.burst, 1);
+        assert_ne!(config.server.db_url, "postgres://elsewhere/chat");
+
+        // the new router is served
+        let req = Request::builder()
+            .uri("/")
+            .header(header::ORIGIN, "https://chat.example.com")
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://chat.example.com"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_should_keep_both_keys_on_a_bad_one() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let router = ReloadableRouter::new(get_router(state.clone()).await?);
+        let (kid, kids) = (state.ek.key_id(), state.dk.key_ids());
+
+        let mut new = AppConfig::clone(&state.config());
+        new.auth.sk = "not a key".to_string();
+        new.auth.extra_pks = vec![new.auth.pk.clone()];
+        assert!(apply_config(&state, &router, new).await.is_err());
+
+        assert_eq!(state.ek.key_id(), kid);
+        assert_eq!(state.dk.key_ids(), kids);
+        assert!(state.config().auth.extra_pks.is_empty());
+        Ok(())
+    }
+}
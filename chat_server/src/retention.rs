@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically purge messages and unused files older than the retention period of their
+/// workspace, and the events older than theirs
+pub fn spawn_retention_purge(state: AppState) -> JoinHandle<()> {
+    let days = state.config().retention.days;
+    let event_days = state.config().retention.event_days;
+    let period = Duration::from_secs(state.config().retention.interval.max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if event_days > 0 {
+                let cutoff = Utc::now() - chrono::Duration::days(event_days as _);
+                match state.purge_events_before(cutoff).await {
+                    Ok(events) => info!("Purged {} events older than {}", events, cutoff),
+                    Err(e) => warn!("Failed to purge expired events: {}", e),
+                }
+            }
+            // the workspaces may have their own retention when the server has none
+            let cutoff = (days > 0).then(|| Utc::now() - chrono::Duration::days(days as _));
+            match state.purge_before(cutoff).await {
+                Ok(stats) => info!(
+                    "Purged {} expired messages and {} files ({} bytes)",
+                    stats.messages, stats.files, stats.bytes
+                ),
+                Err(e) => warn!("Failed to purge expired messages: {}", e),
+            }
+        }
+    })
+}
+
+/// Resume the purges of the deleted workspaces and accounts left halfway, at startup and then
+/// periodically
+pub fn spawn_purge_sweep(state: AppState) -> JoinHandle<()> {
+    let period = Duration::from_secs(state.config().retention.interval.max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match state.resume_purges().await {
+                Ok(0) => {}
+                Ok(n) => info!("Resumed the purge of {} deleted workspaces and accounts", n),
+                Err(e) => warn!("Failed to resume the purges: {}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+use super::{ScanVerdict, Scanner};
+use crate::{config::ClamavConfig, AppError};
+
+/// well below the default StreamMaxLength of clamd
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// clamd, the data is sent with the INSTREAM command
+#[derive(Debug)]
+pub struct ClamavScanner {
+    config: ClamavConfig,
+}
+
+impl ClamavScanner {
+    pub fn new(config: ClamavConfig) -> Self {
+        Self { config }
+    }
+
+    async fn instream(&self, data: &[u8]) -> Result<String, AppError> {
+        let mut stream = TcpStream::connect(&self.config.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        Ok(reply.trim_end_matches(['\0', '\n']).to_string())
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamavScanner {
+    async fn scan(&self, data: &[u8]) -> Result<ScanVerdict, AppError> {
+        let timeout = Duration::from_millis(self.config.timeout);
+        let reply = time::timeout(timeout, self.instream(data))
+            .await
+            .map_err(|_| AppError::ScanError("clamd timed out".to_string()))??;
+        parse_reply(&reply)
+    }
+}
+
+/// `stream: OK`, `stream: Eicar-Signature FOUND` or an error
+fn parse_reply(reply: &str) -> Result<ScanVerdict, AppError> {
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        return Ok(ScanVerdict::Clean);
+    }
+    match result.strip_suffix(" FOUND") {
+        Some(name) => Ok(ScanVerdict::Infected(name.to_string())),
+        None => Err(AppError::ScanError(result.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    /// a clamd that flags the data containing "EICAR"
+    async fn fake_clamd() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).await?;
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = vec![];
+                loop {
+                    let len = stream.read_u32().await? as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; len];
+                    stream.read_exact(&mut chunk).await?;
+                    data.extend(chunk);
+                }
+                let reply: &[u8] = if data.windows(5).any(|w| w == b"EICAR") {
+                    b"stream: Eicar-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                stream.write_all(reply).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn clamav_scanner_should_work() -> Result<()> {
+        let addr = fake_clamd().await?;
+        let scanner = ClamavScanner::new(ClamavConfig {
+            addr,
+            ..Default::default()
+        });
+
+        let data = vec![b'a'; CHUNK_SIZE * 2 + 1];
+        assert_eq!(scanner.scan(&data).await?, ScanVerdict::Clean);
+        let verdict = scanner
+            .scan(b"X5O!P%@AP EICAR-STANDARD-ANTIVIRUS-TEST-FILE")
+            .await?;
+        assert_eq!(
+            verdict,
+            ScanVerdict::Infected("Eicar-Signature".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reply_should_work() {
+        assert!(matches!(
+            parse_reply("INSTREAM size limit exceeded. ERROR"),
+            Err(AppError::ScanError(_))
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+mod clamav;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::{config::ScanConfig, AppError, AppState};
+
+/// most files scanned again in one run
+const RESCAN_BATCH: i64 = 100;
+
+pub use clamav::ClamavScanner;
+
+/// scans the uploads for malware before they're served
+#[async_trait]
+pub trait Scanner: fmt::Debug + Send + Sync {
+    async fn scan(&self, data: &[u8]) -> Result<ScanVerdict, AppError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// what the scanner found
+    Infected(String),
+}
+
+/// clears everything, for when there's no scanner
+#[derive(Debug, Default)]
+pub struct NoopScanner;
+
+#[async_trait]
+impl Scanner for NoopScanner {
+    async fn scan(&self, _data: &[u8]) -> Result<ScanVerdict, AppError> {
+        Ok(ScanVerdict::Clean)
+    }
+}
+
+/// Create the scanner of the config
+pub fn scanner(config: &ScanConfig) -> Arc<dyn Scanner> {
+    match config {
+        ScanConfig::None => Arc::new(NoopScanner),
+        ScanConfig::Clamav(config) => Arc::new(ClamavScanner::new(config.clone())),
+    }
+}
+
+/// Periodically scan again the files left pending or failed, e.g. by a server that stopped
+/// while scanning them
+pub fn spawn_rescan(state: AppState) -> JoinHandle<()> {
+    let interval = state.config().files.rescan_interval;
+    let after = state.config().files.rescan_after;
+    tokio::spawn(async move {
+        if interval == 0 {
+            info!("Scanning the stale files again is disabled");
+            return;
+        }
+
+        let mut interval = time::interval(Duration::from_secs(interval));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let stale_before = Utc::now() - chrono::Duration::seconds(after as _);
+            match state
+                .rescan_stale_attachments(stale_before, RESCAN_BATCH)
+                .await
+            {
+                Ok(0) => {}
+                Ok(n) => info!("Scanned {} stale files again", n),
+                Err(e) => warn!("Failed to scan the stale files again: {}", e),
+            }
+        }
+    })
+}
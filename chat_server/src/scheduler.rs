@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// Periodically deliver due scheduled messages in the background
+pub fn spawn_scheduler(state: AppState) -> JoinHandle<()> {
+    let period = Duration::from_secs(state.config().message.schedule_interval.max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match state.deliver_scheduled_messages().await {
+                Ok(messages) if !messages.is_empty() => {
+                    info!("Delivered {} scheduled messages", messages.len())
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to deliver scheduled messages: {}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,139 @@
+use async_trait::async_trait;
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use super::{Storage, StoredObject};
+use crate::AppError;
+
+/// files on the local filesystem, only fit for a single node
+#[derive(Debug)]
+pub struct LocalStorage {
+    base_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn try_new(base_dir: &Path) -> Result<Self, AppError> {
+        fs::create_dir_all(base_dir).await?;
+        Ok(Self {
+            base_dir: base_dir.to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.base_dir.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), AppError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream(&self, key: &str) -> Result<Option<Body>, AppError> {
+        match fs::File::open(self.path(key)).await {
+            Ok(file) => Ok(Some(Body::from_stream(ReaderStream::new(file)))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(fs::try_exists(self.path(key)).await?)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, AppError> {
+        let mut objects = vec![];
+        let mut dirs = vec![self.base_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                // keys use slashes whatever the platform
+                let Ok(key) = entry.path().strip_prefix(&self.base_dir).map(|path| {
+                    path.components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/")
+                }) else {
+                    continue;
+                };
+                if key.starts_with(prefix) {
+                    objects.push(StoredObject {
+                        key,
+                        modified_at: DateTime::<Utc>::from(metadata.modified()?),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_local_storage_should_work() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat-storage-{}", std::process::id()));
+        let storage = LocalStorage::try_new(&dir).await?;
+
+        let key = "1/abc/def/0123.txt";
+        assert!(!storage.exists(key).await?);
+        assert!(storage.get(key).await?.is_none());
+        assert!(storage.stream(key).await?.is_none());
+
+        storage.put(key, b"hello".to_vec()).await?;
+        assert!(storage.exists(key).await?);
+        let keys: Vec<_> = storage
+            .list("1/")
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .collect();
+        assert_eq!(keys, [key]);
+        assert!(storage.list("2/").await?.is_empty());
+        assert_eq!(storage.get(key).await?.as_deref(), Some(&b"hello"[..]));
+        assert!(storage.stream(key).await?.is_some());
+
+        storage.delete(key).await?;
+        assert!(!storage.exists(key).await?);
+        // deleting again is fine
+        storage.delete(key).await?;
+
+        fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+mod local;
+mod s3;
+
+use async_trait::async_trait;
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::{config::StorageConfig, AppError};
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+/// where the uploaded files are kept, keyed by the path of the file
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), AppError>;
+
+    /// The content of the file, None if it doesn't exist
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError>;
+
+    /// The content of the file as a response body, None if it doesn't exist
+    async fn stream(&self, key: &str) -> Result<Option<Body>, AppError>;
+
+    /// Delete the file, deleting a file that doesn't exist is not an error
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError>;
+
+    /// All the files whose key starts with the prefix
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>, AppError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredObject {
+    pub key: String,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// Create the storage of the config
+pub async fn storage(
+    config: &StorageConfig,
+    base_dir: &Path,
+) -> Result<Arc<dyn Storage>, AppError> {
+    let storage: Arc<dyn Storage> = match config {
+        StorageConfig::Local => Arc::new(LocalStorage::try_new(base_dir).await?),
+        StorageConfig::S3(config) => Arc::new(S3Storage::try_new(config.clone())?),
+    };
+    Ok(storage)
+}
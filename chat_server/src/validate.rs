@@ -0,0 +1,83 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use validator::ValidateArgs;
+
+use crate::{AppConfig, AppError, AppState};
+
+/// A json body checked against the `#[validate]` rules of its type, the rules read their limits
+/// from the config. An invalid body is answered with 422 and an issue per invalid field.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidJson<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<AppState> for ValidJson<T>
+where
+    T: DeserializeOwned + for<'a> ValidateArgs<'a, Args = &'a AppConfig>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Json(input) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        input
+            .validate_with_args(&state.config())
+            .map_err(|e| AppError::from(e).into_response())?;
+        Ok(Self(input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateUser, ErrorOutput};
+    use anyhow::Result;
+    use axum::{body::Body, http::header};
+    use http_body_util::BodyExt as _;
+
+    fn signup(body: &str) -> Request {
+        Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_json_should_list_invalid_fields() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+
+        let body =
+            r#"{"workspace":"acme","full_name":"Tyr Chen","email":"tchen","password":"123"}"#;
+        let Err(resp) = ValidJson::<CreateUser>::from_request(signup(body), &state).await else {
+            panic!("expect the signup to be rejected");
+        };
+        assert_eq!(resp.status(), 422);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        let fields: Vec<_> = ret
+            .details
+            .iter()
+            .map(|v| format!("{}.{}", v.field, v.code))
+            .collect();
+        assert_eq!(fields, ["email.email", "password.too_short"]);
+
+        let body = r#"{"workspace":"acme","full_name":"Tyr Chen","email":"tchen@acme.org","password":"hunter42"}"#;
+        let ValidJson(input) = ValidJson::<CreateUser>::from_request(signup(body), &state)
+            .await
+            .expect("the signup should be valid");
+        assert_eq!(input.email, "tchen@acme.org");
+
+        // a body that isn't json is rejected as before
+        let resp = ValidJson::<CreateUser>::from_request(signup("{"), &state)
+            .await
+            .expect_err("the body should be rejected");
+        assert_eq!(resp.status(), 400);
+        Ok(())
+    }
+}
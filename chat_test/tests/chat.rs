@@ -24,7 +24,11 @@ struct ChatServer {
     client: reqwest::Client,
 }
 
-struct NotifyServer;
+struct NotifyServer {
+    addr: SocketAddr,
+    token: String,
+    client: reqwest::Client,
+}
 
 const WILD_ADDR: &str = "127.0.0.1:0";
 
@@ -33,10 +37,12 @@ async fn chat_server_should_work() -> Result<()> {
     let (tdb, state) = chat_server::AppState::try_new_for_test().await?;
     let chat_server = ChatServer::new(state).await?;
     let db_url = tdb.url();
-    NotifyServer::new(&db_url, &chat_server.token).await?;
+    let notify_server = NotifyServer::new(&db_url, &chat_server.token).await?;
     let chat = chat_server.create_chat().await?;
     let _msg = chat_server.create_message(chat.id as u64).await?;
+    notify_server.relay_typing(chat.id as u64).await?;
     sleep(Duration::from_secs(1)).await;
+    chat_server.catch_up().await?;
     Ok(())
 }
 
@@ -69,11 +75,17 @@ impl NotifyServer {
                             assert_eq!(chat.r#type, ChatType::PrivateChannel);
                         }
                         "NewMessage" => {
+                            let seq = serde_json::from_str::<serde_json::Value>(&message.data)
+                                .unwrap()["seq"]
+                                .clone();
+                            assert_eq!(seq, 2);
                             let message = serde_json::from_str::<Message>(&message.data).unwrap();
                             assert_eq!(message.content, "hello");
                             assert_eq!(message.files.len(), 1);
                             assert_eq!(message.sender_id, 1);
                         }
+                        // the user joined the roster of their chats when connecting
+                        "ChatPresenceChanged" => {}
                         _ => {
                             panic!("Unexpected event: {:?}", message);
                         }
@@ -86,7 +98,33 @@ impl NotifyServer {
             }
         });
 
-        Ok(Self)
+        Ok(Self {
+            addr,
+            token: token.to_string(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    async fn relay_typing(&self, chat_id: u64) -> Result<()> {
+        let url = format!("http://{}/events/ephemeral", self.addr);
+        let resp = self
+            .client
+            .post(&url)
+            .bearer_auth(&self.token)
+            .json(&json!({ "chatId": chat_id, "kind": "typing", "payload": {} }))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        let resp = self
+            .client
+            .post(&url)
+            .bearer_auth(&self.token)
+            .json(&json!({ "chatId": chat_id, "kind": "typing", "payload": "x".repeat(2048) }))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
     }
 }
 
@@ -202,4 +240,47 @@ impl ChatServer {
 
         Ok(msg)
     }
+
+    async fn catch_up(&self) -> Result<()> {
+        let resp = self
+            .client
+            .get(format!("http://{}/api/events?since=0", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // logged by the notify server, typing and presence aren't
+        let ret = resp.json::<serde_json::Value>().await?;
+        let names: Vec<_> = ret["events"]
+            .as_array()
+            .expect("events should be a list")
+            .iter()
+            .map(|e| e["event"]["event"].as_str().unwrap_or_default())
+            .collect();
+        assert_eq!(names, ["NewChat", "NewMessage"]);
+        assert_eq!(ret["lastSeq"], 2);
+        assert_eq!(ret["resync"], false);
+        // being added to the chat is sent again until acked
+        assert_eq!(ret["unacked"][0]["seq"], 1);
+
+        let resp = self
+            .client
+            .post(format!("http://{}/api/events/ack", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .json(&json!({ "seqs": [1] }))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let ret = self
+            .client
+            .get(format!("http://{}/api/events?since=2", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+        assert_eq!(ret["unacked"], json!([]));
+        Ok(())
+    }
 }
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- chat owner, acts as the chat admin
+ALTER TABLE chats
+    ADD COLUMN owner_id bigint REFERENCES users(id);
+
+UPDATE
+    chats
+SET
+    owner_id = members[1];
+
+ALTER TABLE chats
+    ALTER COLUMN owner_id SET NOT NULL;
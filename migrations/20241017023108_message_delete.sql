@@ -0,0 +1,34 @@
+-- Add migration script here
+-- soft delete for messages
+ALTER TABLE messages
+    ADD COLUMN deleted_at timestamptz;
+
+-- if message content changed or message deleted, notify with message data
+CREATE OR REPLACE FUNCTION update_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    RAISE NOTICE 'delete_message: %', NEW.id;
+    -- don't leak the deleted content
+    NEW.content := '';
+    NEW.files := '{}';
+    PERFORM
+      pg_notify('chat_message_deleted', json_build_object('message', NEW, 'members', USERS)::text);
+  ELSIF NEW.content IS DISTINCT FROM OLD.content THEN
+    RAISE NOTICE 'update_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,41 @@
+-- Add migration script here
+-- emoji reactions on messages
+CREATE TABLE IF NOT EXISTS reactions(
+    message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    emoji varchar(32) NOT NULL,
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- if reaction added or removed, notify with reaction data
+CREATE OR REPLACE FUNCTION update_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  REACTION reactions;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    REACTION := NEW;
+  ELSE
+    REACTION := OLD;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = REACTION.chat_id;
+  PERFORM
+    pg_notify('chat_reaction_updated', json_build_object('op', TG_OP, 'reaction', REACTION, 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_reaction_trigger
+  AFTER INSERT OR DELETE ON reactions
+  FOR EACH ROW
+  EXECUTE FUNCTION update_reaction();
@@ -0,0 +1,42 @@
+-- Add migration script here
+-- threaded replies, a reply points to its top-level message
+ALTER TABLE messages
+    ADD COLUMN parent_id bigint REFERENCES messages(id);
+
+CREATE INDEX IF NOT EXISTS messages_parent_id_index ON messages(parent_id)
+WHERE
+    parent_id IS NOT NULL;
+
+-- if new message added, notify with message data
+-- replies are only sent to the thread participants
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
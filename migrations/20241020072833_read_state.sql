@@ -0,0 +1,47 @@
+-- Add migration script here
+-- per member read state of a chat
+CREATE TABLE IF NOT EXISTS chat_members(
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    last_read_message_id bigint NOT NULL DEFAULT 0,
+    read_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS chat_members_user_id_index ON chat_members(user_id);
+
+-- if read state moved forward, notify the senders of the newly read messages
+CREATE OR REPLACE FUNCTION update_read_state()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_READ bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_READ := OLD.last_read_message_id;
+  END IF;
+  IF NEW.last_read_message_id > PREV_READ THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_READ
+      AND id <= NEW.last_read_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_read', json_build_object('read_state', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_read_state_trigger
+  AFTER INSERT OR UPDATE ON chat_members
+  FOR EACH ROW
+  EXECUTE FUNCTION update_read_state();
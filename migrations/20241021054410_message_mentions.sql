@@ -0,0 +1,44 @@
+-- Add migration script here
+-- users mentioned in the message
+ALTER TABLE messages
+    ADD COLUMN mentions bigint[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS messages_mentions_index ON messages USING GIN(mentions);
+
+-- if new message added, notify with message data
+-- replies are only sent to the thread participants, mentioned users get an extra notification
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,27 @@
+-- Add migration script here
+-- full text search for messages
+ALTER TABLE messages
+    ADD COLUMN search_vector tsvector;
+
+CREATE INDEX IF NOT EXISTS messages_search_vector_index ON messages USING GIN(search_vector);
+
+-- keep search vector in sync with content
+CREATE OR REPLACE FUNCTION update_message_search_vector()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  NEW.search_vector := to_tsvector('simple', NEW.content);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_message_search_vector_trigger
+  BEFORE INSERT OR UPDATE OF content ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION update_message_search_vector();
+
+UPDATE
+  messages
+SET
+  search_vector = to_tsvector('simple', content);
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- messages bookmarked by users
+CREATE TABLE IF NOT EXISTS saved_messages(
+    user_id bigint NOT NULL REFERENCES users(id),
+    message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, message_id)
+);
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- messages waiting to be sent at a later time
+CREATE TABLE IF NOT EXISTS scheduled_messages(
+    id bigserial PRIMARY KEY,
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    sender_id bigint NOT NULL REFERENCES users(id),
+    parent_id bigint REFERENCES messages(id) ON DELETE CASCADE,
+    content text NOT NULL,
+    files text[] NOT NULL DEFAULT '{}',
+    send_at timestamptz NOT NULL,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_messages_send_at_index ON scheduled_messages(send_at);
+
+CREATE INDEX IF NOT EXISTS scheduled_messages_sender_id_index ON scheduled_messages(sender_id, send_at);
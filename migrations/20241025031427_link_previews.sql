@@ -0,0 +1,38 @@
+-- Add migration script here
+-- OpenGraph / twitter card metadata of the urls in messages
+CREATE TABLE IF NOT EXISTS link_previews(
+    message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    url text NOT NULL,
+    title text,
+    description text,
+    image text,
+    site_name text,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, url)
+);
+
+-- if a preview is ready, notify chat members with the preview data
+CREATE OR REPLACE FUNCTION add_link_preview()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PERFORM
+    pg_notify('chat_message_preview', json_build_object('preview', NEW, 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_link_preview_trigger
+  AFTER INSERT ON link_previews
+  FOR EACH ROW
+  EXECUTE FUNCTION add_link_preview();
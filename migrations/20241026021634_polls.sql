@@ -0,0 +1,88 @@
+-- Add migration script here
+-- polls are posted as a message whose content is the question
+CREATE TABLE IF NOT EXISTS polls(
+    id bigserial PRIMARY KEY,
+    message_id bigint NOT NULL UNIQUE REFERENCES messages(id) ON DELETE CASCADE,
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    creator_id bigint NOT NULL REFERENCES users(id),
+    question text NOT NULL,
+    options text[] NOT NULL,
+    expires_at timestamptz,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- one vote per user and poll, voting again changes the option
+CREATE TABLE IF NOT EXISTS poll_votes(
+    poll_id bigint NOT NULL REFERENCES polls(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    option int NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (poll_id, user_id)
+);
+
+-- polls with the number of votes of each option
+CREATE OR REPLACE VIEW poll_results AS
+SELECT
+  p.*,
+  ARRAY(
+    SELECT
+      count(v.user_id)
+    FROM
+      generate_subscripts(p.options, 1) AS i
+    LEFT JOIN poll_votes v ON v.poll_id = p.id
+      AND v.option = i - 1
+  GROUP BY
+    i
+  ORDER BY
+    i) AS votes
+FROM
+  polls p;
+
+-- if a poll is created or voted on, notify chat members with the latest results
+CREATE OR REPLACE FUNCTION update_poll()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  POLL_ID bigint;
+  RESULT json;
+BEGIN
+  IF TG_TABLE_NAME = 'polls' THEN
+    POLL_ID := NEW.id;
+  ELSIF TG_OP = 'DELETE' THEN
+    POLL_ID := OLD.poll_id;
+  ELSE
+    POLL_ID := NEW.poll_id;
+  END IF;
+  SELECT
+    row_to_json(r) INTO RESULT
+  FROM
+    poll_results r
+  WHERE
+    r.id = POLL_ID;
+  -- the poll itself is being deleted
+  IF RESULT IS NULL THEN
+    RETURN NULL;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id =(RESULT ->> 'chat_id')::bigint;
+  PERFORM
+    pg_notify('chat_poll_updated', json_build_object('poll', RESULT, 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_poll_trigger
+  AFTER INSERT ON polls
+  FOR EACH ROW
+  EXECUTE FUNCTION update_poll();
+
+CREATE TRIGGER update_poll_vote_trigger
+  AFTER INSERT OR UPDATE OR DELETE ON poll_votes
+  FOR EACH ROW
+  EXECUTE FUNCTION update_poll();
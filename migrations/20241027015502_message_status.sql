@@ -0,0 +1,46 @@
+-- Add migration script here
+-- per member delivery state of a chat, acking a message marks all earlier messages as delivered
+-- so there is one row per member instead of one per message
+CREATE TABLE IF NOT EXISTS message_status(
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    last_delivered_message_id bigint NOT NULL DEFAULT 0,
+    delivered_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+-- if delivery state moved forward, notify the senders of the newly delivered messages
+CREATE OR REPLACE FUNCTION update_message_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_DELIVERED bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_DELIVERED := OLD.last_delivered_message_id;
+  END IF;
+  IF NEW.last_delivered_message_id > PREV_DELIVERED THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_DELIVERED
+      AND id <= NEW.last_delivered_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_delivered', json_build_object('delivery_state', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_message_status_trigger
+  AFTER INSERT OR UPDATE ON message_status
+  FOR EACH ROW
+  EXECUTE FUNCTION update_message_status();
@@ -0,0 +1,15 @@
+-- Add migration script here
+-- metadata of uploaded files, the url is what messages reference in files
+CREATE TABLE IF NOT EXISTS attachments(
+    id bigserial PRIMARY KEY,
+    ws_id bigint NOT NULL REFERENCES workspaces(id),
+    uploader_id bigint NOT NULL REFERENCES users(id),
+    url text NOT NULL UNIQUE,
+    hash varchar(40) NOT NULL,
+    filename text NOT NULL,
+    size bigint NOT NULL,
+    mime varchar(128) NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS attachments_ws_id_index ON attachments(ws_id, id DESC);
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- find messages referencing a file when purging orphaned files
+CREATE INDEX IF NOT EXISTS messages_files_index ON messages USING GIN(files);
+
+CREATE INDEX IF NOT EXISTS messages_created_at_index ON messages(created_at);
@@ -0,0 +1,60 @@
+-- Add migration script here
+-- per user settings of a chat, shared by chat_server and notify_server
+CREATE TABLE IF NOT EXISTS chat_settings(
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    muted boolean NOT NULL DEFAULT FALSE,
+    -- NULL means muted until unmuted
+    muted_until timestamptz,
+    updated_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+-- if new message added, notify with message data
+-- replies are only sent to the thread participants, mentioned users get an extra notification,
+-- members who muted the chat are listed so that notify_server skips them
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS)::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- chat avatar, points to an uploaded file of the chat's workspace
+ALTER TABLE chats
+    ADD COLUMN avatar_url varchar(256);
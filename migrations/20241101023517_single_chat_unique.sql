@@ -0,0 +1,73 @@
+-- Add migration script here
+-- a single chat is the direct message between two users, there is only one per pair
+-- merge existing duplicates into the oldest chat first
+CREATE TEMP TABLE duplicated_single_chats AS
+SELECT
+    id,
+    first_value(id) OVER (PARTITION BY ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]) ORDER BY id) AS keep_id
+FROM
+    chats
+WHERE
+    type = 'single';
+
+DELETE FROM duplicated_single_chats
+WHERE id = keep_id;
+
+UPDATE
+    messages m
+SET
+    chat_id = d.keep_id
+FROM
+    duplicated_single_chats d
+WHERE
+    m.chat_id = d.id;
+
+UPDATE
+    reactions r
+SET
+    chat_id = d.keep_id
+FROM
+    duplicated_single_chats d
+WHERE
+    r.chat_id = d.id;
+
+UPDATE
+    link_previews p
+SET
+    chat_id = d.keep_id
+FROM
+    duplicated_single_chats d
+WHERE
+    p.chat_id = d.id;
+
+UPDATE
+    polls p
+SET
+    chat_id = d.keep_id
+FROM
+    duplicated_single_chats d
+WHERE
+    p.chat_id = d.id;
+
+UPDATE
+    scheduled_messages s
+SET
+    chat_id = d.keep_id
+FROM
+    duplicated_single_chats d
+WHERE
+    s.chat_id = d.id;
+
+-- per member states of the duplicates are dropped with them
+DELETE FROM chats
+WHERE id IN (
+        SELECT
+            id
+        FROM
+            duplicated_single_chats);
+
+DROP TABLE duplicated_single_chats;
+
+CREATE UNIQUE INDEX IF NOT EXISTS chats_single_members_index ON chats(ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]))
+WHERE
+    type = 'single';
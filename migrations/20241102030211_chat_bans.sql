@@ -0,0 +1,9 @@
+-- Add migration script here
+-- users banned from a chat can't be added back or join it by themselves
+CREATE TABLE IF NOT EXISTS chat_bans(
+    chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    banned_by bigint NOT NULL REFERENCES users(id),
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
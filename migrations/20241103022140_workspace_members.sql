@@ -0,0 +1,34 @@
+-- Add migration script here
+-- users can belong to several workspaces, users.ws_id stays as the default one
+CREATE TABLE IF NOT EXISTS workspace_members(
+    ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    user_id bigint NOT NULL REFERENCES users(id),
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_index ON workspace_members(user_id);
+
+INSERT INTO workspace_members(ws_id, user_id)
+SELECT ws_id, id
+FROM users
+WHERE EXISTS (SELECT 1 FROM workspaces w WHERE w.id = users.ws_id)
+ON CONFLICT DO NOTHING;
+
+-- a new user is a member of the workspace it signed up with
+CREATE OR REPLACE FUNCTION add_workspace_member()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  INSERT INTO workspace_members(ws_id, user_id)
+    VALUES (NEW.ws_id, NEW.id)
+  ON CONFLICT DO NOTHING;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_workspace_member_trigger
+  AFTER INSERT ON users
+  FOR EACH ROW
+  EXECUTE FUNCTION add_workspace_member();
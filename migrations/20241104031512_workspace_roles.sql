@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TYPE workspace_role AS ENUM(
+    'member',
+    'admin',
+    'owner'
+);
+
+ALTER TABLE workspace_members
+    ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+UPDATE workspace_members wm
+SET role = 'owner'
+FROM workspaces w
+WHERE w.id = wm.ws_id AND w.owner_id = wm.user_id;
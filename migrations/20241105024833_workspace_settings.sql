@@ -0,0 +1,4 @@
+-- Add migration script here
+-- settings managed by the workspace admins, see WorkspaceSettings
+ALTER TABLE workspaces
+    ADD COLUMN settings jsonb NOT NULL DEFAULT '{}';
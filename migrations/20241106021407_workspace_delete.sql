@@ -0,0 +1,27 @@
+-- Add migration script here
+-- deleted workspaces are kept as tombstones, their chats and files are purged in the background
+ALTER TABLE workspaces
+    ADD COLUMN deleted_at timestamptz;
+
+-- if workspace deleted, notify its members so they can leave it
+CREATE OR REPLACE FUNCTION add_to_workspace_deleted()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    USERS := ARRAY(SELECT user_id FROM workspace_members WHERE ws_id = NEW.id);
+    RAISE NOTICE 'add_to_workspace_deleted: %', NEW.id;
+    PERFORM
+      pg_notify('workspace_deleted', json_build_object('workspace', json_build_object('id', NEW.id, 'name', NEW.name, 'ownerId', NEW.owner_id, 'createdAt', NEW.created_at), 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_workspace_deleted_trigger
+  AFTER UPDATE OF deleted_at ON workspaces
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_workspace_deleted();
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- url of the resized avatar image, served by the files route
+ALTER TABLE users
+    ADD COLUMN avatar_url varchar(256);
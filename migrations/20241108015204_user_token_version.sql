@@ -0,0 +1,4 @@
+-- Add migration script here
+-- tokens carry the version they were issued with, bumping it revokes all of them
+ALTER TABLE users
+    ADD COLUMN token_version integer NOT NULL DEFAULT 0;
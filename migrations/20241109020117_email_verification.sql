@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE users
+    ADD COLUMN email_verified boolean NOT NULL DEFAULT FALSE;
+
+-- accounts created before the verification existed are trusted
+UPDATE users SET email_verified = TRUE;
+
+-- tokens sent to the users to verify their email, one per signup
+CREATE TABLE IF NOT EXISTS email_verifications(
+    token char(64) PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- deleted accounts lose access at once and are anonymized in the background
+ALTER TABLE users
+    ADD COLUMN deleted_at timestamptz;
@@ -0,0 +1,44 @@
+-- Add migration script here
+CREATE TYPE presence_status AS ENUM(
+    'online',
+    'away',
+    'offline'
+);
+
+-- maintained by notify_server from the open event streams of the users
+CREATE TABLE IF NOT EXISTS user_presence(
+    user_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    status presence_status NOT NULL DEFAULT 'offline',
+    -- number of open event streams
+    connections integer NOT NULL DEFAULT 0,
+    updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- if the status changed, notify the users sharing a workspace with the user
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' OR OLD.status IS DISTINCT FROM NEW.status THEN
+    USERS := ARRAY(
+      SELECT DISTINCT wm.user_id
+      FROM workspace_members wm
+      WHERE wm.user_id <> NEW.user_id AND wm.ws_id IN (
+        SELECT ws_id FROM workspace_members WHERE user_id = NEW.user_id
+      )
+    );
+    RAISE NOTICE 'add_to_user_presence: %', NEW.user_id;
+    PERFORM
+      pg_notify('user_presence_changed', json_build_object('presence', json_build_object('userId', NEW.user_id, 'status', NEW.status, 'updatedAt', NEW.updated_at), 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_user_presence_trigger
+  AFTER INSERT OR UPDATE ON user_presence
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_user_presence();
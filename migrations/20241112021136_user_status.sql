@@ -0,0 +1,41 @@
+-- Add migration script here
+-- custom status of the user: {"emoji", "text", "expiresAt"}, null when not set
+ALTER TABLE users
+    ADD COLUMN status jsonb;
+
+-- the status if it hasn't expired yet
+CREATE OR REPLACE FUNCTION active_user_status(status jsonb)
+  RETURNS jsonb
+  AS $$
+  SELECT CASE WHEN COALESCE((status->>'expiresAt')::timestamptz > NOW(), TRUE) THEN status END
+$$
+LANGUAGE sql STABLE;
+
+-- if the status changed, notify the users sharing a workspace with the user
+CREATE OR REPLACE FUNCTION add_to_user_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF OLD.status IS DISTINCT FROM NEW.status THEN
+    USERS := ARRAY(
+      SELECT DISTINCT wm.user_id
+      FROM workspace_members wm
+      WHERE wm.user_id <> NEW.id AND wm.ws_id IN (
+        SELECT ws_id FROM workspace_members WHERE user_id = NEW.id
+      )
+    );
+    RAISE NOTICE 'add_to_user_status: %', NEW.id;
+    PERFORM
+      pg_notify('user_status_changed', json_build_object('user', json_build_object('id', NEW.id, 'fullName', NEW.full_name, 'email', NEW.email, 'avatarUrl', NEW.avatar_url, 'status', NEW.status), 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_user_status_trigger
+  AFTER UPDATE OF status ON users
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_user_status();
@@ -0,0 +1,67 @@
+-- Add migration script here
+-- users blocked by a user, shared by chat_server and notify_server
+CREATE TABLE IF NOT EXISTS user_blocks(
+    blocker_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    blocked_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (blocker_id, blocked_id)
+);
+
+CREATE INDEX IF NOT EXISTS user_blocks_blocked_id_index ON user_blocks(blocked_id);
+
+-- if new message added, notify with message data
+-- replies are only sent to the thread participants, mentioned users get an extra notification,
+-- members who muted the chat or blocked the sender are listed so that notify_server skips them
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+  BLOCKING_USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    SELECT
+      COALESCE(array_agg(blocker_id), '{}') INTO BLOCKING_USERS
+    FROM
+      user_blocks
+    WHERE
+      blocked_id = NEW.sender_id
+      AND blocker_id = ANY (USERS);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS, 'blocked_by', BLOCKING_USERS)::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
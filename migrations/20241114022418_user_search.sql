@@ -0,0 +1,6 @@
+-- Add migration script here
+-- prefix search of users by name or email for the member picker
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS users_full_name_trgm_index ON users USING gin (full_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS users_email_trgm_index ON users USING gin (email gin_trgm_ops);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- deactivated members keep their data but can't use the workspace until reactivated
+ALTER TABLE workspace_members
+    ADD COLUMN deactivated_at timestamptz;
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- only the sha256 of a refresh token is stored, a token is deleted when used
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+    token_hash char(64) PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- the workspace the access tokens are scoped to
+    ws_id bigint NOT NULL,
+    -- the users.token_version it was issued for, a password change revokes it
+    token_version integer NOT NULL,
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_index ON refresh_tokens(user_id);
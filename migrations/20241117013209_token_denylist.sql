@@ -0,0 +1,8 @@
+-- Add migration script here
+-- access tokens revoked before they expire, rows can go once the token expired
+CREATE TABLE IF NOT EXISTS token_denylist(
+    jti varchar(64) PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS token_denylist_expires_at_index ON token_denylist(expires_at);
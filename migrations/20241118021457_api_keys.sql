@@ -0,0 +1,22 @@
+-- Add migration script here
+-- the service identity of an API key, below member so it gets the fewest permissions
+ALTER TYPE workspace_role ADD VALUE IF NOT EXISTS 'bot' BEFORE 'member';
+
+-- only the sha256 of a key is stored, the prefix tells the keys apart in listings
+CREATE TABLE IF NOT EXISTS api_keys(
+    id bigserial PRIMARY KEY,
+    ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    -- the bot user acting with the key
+    user_id bigint NOT NULL REFERENCES users(id),
+    created_by bigint NOT NULL REFERENCES users(id),
+    name varchar(64) NOT NULL,
+    prefix varchar(16) NOT NULL,
+    key_hash char(64) NOT NULL UNIQUE,
+    -- the chats the bot was added to
+    chat_ids bigint[] NOT NULL DEFAULT '{}',
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at timestamptz,
+    revoked_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS api_keys_ws_id_index ON api_keys(ws_id);
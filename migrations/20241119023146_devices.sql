@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TYPE device_platform AS ENUM(
+    'apns',
+    'fcm',
+    'webpush'
+);
+
+-- push tokens of the user devices, a token belongs to the last user registering it
+CREATE TABLE IF NOT EXISTS devices(
+    id bigserial PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    platform device_platform NOT NULL,
+    -- the APNs or FCM token, or the WebPush subscription
+    token text NOT NULL,
+    name varchar(64),
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (platform, token)
+);
+
+CREATE INDEX IF NOT EXISTS devices_user_id_index ON devices(user_id);
+CREATE INDEX IF NOT EXISTS devices_last_seen_at_index ON devices(last_seen_at);
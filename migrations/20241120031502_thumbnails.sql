@@ -0,0 +1,3 @@
+-- Add migration script here
+-- urls of the downscaled versions of the uploaded images
+ALTER TABLE attachments ADD COLUMN thumbnails jsonb NOT NULL DEFAULT '{}';
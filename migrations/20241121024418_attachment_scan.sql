@@ -0,0 +1,7 @@
+-- Add migration script here
+-- uploads are served once the content scanner cleared them, flagged ones are quarantined
+CREATE TYPE scan_status AS ENUM ('pending', 'clean', 'infected', 'failed');
+
+ALTER TABLE attachments
+    ADD COLUMN scan_status scan_status NOT NULL DEFAULT 'clean',
+    ADD COLUMN scan_result text;
@@ -0,0 +1,60 @@
+-- Add migration script here
+-- the content of the files is stored once, whatever the workspace, and kept as long as
+-- an attachment refers to it
+CREATE TABLE IF NOT EXISTS blobs (
+  hash varchar(40) PRIMARY KEY,
+  size bigint NOT NULL,
+  ref_count int NOT NULL DEFAULT 0,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO blobs (hash, size, ref_count)
+SELECT
+  hash,
+  MAX(size),
+  COUNT(*)
+FROM
+  attachments
+GROUP BY
+  hash;
+
+-- a new blob starts unreferenced, so that an upsert hitting an existing url adds no reference
+CREATE OR REPLACE FUNCTION ensure_blob()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  INSERT INTO blobs (hash, size)
+    VALUES (NEW.hash, NEW.size)
+  ON CONFLICT (hash)
+    DO NOTHING;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER ensure_blob_trigger
+  BEFORE INSERT ON attachments
+  FOR EACH ROW
+  EXECUTE FUNCTION ensure_blob();
+
+CREATE OR REPLACE FUNCTION update_blob_ref_count()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    UPDATE blobs SET ref_count = ref_count + 1 WHERE hash = NEW.hash;
+  ELSE
+    UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = OLD.hash;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_blob_ref_count_trigger
+  AFTER INSERT OR DELETE ON attachments
+  FOR EACH ROW
+  EXECUTE FUNCTION update_blob_ref_count();
+
+ALTER TABLE attachments
+  ADD CONSTRAINT attachments_hash_fkey FOREIGN KEY (hash) REFERENCES blobs (hash);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- duration and dimensions of audios and videos, with the url of their web rendition
+ALTER TABLE attachments ADD COLUMN media jsonb NOT NULL DEFAULT '{}';
@@ -0,0 +1,42 @@
+-- Add migration script here
+-- when a user opens their first event stream or closes their last one, tell the members of
+-- their chats who is connected there
+CREATE OR REPLACE FUNCTION notify_chat_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  JOINED boolean;
+  rec record;
+BEGIN
+  JOINED := NEW.connections > 0;
+  IF TG_OP = 'INSERT' AND NOT JOINED THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'UPDATE' AND (OLD.connections > 0) = JOINED THEN
+    RETURN NEW;
+  END IF;
+  FOR rec IN
+    SELECT
+      c.id,
+      c.members,
+      ARRAY(
+        SELECT p.user_id
+        FROM user_presence p
+        WHERE p.user_id = ANY(c.members) AND p.connections > 0
+        ORDER BY p.user_id
+      ) AS online
+    FROM chats c
+    WHERE c.members @> ARRAY[NEW.user_id]
+  LOOP
+    PERFORM
+      pg_notify('chat_presence_changed', json_build_object('presence', json_build_object('chatId', rec.id, 'userId', NEW.user_id, 'joined', JOINED, 'online', rec.online), 'members', rec.members)::text);
+  END LOOP;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_chat_presence_trigger
+  AFTER INSERT OR UPDATE OF connections ON user_presence
+  FOR EACH ROW
+  EXECUTE FUNCTION notify_chat_presence();
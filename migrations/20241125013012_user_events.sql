@@ -0,0 +1,16 @@
+-- Add migration script here
+-- the events sent to each user, numbered per user, so that a client back from offline
+-- fetches what it missed instead of every chat
+ALTER TABLE users
+  ADD COLUMN event_seq bigint NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS user_events(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  seq bigint NOT NULL,
+  event jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, seq)
+);
+
+-- for the purge of the old events
+CREATE INDEX IF NOT EXISTS user_events_created_at_index ON user_events(created_at);
@@ -0,0 +1,84 @@
+-- Add migration script here
+-- which new messages a user is notified of: all of them, the ones mentioning them or none
+CREATE TYPE notify_level AS ENUM(
+  'all',
+  'mentions',
+  'nothing'
+);
+
+-- the default of the user, and the one of a chat overriding it, NULL follows the default
+ALTER TABLE users
+  ADD COLUMN notify_level notify_level NOT NULL DEFAULT 'all';
+
+ALTER TABLE chat_settings
+  ADD COLUMN notify_level notify_level;
+
+-- if new message added, notify with message data
+-- replies are only sent to the thread participants, mentioned users get an extra notification,
+-- members who muted the chat, blocked the sender or only want some messages are listed so
+-- that notify_server skips them
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+  BLOCKING_USERS bigint[];
+  MENTIONS_ONLY_USERS bigint[];
+  SILENT_USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    SELECT
+      COALESCE(array_agg(blocker_id), '{}') INTO BLOCKING_USERS
+    FROM
+      user_blocks
+    WHERE
+      blocked_id = NEW.sender_id
+      AND blocker_id = ANY (USERS);
+    SELECT
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'mentions'), '{}'),
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'nothing'), '{}')
+      INTO MENTIONS_ONLY_USERS, SILENT_USERS
+    FROM
+      users u
+      LEFT JOIN chat_settings s ON s.chat_id = NEW.chat_id
+        AND s.user_id = u.id
+    WHERE
+      u.id = ANY (USERS);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS, 'blocked_by', BLOCKING_USERS, 'mentions_only', MENTIONS_ONLY_USERS, 'silent', SILENT_USERS)::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
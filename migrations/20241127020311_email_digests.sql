@@ -0,0 +1,15 @@
+-- Add migration script here
+-- when the user last had an open event stream, the users away for long get a digest of what
+-- they missed by email
+ALTER TABLE user_presence
+  ADD COLUMN last_connected_at timestamptz;
+
+UPDATE
+  user_presence
+SET
+  last_connected_at = updated_at;
+
+ALTER TABLE users
+  ADD COLUMN email_digest boolean NOT NULL DEFAULT TRUE,
+  -- the messages up to then were in a digest already
+  ADD COLUMN digest_sent_at timestamptz;
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- the events a client must not miss, e.g. being added to a chat, are sent again until the
+-- client acknowledges them
+ALTER TABLE user_events
+  ADD COLUMN critical boolean NOT NULL DEFAULT FALSE,
+  ADD COLUMN acked_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS user_events_unacked_index ON user_events(user_id, seq)
+WHERE
+  critical AND acked_at IS NULL;
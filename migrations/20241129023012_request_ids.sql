@@ -0,0 +1,357 @@
+-- Add migration script here
+-- the id of the request whose statement fired a trigger, set on each connection by chat_core's
+-- connect_db, null for the statements run outside of a request
+CREATE OR REPLACE FUNCTION current_request_id()
+  RETURNS text
+  AS $$
+  SELECT NULLIF(current_setting('app.request_id', TRUE), '');
+$$
+LANGUAGE sql STABLE;
+
+-- the notifications carry the request id so that notify_server's logs correlate with the api call
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW, 'request_id', current_request_id())::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+  BLOCKING_USERS bigint[];
+  MENTIONS_ONLY_USERS bigint[];
+  SILENT_USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    SELECT
+      COALESCE(array_agg(blocker_id), '{}') INTO BLOCKING_USERS
+    FROM
+      user_blocks
+    WHERE
+      blocked_id = NEW.sender_id
+      AND blocker_id = ANY (USERS);
+    SELECT
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'mentions'), '{}'),
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'nothing'), '{}')
+      INTO MENTIONS_ONLY_USERS, SILENT_USERS
+    FROM
+      users u
+      LEFT JOIN chat_settings s ON s.chat_id = NEW.chat_id
+        AND s.user_id = u.id
+    WHERE
+      u.id = ANY (USERS);
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS, 'blocked_by', BLOCKING_USERS, 'mentions_only', MENTIONS_ONLY_USERS, 'silent', SILENT_USERS, 'request_id', current_request_id())::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions, 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    RAISE NOTICE 'delete_message: %', NEW.id;
+    -- don't leak the deleted content
+    NEW.content := '';
+    NEW.files := '{}';
+    PERFORM
+      pg_notify('chat_message_deleted', json_build_object('message', NEW, 'members', USERS, 'request_id', current_request_id())::text);
+  ELSIF NEW.content IS DISTINCT FROM OLD.content THEN
+    RAISE NOTICE 'update_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS, 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  REACTION reactions;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    REACTION := NEW;
+  ELSE
+    REACTION := OLD;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = REACTION.chat_id;
+  PERFORM
+    pg_notify('chat_reaction_updated', json_build_object('op', TG_OP, 'reaction', REACTION, 'members', USERS, 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_read_state()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_READ bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_READ := OLD.last_read_message_id;
+  END IF;
+  IF NEW.last_read_message_id > PREV_READ THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_READ
+      AND id <= NEW.last_read_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_read', json_build_object('read_state', NEW, 'members', USERS, 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_link_preview()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PERFORM
+    pg_notify('chat_message_preview', json_build_object('preview', NEW, 'members', USERS, 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_poll()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  POLL_ID bigint;
+  RESULT json;
+BEGIN
+  IF TG_TABLE_NAME = 'polls' THEN
+    POLL_ID := NEW.id;
+  ELSIF TG_OP = 'DELETE' THEN
+    POLL_ID := OLD.poll_id;
+  ELSE
+    POLL_ID := NEW.poll_id;
+  END IF;
+  SELECT
+    row_to_json(r) INTO RESULT
+  FROM
+    poll_results r
+  WHERE
+    r.id = POLL_ID;
+  -- the poll itself is being deleted
+  IF RESULT IS NULL THEN
+    RETURN NULL;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id =(RESULT ->> 'chat_id')::bigint;
+  PERFORM
+    pg_notify('chat_poll_updated', json_build_object('poll', RESULT, 'members', USERS, 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_message_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_DELIVERED bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_DELIVERED := OLD.last_delivered_message_id;
+  END IF;
+  IF NEW.last_delivered_message_id > PREV_DELIVERED THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_DELIVERED
+      AND id <= NEW.last_delivered_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_delivered', json_build_object('delivery_state', NEW, 'members', USERS, 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_workspace_deleted()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    USERS := ARRAY(SELECT user_id FROM workspace_members WHERE ws_id = NEW.id);
+    RAISE NOTICE 'add_to_workspace_deleted: %', NEW.id;
+    PERFORM
+      pg_notify('workspace_deleted', json_build_object('workspace', json_build_object('id', NEW.id, 'name', NEW.name, 'ownerId', NEW.owner_id, 'createdAt', NEW.created_at), 'members', USERS, 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' OR OLD.status IS DISTINCT FROM NEW.status THEN
+    USERS := ARRAY(
+      SELECT DISTINCT wm.user_id
+      FROM workspace_members wm
+      WHERE wm.user_id <> NEW.user_id AND wm.ws_id IN (
+        SELECT ws_id FROM workspace_members WHERE user_id = NEW.user_id
+      )
+    );
+    RAISE NOTICE 'add_to_user_presence: %', NEW.user_id;
+    PERFORM
+      pg_notify('user_presence_changed', json_build_object('presence', json_build_object('userId', NEW.user_id, 'status', NEW.status, 'updatedAt', NEW.updated_at), 'members', USERS, 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_user_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF OLD.status IS DISTINCT FROM NEW.status THEN
+    USERS := ARRAY(
+      SELECT DISTINCT wm.user_id
+      FROM workspace_members wm
+      WHERE wm.user_id <> NEW.id AND wm.ws_id IN (
+        SELECT ws_id FROM workspace_members WHERE user_id = NEW.id
+      )
+    );
+    RAISE NOTICE 'add_to_user_status: %', NEW.id;
+    PERFORM
+      pg_notify('user_status_changed', json_build_object('user', json_build_object('id', NEW.id, 'fullName', NEW.full_name, 'email', NEW.email, 'avatarUrl', NEW.avatar_url, 'status', NEW.status), 'members', USERS, 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION notify_chat_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  JOINED boolean;
+  rec record;
+BEGIN
+  JOINED := NEW.connections > 0;
+  IF TG_OP = 'INSERT' AND NOT JOINED THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'UPDATE' AND (OLD.connections > 0) = JOINED THEN
+    RETURN NEW;
+  END IF;
+  FOR rec IN
+    SELECT
+      c.id,
+      c.members,
+      ARRAY(
+        SELECT p.user_id
+        FROM user_presence p
+        WHERE p.user_id = ANY(c.members) AND p.connections > 0
+        ORDER BY p.user_id
+      ) AS online
+    FROM chats c
+    WHERE c.members @> ARRAY[NEW.user_id]
+  LOOP
+    PERFORM
+      pg_notify('chat_presence_changed', json_build_object('presence', json_build_object('chatId', rec.id, 'userId', NEW.user_id, 'joined', JOINED, 'online', rec.online), 'members', rec.members, 'request_id', current_request_id())::text);
+  END LOOP;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,23 @@
+-- Add migration script here
+-- the notify servers running, a replica missing its heartbeats is gone along with its streams
+CREATE TABLE IF NOT EXISTS notify_replicas(
+    id bigserial PRIMARY KEY,
+    heartbeat_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the open event streams of the users per replica, user_presence.connections is their sum
+CREATE TABLE IF NOT EXISTS presence_connections(
+    replica_id bigint NOT NULL,
+    user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    connections integer NOT NULL DEFAULT 0,
+    PRIMARY KEY (replica_id, user_id)
+);
+
+-- ids of the events sent to the streams, shared by the replicas so that a client can resume
+-- its stream on any of them
+CREATE SEQUENCE IF NOT EXISTS stream_event_ids;
+
+-- the streams counted so far belong to no replica
+UPDATE user_presence
+SET connections = 0, status = 'offline', updated_at = CURRENT_TIMESTAMP
+WHERE connections > 0 OR status <> 'offline';
@@ -0,0 +1,399 @@
+-- Add migration script here
+-- the events are logged by the triggers sending them, in the transaction of the change, so the
+-- log has them even when notify_server is down. The notifications carry the log id of each
+-- event under `logged`, notify_server reads the numbers of the users from the log.
+ALTER TABLE user_events
+  ADD COLUMN log_id bigint;
+
+CREATE INDEX IF NOT EXISTS user_events_log_id_index ON user_events(log_id);
+
+CREATE SEQUENCE IF NOT EXISTS user_event_log_ids;
+
+-- snake_case to camelCase, as the api serializes the fields and enum values
+CREATE OR REPLACE FUNCTION camel_case(name text)
+  RETURNS text
+  AS $$
+  SELECT lower(left(name, 1)) || substr(replace(initcap(name), '_', ''), 2);
+$$
+LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION camel_keys(obj jsonb)
+  RETURNS jsonb
+  AS $$
+  SELECT COALESCE(jsonb_object_agg(camel_case(k), v), '{}'::jsonb)
+  FROM jsonb_each(obj) AS e(k, v);
+$$
+LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION chat_json(chat chats)
+  RETURNS jsonb
+  AS $$
+  SELECT camel_keys(to_jsonb(chat)) || jsonb_build_object('type', camel_case((chat).type::text));
+$$
+LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION message_json(message messages)
+  RETURNS jsonb
+  AS $$
+  SELECT camel_keys(to_jsonb(message) - 'search_vector');
+$$
+LANGUAGE sql IMMUTABLE;
+
+-- Add the event to the log of each user, numbered per user, the id of the log entry is
+-- returned, null when there's nobody to tell. The users are locked in order so that
+-- concurrent changes don't deadlock.
+CREATE OR REPLACE FUNCTION log_user_event(user_ids bigint[], event_name text, event_data jsonb, is_critical boolean)
+  RETURNS bigint
+  AS $$
+DECLARE
+  LOG_ID bigint;
+BEGIN
+  IF COALESCE(cardinality(user_ids), 0) = 0 THEN
+    RETURN NULL;
+  END IF;
+  LOG_ID := nextval('user_event_log_ids');
+  WITH locked AS (
+    SELECT users.id FROM users WHERE users.id = ANY (user_ids) ORDER BY id FOR UPDATE
+  ), seqs AS (
+    UPDATE users u SET event_seq = u.event_seq + 1
+    FROM locked
+    WHERE u.id = locked.id
+    RETURNING u.id AS user_id, u.event_seq AS seq
+  )
+  INSERT INTO user_events (user_id, seq, event, critical, log_id)
+  SELECT seqs.user_id, seqs.seq, jsonb_build_object('event', event_name) || event_data, is_critical, LOG_ID
+  FROM seqs;
+  RETURN LOG_ID;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  LOGGED jsonb := '{}';
+  ADDED bigint[];
+  REMOVED bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  IF TG_OP = 'INSERT' THEN
+    LOGGED := jsonb_build_object('NewChat', log_user_event(NEW.members, 'NewChat', chat_json(NEW), TRUE));
+  ELSIF TG_OP = 'DELETE' THEN
+    LOGGED := jsonb_build_object('ChatDeleted', log_user_event(OLD.members, 'ChatDeleted', chat_json(OLD), TRUE));
+  ELSE
+    ADDED := ARRAY(SELECT unnest(NEW.members) EXCEPT SELECT unnest(OLD.members));
+    REMOVED := ARRAY(SELECT unnest(OLD.members) EXCEPT SELECT unnest(NEW.members));
+    IF cardinality(ADDED) > 0 THEN
+      LOGGED := LOGGED || jsonb_build_object('MemberAdded', log_user_event(NEW.members, 'MemberAdded', jsonb_build_object('chat', chat_json(NEW), 'userIds', to_jsonb(ADDED)), TRUE));
+    END IF;
+    IF cardinality(REMOVED) > 0 THEN
+      LOGGED := LOGGED || jsonb_build_object('MemberRemoved', log_user_event(OLD.members, 'MemberRemoved', jsonb_build_object('chat', chat_json(NEW), 'userIds', to_jsonb(REMOVED)), TRUE));
+    END IF;
+    IF NEW.name IS DISTINCT FROM OLD.name THEN
+      LOGGED := LOGGED || jsonb_build_object('ChatRenamed', log_user_event(NEW.members, 'ChatRenamed', chat_json(NEW), FALSE));
+    END IF;
+    IF (to_jsonb(NEW) - 'members' - 'name') IS DISTINCT FROM (to_jsonb(OLD) - 'members' - 'name') THEN
+      LOGGED := LOGGED || jsonb_build_object('ChatUpdated', log_user_event(NEW.members, 'ChatUpdated', chat_json(NEW), FALSE));
+    END IF;
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW, 'logged', LOGGED, 'request_id', current_request_id())::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+  BLOCKING_USERS bigint[];
+  MENTIONS_ONLY_USERS bigint[];
+  SILENT_USERS bigint[];
+  RECIPIENTS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    SELECT
+      COALESCE(array_agg(blocker_id), '{}') INTO BLOCKING_USERS
+    FROM
+      user_blocks
+    WHERE
+      blocked_id = NEW.sender_id
+      AND blocker_id = ANY (USERS);
+    SELECT
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'mentions'), '{}'),
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'nothing'), '{}')
+      INTO MENTIONS_ONLY_USERS, SILENT_USERS
+    FROM
+      users u
+      LEFT JOIN chat_settings s ON s.chat_id = NEW.chat_id
+        AND s.user_id = u.id
+    WHERE
+      u.id = ANY (USERS);
+    -- the members told about the message, as notify_server picks them
+    RECIPIENTS := ARRAY(
+      SELECT id
+      FROM unnest(USERS) AS id
+      WHERE id <> ALL (MUTED_USERS)
+        AND id <> ALL (BLOCKING_USERS)
+        AND id <> ALL (SILENT_USERS)
+        AND (id <> ALL (MENTIONS_ONLY_USERS) OR id = ANY (NEW.mentions))
+    );
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS, 'blocked_by', BLOCKING_USERS, 'mentions_only', MENTIONS_ONLY_USERS, 'silent', SILENT_USERS, 'logged', jsonb_build_object('NewMessage', log_user_event(RECIPIENTS, 'NewMessage', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+    IF cardinality(NEW.mentions) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', NEW.mentions, 'logged', jsonb_build_object('Mention', log_user_event(NEW.mentions, 'Mention', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    RAISE NOTICE 'delete_message: %', NEW.id;
+    -- don't leak the deleted content
+    NEW.content := '';
+    NEW.files := '{}';
+    PERFORM
+      pg_notify('chat_message_deleted', json_build_object('message', NEW, 'members', USERS, 'logged', jsonb_build_object('MessageDeleted', log_user_event(USERS, 'MessageDeleted', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+  ELSIF NEW.content IS DISTINCT FROM OLD.content THEN
+    RAISE NOTICE 'update_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS, 'logged', jsonb_build_object('MessageEdited', log_user_event(USERS, 'MessageEdited', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  REACTION reactions;
+  EVENT text;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    REACTION := NEW;
+    EVENT := 'ReactionAdded';
+  ELSE
+    REACTION := OLD;
+    EVENT := 'ReactionRemoved';
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = REACTION.chat_id;
+  PERFORM
+    pg_notify('chat_reaction_updated', json_build_object('op', TG_OP, 'reaction', REACTION, 'members', USERS, 'logged', jsonb_build_object(EVENT, log_user_event(USERS, EVENT, camel_keys(to_jsonb(REACTION)), FALSE)), 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_read_state()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_READ bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_READ := OLD.last_read_message_id;
+  END IF;
+  IF NEW.last_read_message_id > PREV_READ THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_READ
+      AND id <= NEW.last_read_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_read', json_build_object('read_state', NEW, 'members', USERS, 'logged', jsonb_build_object('MessageRead', log_user_event(USERS, 'MessageRead', camel_keys(to_jsonb(NEW)), FALSE)), 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_link_preview()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PERFORM
+    pg_notify('chat_message_preview', json_build_object('preview', NEW, 'members', USERS, 'logged', jsonb_build_object('MessagePreviewReady', log_user_event(USERS, 'MessagePreviewReady', camel_keys(to_jsonb(NEW)), FALSE)), 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_poll()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  POLL_ID bigint;
+  RESULT json;
+BEGIN
+  IF TG_TABLE_NAME = 'polls' THEN
+    POLL_ID := NEW.id;
+  ELSIF TG_OP = 'DELETE' THEN
+    POLL_ID := OLD.poll_id;
+  ELSE
+    POLL_ID := NEW.poll_id;
+  END IF;
+  SELECT
+    row_to_json(r) INTO RESULT
+  FROM
+    poll_results r
+  WHERE
+    r.id = POLL_ID;
+  -- the poll itself is being deleted
+  IF RESULT IS NULL THEN
+    RETURN NULL;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id =(RESULT ->> 'chat_id')::bigint;
+  PERFORM
+    pg_notify('chat_poll_updated', json_build_object('poll', RESULT, 'members', USERS, 'logged', jsonb_build_object('PollUpdated', log_user_event(USERS, 'PollUpdated', camel_keys(RESULT::jsonb), FALSE)), 'request_id', current_request_id())::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_message_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PREV_DELIVERED bigint := 0;
+BEGIN
+  IF TG_OP = 'UPDATE' THEN
+    PREV_DELIVERED := OLD.last_delivered_message_id;
+  END IF;
+  IF NEW.last_delivered_message_id > PREV_DELIVERED THEN
+    SELECT
+      array_agg(DISTINCT sender_id) INTO USERS
+    FROM
+      messages
+    WHERE
+      chat_id = NEW.chat_id
+      AND id > PREV_DELIVERED
+      AND id <= NEW.last_delivered_message_id
+      AND sender_id <> NEW.user_id;
+    IF USERS IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_delivered', json_build_object('delivery_state', NEW, 'members', USERS, 'logged', jsonb_build_object('MessageDelivered', log_user_event(USERS, 'MessageDelivered', camel_keys(to_jsonb(NEW)), FALSE)), 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_workspace_deleted()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  WORKSPACE jsonb;
+BEGIN
+  IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+    USERS := ARRAY(SELECT user_id FROM workspace_members WHERE ws_id = NEW.id);
+    WORKSPACE := jsonb_build_object('id', NEW.id, 'name', NEW.name, 'ownerId', NEW.owner_id, 'createdAt', NEW.created_at);
+    RAISE NOTICE 'add_to_workspace_deleted: %', NEW.id;
+    PERFORM
+      pg_notify('workspace_deleted', json_build_object('workspace', WORKSPACE, 'members', USERS, 'logged', jsonb_build_object('WorkspaceDeleted', log_user_event(USERS, 'WorkspaceDeleted', WORKSPACE, FALSE)), 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_user_status()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  CHAT_USER jsonb;
+BEGIN
+  IF OLD.status IS DISTINCT FROM NEW.status THEN
+    USERS := ARRAY(
+      SELECT DISTINCT wm.user_id
+      FROM workspace_members wm
+      WHERE wm.user_id <> NEW.id AND wm.ws_id IN (
+        SELECT ws_id FROM workspace_members WHERE user_id = NEW.id
+      )
+    );
+    CHAT_USER := jsonb_build_object('id', NEW.id, 'fullName', NEW.full_name, 'email', NEW.email, 'avatarUrl', NEW.avatar_url, 'status', NEW.status);
+    RAISE NOTICE 'add_to_user_status: %', NEW.id;
+    PERFORM
+      pg_notify('user_status_changed', json_build_object('user', CHAT_USER, 'members', USERS, 'logged', jsonb_build_object('UserStatusChanged', log_user_event(USERS, 'UserStatusChanged', CHAT_USER, FALSE)), 'request_id', current_request_id())::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
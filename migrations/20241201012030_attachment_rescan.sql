@@ -0,0 +1,7 @@
+-- Add migration script here
+-- the uploads still pending or failed some time after their last scan are scanned again
+ALTER TABLE attachments
+    ADD COLUMN scanned_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS attachments_unscanned_index ON attachments(COALESCE(scanned_at, created_at))
+    WHERE scan_status IN ('pending', 'failed');
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- set once the purge of a deleted account is done, the sweep resumes the others
+ALTER TABLE users
+    ADD COLUMN purged_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS users_unpurged_index ON users(deleted_at)
+    WHERE deleted_at IS NOT NULL AND purged_at IS NULL;
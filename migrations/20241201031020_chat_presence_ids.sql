@@ -0,0 +1,22 @@
+-- Add migration script here
+-- the chats and the members of a user may not fit in a notification, whose payload is at most
+-- 8000 bytes, only the user is sent and notify_server looks up their chats
+CREATE OR REPLACE FUNCTION notify_chat_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  JOINED boolean;
+BEGIN
+  JOINED := NEW.connections > 0;
+  IF TG_OP = 'INSERT' AND NOT JOINED THEN
+    RETURN NEW;
+  END IF;
+  IF TG_OP = 'UPDATE' AND (OLD.connections > 0) = JOINED THEN
+    RETURN NEW;
+  END IF;
+  PERFORM
+    pg_notify('chat_presence_changed', json_build_object('user_id', NEW.user_id, 'joined', JOINED, 'request_id', current_request_id())::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
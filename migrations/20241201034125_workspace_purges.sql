@@ -0,0 +1,7 @@
+-- Add migration script here
+-- set once the purge of a deleted workspace is done, the sweep resumes the others
+ALTER TABLE workspaces
+    ADD COLUMN purged_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS workspaces_unpurged_index ON workspaces(deleted_at)
+    WHERE deleted_at IS NOT NULL AND purged_at IS NULL;
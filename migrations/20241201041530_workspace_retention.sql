@@ -0,0 +1,12 @@
+-- Add migration script here
+-- messages and files of the workspace created before this are purged, its retention_days
+-- setting replaces the server's cutoff, null keeps them
+CREATE OR REPLACE FUNCTION workspace_cutoff(settings jsonb, server_cutoff timestamptz)
+  RETURNS timestamptz
+  AS $$
+  SELECT CASE WHEN COALESCE((settings->>'retention_days')::bigint, 0) > 0
+    THEN NOW() - make_interval(days => (settings->>'retention_days')::int)
+    ELSE server_cutoff
+  END
+$$
+LANGUAGE sql STABLE;
@@ -0,0 +1,87 @@
+-- Add migration script here
+-- the users who blocked the sender aren't told about being mentioned either
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  MUTED_USERS bigint[];
+  BLOCKING_USERS bigint[];
+  MENTIONS_ONLY_USERS bigint[];
+  SILENT_USERS bigint[];
+  RECIPIENTS bigint[];
+  MENTIONED bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    IF NEW.parent_id IS NOT NULL THEN
+      SELECT
+        array_agg(DISTINCT sender_id) INTO USERS
+      FROM
+        messages
+      WHERE (id = NEW.parent_id
+        OR parent_id = NEW.parent_id)
+      AND sender_id = ANY (USERS);
+    END IF;
+    SELECT
+      COALESCE(array_agg(user_id), '{}') INTO MUTED_USERS
+    FROM
+      chat_settings
+    WHERE
+      chat_id = NEW.chat_id
+      AND muted
+      AND (muted_until IS NULL
+        OR muted_until > CURRENT_TIMESTAMP);
+    SELECT
+      COALESCE(array_agg(blocker_id), '{}') INTO BLOCKING_USERS
+    FROM
+      user_blocks
+    WHERE
+      blocked_id = NEW.sender_id
+      AND blocker_id = ANY (USERS);
+    SELECT
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'mentions'), '{}'),
+      COALESCE(array_agg(u.id) FILTER (WHERE COALESCE(s.notify_level, u.notify_level) = 'nothing'), '{}')
+      INTO MENTIONS_ONLY_USERS, SILENT_USERS
+    FROM
+      users u
+      LEFT JOIN chat_settings s ON s.chat_id = NEW.chat_id
+        AND s.user_id = u.id
+    WHERE
+      u.id = ANY (USERS);
+    -- the members told about the message, as notify_server picks them
+    RECIPIENTS := ARRAY(
+      SELECT id
+      FROM unnest(USERS) AS id
+      WHERE id <> ALL (MUTED_USERS)
+        AND id <> ALL (BLOCKING_USERS)
+        AND id <> ALL (SILENT_USERS)
+        AND (id <> ALL (MENTIONS_ONLY_USERS) OR id = ANY (NEW.mentions))
+    );
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'muted', MUTED_USERS, 'blocked_by', BLOCKING_USERS, 'mentions_only', MENTIONS_ONLY_USERS, 'silent', SILENT_USERS, 'logged', jsonb_build_object('NewMessage', log_user_event(RECIPIENTS, 'NewMessage', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+    -- the mentioned users may not be in the thread, their blocks are looked up apart
+    MENTIONED := ARRAY(
+      SELECT id
+      FROM unnest(NEW.mentions) AS id
+      WHERE NOT EXISTS (
+        SELECT 1 FROM user_blocks
+        WHERE blocker_id = id AND blocked_id = NEW.sender_id
+      )
+    );
+    IF cardinality(MENTIONED) > 0 THEN
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', NEW, 'members', MENTIONED, 'logged', jsonb_build_object('Mention', log_user_event(MENTIONED, 'Mention', message_json(NEW), FALSE)), 'request_id', current_request_id())::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
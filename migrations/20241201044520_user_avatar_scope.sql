@@ -0,0 +1,12 @@
+-- Add migration script here
+-- the avatars of the users are served to all their workspaces, not only the one they were
+-- uploaded in
+UPDATE
+  users u
+SET
+  avatar_url = '/avatars/' || u.id || '/' || a.hash || '.png'
+FROM
+  attachments a
+WHERE
+  a.url = u.avatar_url
+  AND a.uploader_id = u.id;
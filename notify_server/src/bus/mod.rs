@@ -0,0 +1,132 @@
+mod redis;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tracing::info;
+
+use crate::{config::BusConfig, notify::Notification, UserMap};
+
+pub use self::redis::RedisBus;
+
+/// Gets the notifications to the streams of the users, whatever replica they're
+/// connected to
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, notification: Notification) -> Result<()>;
+
+    /// Whether other replicas share the bus, a single replica forwards the notifications
+    /// of the database then
+    fn is_shared(&self) -> bool;
+}
+
+/// what goes through a shared bus, the event is borrowed to be sent and owned once received
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct BusMessage<E> {
+    user_ids: HashSet<u64>,
+    event: E,
+    #[serde(default)]
+    seqs: HashMap<u64, i64>,
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+/// delivers to the users connected to this server, for when it's the only one
+pub struct LocalBus {
+    users: UserMap,
+}
+
+#[async_trait]
+impl EventBus for LocalBus {
+    async fn publish(&self, notification: Notification) -> Result<()> {
+        deliver(&self.users, &notification);
+        Ok(())
+    }
+
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+/// Send the notification to the streams of its users connected to this server
+pub(crate) fn deliver(users: &UserMap, notification: &Notification) {
+    for user_id in &notification.user_ids {
+        if let Some(channel) = users.get(user_id) {
+            let seq = notification.seqs.get(user_id).copied();
+            channel.send(notification.event.clone(), notification.id, seq);
+            info!(
+                "Sent notification {:?} to user[{}]",
+                notification.id, user_id
+            );
+        }
+    }
+}
+
+/// Create the bus of the config
+pub async fn event_bus(config: &BusConfig, users: UserMap) -> Result<Arc<dyn EventBus>> {
+    Ok(match config {
+        BusConfig::Local => Arc::new(LocalBus { users }),
+        BusConfig::Redis(config) => Arc::new(RedisBus::try_new(config, users).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::EventsConfig, AppEvent, UserChannel};
+    use chat_core::{Presence, PresenceStatus};
+    use dashmap::DashMap;
+
+    fn notification(user_ids: &[u64]) -> Notification {
+        Notification {
+            user_ids: user_ids.iter().copied().collect(),
+            event: Arc::new(AppEvent::PresenceChanged(Presence {
+                user_id: 3,
+                status: PresenceStatus::Away,
+                updated_at: None,
+            })),
+            seqs: HashMap::new(),
+            id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn local_bus_should_deliver_to_connected_users() -> Result<()> {
+        let users: UserMap = Arc::new(DashMap::new());
+        let channel = Arc::new(UserChannel::new(&EventsConfig::default(), 0));
+        users.insert(1, channel.clone());
+        let mut rx = channel.subscribe(None).rx;
+
+        let bus = event_bus(&BusConfig::Local, users).await?;
+        assert!(!bus.is_shared());
+        bus.publish(notification(&[1, 2])).await?;
+        let sent = rx.recv().await?;
+        assert!(matches!(sent.event.as_ref(), AppEvent::PresenceChanged(p) if p.user_id == 3));
+        Ok(())
+    }
+
+    #[test]
+    fn bus_message_should_round_trip() -> Result<()> {
+        let mut notification = notification(&[1, 2]);
+        notification.id = Some(7);
+        let message = BusMessage {
+            user_ids: notification.user_ids.clone(),
+            event: notification.event.as_ref(),
+            seqs: notification.seqs.clone(),
+            id: notification.id,
+        };
+        let payload = serde_json::to_string(&message)?;
+        let received: BusMessage<AppEvent> = serde_json::from_str(&payload)?;
+        assert_eq!(received.user_ids, notification.user_ids);
+        assert!(received.seqs.is_empty());
+        // the replicas send the event with the id the forwarder gave it
+        assert_eq!(received.id, Some(7));
+        assert!(
+            matches!(received.event, AppEvent::PresenceChanged(p) if p.status == PresenceStatus::Away)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use std::{sync::Arc, time::Duration};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use super::{deliver, BusMessage, EventBus};
+use crate::{config::RedisConfig, notify::Notification, AppEvent, UserMap};
+
+/// wait before subscribing again when the connection to redis is lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Redis pub/sub shared by the replicas, each one delivers what it receives to the users
+/// connected to it. Events published while a replica is disconnected are missed by it.
+pub struct RedisBus {
+    conn: MultiplexedConnection,
+    channel: String,
+}
+
+impl RedisBus {
+    pub async fn try_new(config: &RedisConfig, users: UserMap) -> Result<Self> {
+        let client = Client::open(config.url.as_str())?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        spawn_subscriber(client, config.channel.clone(), users);
+        Ok(Self {
+            conn,
+            channel: config.channel.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventBus for RedisBus {
+    async fn publish(&self, notification: Notification) -> Result<()> {
+        let message = BusMessage {
+            user_ids: notification.user_ids,
+            event: notification.event.as_ref(),
+            seqs: notification.seqs,
+            id: notification.id,
+        };
+        let payload = serde_json::to_string(&message)?;
+        let mut conn = self.conn.clone();
+        conn.publish::<_, _, ()>(&self.channel, payload).await?;
+        Ok(())
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+fn spawn_subscriber(client: Client, channel: String, users: UserMap) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = subscribe(&client, &channel, &users).await {
+                warn!("Lost the subscription to {}: {}", channel, e);
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    });
+}
+
+/// Deliver the messages of the channel until the connection is lost
+async fn subscribe(client: &Client, channel: &str, users: &UserMap) -> Result<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    info!("Subscribed to {}", channel);
+
+    let mut stream = pubsub.on_message();
+    while let Some(msg) = stream.next().await {
+        let payload: String = msg.get_payload()?;
+        match serde_json::from_str::<BusMessage<AppEvent>>(&payload) {
+            Ok(message) => deliver(
+                users,
+                &Notification {
+                    user_ids: message.user_ids,
+                    event: Arc::new(message.event),
+                    seqs: message.seqs,
+                    id: message.id,
+                },
+            ),
+            Err(e) => warn!("Invalid message on {}: {}", channel, e),
+        }
+    }
+    Ok(())
+}
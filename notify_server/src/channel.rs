@@ -0,0 +1,182 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+
+use crate::{config::EventsConfig, AppEvent};
+
+/// an event sent to a user, the id is what the client resumes from
+#[derive(Debug)]
+pub struct SentEvent {
+    /// assigned by the replica forwarding the notifications, none for the ephemeral events
+    pub id: Option<u64>,
+    pub event: Arc<AppEvent>,
+    /// its number in the log of the user, for the logged events
+    pub seq: Option<i64>,
+    sent_at: Instant,
+}
+
+/// The events of a user, the live ones and the recent ones replayed to the clients
+/// reconnecting with a Last-Event-ID
+pub struct UserChannel {
+    tx: broadcast::Sender<Arc<SentEvent>>,
+    history: Mutex<History>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+struct History {
+    /// the clients whose last event is older than this may have missed some
+    floor: u64,
+    events: VecDeque<Arc<SentEvent>>,
+}
+
+/// the events of a stream, the ones it missed first
+pub struct Subscription {
+    pub missed: Vec<Arc<SentEvent>>,
+    /// some events the client missed are gone, it has to fetch the state again
+    pub lost: bool,
+    pub rx: broadcast::Receiver<Arc<SentEvent>>,
+}
+
+impl UserChannel {
+    /// The channel of a user, the events up to `floor` were sent before it existed,
+    /// e.g. by another replica or a previous run
+    pub fn new(config: &EventsConfig, floor: u64) -> Self {
+        let (tx, _) = broadcast::channel(config.channel_capacity.max(1));
+        Self {
+            tx,
+            history: Mutex::new(History {
+                floor,
+                events: VecDeque::new(),
+            }),
+            capacity: config.replay_capacity,
+            ttl: Duration::from_secs(config.replay_ttl),
+        }
+    }
+
+    /// Send an event to the streams of the user and keep it for the ones reconnecting,
+    /// the events without an id, e.g. ephemeral ones, are only sent live
+    pub fn send(&self, event: Arc<AppEvent>, id: Option<u64>, seq: Option<i64>) {
+        let mut history = self.history.lock().expect("history lock poisoned");
+        let sent = Arc::new(SentEvent {
+            id,
+            event,
+            seq,
+            sent_at: Instant::now(),
+        });
+        if id.is_some() {
+            history.events.push_back(sent.clone());
+            self.trim(&mut history);
+        }
+        // nobody listening is fine, the event is replayed when they're back
+        let _ = self.tx.send(sent);
+    }
+
+    /// Subscribe to the events of the user, along with the ones still kept that were sent
+    /// after the last one the client got
+    pub fn subscribe(&self, last_id: Option<u64>) -> Subscription {
+        // under the lock of the senders, so that no event is both missed and received
+        let mut history = self.history.lock().expect("history lock poisoned");
+        self.trim(&mut history);
+        let (missed, lost) = match last_id {
+            Some(last_id) => {
+                let missed = history
+                    .events
+                    .iter()
+                    .filter(|sent| sent.id.is_some_and(|id| id > last_id))
+                    .cloned()
+                    .collect();
+                (missed, last_id < history.floor)
+            }
+            None => (vec![], false),
+        };
+        Subscription {
+            missed,
+            lost,
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    fn trim(&self, history: &mut History) {
+        while history.events.len() > self.capacity
+            || history
+                .events
+                .front()
+                .is_some_and(|sent| sent.sent_at.elapsed() > self.ttl)
+        {
+            if let Some(id) = history.events.pop_front().and_then(|sent| sent.id) {
+                history.floor = id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{Presence, PresenceStatus};
+
+    fn event(user_id: i64) -> Arc<AppEvent> {
+        Arc::new(AppEvent::PresenceChanged(Presence {
+            user_id,
+            status: PresenceStatus::Online,
+            updated_at: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn user_channel_should_replay_missed_events() {
+        let config = EventsConfig {
+            replay_capacity: 2,
+            ..Default::default()
+        };
+        let channel = UserChannel::new(&config, 10);
+        channel.send(event(1), Some(11), None);
+        channel.send(event(2), Some(12), Some(1));
+        // only sent live
+        channel.send(event(2), None, None);
+
+        let mut subscription = channel.subscribe(Some(11));
+        let ids: Vec<_> = subscription.missed.iter().map(|sent| sent.id).collect();
+        assert_eq!(ids, [Some(12)]);
+        assert_eq!(subscription.missed[0].seq, Some(1));
+        assert!(!subscription.lost);
+        channel.send(event(3), Some(13), None);
+        let sent = subscription.rx.recv().await.expect("event should be sent");
+        assert_eq!(sent.id, Some(13));
+
+        // the first one is gone, the client has to fetch the state again
+        let subscription = channel.subscribe(Some(10));
+        let ids: Vec<_> = subscription.missed.iter().map(|sent| sent.id).collect();
+        assert_eq!(ids, [Some(12), Some(13)]);
+        assert!(subscription.lost);
+        // the events sent before the channel existed, e.g. by the replica the client was on
+        let other = UserChannel::new(&config, 13);
+        assert!(other.subscribe(Some(12)).lost);
+        assert!(!other.subscribe(Some(13)).lost);
+        // and a new stream replays nothing
+        let subscription = channel.subscribe(None);
+        assert!(subscription.missed.is_empty() && !subscription.lost);
+    }
+
+    #[tokio::test]
+    async fn user_channel_should_overflow_at_its_capacity() {
+        let config = EventsConfig {
+            channel_capacity: 2,
+            ..Default::default()
+        };
+        let channel = UserChannel::new(&config, 0);
+        let mut subscription = channel.subscribe(None);
+        for user_id in 1..=3 {
+            channel.send(event(user_id), Some(user_id as u64), None);
+        }
+
+        let ret = subscription.rx.recv().await;
+        assert!(matches!(ret, Err(broadcast::error::RecvError::Lagged(1))));
+        assert!(subscription.rx.recv().await.is_ok());
+    }
+}
@@ -1,39 +1,249 @@
-use std::{env, fs::File};
+use std::{env, path::PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::Result;
+use chat_core::{
+    middlewares::{CompressionConfig, CorsConfig, MetricsConfig, NetworkAcl, RouteRateLimits},
+    ConfigLoader, JwtConfig, ListenAddr, LogConfig,
+};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// the networks the clients may connect from
+    #[serde(default)]
+    pub acl: NetworkAcl,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub bus: BusConfig,
+    #[serde(default)]
+    pub ephemeral: EphemeralConfig,
+    /// the event streams are opened with a read, the ephemeral events are sent with a write
+    #[serde(default)]
+    pub rate_limit: RouteRateLimits,
+    #[serde(default)]
+    pub push: PushConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub pk: String,
+    /// other public keys tokens may be signed with, e.g. the previous key during a rotation
+    #[serde(default)]
+    pub extra_pks: Vec<String>,
+    /// seconds between two reloads of the config file, 0 disables it, SIGHUP reloads it as well
+    #[serde(default)]
+    pub key_reload_interval: u64,
+    /// must match the one of the chat server
+    #[serde(default)]
+    pub jwt: JwtConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl AuthConfig {
+    /// All the public keys tokens may be signed with, the current one first
+    pub fn pks(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.pk.as_str()).chain(self.extra_pks.iter().map(String::as_str))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// a TCP address or the path of a unix socket, all the interfaces on `port` by default
+    #[serde(default)]
+    pub listen: Option<ListenAddr>,
+    /// the proxies in front of the server, whose x-request-id is kept, the clients of a unix
+    /// socket are 127.0.0.1
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// events kept per user for the clients reconnecting with a Last-Event-ID
+    pub replay_capacity: usize,
+    /// seconds an event is kept for the clients reconnecting
+    pub replay_ttl: u64,
+    /// seconds between two pings of a quiet stream, below the idle timeout of the proxies
+    pub keep_alive: u64,
+    /// milliseconds the clients wait before reconnecting a dropped stream
+    pub retry: u64,
+    /// seconds the streams get to send what's queued and close on shutdown
+    pub close_timeout: u64,
+    /// events queued per user for the streams not keeping up
+    pub channel_capacity: usize,
+    /// what's done with a stream whose queue overflowed
+    pub overflow: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// the oldest events are dropped, the client is sent a Resync event
+    #[default]
+    Resync,
+    /// the stream is closed, the client reconnects and gets the events it missed replayed
+    Disconnect,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            replay_capacity: 256,
+            replay_ttl: 300,
+            keep_alive: 15,
+            retry: 3000,
+            close_timeout: 10,
+            channel_capacity: 256,
+            overflow: OverflowPolicy::Resync,
+        }
+    }
+}
+
+/// limits of the events clients relay to the other members of their chats
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EphemeralConfig {
+    /// bytes of the json payload of an event
+    pub max_size: usize,
+    /// events a user may relay per second
+    pub per_second: u32,
+}
+
+impl Default for EphemeralConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1024,
+            per_second: 10,
+        }
+    }
+}
+
+/// how the events get to the replica the user is connected to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum BusConfig {
+    /// a single notify server, it delivers all the events
+    #[default]
+    Local,
+    Redis(RedisConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub url: String,
+    /// pub/sub channel the replicas share
+    pub channel: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://localhost:6379".to_string(),
+            channel: "chat:events".to_string(),
+        }
+    }
+}
+
+/// pushes to the devices of the users without an open event stream, the devices of a
+/// platform without a provider get none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    /// pushes waiting to be sent, new ones are dropped when it's full
+    pub queue_size: usize,
+    /// tries of a push before giving up, the delay doubles between two of them
+    pub max_attempts: u32,
+    /// milliseconds before the first retry
+    pub retry_delay: u64,
+    /// the pushes show the content of the messages, or only who sent them
+    pub show_content: bool,
+    pub fcm: Option<FcmConfig>,
+    pub apns: Option<ApnsConfig>,
+    pub webpush: Option<WebPushConfig>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            max_attempts: 4,
+            retry_delay: 1000,
+            show_content: true,
+            fcm: None,
+            apns: None,
+            webpush: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcmConfig {
+    /// path of the json key of the service account of the Firebase project
+    pub credentials: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApnsConfig {
+    /// the .p8 signing key of the team, in PEM
+    pub key: String,
+    pub key_id: String,
+    pub team_id: String,
+    /// bundle id of the app
+    pub topic: String,
+    /// the development environment, for the debug builds of the app
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebPushConfig {
+    /// the P-256 VAPID private key, in PEM, the browsers subscribe with its public key
+    pub vapid_key: String,
+    /// contact of the operator, e.g. mailto:admin@example.com
+    pub subject: String,
+}
+
+impl ServerConfig {
+    pub fn listen_addr(&self) -> ListenAddr {
+        self.listen
+            .clone()
+            .unwrap_or_else(|| ListenAddr::Tcp(([0, 0, 0, 0], self.port).into()))
+    }
 }
 
 impl AppConfig {
+    /// Load the config from the defaults, then ./notify.yml, /etc/config/notify.yml or the file
+    /// in env NOTIFY_CONFIG, then the `NOTIFY__SECTION__FIELD` env vars
     pub fn try_load() -> Result<Self> {
-        // read from ./notify.yml, or /etc/config/notify.yml, or from env NOTIFY_CONFIG
-        let ret = match (
-            File::open("notify.yml"),
-            File::open("/etc/config/notify.yml"),
-            env::var("NOTIFY_CONFIG"),
-        ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
-            (_, Ok(reader), _) => serde_yaml::from_reader(reader),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
-            _ => bail!("Failed to load config"),
-        };
-
-        Ok(ret?)
+        Self::try_load_with_args(std::iter::empty())
+    }
+
+    /// Like `try_load`, with the `--section.field=value` flags overriding all the other layers
+    pub fn try_load_with_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let files = ["notify.yml".into(), "/etc/config/notify.yml".into()]
+            .into_iter()
+            .chain(env::var("NOTIFY_CONFIG").ok().map(PathBuf::from));
+        let config: Self = ConfigLoader::new()
+            .file(files)?
+            .env("NOTIFY", env::vars())
+            .args(args)?
+            .build()?;
+        config.cors.validate()?;
+        Ok(config)
     }
 }
@@ -0,0 +1,140 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::User;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{notify::Notification, AppError, AppEvent, AppState};
+
+/// longest kind of event, e.g. `typing` or `call_ringing`
+const MAX_KIND_LEN: usize = 32;
+/// the window the rate of the events of a user is counted over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// an event the client sends to the other members of a chat, neither stored nor replayed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EphemeralInput {
+    #[serde(alias = "chat_id")]
+    pub chat_id: u64,
+    /// what the payload is about, lowercase letters, digits and '_'
+    pub kind: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+/// what the other members get, along with who sent it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EphemeralEvent {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+}
+
+/// the events sent by each user in the current window
+#[derive(Default)]
+pub(crate) struct EphemeralLimiter {
+    windows: DashMap<u64, (Instant, u32)>,
+}
+
+impl EphemeralLimiter {
+    /// Count an event of the user, false when they sent too many in the window
+    fn allow(&self, user_id: u64, per_window: u32) -> bool {
+        let now = Instant::now();
+        let mut window = self.windows.entry(user_id).or_insert((now, 0));
+        if now.duration_since(window.0) >= RATE_WINDOW {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        window.1 <= per_window
+    }
+}
+
+/// Relay a small event, e.g. typing, to the other members of a chat of mine
+pub(crate) async fn ephemeral_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<EphemeralInput>,
+) -> Result<impl IntoResponse, AppError> {
+    let config = &state.config().ephemeral;
+    let valid_kind = input
+        .kind
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if input.kind.is_empty() || input.kind.len() > MAX_KIND_LEN || !valid_kind {
+        return Err(AppError::InvalidEvent(format!(
+            "kind must be 1 to {MAX_KIND_LEN} lowercase letters, digits or '_'"
+        )));
+    }
+    let size = input.payload.to_string().len();
+    if size > config.max_size {
+        return Err(AppError::InvalidEvent(format!(
+            "payload is {size} bytes, at most {} are relayed",
+            config.max_size
+        )));
+    }
+    if !state
+        .ephemeral_limiter
+        .allow(user.id as _, config.per_second)
+    {
+        return Err(AppError::TooManyEvents);
+    }
+
+    let members: Option<Vec<i64>> =
+        sqlx::query_scalar("SELECT members FROM chats WHERE id = $1 AND $2 = ANY(members)")
+            .bind(input.chat_id as i64)
+            .bind(user.id)
+            .fetch_optional(&state.pool)
+            .await?;
+    let Some(members) = members else {
+        return Err(AppError::NotChatMember(input.chat_id));
+    };
+
+    let user_ids: HashSet<u64> = members
+        .into_iter()
+        .filter(|id| *id != user.id)
+        .map(|id| id as u64)
+        .collect();
+    let event = EphemeralEvent {
+        chat_id: input.chat_id as _,
+        user_id: user.id,
+        kind: input.kind,
+        payload: input.payload,
+    };
+    state
+        .bus
+        .publish(Notification {
+            user_ids,
+            event: Arc::new(AppEvent::Ephemeral(event)),
+            seqs: HashMap::new(),
+            id: None,
+        })
+        .await
+        .map_err(|e| AppError::BusError(format!("{e:#}")))?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ephemeral_limiter_should_limit_per_user() {
+        let limiter = EphemeralLimiter::default();
+        assert!(limiter.allow(1, 2));
+        assert!(limiter.allow(1, 2));
+        assert!(!limiter.allow(1, 2));
+        assert!(limiter.allow(2, 2));
+
+        // a new window starts over
+        limiter.windows.insert(1, (Instant::now() - RATE_WINDOW, 5));
+        assert!(limiter.allow(1, 2));
+    }
+}
@@ -3,12 +3,16 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use chat_core::middlewares::current_request_id;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorOutput {
     pub error: String,
+    /// the id of the failed request, to look it up in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -18,12 +22,40 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("token has been revoked")]
+    TokenRevoked,
+
+    #[error("user is deactivated in workspace {0}")]
+    MemberDeactivated(i64),
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("invalid event: {0}")]
+    InvalidEvent(String),
+
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+
+    #[error("not a member of chat {0}")]
+    NotChatMember(u64),
+
+    #[error("too many events, slow down")]
+    TooManyEvents,
+
+    #[error("event bus error: {0}")]
+    BusError(String),
+
+    #[error("the server is shutting down")]
+    ServerClosing,
 }
 
 impl ErrorOutput {
     pub fn new(error: impl Into<String>) -> Self {
         Self {
             error: error.into(),
+            request_id: current_request_id(),
         }
     }
 }
@@ -33,6 +65,15 @@ impl IntoResponse for AppError {
         let status = match &self {
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::JwtError(_) => StatusCode::FORBIDDEN,
+            Self::TokenRevoked => StatusCode::UNAUTHORIZED,
+            Self::MemberDeactivated(_) => StatusCode::UNAUTHORIZED,
+            Self::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidEvent(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+            Self::NotChatMember(_) => StatusCode::FORBIDDEN,
+            Self::TooManyEvents => StatusCode::TOO_MANY_REQUESTS,
+            Self::BusError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::ServerClosing => StatusCode::SERVICE_UNAVAILABLE,
         };
 
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
@@ -0,0 +1,210 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use prometheus::{register_int_counter, IntCounter};
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+
+use crate::AppState;
+
+/// what the listener of the database notifications is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerStatus {
+    Connecting,
+    /// getting the notifications of the database
+    Listening,
+    /// another replica forwards the notifications, this one gets them from the bus
+    Standby,
+    /// the connection was lost, waiting before connecting again
+    Reconnecting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerHealth {
+    pub status: ListenerStatus,
+    /// failed attempts since the listener last worked
+    pub failures: u32,
+    /// why the last attempt failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ListenerHealth {
+    pub fn is_ready(&self) -> bool {
+        matches!(
+            self.status,
+            ListenerStatus::Listening | ListenerStatus::Standby
+        )
+    }
+}
+
+/// The health of the listener, updated by its supervisor
+pub(crate) struct ListenerState(Mutex<ListenerHealth>);
+
+impl ListenerState {
+    pub(crate) fn new() -> Self {
+        Self(Mutex::new(ListenerHealth {
+            status: ListenerStatus::Connecting,
+            failures: 0,
+            error: None,
+        }))
+    }
+
+    pub(crate) fn get(&self) -> ListenerHealth {
+        self.0.lock().expect("listener health poisoned").clone()
+    }
+
+    /// Set what the listener is doing, the failures are cleared once it works again
+    pub(crate) fn set(&self, status: ListenerStatus) {
+        let mut health = self.0.lock().expect("listener health poisoned");
+        health.status = status;
+        if health.is_ready() {
+            health.failures = 0;
+            health.error = None;
+        }
+    }
+
+    /// The listener stopped, the number of failures in a row is returned
+    pub(crate) fn fail(&self, error: &anyhow::Error) -> u32 {
+        let mut health = self.0.lock().expect("listener health poisoned");
+        health.status = ListenerStatus::Reconnecting;
+        health.failures += 1;
+        health.error = Some(format!("{error:#}"));
+        health.failures
+    }
+}
+
+/// How the event streams kept up since the server started, the counters are also served
+/// on `/metrics`
+#[derive(Debug, Clone)]
+pub(crate) struct StreamMetrics {
+    overflows: IntCounter,
+    dropped_events: IntCounter,
+    disconnects: IntCounter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StreamStats {
+    /// times a stream fell too far behind its events
+    pub overflows: u64,
+    /// events dropped from the queues that overflowed
+    pub dropped_events: u64,
+    /// streams closed for overflowing
+    pub disconnects: u64,
+}
+
+impl Default for StreamMetrics {
+    /// registered once in the default registry, shared by all the states of the process
+    fn default() -> Self {
+        static METRICS: OnceLock<StreamMetrics> = OnceLock::new();
+        METRICS
+            .get_or_init(|| Self {
+                overflows: register_int_counter!(
+                    "sse_stream_overflows_total",
+                    "Times an event stream fell too far behind its events"
+                )
+                .expect("Failed to register sse_stream_overflows_total"),
+                dropped_events: register_int_counter!(
+                    "sse_dropped_events_total",
+                    "Events dropped from the queues of the streams that overflowed"
+                )
+                .expect("Failed to register sse_dropped_events_total"),
+                disconnects: register_int_counter!(
+                    "sse_stream_disconnects_total",
+                    "Event streams closed for overflowing"
+                )
+                .expect("Failed to register sse_stream_disconnects_total"),
+            })
+            .clone()
+    }
+}
+
+impl StreamMetrics {
+    pub(crate) fn overflowed(&self, dropped: u64, disconnected: bool) {
+        self.overflows.inc();
+        self.dropped_events.inc_by(dropped);
+        if disconnected {
+            self.disconnects.inc();
+        }
+    }
+
+    pub(crate) fn get(&self) -> StreamStats {
+        StreamStats {
+            overflows: self.overflows.get(),
+            dropped_events: self.dropped_events.get(),
+            disconnects: self.disconnects.get(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthOutput {
+    ready: bool,
+    /// the server is shutting down, the load balancer should stop sending it clients
+    closing: bool,
+    listener: ListenerHealth,
+    streams: StreamStats,
+}
+
+/// For the readiness checks, 503 while the notifications of the database aren't received
+/// or the server is shutting down
+pub(crate) async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let listener = state.listener.get();
+    let closing = *state.closing.borrow();
+    let ready = listener.is_ready() && !closing;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(HealthOutput {
+            ready,
+            closing,
+            listener,
+            streams: state.streams.get(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_state_should_track_failures() {
+        let state = ListenerState::new();
+        assert!(!state.get().is_ready());
+
+        let error = anyhow::anyhow!("connection refused");
+        assert_eq!(state.fail(&error), 1);
+        assert_eq!(state.fail(&error), 2);
+        let health = state.get();
+        assert_eq!(health.status, ListenerStatus::Reconnecting);
+        assert_eq!(health.error.as_deref(), Some("connection refused"));
+
+        state.set(ListenerStatus::Listening);
+        let health = state.get();
+        assert!(health.is_ready());
+        assert_eq!(health.failures, 0);
+        assert!(health.error.is_none());
+    }
+
+    #[test]
+    fn stream_metrics_should_be_registered() {
+        let metrics = StreamMetrics::default();
+        let before = StreamMetrics::default().get();
+        metrics.overflowed(3, true);
+
+        let names: Vec<_> = prometheus::gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert!(names.iter().any(|name| name == "sse_dropped_events_total"));
+        // the counters are shared, other tests may add to them meanwhile
+        let after = StreamMetrics::default().get();
+        assert!(after.overflows > before.overflows);
+        assert!(after.dropped_events >= before.dropped_events + 3);
+        assert!(after.disconnects > before.disconnects);
+    }
+}
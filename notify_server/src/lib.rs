@@ -1,51 +1,119 @@
+mod bus;
+mod channel;
 mod config;
+mod ephemeral;
 mod error;
+mod health;
 mod notify;
+mod presence;
+mod push;
+mod reload;
+mod shutdown;
 mod sse;
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use axum::{
+    extract::DefaultBodyLimit,
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
+use bus::EventBus;
 use chat_core::{
-    middlewares::{verify_token, TokenVerify},
-    DecodingKey, User,
+    is_member_deactivated, is_token_denied, is_token_outdated,
+    middlewares::{
+        rate_limit, set_layer, verify_token, RouteRateLimit, RouteRateLimiter, TokenVerify,
+    },
+    DecodingKey, ReloadableRouter, User,
 };
 use dashmap::DashMap;
+use ephemeral::{ephemeral_handler, EphemeralLimiter};
+use health::{health_handler, ListenerState, StreamMetrics};
+use push::PushQueue;
+use sqlx::PgPool;
 use sse::sse_handler;
 use std::{ops::Deref, sync::Arc};
-use tokio::sync::broadcast;
+use tokio::sync::watch;
 
-pub use config::AppConfig;
+pub use channel::{SentEvent, Subscription, UserChannel};
+pub use config::{AppConfig, OverflowPolicy};
+pub use ephemeral::{EphemeralEvent, EphemeralInput};
 pub use error::AppError;
+pub use health::{ListenerHealth, ListenerStatus, StreamStats};
 pub use notify::AppEvent;
+pub use reload::spawn_config_reload;
+pub use shutdown::Shutdown;
 
 const INDEX_HTML: &str = include_str!("../index.html");
+/// the payloads are checked against their own limit, this only refuses oversized requests early
+const EPHEMERAL_BODY_LIMIT: usize = 64 * 1024;
 
-pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
+pub type UserMap = Arc<DashMap<u64, Arc<UserChannel>>>;
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
 
 pub struct AppStateInner {
-    pub config: AppConfig,
+    pub config: ArcSwap<AppConfig>,
     users: UserMap,
+    bus: Arc<dyn EventBus>,
+    ephemeral_limiter: EphemeralLimiter,
+    /// requests per user
+    route_limiter: Arc<RouteRateLimiter>,
+    push: PushQueue,
+    listener: ListenerState,
+    streams: StreamMetrics,
+    /// set on shutdown, the streams send what's queued and end
+    closing: watch::Sender<bool>,
     dk: DecodingKey,
+    pool: PgPool,
+    /// the row of the replica in notify_replicas, its streams are counted under it
+    replica_id: i64,
 }
 
 pub async fn get_router(config: AppConfig) -> Result<Router> {
-    let state = AppState::new(config);
+    let (app, _) = get_server(config, vec![]).await?;
+    Ok(app)
+}
+
+/// The router, with the handle closing its event streams on shutdown. The config is reloaded
+/// with the flags in `args`, see `spawn_config_reload`.
+pub async fn get_server(config: AppConfig, args: Vec<String>) -> Result<(Router, Shutdown)> {
+    let pool = PgPool::connect(&config.server.db_url).await?;
+    let replica_id = presence::register_replica(&pool).await?;
+    presence::spawn_heartbeat(pool.clone(), replica_id);
+    let state = AppState::try_new(config, pool, replica_id).await?;
     notify::setup_pg_listener(state.clone()).await?;
+    let router = ReloadableRouter::new(build_router(state.clone()));
+    spawn_config_reload(state.clone(), router.clone(), args);
+    Ok((router.router(), Shutdown(state)))
+}
+
+fn build_router(state: AppState) -> Router {
+    let config = state.config();
+    let limit = RouteRateLimit::new(state.route_limiter.clone(), &config.rate_limit);
     let app = Router::new()
         .route("/events", get(sse_handler))
+        .route(
+            "/events/ephemeral",
+            post(ephemeral_handler).layer(DefaultBodyLimit::max(EPHEMERAL_BODY_LIMIT)),
+        )
+        .layer(from_fn_with_state(limit, rate_limit))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/", get(index_handler))
+        .route("/health", get(health_handler))
         .with_state(state);
 
-    Ok(app)
+    set_layer(
+        app,
+        &config.acl,
+        &config.metrics,
+        &config.server.trusted_proxies,
+        &config.cors,
+        &config.compression,
+    )
 }
 
 async fn index_handler() -> impl IntoResponse {
@@ -55,8 +123,22 @@ async fn index_handler() -> impl IntoResponse {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        let token = self.dk.verify_access_token(token)?;
+        if let Some(jti) = &token.jti {
+            if is_token_denied(&self.pool, jti).await? {
+                return Err(AppError::TokenRevoked);
+            }
+        }
+        let user = token.user;
+        // a password change revokes the tokens issued before, as in chat_server
+        if is_token_outdated(&self.pool, &user).await? {
+            return Err(AppError::TokenRevoked);
+        }
+        if is_member_deactivated(&self.pool, user.ws_id, user.id).await? {
+            return Err(AppError::MemberDeactivated(user.ws_id));
+        }
+        Ok(user)
     }
 }
 
@@ -69,11 +151,58 @@ impl Deref for AppState {
 }
 
 impl AppState {
-    fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
-        let users = Arc::new(DashMap::new());
-        let inner = Arc::new(AppStateInner { config, users, dk });
+    /// The config, it changes when reloaded so it's read again rather than kept
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.load_full()
+    }
+
+    async fn try_new(config: AppConfig, pool: PgPool, replica_id: i64) -> Result<Self> {
+        let dk = DecodingKey::load_with_config(config.auth.pks(), config.auth.jwt.clone())
+            .expect("Failed to load public keys");
+        let users: UserMap = Arc::new(DashMap::new());
+        let bus = bus::event_bus(&config.bus, users.clone()).await?;
+        let push = PushQueue::try_new(&config.push, pool.clone())?;
+        let inner = Arc::new(AppStateInner {
+            config: ArcSwap::from_pointee(config),
+            users,
+            bus,
+            ephemeral_limiter: EphemeralLimiter::default(),
+            route_limiter: Arc::default(),
+            push,
+            listener: ListenerState::new(),
+            streams: StreamMetrics::default(),
+            closing: watch::Sender::new(false),
+            dk,
+            pool,
+            replica_id,
+        });
+
+        Ok(Self(inner))
+    }
+}
+
+#[cfg(test)]
+mod test_util {
+    use super::*;
+    use sqlx::Executor;
+    use sqlx_db_tester::TestPg;
+    use std::path::Path;
+
+    impl AppState {
+        /// The state on a database of its own, with the fixtures of the chat server
+        pub(crate) async fn try_new_for_test() -> Result<(TestPg, Self)> {
+            let config = AppConfig::try_load()?;
+            let tdb = TestPg::new(config.server.db_url.clone(), Path::new("../migrations"));
+            let pool = tdb.get_pool().await;
+            let sql = include_str!("../../chat_server/fixtures/test.sql").split(';');
+            let mut tx = pool.begin().await?;
+            for s in sql.filter(|s| !s.trim().is_empty()) {
+                tx.execute(s).await?;
+            }
+            tx.commit().await?;
 
-        Self(inner)
+            let state = Self::try_new(config, pool, 0).await?;
+            Ok((tdb, state))
+        }
     }
 }
@@ -1,23 +1,27 @@
 use anyhow::Result;
-use notify_server::{get_router, AppConfig};
-use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use chat_core::{init_logging, serve};
+use notify_server::{get_server, AppConfig};
+use std::{env, time::Duration};
+use tracing::warn;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = AppConfig::try_load_with_args(args.clone())?;
+    init_logging(&config.log)?;
 
-    let addr = "0.0.0.0:6687";
+    let addr = config.server.listen_addr();
+    let timeout = Duration::from_secs(config.events.close_timeout);
+    let (app, shutdown) = get_server(config, args).await?;
 
-    let config = AppConfig::try_load().expect("Failed to load config");
-    let app = get_router(config).await?;
-
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
-
-    axum::serve(listener, app.into_make_service()).await?;
+    // no new connections once closing, the open streams get until the deadline to end
+    let server = serve(app, &addr, shutdown.clone().on_signal());
+    tokio::select! {
+        ret = server => ret?,
+        _ = shutdown.deadline(timeout) => {
+            warn!("Some streams were still open after {:?}, exiting", timeout);
+        }
+    }
 
     Ok(())
 }
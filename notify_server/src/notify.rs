@@ -1,28 +1,163 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use anyhow::Result;
-use chat_core::{Chat, Message};
+use chat_core::{
+    Chat, ChatPresence, ChatUser, DeliveryState, LinkPreview, Message, Poll, Presence, Reaction,
+    ReadState, UploadProgress, Workspace,
+};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
-use tokio_stream::StreamExt;
-use tracing::{info, warn};
+use sqlx::{
+    postgres::{PgListener, PgNotification},
+    types::Json,
+    Connection, FromRow, PgConnection, PgPool,
+};
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, info_span, warn, Instrument};
 
-use crate::AppState;
+use crate::{health::ListenerStatus, AppState, EphemeralEvent};
+
+/// advisory lock held by the replica forwarding the notifications of the database to a
+/// shared bus
+const FORWARDER_LOCK: i64 = 0x6e6f_7469_6679;
+/// how often the other replicas try to take the lock, and the forwarder checks it still has it
+const FORWARDER_CHECK: Duration = Duration::from_secs(5);
+/// delay before connecting the listener again, doubled with each failure in a row
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
 pub enum AppEvent {
     NewChat(Chat),
-    AddToChat(Chat),
-    RemoveFromChat(Chat),
+    /// sent to all the members, the added ones included
+    MemberAdded {
+        chat: Chat,
+        user_ids: Vec<i64>,
+    },
+    /// sent to the remaining members and the removed ones
+    MemberRemoved {
+        chat: Chat,
+        user_ids: Vec<i64>,
+    },
+    ChatRenamed(Chat),
+    /// any other change of the chat, e.g. its owner or avatar
+    ChatUpdated(Chat),
+    ChatDeleted(Chat),
     NewMessage(Message),
+    MessageEdited(Message),
+    MessageDeleted(Message),
+    ReactionAdded(Reaction),
+    ReactionRemoved(Reaction),
+    MessageRead(ReadState),
+    MessageDelivered(DeliveryState),
+    Mention(Message),
+    MessagePreviewReady(LinkPreview),
+    PollUpdated(Poll),
+    WorkspaceDeleted(Workspace),
+    PresenceChanged(Presence),
+    ChatPresenceChanged(ChatPresence),
+    UserStatusChanged(ChatUser),
+    UploadProgress(UploadProgress),
+    Ephemeral(EphemeralEvent),
+}
+
+impl AppEvent {
+    /// Name of the event in the stream
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::MemberAdded { .. } => "MemberAdded",
+            AppEvent::MemberRemoved { .. } => "MemberRemoved",
+            AppEvent::ChatRenamed(_) => "ChatRenamed",
+            AppEvent::ChatUpdated(_) => "ChatUpdated",
+            AppEvent::ChatDeleted(_) => "ChatDeleted",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageEdited(_) => "MessageEdited",
+            AppEvent::MessageDeleted(_) => "MessageDeleted",
+            AppEvent::ReactionAdded(_) => "ReactionAdded",
+            AppEvent::ReactionRemoved(_) => "ReactionRemoved",
+            AppEvent::MessageRead(_) => "MessageRead",
+            AppEvent::MessageDelivered(_) => "MessageDelivered",
+            AppEvent::Mention(_) => "Mention",
+            AppEvent::MessagePreviewReady(_) => "MessagePreviewReady",
+            AppEvent::PollUpdated(_) => "PollUpdated",
+            AppEvent::WorkspaceDeleted(_) => "WorkspaceDeleted",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+            AppEvent::ChatPresenceChanged(_) => "ChatPresenceChanged",
+            AppEvent::UserStatusChanged(_) => "UserStatusChanged",
+            AppEvent::UploadProgress(_) => "UploadProgress",
+            AppEvent::Ephemeral(_) => "Ephemeral",
+        }
+    }
+
+    /// Whether the event is kept in the log of the users for when they're back from offline,
+    /// presence and progress are only worth sending live
+    pub fn is_logged(&self) -> bool {
+        !matches!(
+            self,
+            AppEvent::PresenceChanged(_)
+                | AppEvent::ChatPresenceChanged(_)
+                | AppEvent::UploadProgress(_)
+                | AppEvent::Ephemeral(_)
+        )
+    }
+
+    /// Whether the event is sent again on each new stream of the user until a client
+    /// acknowledges it, missing it leaves the client unaware of a chat it's in
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            AppEvent::NewChat(_)
+                | AppEvent::MemberAdded { .. }
+                | AppEvent::MemberRemoved { .. }
+                | AppEvent::ChatDeleted(_)
+        )
+    }
+
+    /// The chat the event is about, none for the events about users or workspaces
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            AppEvent::NewChat(chat)
+            | AppEvent::MemberAdded { chat, .. }
+            | AppEvent::MemberRemoved { chat, .. }
+            | AppEvent::ChatRenamed(chat)
+            | AppEvent::ChatUpdated(chat)
+            | AppEvent::ChatDeleted(chat) => Some(chat.id),
+            AppEvent::NewMessage(message)
+            | AppEvent::MessageEdited(message)
+            | AppEvent::MessageDeleted(message)
+            | AppEvent::Mention(message) => Some(message.chat_id),
+            AppEvent::ReactionAdded(reaction) | AppEvent::ReactionRemoved(reaction) => {
+                Some(reaction.chat_id)
+            }
+            AppEvent::MessageRead(read_state) => Some(read_state.chat_id),
+            AppEvent::MessageDelivered(delivery_state) => Some(delivery_state.chat_id),
+            AppEvent::MessagePreviewReady(preview) => Some(preview.chat_id),
+            AppEvent::PollUpdated(poll) => Some(poll.chat_id),
+            AppEvent::ChatPresenceChanged(presence) => Some(presence.chat_id),
+            AppEvent::Ephemeral(event) => Some(event.chat_id),
+            AppEvent::WorkspaceDeleted(_)
+            | AppEvent::PresenceChanged(_)
+            | AppEvent::UserStatusChanged(_)
+            | AppEvent::UploadProgress(_) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Notification {
+pub(crate) struct Notification {
     // users being impacted, so we should send the notification to them
-    user_ids: HashSet<u64>,
-    event: Arc<AppEvent>,
+    pub(crate) user_ids: HashSet<u64>,
+    pub(crate) event: Arc<AppEvent>,
+    // sequence number of the event in the log of each user, empty until it's logged
+    pub(crate) seqs: HashMap<u64, i64>,
+    // id of the event in the streams, assigned by the forwarder so that it's the same on
+    // all the replicas, none for the ephemeral events
+    pub(crate) id: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,72 +167,631 @@ struct ChatUpdated {
     new: Option<Chat>,
 }
 
+// payload of chat_message_created / chat_message_updated / chat_message_deleted,
+// for chat_message_mentioned the members are the mentioned users
 #[derive(Debug, Serialize, Deserialize)]
-struct ChatMessageCreated {
-    #[serde(flatten)]
+struct ChatMessageChanged {
     message: Message,
     members: Vec<u64>,
+    // members who muted the chat, only set for chat_message_created
+    #[serde(default)]
+    muted: Vec<u64>,
+    // members who blocked the sender, only set for chat_message_created
+    #[serde(default)]
+    blocked_by: Vec<u64>,
+    // members only told about the messages mentioning them, only set for chat_message_created
+    #[serde(default)]
+    mentions_only: Vec<u64>,
+    // members told about no message, only set for chat_message_created
+    #[serde(default)]
+    silent: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatReactionUpdated {
+    op: String,
+    reaction: Reaction,
+    members: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageRead {
+    read_state: ReadState,
+    members: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageDelivered {
+    delivery_state: DeliveryState,
+    members: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessagePreview {
+    preview: LinkPreview,
+    members: Vec<u64>,
+}
+
+// payload of chat_poll_updated, sent when a poll is created or its votes change
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatPollUpdated {
+    poll: Poll,
+    members: Vec<u64>,
+}
+
+// payload of workspace_deleted, the members are taken before their access is revoked
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkspaceDeleted {
+    workspace: Workspace,
+    members: Vec<u64>,
+}
+
+// payload of user_presence_changed, the members share a workspace with the user
+#[derive(Debug, Serialize, Deserialize)]
+struct UserPresenceChanged {
+    presence: Presence,
+    members: Vec<u64>,
 }
 
+// payload of chat_presence_changed, the chats of the user and their members are looked up as
+// they may not fit in a notification
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatPresenceChanged {
+    user_id: i64,
+    joined: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct ChatRoster {
+    id: i64,
+    members: Vec<i64>,
+    online: Vec<i64>,
+}
+
+// payload of user_status_changed, the members share a workspace with the user
+#[derive(Debug, Serialize, Deserialize)]
+struct UserStatusChanged {
+    user: ChatUser,
+    members: Vec<u64>,
+}
+
+// payload of upload_progress, sent by the chat server itself, the member is the uploader
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadProgressed {
+    progress: UploadProgress,
+    members: Vec<u64>,
+}
+
+// the request id all the payloads carry, null for the changes made outside of a request
+#[derive(Debug, Deserialize)]
+struct NotificationOrigin {
+    request_id: Option<String>,
+}
+
+// the log ids of the events the trigger logged, by event name, null when nobody was told
+#[derive(Debug, Default, Deserialize)]
+struct LoggedEvents {
+    #[serde(default)]
+    logged: HashMap<String, Option<i64>>,
+}
+
+/// Forward the notifications of the database to the users, the listener connects again
+/// with a growing delay when its connection is lost. The notifications sent in between are
+/// lost, the clients catch up with the events API.
 pub async fn setup_pg_listener(state: AppState) -> Result<()> {
-    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+    // a replica that can't listen at all doesn't start
+    let listener = if state.bus.is_shared() {
+        None
+    } else {
+        Some(listen(&state).await?)
+    };
+    tokio::spawn(supervise(state, listener));
+    Ok(())
+}
+
+async fn supervise(state: AppState, mut listener: Option<PgListener>) {
+    loop {
+        let ret = if state.bus.is_shared() {
+            // every replica gets the notifications of the database, the one holding the
+            // lock forwards them to the others
+            forward_when_elected(&state).await
+        } else {
+            forward(&state, listener.take()).await
+        };
+        let e = ret
+            .err()
+            .unwrap_or_else(|| anyhow::anyhow!("listener stopped"));
+        let failures = state.listener.fail(&e);
+        let delay = reconnect_delay(failures);
+        warn!(
+            "Stopped forwarding the notifications, reconnecting in {:?}: {:#}",
+            delay, e
+        );
+        time::sleep(delay).await;
+    }
+}
+
+/// Doubles with each failure in a row, up to a cap
+fn reconnect_delay(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    RECONNECT_MIN_DELAY
+        .saturating_mul(factor)
+        .min(RECONNECT_MAX_DELAY)
+}
+
+async fn listen(state: &AppState) -> Result<PgListener> {
+    let mut listener = PgListener::connect(&state.config().server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_message_updated").await?;
+    listener.listen("chat_message_deleted").await?;
+    listener.listen("chat_reaction_updated").await?;
+    listener.listen("chat_message_read").await?;
+    listener.listen("chat_message_delivered").await?;
+    listener.listen("chat_message_mentioned").await?;
+    listener.listen("chat_message_preview").await?;
+    listener.listen("chat_poll_updated").await?;
+    listener.listen("workspace_deleted").await?;
+    listener.listen("user_presence_changed").await?;
+    listener.listen("chat_presence_changed").await?;
+    listener.listen("user_status_changed").await?;
+    listener.listen("upload_progress").await?;
+    Ok(listener)
+}
 
-    let mut stream = listener.into_stream();
-
-    tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
-            info!("Got notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user[{}]", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user[{}]: {}", user_id, e);
-                    }
-                }
+/// Wait for the forwarder lock, then forward the notifications of the database to the bus
+/// for as long as the lock is held. It's released with the connection, e.g. when the
+/// replica stops.
+async fn forward_when_elected(state: &AppState) -> Result<()> {
+    let mut conn = PgConnection::connect(&state.config().server.db_url).await?;
+    state.listener.set(ListenerStatus::Standby);
+    let mut interval = time::interval(FORWARDER_CHECK);
+    loop {
+        interval.tick().await;
+        let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(FORWARDER_LOCK)
+            .fetch_one(&mut conn)
+            .await?;
+        if locked {
+            break;
+        }
+    }
+
+    info!("Forwarding the notifications of the database to the replicas");
+    let mut listener = listen(state).await?;
+    state.listener.set(ListenerStatus::Listening);
+    loop {
+        tokio::select! {
+            notif = listener.recv() => dispatch(state, notif?).await,
+            // another replica takes over when the connection holding the lock is lost
+            _ = interval.tick() => {
+                sqlx::query("SELECT 1").execute(&mut conn).await?;
             }
         }
-        Ok::<_, anyhow::Error>(())
-    });
+    }
+}
 
-    Ok(())
+async fn forward(state: &AppState, listener: Option<PgListener>) -> Result<()> {
+    let mut listener = match listener {
+        Some(listener) => listener,
+        None => listen(state).await?,
+    };
+    state.listener.set(ListenerStatus::Listening);
+    loop {
+        let notif = listener.recv().await?;
+        dispatch(state, notif).await;
+    }
+}
+
+/// Log, push and publish the events of a notification, one that can't be loaded is skipped
+/// rather than stopping the listener
+async fn dispatch(state: &AppState, notif: PgNotification) {
+    let span = info_span!(
+        "notification",
+        channel = notif.channel(),
+        request_id = request_id(notif.payload()).as_deref().unwrap_or_default(),
+    );
+    dispatch_in_span(state, notif).instrument(span).await
+}
+
+async fn dispatch_in_span(state: &AppState, notif: PgNotification) {
+    info!("Got notification: {:?}", notif);
+    let notifications = match notif.channel() {
+        "chat_presence_changed" => {
+            Notification::load_chat_presence(&state.pool, notif.payload()).await
+        }
+        channel => Notification::load(channel, notif.payload()),
+    };
+    let notifications = match notifications {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            warn!(
+                "Failed to load notification of {}: {:#}",
+                notif.channel(),
+                e
+            );
+            return;
+        }
+    };
+    let logged = logged_events(notif.payload());
+    for mut notification in notifications {
+        // the triggers log the events along with the users to tell, the live ones aside
+        if notification.event.is_logged() {
+            let Some(log_id) = logged.get(notification.event.name()).copied().flatten() else {
+                continue;
+            };
+            // the event is still sent live when its log can't be read
+            if let Err(e) = notification.read_log(&state.pool, log_id).await {
+                warn!("Failed to read the log of notification: {:#}", e);
+            }
+        }
+        // without an id the event is only sent live, it can't be replayed
+        match next_event_id(&state.pool).await {
+            Ok(id) => notification.id = Some(id),
+            Err(e) => warn!("Failed to assign an id to notification: {:#}", e),
+        }
+        if let Err(e) = state.push.push_offline(&state.pool, &notification).await {
+            warn!("Failed to queue the pushes of notification: {:#}", e);
+        }
+        if let Err(e) = state.bus.publish(notification).await {
+            warn!("Failed to publish notification: {:#}", e);
+        }
+    }
+}
+
+/// The id of the next event sent to the streams, increasing across the replicas and restarts
+async fn next_event_id(pool: &PgPool) -> Result<u64> {
+    let id: i64 = sqlx::query_scalar("SELECT nextval('stream_event_ids')")
+        .fetch_one(pool)
+        .await?;
+    Ok(id as u64)
+}
+
+/// The id of the last event sent to the streams, the channels created from now on only
+/// get the ones after it
+pub(crate) async fn last_event_id(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let id: i64 = sqlx::query_scalar(
+        "SELECT CASE WHEN is_called THEN last_value ELSE 0 END FROM stream_event_ids",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(id as u64)
+}
+
+/// The id of the request that caused the notification, set by the triggers and the chat server
+fn request_id(payload: &str) -> Option<String> {
+    serde_json::from_str::<NotificationOrigin>(payload)
+        .ok()?
+        .request_id
+}
+
+/// The log ids of the events of the notification, see `LoggedEvents`
+fn logged_events(payload: &str) -> HashMap<String, Option<i64>> {
+    serde_json::from_str::<LoggedEvents>(payload)
+        .unwrap_or_default()
+        .logged
 }
 
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> Result<Self> {
+    /// The users the trigger logged the event for, with its number in the log of each
+    async fn read_log(&mut self, pool: &PgPool, log_id: i64) -> Result<()> {
+        let seqs: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT user_id, seq FROM user_events WHERE log_id = $1")
+                .bind(log_id)
+                .fetch_all(pool)
+                .await?;
+        self.seqs = seqs
+            .into_iter()
+            .map(|(user_id, seq)| (user_id as u64, seq))
+            .collect();
+        self.user_ids = self.seqs.keys().copied().collect();
+        Ok(())
+    }
+
+    /// A notification per chat of the user who joined or left, with who is connected there
+    async fn load_chat_presence(pool: &PgPool, payload: &str) -> Result<Vec<Self>> {
+        let payload = serde_json::from_str::<ChatPresenceChanged>(payload)?;
+        let chats: Vec<ChatRoster> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.members,
+                ARRAY(
+                    SELECT p.user_id FROM user_presence p
+                    WHERE p.user_id = ANY(c.members) AND p.connections > 0
+                    ORDER BY p.user_id
+                ) AS online
+            FROM chats c
+            WHERE c.members @> ARRAY[$1]
+            ORDER BY c.id
+            "#,
+        )
+        .bind(payload.user_id)
+        .fetch_all(pool)
+        .await?;
+        let notifications = chats
+            .into_iter()
+            .map(|chat| Self {
+                user_ids: chat.members.iter().map(|id| *id as u64).collect(),
+                event: Arc::new(AppEvent::ChatPresenceChanged(ChatPresence {
+                    chat_id: chat.id,
+                    user_id: payload.user_id,
+                    joined: payload.joined,
+                    online: chat.online,
+                })),
+                seqs: HashMap::new(),
+                id: None,
+            })
+            .collect();
+        Ok(notifications)
+    }
+
+    fn load(r#type: &str, payload: &str) -> Result<Vec<Self>> {
         match r#type {
             "chat_updated" => {
                 let payload = serde_json::from_str::<ChatUpdated>(payload)?;
                 info!("Got chat updated notification: {:?}", payload);
-                let user_ids =
-                    get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
+                match (payload.op.as_str(), payload.old, payload.new) {
+                    ("INSERT", None, Some(new)) => Ok(vec![Self {
+                        user_ids: get_affected_chat_user_ids(None, Some(&new)),
+                        event: Arc::new(AppEvent::NewChat(new)),
+                        seqs: HashMap::new(),
+                        id: None,
+                    }]),
+                    ("UPDATE", Some(old), Some(new)) => Ok(Self::load_member_changes(old, new)),
+                    ("DELETE", Some(old), None) => Ok(vec![Self {
+                        user_ids: get_affected_chat_user_ids(Some(&old), None),
+                        event: Arc::new(AppEvent::ChatDeleted(old)),
+                        seqs: HashMap::new(),
+                        id: None,
+                    }]),
+                    _ => Err(anyhow::anyhow!("Invalid operation")),
+                }
+            }
+            "chat_message_created" => {
+                let payload = serde_json::from_str::<ChatMessageChanged>(payload)?;
+                let mentioned = |id: &u64| payload.message.mentions.contains(&(*id as i64));
+                let user_ids = payload
+                    .members
+                    .iter()
+                    .filter(|id| !payload.muted.contains(id) && !payload.blocked_by.contains(id))
+                    .filter(|id| !payload.silent.contains(id))
+                    .filter(|id| !payload.mentions_only.contains(id) || mentioned(id))
+                    .copied()
+                    .collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::NewMessage(payload.message)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_message_updated" => {
+                let payload = serde_json::from_str::<ChatMessageChanged>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageEdited(payload.message)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_message_deleted" => {
+                let payload = serde_json::from_str::<ChatMessageChanged>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageDeleted(payload.message)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_reaction_updated" => {
+                let payload = serde_json::from_str::<ChatReactionUpdated>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
                 let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("new should be present")),
-                    "UPDATE" => AppEvent::AddToChat(payload.old.expect("new should be present")),
-                    "DELETE" => {
-                        AppEvent::RemoveFromChat(payload.old.expect("old should be present"))
-                    }
+                    "INSERT" => AppEvent::ReactionAdded(payload.reaction),
+                    "DELETE" => AppEvent::ReactionRemoved(payload.reaction),
                     _ => return Err(anyhow::anyhow!("Invalid operation")),
                 };
-                Ok(Self {
+                Ok(vec![Self {
                     user_ids,
                     event: Arc::new(event),
-                })
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
             }
-            "chat_message_created" => {
-                let payload = serde_json::from_str::<ChatMessageCreated>(payload)?;
+            "chat_message_mentioned" => {
+                let payload = serde_json::from_str::<ChatMessageChanged>(payload)?;
                 let user_ids = payload.members.iter().copied().collect();
-                Ok(Self {
+                Ok(vec![Self {
                     user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
-                })
+                    event: Arc::new(AppEvent::Mention(payload.message)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_message_read" => {
+                let payload = serde_json::from_str::<ChatMessageRead>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageRead(payload.read_state)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_message_delivered" => {
+                let payload = serde_json::from_str::<ChatMessageDelivered>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessageDelivered(payload.delivery_state)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_message_preview" => {
+                let payload = serde_json::from_str::<ChatMessagePreview>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::MessagePreviewReady(payload.preview)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "chat_poll_updated" => {
+                let payload = serde_json::from_str::<ChatPollUpdated>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::PollUpdated(payload.poll)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "workspace_deleted" => {
+                let payload = serde_json::from_str::<WorkspaceDeleted>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::WorkspaceDeleted(payload.workspace)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "user_presence_changed" => {
+                let payload = serde_json::from_str::<UserPresenceChanged>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::PresenceChanged(payload.presence)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "user_status_changed" => {
+                let payload = serde_json::from_str::<UserStatusChanged>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::UserStatusChanged(payload.user)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
+            }
+            "upload_progress" => {
+                let payload = serde_json::from_str::<UploadProgressed>(payload)?;
+                let user_ids = payload.members.iter().copied().collect();
+                Ok(vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::UploadProgress(payload.progress)),
+                    seqs: HashMap::new(),
+                    id: None,
+                }])
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
+
+    // the members and the name of the chat each get their own event, every member before and
+    // after the change is told about it, any other change of the chat is a ChatUpdated
+    fn load_member_changes(old: Chat, new: Chat) -> Vec<Self> {
+        let old_members: HashSet<_> = old.members.iter().copied().collect();
+        let new_members: HashSet<_> = new.members.iter().copied().collect();
+        let added: Vec<_> = new
+            .members
+            .iter()
+            .copied()
+            .filter(|id| !old_members.contains(id))
+            .collect();
+        let removed: Vec<_> = old
+            .members
+            .iter()
+            .copied()
+            .filter(|id| !new_members.contains(id))
+            .collect();
+        let renamed = old.name != new.name;
+        let updated = Chat {
+            members: new.members.clone(),
+            name: new.name.clone(),
+            ..old.clone()
+        } != new;
+
+        let members = get_affected_chat_user_ids(None, Some(&new));
+        let mut notifications = vec![];
+        if !added.is_empty() {
+            notifications.push(Self {
+                user_ids: members.clone(),
+                event: Arc::new(AppEvent::MemberAdded {
+                    chat: new.clone(),
+                    user_ids: added,
+                }),
+                seqs: HashMap::new(),
+                id: None,
+            });
+        }
+        if !removed.is_empty() {
+            notifications.push(Self {
+                user_ids: get_affected_chat_user_ids(Some(&old), None),
+                event: Arc::new(AppEvent::MemberRemoved {
+                    chat: new.clone(),
+                    user_ids: removed,
+                }),
+                seqs: HashMap::new(),
+                id: None,
+            });
+        }
+        if renamed {
+            notifications.push(Self {
+                user_ids: members.clone(),
+                event: Arc::new(AppEvent::ChatRenamed(new.clone())),
+                seqs: HashMap::new(),
+                id: None,
+            });
+        }
+        if updated {
+            notifications.push(Self {
+                user_ids: members,
+                event: Arc::new(AppEvent::ChatUpdated(new)),
+                seqs: HashMap::new(),
+                id: None,
+            });
+        }
+        notifications
+    }
+}
+
+/// The critical events of the user no client acknowledged yet, oldest first
+pub(crate) async fn unacked_events(
+    pool: &PgPool,
+    user_id: u64,
+    limit: i64,
+) -> Result<Vec<(i64, AppEvent)>> {
+    let rows: Vec<(i64, Json<serde_json::Value>)> = sqlx::query_as(
+        r#"
+        SELECT seq, event
+        FROM user_events
+        WHERE user_id = $1 AND critical AND acked_at IS NULL
+        ORDER BY seq
+        LIMIT $2
+        "#,
+    )
+    .bind(user_id as i64)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    // the events logged by an older version may no longer parse
+    let events = rows
+        .into_iter()
+        .filter_map(|(seq, event)| match serde_json::from_value(event.0) {
+            Ok(event) => Some((seq, event)),
+            Err(e) => {
+                warn!("Skipping unacked event {} of user {}: {}", seq, user_id, e);
+                None
+            }
+        })
+        .collect();
+    Ok(events)
 }
 
 fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
@@ -119,3 +813,149 @@ fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet
         _ => HashSet::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn new_message_should_follow_notify_levels() -> Result<()> {
+        let payload = json!({
+            "message": {
+                "id": 1,
+                "chat_id": 1,
+                "sender_id": 1,
+                "parent_id": null,
+                "content": "hi @3",
+                "files": [],
+                "mentions": [3],
+                "created_at": "2024-11-26T00:00:00Z",
+                "updated_at": null,
+                "deleted_at": null
+            },
+            "members": [1, 2, 3, 4, 5],
+            "muted": [2],
+            "blocked_by": [],
+            "mentions_only": [3, 4],
+            "silent": [5]
+        });
+        let notifications = Notification::load("chat_message_created", &payload.to_string())?;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_update_should_load_membership_events() -> Result<()> {
+        let chat = |name: &str, members: &[i64]| {
+            json!({
+                "id": 1,
+                "ws_id": 1,
+                "name": name,
+                "type": "group",
+                "members": members,
+                "owner_id": 1,
+                "created_at": "2024-11-28T00:00:00Z"
+            })
+        };
+        let load = |old, new| {
+            let payload = json!({ "op": "UPDATE", "old": old, "new": new });
+            Notification::load("chat_updated", &payload.to_string())
+        };
+
+        let notifications = load(chat("team", &[1, 2, 3]), chat("dev", &[1, 3, 4]))?;
+        let names: Vec<_> = notifications.iter().map(|n| n.event.name()).collect();
+        assert_eq!(names, ["MemberAdded", "MemberRemoved", "ChatRenamed"]);
+        assert!(matches!(
+            notifications[0].event.as_ref(),
+            AppEvent::MemberAdded { user_ids, .. } if user_ids == &[4]
+        ));
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 3, 4]));
+        assert!(matches!(
+            notifications[1].event.as_ref(),
+            AppEvent::MemberRemoved { user_ids, .. } if user_ids == &[2]
+        ));
+        assert_eq!(notifications[1].user_ids, HashSet::from([1, 2, 3]));
+        assert_eq!(notifications[2].user_ids, HashSet::from([1, 3, 4]));
+
+        let mut new = chat("team", &[1, 2, 3]);
+        new["owner_id"] = json!(2);
+        let notifications = load(chat("team", &[1, 2, 3]), new)?;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].event.name(), "ChatUpdated");
+
+        assert!(load(chat("team", &[1, 2]), chat("team", &[1, 2]))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn message_edits_and_deletions_should_load() -> Result<()> {
+        let payload = |content: &str, deleted_at: Option<&str>| {
+            json!({
+                "message": {
+                    "id": 1,
+                    "chat_id": 1,
+                    "sender_id": 1,
+                    "parent_id": null,
+                    "content": content,
+                    "files": [],
+                    "mentions": [],
+                    "created_at": "2024-11-28T00:00:00Z",
+                    "updated_at": "2024-11-28T00:01:00Z",
+                    "deleted_at": deleted_at
+                },
+                "members": [1, 2]
+            })
+            .to_string()
+        };
+
+        let edited = Notification::load("chat_message_updated", &payload("edited", None))?;
+        assert_eq!(edited.len(), 1);
+        assert_eq!(edited[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(
+            edited[0].event.as_ref(),
+            AppEvent::MessageEdited(message) if message.content == "edited"
+        ));
+
+        // the trigger clears the content of a deleted message
+        let deleted = payload("", Some("2024-11-28T00:02:00Z"));
+        let deleted = Notification::load("chat_message_deleted", &deleted)?;
+        assert_eq!(deleted[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(
+            deleted[0].event.as_ref(),
+            AppEvent::MessageDeleted(message) if message.deleted_at.is_some()
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_presence_should_load_the_chats_of_the_user() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        sqlx::query(
+            "INSERT INTO user_presence (user_id, status, connections) VALUES (3, 'online', 1)",
+        )
+        .execute(&state.pool)
+        .await?;
+
+        let payload = json!({ "user_id": 3, "joined": true, "request_id": null }).to_string();
+        let notifications = Notification::load_chat_presence(&state.pool, &payload).await?;
+        // general, private and the group
+        assert_eq!(notifications.len(), 3);
+        let AppEvent::ChatPresenceChanged(presence) = notifications[0].event.as_ref() else {
+            panic!("expected a chat presence event");
+        };
+        assert_eq!(presence.chat_id, 1);
+        assert!(presence.joined);
+        assert_eq!(presence.online, [3]);
+        assert_eq!(notifications[0].user_ids, (1..=5).collect());
+        Ok(())
+    }
+
+    #[test]
+    fn reconnect_delay_should_back_off() {
+        assert_eq!(reconnect_delay(1), RECONNECT_MIN_DELAY);
+        assert_eq!(reconnect_delay(3), RECONNECT_MIN_DELAY * 4);
+        assert_eq!(reconnect_delay(100), RECONNECT_MAX_DELAY);
+    }
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use chat_core::begin_tagged;
+use futures::{stream, Stream, StreamExt};
+use sqlx::PgPool;
+use tokio::time;
+use tracing::warn;
+
+/// how often a replica tells the others it's still running
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// a replica missing its heartbeats for this long is gone, its streams no longer count
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counts an open event stream of the user while alive, the user is online as long as
+/// one of their streams is open on any replica
+pub(crate) struct PresenceGuard {
+    pool: PgPool,
+    replica_id: i64,
+    user_id: u64,
+}
+
+impl PresenceGuard {
+    pub(crate) async fn connect(pool: PgPool, replica_id: i64, user_id: u64) -> Self {
+        if let Err(e) = connect(&pool, replica_id, user_id).await {
+            warn!("Failed to mark user {} online: {}", user_id, e);
+        }
+
+        Self {
+            pool,
+            replica_id,
+            user_id,
+        }
+    }
+
+    /// Keep the user online until the stream ends or is dropped
+    pub(crate) fn hold<S>(self, events: S) -> impl Stream<Item = S::Item> + Send + 'static
+    where
+        S: Stream + Send + 'static,
+    {
+        stream::unfold((Box::pin(events), self), |(mut events, guard)| async move {
+            let event = events.next().await?;
+            Some((event, (events, guard)))
+        })
+    }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let pool = self.pool.clone();
+        let replica_id = self.replica_id;
+        let user_id = self.user_id;
+        tokio::spawn(async move {
+            if let Err(e) = disconnect(&pool, replica_id, user_id).await {
+                warn!("Failed to mark user {} offline: {}", user_id, e);
+            }
+        });
+    }
+}
+
+async fn connect(pool: &PgPool, replica_id: i64, user_id: u64) -> Result<(), sqlx::Error> {
+    let mut tx = begin_tagged(pool).await?;
+    sqlx::query(
+        r#"
+        INSERT INTO presence_connections (replica_id, user_id, connections)
+        VALUES ($1, $2, 1)
+        ON CONFLICT (replica_id, user_id) DO UPDATE
+        SET connections = presence_connections.connections + 1
+        "#,
+    )
+    .bind(replica_id)
+    .bind(user_id as i64)
+    .execute(&mut *tx)
+    .await?;
+    // an away user stays away when opening another stream
+    sqlx::query(
+        r#"
+        INSERT INTO user_presence (user_id, status, connections, last_connected_at)
+        VALUES ($1, 'online', 1, CURRENT_TIMESTAMP)
+        ON CONFLICT (user_id) DO UPDATE
+        SET connections = user_presence.connections + 1,
+            last_connected_at = CURRENT_TIMESTAMP,
+            status = CASE WHEN user_presence.status = 'offline' THEN 'online' ELSE user_presence.status END,
+            updated_at = CASE WHEN user_presence.status = 'offline' THEN CURRENT_TIMESTAMP ELSE user_presence.updated_at END
+        "#,
+    )
+    .bind(user_id as i64)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+async fn disconnect(pool: &PgPool, replica_id: i64, user_id: u64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // the rows of a replica taken for gone are dropped along with its count
+    let counted: Option<i32> = sqlx::query_scalar(
+        r#"
+        UPDATE presence_connections
+        SET connections = connections - 1
+        WHERE replica_id = $1 AND user_id = $2
+        RETURNING connections
+        "#,
+    )
+    .bind(replica_id)
+    .bind(user_id as i64)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(left) = counted else {
+        return tx.commit().await;
+    };
+    if left <= 0 {
+        sqlx::query("DELETE FROM presence_connections WHERE replica_id = $1 AND user_id = $2")
+            .bind(replica_id)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query(
+        r#"
+        UPDATE user_presence
+        SET connections = GREATEST(connections - 1, 0),
+            last_connected_at = CURRENT_TIMESTAMP,
+            status = CASE WHEN connections <= 1 THEN 'offline' ELSE status END,
+            updated_at = CASE WHEN connections <= 1 THEN CURRENT_TIMESTAMP ELSE updated_at END
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id as i64)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Register the replica, the streams of the ones that stopped without a word are taken
+/// out of the presence of their users first
+pub(crate) async fn register_replica(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    sweep_replicas(pool).await?;
+    sqlx::query_scalar("INSERT INTO notify_replicas DEFAULT VALUES RETURNING id")
+        .fetch_one(pool)
+        .await
+}
+
+/// Keep the replica alive for the others and sweep the ones that are gone
+pub(crate) fn spawn_heartbeat(pool: PgPool, replica_id: i64) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = heartbeat(&pool, replica_id).await {
+                warn!(
+                    "Failed to send the heartbeat of replica {}: {}",
+                    replica_id, e
+                );
+            }
+            if let Err(e) = sweep_replicas(&pool).await {
+                warn!("Failed to sweep the stopped replicas: {}", e);
+            }
+        }
+    });
+}
+
+async fn heartbeat(pool: &PgPool, replica_id: i64) -> Result<(), sqlx::Error> {
+    // a replica swept while out of touch registers again, its streams open before are
+    // no longer counted
+    sqlx::query(
+        r#"
+        INSERT INTO notify_replicas (id) VALUES ($1)
+        ON CONFLICT (id) DO UPDATE SET heartbeat_at = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(replica_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Take the streams of the replicas missing their heartbeats out of the presence of
+/// their users
+async fn sweep_replicas(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        WITH gone AS (
+            DELETE FROM notify_replicas
+            WHERE heartbeat_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+            RETURNING id
+        ), streams AS (
+            DELETE FROM presence_connections pc
+            USING gone
+            WHERE pc.replica_id = gone.id
+            RETURNING pc.user_id, pc.connections
+        ), lost AS (
+            SELECT user_id, SUM(connections)::integer AS connections
+            FROM streams
+            GROUP BY user_id
+        )
+        UPDATE user_presence p
+        SET connections = GREATEST(p.connections - lost.connections, 0),
+            last_connected_at = CURRENT_TIMESTAMP,
+            status = CASE WHEN p.connections <= lost.connections THEN 'offline' ELSE p.status END,
+            updated_at = CASE WHEN p.connections <= lost.connections THEN CURRENT_TIMESTAMP ELSE p.updated_at END
+        FROM lost
+        WHERE p.user_id = lost.user_id
+        "#,
+    )
+    .bind(REPLICA_TIMEOUT.as_secs_f64())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    async fn presence(pool: &PgPool, user_id: i64) -> Result<(String, i32)> {
+        let row = sqlx::query_as(
+            "SELECT status::text, connections FROM user_presence WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+        Ok(row)
+    }
+
+    #[tokio::test]
+    async fn presence_should_be_counted_per_replica() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let pool = &state.pool;
+
+        let first = register_replica(pool).await?;
+        let second = register_replica(pool).await?;
+        connect(pool, first, 1).await?;
+        connect(pool, second, 1).await?;
+        connect(pool, second, 1).await?;
+        assert_eq!(presence(pool, 1).await?, ("online".to_string(), 3));
+
+        // a replica starting doesn't reset the streams of the others
+        register_replica(pool).await?;
+        assert_eq!(presence(pool, 1).await?.1, 3);
+
+        // the second one stops without a word
+        sqlx::query(
+            "UPDATE notify_replicas SET heartbeat_at = NOW() - INTERVAL '1 minute' WHERE id = $1",
+        )
+        .bind(second)
+        .execute(pool)
+        .await?;
+        sweep_replicas(pool).await?;
+        assert_eq!(presence(pool, 1).await?, ("online".to_string(), 1));
+
+        disconnect(pool, first, 1).await?;
+        assert_eq!(presence(pool, 1).await?, ("offline".to_string(), 0));
+        // the streams of a swept replica are no longer counted
+        disconnect(pool, second, 1).await?;
+        assert_eq!(presence(pool, 1).await?, ("offline".to_string(), 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_be_held_by_the_stream() -> Result<()> {
+        let (_tdb, state) = AppState::try_new_for_test().await?;
+        let pool = &state.pool;
+        let replica_id = register_replica(pool).await?;
+
+        let guard = PresenceGuard::connect(pool.clone(), replica_id, 1).await;
+        let events = guard.hold(stream::pending::<()>());
+        assert_eq!(presence(pool, 1).await?, ("online".to_string(), 1));
+
+        // the guard goes with the stream, it disconnects in the background
+        drop(events);
+        for _ in 0..50 {
+            if presence(pool, 1).await?.1 == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(presence(pool, 1).await?, ("offline".to_string(), 0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use jwt_simple::prelude::{Claims, Duration as JwtDuration, ECDSAP256KeyPairLike, ES256KeyPair};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use super::{request_error, status_error, PushError, PushMessage, PushProvider};
+use crate::config::ApnsConfig;
+
+const PRODUCTION_URL: &str = "https://api.push.apple.com";
+const SANDBOX_URL: &str = "https://api.sandbox.push.apple.com";
+/// APNs refuses the tokens older than an hour, and the ones renewed more than every 20 minutes
+const TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+/// the reasons of a 400 meaning the token won't ever work
+const INVALID_TOKEN_REASONS: [&str; 2] = ["BadDeviceToken", "DeviceTokenNotForTopic"];
+
+#[derive(Debug, Default, Deserialize)]
+struct ApnsError {
+    #[serde(default)]
+    reason: String,
+}
+
+/// Apple Push Notification service, with token based authentication over HTTP/2
+pub struct ApnsPush {
+    http: Client,
+    key: ES256KeyPair,
+    team_id: String,
+    topic: String,
+    url: &'static str,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ApnsPush {
+    pub fn try_new(config: &ApnsConfig, http: Client) -> Result<Self> {
+        let key = ES256KeyPair::from_pem(&config.key)?.with_key_id(&config.key_id);
+        Ok(Self {
+            http,
+            key,
+            team_id: config.team_id.clone(),
+            topic: config.topic.clone(),
+            url: if config.sandbox {
+                SANDBOX_URL
+            } else {
+                PRODUCTION_URL
+            },
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The provider token, signed again when it's about to expire
+    async fn provider_token(&self) -> Result<String, PushError> {
+        let mut token = self.token.lock().await;
+        if let Some((token, signed_at)) = token.as_ref() {
+            if signed_at.elapsed() < TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let claims = Claims::create(JwtDuration::from_hours(1)).with_issuer(&self.team_id);
+        let signed = self
+            .key
+            .sign(claims)
+            .map_err(|e| PushError::Failed(e.to_string()))?;
+        *token = Some((signed.clone(), Instant::now()));
+        Ok(signed)
+    }
+}
+
+#[async_trait]
+impl PushProvider for ApnsPush {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError> {
+        let provider_token = self.provider_token().await?;
+        let body = json!({
+            "aps": {
+                "alert": { "title": message.title, "body": message.body },
+                "sound": "default",
+                "thread-id": message.chat_id.to_string(),
+            },
+            "chatId": message.chat_id,
+            "messageId": message.message_id,
+        });
+        let resp = self
+            .http
+            .post(format!("{}/3/device/{}", self.url, token))
+            .bearer_auth(provider_token)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .json(&body)
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = resp.text().await.unwrap_or_default();
+        let error: ApnsError = serde_json::from_str(&body).unwrap_or_default();
+        match status {
+            // Unregistered
+            StatusCode::GONE => Err(PushError::InvalidToken),
+            StatusCode::BAD_REQUEST if INVALID_TOKEN_REASONS.contains(&error.reason.as_str()) => {
+                Err(PushError::InvalidToken)
+            }
+            _ => Err(status_error(status, &body)),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use jwt_simple::prelude::{Claims, Duration as JwtDuration, RS256KeyPair, RSAKeyPairLike};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    fs::File,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use super::{request_error, status_error, PushError, PushMessage, PushProvider};
+use crate::config::FcmConfig;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+/// an access token is renewed this long before it expires
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+/// the json key of a service account, as downloaded from the Firebase console
+#[derive(Debug, Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScopeClaim {
+    scope: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Firebase Cloud Messaging, with the HTTP v1 API
+pub struct FcmPush {
+    http: Client,
+    account: ServiceAccount,
+    key: RS256KeyPair,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl FcmPush {
+    pub fn try_new(config: &FcmConfig, http: Client) -> Result<Self> {
+        let file = File::open(&config.credentials)
+            .with_context(|| format!("Failed to open {}", config.credentials))?;
+        let account: ServiceAccount = serde_json::from_reader(file)?;
+        let key = RS256KeyPair::from_pem(&account.private_key)?;
+        Ok(Self {
+            http,
+            account,
+            key,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// The access token of the service account, renewed before it expires
+    async fn access_token(&self) -> Result<String, PushError> {
+        let mut token = self.token.lock().await;
+        if let Some((token, expires_at)) = token.as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let scope = ScopeClaim {
+            scope: SCOPE.to_string(),
+        };
+        let claims = Claims::with_custom_claims(scope, JwtDuration::from_hours(1))
+            .with_issuer(&self.account.client_email)
+            .with_audience(&self.account.token_uri);
+        let assertion = self
+            .key
+            .sign(claims)
+            .map_err(|e| PushError::Failed(e.to_string()))?;
+        let resp = self
+            .http
+            .post(&self.account.token_uri)
+            .form(&[("grant_type", GRANT_TYPE), ("assertion", &assertion)])
+            .send()
+            .await
+            .map_err(request_error)?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(status_error(status, &body));
+        }
+        let ret: AccessToken = resp.json().await.map_err(request_error)?;
+        let expires_at = Instant::now() + Duration::from_secs(ret.expires_in) - TOKEN_MARGIN;
+        *token = Some((ret.access_token.clone(), expires_at));
+        Ok(ret.access_token)
+    }
+}
+
+#[async_trait]
+impl PushProvider for FcmPush {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError> {
+        let access_token = self.access_token().await?;
+        let url = format!(
+            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+            self.account.project_id
+        );
+        let body = json!({
+            "message": {
+                "token": token,
+                "notification": { "title": message.title, "body": message.body },
+                // the values of the data must be strings
+                "data": {
+                    "chatId": message.chat_id.to_string(),
+                    "messageId": message.message_id.to_string(),
+                },
+            }
+        });
+        let resp = self
+            .http
+            .post(url)
+            .bearer_auth(access_token)
+            .json(&body)
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        let status = resp.status();
+        match status {
+            s if s.is_success() => Ok(()),
+            // UNREGISTERED
+            StatusCode::NOT_FOUND => Err(PushError::InvalidToken),
+            _ => {
+                let body = resp.text().await.unwrap_or_default();
+                // a token of another project or malformed one
+                if body.contains("INVALID_ARGUMENT") && body.contains("registration token") {
+                    return Err(PushError::InvalidToken);
+                }
+                Err(status_error(status, &body))
+            }
+        }
+    }
+}
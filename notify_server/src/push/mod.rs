@@ -0,0 +1,438 @@
+mod apns;
+mod fcm;
+mod webpush;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{info, warn};
+
+use crate::{config::PushConfig, notify::Notification, AppEvent};
+
+pub use self::{apns::ApnsPush, fcm::FcmPush, webpush::WebPush};
+
+/// pushes being sent at once
+const MAX_CONCURRENT_PUSHES: usize = 32;
+/// longest body of a push, the content of the message is cut there
+const MAX_BODY_CHARS: usize = 160;
+
+/// the platform of a device, as registered with the chat server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "device_platform", rename_all = "lowercase")]
+pub enum Platform {
+    Apns,
+    Fcm,
+    Webpush,
+}
+
+/// what the user is told about
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushMessage {
+    pub title: String,
+    pub body: String,
+    pub chat_id: i64,
+    pub message_id: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PushError {
+    /// the device is gone, e.g. the app was uninstalled
+    #[error("the token is no longer valid")]
+    InvalidToken,
+    /// worth trying again later, e.g. the service is overloaded
+    #[error("temporary failure: {0}")]
+    Retry(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// Sends the pushes of a platform
+#[async_trait]
+pub trait PushProvider: Send + Sync {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError>;
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct PushDevice {
+    id: i64,
+    user_id: i64,
+    platform: Platform,
+    token: String,
+}
+
+struct PushJob {
+    device: PushDevice,
+    message: Arc<PushMessage>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    delay: Duration,
+}
+
+/// The pushes waiting to be sent, a worker sends them in the background
+pub(crate) struct PushQueue {
+    tx: mpsc::Sender<PushJob>,
+    platforms: Vec<Platform>,
+    show_content: bool,
+}
+
+impl PushQueue {
+    pub(crate) fn try_new(config: &PushConfig, pool: PgPool) -> Result<Self> {
+        let providers = push_providers(config)?;
+        let platforms = providers.keys().copied().collect();
+        let (tx, rx) = mpsc::channel(config.queue_size.max(1));
+        let policy = RetryPolicy {
+            max_attempts: config.max_attempts.max(1),
+            delay: Duration::from_millis(config.retry_delay),
+        };
+        if !providers.is_empty() {
+            tokio::spawn(run(rx, providers, policy, pool));
+        }
+        Ok(Self {
+            tx,
+            platforms,
+            show_content: config.show_content,
+        })
+    }
+
+    /// Queue a push to the devices of the users of a new message or mention without an open
+    /// event stream. A mentioned user only gets the push of the mention, the users who want
+    /// nothing of the chat or blocked the sender get none.
+    pub(crate) async fn push_offline(
+        &self,
+        pool: &PgPool,
+        notification: &Notification,
+    ) -> Result<()> {
+        let (message, mention) = match notification.event.as_ref() {
+            AppEvent::NewMessage(message) => (message, false),
+            AppEvent::Mention(message) => (message, true),
+            _ => return Ok(()),
+        };
+        if self.platforms.is_empty() {
+            return Ok(());
+        }
+        let user_ids: Vec<i64> = notification
+            .user_ids
+            .iter()
+            .map(|id| *id as i64)
+            .filter(|id| *id != message.sender_id)
+            .filter(|id| mention || !message.mentions.contains(id))
+            .collect();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+
+        let devices: Vec<PushDevice> = sqlx::query_as(
+            r#"
+            SELECT d.id, d.user_id, d.platform, d.token
+            FROM devices d
+            JOIN users u ON u.id = d.user_id
+            LEFT JOIN user_presence p ON p.user_id = d.user_id
+            LEFT JOIN chat_settings s ON s.chat_id = $2 AND s.user_id = d.user_id
+            WHERE d.user_id = ANY($1) AND COALESCE(p.connections, 0) = 0
+                AND COALESCE(s.notify_level, u.notify_level) <> 'nothing'
+                AND NOT EXISTS (
+                    SELECT 1 FROM user_blocks b WHERE b.blocker_id = d.user_id AND b.blocked_id = $3
+                )
+            "#,
+        )
+        .bind(&user_ids)
+        .bind(message.chat_id)
+        .bind(message.sender_id)
+        .fetch_all(pool)
+        .await?;
+        let devices: Vec<_> = devices
+            .into_iter()
+            .filter(|device| self.platforms.contains(&device.platform))
+            .collect();
+        if devices.is_empty() {
+            return Ok(());
+        }
+
+        let sender: String = sqlx::query_scalar("SELECT full_name FROM users WHERE id = $1")
+            .bind(message.sender_id)
+            .fetch_optional(pool)
+            .await?
+            .unwrap_or_default();
+        let title = if mention {
+            format!("{sender} mentioned you")
+        } else {
+            sender
+        };
+        let body = match (self.show_content, message.content.is_empty()) {
+            (true, false) => truncate(&message.content, MAX_BODY_CHARS),
+            (_, true) if !message.files.is_empty() => "Sent a file".to_string(),
+            _ => "New message".to_string(),
+        };
+        let push = Arc::new(PushMessage {
+            title,
+            body,
+            chat_id: message.chat_id,
+            message_id: message.id,
+        });
+
+        for device in devices {
+            let job = PushJob {
+                device,
+                message: push.clone(),
+            };
+            if let Err(e) = self.tx.try_send(job) {
+                let device = e.into_inner().device;
+                warn!(
+                    "Push queue is full, dropped the push to device {}",
+                    device.id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send the queued pushes, the devices whose token is no longer valid are removed
+async fn run(
+    mut rx: mpsc::Receiver<PushJob>,
+    providers: HashMap<Platform, Arc<dyn PushProvider>>,
+    policy: RetryPolicy,
+    pool: PgPool,
+) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_PUSHES));
+    while let Some(job) = rx.recv().await {
+        let Some(provider) = providers.get(&job.device.platform).cloned() else {
+            continue;
+        };
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let device = &job.device;
+            match send_with_retry(provider.as_ref(), &device.token, &job.message, policy).await {
+                Ok(()) => info!("Pushed to device {} of user {}", device.id, device.user_id),
+                Err(PushError::InvalidToken) => {
+                    info!(
+                        "Removing device {}, its token is no longer valid",
+                        device.id
+                    );
+                    let ret = sqlx::query("DELETE FROM devices WHERE id = $1 AND token = $2")
+                        .bind(device.id)
+                        .bind(&device.token)
+                        .execute(&pool)
+                        .await;
+                    if let Err(e) = ret {
+                        warn!("Failed to remove device {}: {}", device.id, e);
+                    }
+                }
+                Err(e) => warn!("Failed to push to device {}: {}", device.id, e),
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn send_with_retry(
+    provider: &dyn PushProvider,
+    token: &str,
+    message: &PushMessage,
+    policy: RetryPolicy,
+) -> Result<(), PushError> {
+    let mut delay = policy.delay;
+    for _ in 1..policy.max_attempts {
+        match provider.send(token, message).await {
+            Err(PushError::Retry(e)) => {
+                warn!("Push failed, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            ret => return ret,
+        }
+    }
+    provider.send(token, message).await
+}
+
+/// Create the providers of the config, by the platform they push to
+pub fn push_providers(config: &PushConfig) -> Result<HashMap<Platform, Arc<dyn PushProvider>>> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let mut providers: HashMap<Platform, Arc<dyn PushProvider>> = HashMap::new();
+    if let Some(config) = &config.fcm {
+        providers.insert(
+            Platform::Fcm,
+            Arc::new(FcmPush::try_new(config, http.clone())?),
+        );
+    }
+    if let Some(config) = &config.apns {
+        providers.insert(
+            Platform::Apns,
+            Arc::new(ApnsPush::try_new(config, http.clone())?),
+        );
+    }
+    if let Some(config) = &config.webpush {
+        providers.insert(Platform::Webpush, Arc::new(WebPush::try_new(config, http)?));
+    }
+    Ok(providers)
+}
+
+/// The error of a response the service refused, whether it's worth trying again
+fn status_error(status: StatusCode, body: &str) -> PushError {
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        PushError::Retry(format!("{status}: {body}"))
+    } else {
+        PushError::Failed(format!("{status}: {body}"))
+    }
+}
+
+/// Requests that didn't get a response are tried again
+fn request_error(e: reqwest::Error) -> PushError {
+    PushError::Retry(e.to_string())
+}
+
+fn truncate(content: &str, max_chars: usize) -> String {
+    match content.char_indices().nth(max_chars) {
+        Some((i, _)) => format!("{}…", &content[..i]),
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::Message;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// fails with the given error until the given attempt
+    struct FlakyProvider {
+        attempts: AtomicU32,
+        succeeds_at: u32,
+        invalid: bool,
+    }
+
+    #[async_trait]
+    impl PushProvider for FlakyProvider {
+        async fn send(&self, _token: &str, _message: &PushMessage) -> Result<(), PushError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            match (self.invalid, attempt >= self.succeeds_at) {
+                (true, _) => Err(PushError::InvalidToken),
+                (false, true) => Ok(()),
+                (false, false) => Err(PushError::Retry("unavailable".to_string())),
+            }
+        }
+    }
+
+    fn provider(succeeds_at: u32, invalid: bool) -> FlakyProvider {
+        FlakyProvider {
+            attempts: AtomicU32::new(0),
+            succeeds_at,
+            invalid,
+        }
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_should_retry_temporary_failures() {
+        let message = PushMessage {
+            title: "Alice".to_string(),
+            body: "hi".to_string(),
+            chat_id: 1,
+            message_id: 1,
+        };
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            delay: Duration::ZERO,
+        };
+
+        let flaky = provider(3, false);
+        assert!(send_with_retry(&flaky, "t", &message, policy).await.is_ok());
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+
+        let down = provider(4, false);
+        let ret = send_with_retry(&down, "t", &message, policy).await;
+        assert!(matches!(ret, Err(PushError::Retry(_))));
+        assert_eq!(down.attempts.load(Ordering::SeqCst), 3);
+
+        // an invalid token is not tried again
+        let gone = provider(1, true);
+        let ret = send_with_retry(&gone, "t", &message, policy).await;
+        assert!(matches!(ret, Err(PushError::InvalidToken)));
+        assert_eq!(gone.attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn push_offline_should_skip_the_users_not_to_tell() -> anyhow::Result<()> {
+        let (_tdb, state) = crate::AppState::try_new_for_test().await?;
+        let pool = &state.pool;
+        for user_id in 2..=5 {
+            sqlx::query("INSERT INTO devices (user_id, platform, token) VALUES ($1, 'fcm', $2)")
+                .bind(user_id)
+                .bind(format!("token-{user_id}"))
+                .execute(pool)
+                .await?;
+        }
+        // 3 wants nothing of the chat, 4 blocked the sender and 5 is online
+        sqlx::query(
+            "INSERT INTO chat_settings (chat_id, user_id, notify_level) VALUES (1, 3, 'nothing')",
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query("INSERT INTO user_blocks (blocker_id, blocked_id) VALUES (4, 1)")
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO user_presence (user_id, status, connections) VALUES (5, 'online', 1)",
+        )
+        .execute(pool)
+        .await?;
+
+        let message: Message = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, parent_id, content, files, mentions, created_at,
+                updated_at, deleted_at
+            FROM messages WHERE id = 1
+            "#,
+        )
+        .fetch_one(pool)
+        .await?;
+        let notification = Notification {
+            user_ids: (1..=5).collect(),
+            event: Arc::new(AppEvent::NewMessage(message)),
+            seqs: HashMap::new(),
+            id: None,
+        };
+        let (tx, mut rx) = mpsc::channel(8);
+        let queue = PushQueue {
+            tx,
+            platforms: vec![Platform::Fcm],
+            show_content: true,
+        };
+        queue.push_offline(pool, &notification).await?;
+        drop(queue);
+
+        let mut users = vec![];
+        while let Some(job) = rx.recv().await {
+            assert_eq!(job.message.body, "Hello, world!");
+            users.push(job.device.user_id);
+        }
+        assert_eq!(users, [2]);
+        Ok(())
+    }
+
+    #[test]
+    fn status_error_should_retry_overload_only() {
+        let retry = |status| matches!(status_error(status, ""), PushError::Retry(_));
+        assert!(retry(StatusCode::TOO_MANY_REQUESTS));
+        assert!(retry(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!retry(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn truncate_should_cut_on_chars() {
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("héllo world", 5), "héllo…");
+    }
+}
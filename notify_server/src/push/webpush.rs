@@ -0,0 +1,86 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jwt_simple::prelude::{
+    Claims, Duration as JwtDuration, ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, ES256KeyPair,
+};
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+
+use super::{request_error, status_error, PushError, PushMessage, PushProvider};
+use crate::config::WebPushConfig;
+
+/// seconds the push service keeps a push for a browser that's offline
+const PUSH_TTL: &str = "86400";
+
+/// the token of a browser, the PushSubscription it got from the push service
+#[derive(Debug, Deserialize)]
+struct Subscription {
+    endpoint: String,
+}
+
+/// Web Push with VAPID. The pushes carry no payload, the service worker fetches the events
+/// it missed with GET /api/events when it's woken up.
+pub struct WebPush {
+    http: Client,
+    key: ES256KeyPair,
+    /// the public key the browsers subscribed with, url-safe base64 of the uncompressed point
+    public_key: String,
+    subject: String,
+}
+
+impl WebPush {
+    pub fn try_new(config: &WebPushConfig, http: Client) -> Result<Self> {
+        let key = ES256KeyPair::from_pem(&config.vapid_key)?;
+        let public_key =
+            URL_SAFE_NO_PAD.encode(key.public_key().public_key().to_bytes_uncompressed());
+        Ok(Self {
+            http,
+            key,
+            public_key,
+            subject: config.subject.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl PushProvider for WebPush {
+    async fn send(&self, token: &str, _message: &PushMessage) -> Result<(), PushError> {
+        let subscription: Subscription =
+            serde_json::from_str(token).map_err(|_| PushError::InvalidToken)?;
+        let endpoint = Url::parse(&subscription.endpoint).map_err(|_| PushError::InvalidToken)?;
+        let audience = endpoint.origin().ascii_serialization();
+        let claims = Claims::create(JwtDuration::from_hours(12))
+            .with_audience(audience)
+            .with_subject(&self.subject);
+        let signed = self
+            .key
+            .sign(claims)
+            .map_err(|e| PushError::Failed(e.to_string()))?;
+
+        let resp = self
+            .http
+            .post(endpoint)
+            .header(
+                "Authorization",
+                format!("vapid t={}, k={}", signed, self.public_key),
+            )
+            .header("TTL", PUSH_TTL)
+            .header("Urgency", "high")
+            .header("Content-Length", "0")
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        let status = resp.status();
+        match status {
+            s if s.is_success() => Ok(()),
+            // the subscription expired or was unsubscribed
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(PushError::InvalidToken),
+            _ => {
+                let body = resp.text().await.unwrap_or_default();
+                Err(status_error(status, &body))
+            }
+        }
+    }
+}
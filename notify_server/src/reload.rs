@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chat_core::{reload_config, reload_logging, ConfigReload, ReloadTrigger, ReloadableRouter};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{build_router, AppConfig, AppState};
+
+/// the settings a reload applies, the others need a restart
+const RELOADABLE: &[&str] = &[
+    "log.level",
+    "log.filters",
+    "cors.allowed_origins",
+    "acl",
+    "ephemeral",
+    "rate_limit",
+    "auth.pk",
+    "auth.extra_pks",
+];
+
+/// Reload the config on SIGHUP and every `auth.key_reload_interval` seconds, see the chat
+/// server for the settings applied and the key rotation steps
+pub fn spawn_config_reload(
+    state: AppState,
+    router: ReloadableRouter,
+    args: Vec<String>,
+) -> JoinHandle<()> {
+    let trigger = ReloadTrigger::new(state.config().auth.key_reload_interval);
+    tokio::spawn(async move {
+        loop {
+            trigger.wait().await;
+            let ret = AppConfig::try_load_with_args(args.clone())
+                .and_then(|config| apply_config(&state, &router, config));
+            if let Err(e) = ret {
+                warn!("Failed to reload the config: {:#}", e);
+            }
+        }
+    })
+}
+
+/// Apply the changes of the reloadable settings, the changes of the others are ignored
+fn apply_config(
+    state: &AppState,
+    router: &ReloadableRouter,
+    new: AppConfig,
+) -> Result<Vec<String>> {
+    let ConfigReload {
+        config,
+        applied,
+        rejected,
+    } = reload_config(&*state.config(), &new, RELOADABLE)?;
+    for field in &rejected {
+        warn!("Ignored the change of {}, it needs a restart", field);
+    }
+    if applied.is_empty() {
+        return Ok(applied);
+    }
+
+    config.cors.validate()?;
+    reload_logging(&config.log)?;
+    if applied.iter().any(|field| field.starts_with("auth.")) {
+        state.dk.reload(config.auth.pks())?;
+        info!("Reloaded the keys, verifying with {:?}", state.dk.key_ids());
+    }
+    state.config.store(Arc::new(config));
+    router.store(build_router(state.clone()));
+    info!("Reloaded the config, changed {}", applied.join(", "));
+    Ok(applied)
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use tokio::{signal, time};
+use tracing::info;
+
+use crate::AppState;
+
+/// Closes the event streams of the server, they send what's queued for them and a last
+/// ServerClosing event, then end
+#[derive(Clone)]
+pub struct Shutdown(pub(crate) AppState);
+
+impl Shutdown {
+    pub fn close(&self) {
+        if !self.0.closing.send_replace(true) {
+            info!("Closing the event streams");
+        }
+    }
+
+    /// Close on SIGTERM or ctrl-c, for `axum::serve(..).with_graceful_shutdown`
+    pub async fn on_signal(self) {
+        let ctrl_c = async {
+            let _ = signal::ctrl_c().await;
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                }
+                Err(_) => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+        self.close();
+    }
+
+    /// Resolves `timeout` after the server started closing, the streams still open then
+    /// are cut
+    pub async fn deadline(&self, timeout: Duration) {
+        let mut closing = self.0.closing.subscribe();
+        let _ = closing.wait_for(|closing| *closing).await;
+        time::sleep(timeout).await;
+    }
+}
@@ -1,51 +1,346 @@
 use axum::{
     // debug_handler,
-    extract::State,
-    response::{sse::Event, Sse},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
     Extension,
 };
 use chat_core::User;
-use futures::Stream;
-use std::{convert::Infallible, time::Duration};
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
-use tracing::info;
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::OverflowPolicy,
+    notify::{last_event_id, unacked_events},
+    presence::PresenceGuard,
+    AppError, AppEvent, AppState, SentEvent, UserChannel,
+};
+
+/// tells nginx not to buffer the stream
+const X_ACCEL_BUFFERING: HeaderName = HeaderName::from_static("x-accel-buffering");
+/// the name of the event telling the client to fetch its chats and messages again
+const RESYNC_EVENT: &str = "Resync";
+/// the last event of a stream, the server is going away and the client should reconnect
+const SERVER_CLOSING_EVENT: &str = "ServerClosing";
+/// unacknowledged critical events sent again at the start of a stream, the client catches
+/// up on the rest with the events API
+const MAX_REDELIVERED: i64 = 100;
+
+/// sent when events were lost on their way to the client, e.g. it was too slow to keep up
+/// or reconnected after the events it missed were no longer kept
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Resync {
+    /// how many events were dropped, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missed: Option<u64>,
+}
+
+impl Resync {
+    fn event(&self) -> Event {
+        let v = serde_json::to_string(self).expect("Failed to serialize resync");
+        Event::default().data(v).event(RESYNC_EVENT)
+    }
+}
+
+/// sent before a stream ends on shutdown, the replica behind the load balancer may change
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerClosing {
+    /// milliseconds to wait before reconnecting, with the last event id
+    retry: u64,
+}
+
+impl ServerClosing {
+    fn event(&self) -> Event {
+        let v = serde_json::to_string(self).expect("Failed to serialize server closing");
+        Event::default()
+            .data(v)
+            .event(SERVER_CLOSING_EVENT)
+            .retry(Duration::from_millis(self.retry))
+    }
+}
+
+/// The events until the server closes, the ones already queued are sent before the
+/// ServerClosing event ending the stream
+fn until_closing(
+    events: impl Stream<Item = Event> + Send + 'static,
+    closing: watch::Receiver<bool>,
+    retry: u64,
+) -> impl Stream<Item = Event> {
+    let events = Box::pin(events);
+    stream::unfold(Some((events, closing)), move |state| async move {
+        let (mut events, mut closing) = state?;
+        let next = tokio::select! {
+            biased;
+            event = events.next() => Ok(event),
+            _ = closing.wait_for(|closing| *closing) => Err(()),
+        };
+        match next {
+            Ok(Some(event)) => Some((event, Some((events, closing)))),
+            // e.g. closed for overflowing
+            Ok(None) => None,
+            Err(()) => Some((ServerClosing { retry }.event(), None)),
+        }
+    })
+}
+
+/// the event with its number in the log of the user, if logged, under `seq`. The ones
+/// without an id leave the last event id of the client as it is.
+fn sent_event(sent: &SentEvent) -> Event {
+    let event = logged_event(&sent.event, sent.seq);
+    match sent.id {
+        Some(id) => event.id(id.to_string()),
+        None => event,
+    }
+}
+
+fn logged_event(event: &AppEvent, seq: Option<i64>) -> Event {
+    let mut v = serde_json::to_value(event).expect("Failed to serialize event");
+    if let (Some(seq), Some(fields)) = (seq, v.as_object_mut()) {
+        fields.insert("seq".to_string(), seq.into());
+    }
+    Event::default().data(v.to_string()).event(event.name())
+}
+
+/// what a stream is limited to, e.g. `?chats=1,2&types=NewMessage,NewChat`
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct EventFilter {
+    /// comma separated chat ids, the events about other chats are left out while the ones
+    /// about no chat, e.g. PresenceChanged, are kept
+    #[serde(default)]
+    chats: Option<String>,
+    /// comma separated event names
+    #[serde(default)]
+    types: Option<String>,
+}
+
+/// the parsed filter, none for no limit
+#[derive(Debug, Default)]
+struct StreamFilter {
+    chats: Option<HashSet<i64>>,
+    types: Option<HashSet<String>>,
+}
+
+impl EventFilter {
+    fn parse(&self) -> Result<StreamFilter, AppError> {
+        let chats = self
+            .chats
+            .as_deref()
+            .map(|chats| {
+                split(chats)
+                    .map(|id| id.parse::<i64>())
+                    .collect::<Result<HashSet<_>, _>>()
+                    .map_err(|_| {
+                        AppError::InvalidFilter("chats must be comma separated ids".to_string())
+                    })
+            })
+            .transpose()?;
+        let types = self
+            .types
+            .as_deref()
+            .map(|types| split(types).map(str::to_string).collect());
 
-use crate::{AppEvent, AppState};
+        Ok(StreamFilter { chats, types })
+    }
+}
 
-const CHANNEL_CAPACITY: usize = 256;
+impl StreamFilter {
+    fn matches(&self, event: &AppEvent) -> bool {
+        let chat = match (&self.chats, event.chat_id()) {
+            (Some(chats), Some(chat_id)) => chats.contains(&chat_id),
+            _ => true,
+        };
+        let kind = match &self.types {
+            Some(types) => types.contains(event.name()),
+            None => true,
+        };
+        chat && kind
+    }
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
 
 // #[debug_handler]
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(filter): Query<EventFilter>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    if *state.closing.borrow() {
+        return Err(AppError::ServerClosing);
+    }
+    let filter = filter.parse()?;
     let user_id = user.id as u64;
-    let users = &state.users;
-
-    let rx = if let Some(tx) = users.get(&user_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-        state.users.insert(user_id as _, tx);
-        rx
+    // sent back by the browsers when they reconnect
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    let channel = match state.users.get(&user_id) {
+        Some(channel) => channel.clone(),
+        None => {
+            // what was sent before is only replayed by the replicas that had the channel
+            let floor = last_event_id(&state.pool).await?;
+            state
+                .users
+                .entry(user_id)
+                .or_insert_with(|| Arc::new(UserChannel::new(&state.config().events, floor)))
+                .clone()
+        }
+    };
+    let subscription = channel.subscribe(last_id);
+    info!(
+        "User {} subscribed, {} missed events replayed",
+        user_id,
+        subscription.missed.len()
+    );
+    // dropped with the stream when the client disconnects
+    let presence = PresenceGuard::connect(state.pool.clone(), state.replica_id, user_id).await;
+
+    // sent again until a client acks them, without an id so that the replay isn't affected
+    let unacked = match unacked_events(&state.pool, user_id, MAX_REDELIVERED).await {
+        Ok(events) => events,
+        Err(e) => {
+            warn!(
+                "Failed to load the unacked events of user {}: {}",
+                user_id, e
+            );
+            vec![]
+        }
     };
-    info!("User {} subscribed", user_id);
-
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
+    let redelivered_seqs: HashSet<i64> = unacked.iter().map(|(seq, _)| *seq).collect();
+    let redelivered: Vec<_> = unacked
+        .iter()
+        .filter(|(_, event)| filter.matches(event))
+        .map(|(seq, event)| logged_event(event, Some(*seq)))
+        .collect();
+    let not_redelivered =
+        move |sent: &SentEvent| sent.seq.is_none_or(|seq| !redelivered_seqs.contains(&seq));
+
+    let config = &state.config().events;
+    // how long the client waits before reconnecting, it keeps its last event id
+    let retry = Event::default().retry(Duration::from_millis(config.retry));
+    // the replay is incomplete, the client is told before getting what's left of it
+    let resync = subscription.lost.then(|| Resync::default().event());
+    let missed: Vec<_> = subscription
+        .missed
+        .iter()
+        .filter(|sent| filter.matches(&sent.event) && not_redelivered(sent))
+        .map(|sent| sent_event(sent))
+        .collect();
+    let overflow = config.overflow;
+    let metrics = state.clone();
+    let live = BroadcastStream::new(subscription.rx)
+        .map_while(move |v| match v {
+            Ok(sent) => Some(
+                (filter.matches(&sent.event) && not_redelivered(&sent)).then(|| sent_event(&sent)),
+            ),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("User {} lagged behind, {} events dropped", user_id, n);
+                let disconnect = overflow == OverflowPolicy::Disconnect;
+                metrics.streams.overflowed(n, disconnect);
+                // the client reconnects with its last event id
+                (!disconnect).then(|| Some(Resync { missed: Some(n) }.event()))
+            }
+        })
+        .filter_map(|event| event);
+    let events = presence.hold(
+        tokio_stream::iter(resync)
+            .chain(tokio_stream::iter(redelivered))
+            .chain(tokio_stream::iter(missed))
+            .chain(live),
+    );
+    let events = until_closing(events, state.closing.subscribe(), config.retry);
+    let stream = tokio_stream::once(retry)
+        .chain(events)
+        .map(Ok::<_, Infallible>);
+
+    let keep_alive = KeepAlive::new()
+        .interval(Duration::from_secs(config.keep_alive))
+        .text("ping");
+    let headers = [
+        (header::CACHE_CONTROL, "no-cache"),
+        (X_ACCEL_BUFFERING, "no"),
+    ];
+    Ok((headers, Sse::new(stream).keep_alive(keep_alive)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{Presence, PresenceStatus};
+
+    #[test]
+    fn event_filter_should_work() -> anyhow::Result<()> {
+        let presence = AppEvent::PresenceChanged(Presence {
+            user_id: 1,
+            status: PresenceStatus::Online,
+            updated_at: None,
+        });
+        let typing = |chat_id| {
+            AppEvent::Ephemeral(crate::EphemeralEvent {
+                chat_id,
+                user_id: 1,
+                kind: "typing".to_string(),
+                payload: Default::default(),
+            })
         };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        Ok(Event::default().data(v).event(name))
-    });
-
-    Sse::new(stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-            .interval(Duration::from_secs(1))
-            .text("keep-alive-text"),
-    )
+
+        let filter = EventFilter {
+            chats: Some("1, 2".to_string()),
+            types: None,
+        }
+        .parse()?;
+        assert!(filter.matches(&typing(1)));
+        assert!(!filter.matches(&typing(3)));
+        assert!(filter.matches(&presence));
+
+        let filter = EventFilter {
+            chats: Some("2".to_string()),
+            types: Some("Ephemeral".to_string()),
+        }
+        .parse()?;
+        assert!(filter.matches(&typing(2)));
+        assert!(!filter.matches(&presence));
+
+        assert!(EventFilter::default().parse()?.matches(&typing(3)));
+        let invalid = EventFilter {
+            chats: Some("1,x".to_string()),
+            types: None,
+        };
+        assert!(matches!(invalid.parse(), Err(AppError::InvalidFilter(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn until_closing_should_send_queued_events_first() {
+        let (tx, rx) = watch::channel(false);
+        let queued = tokio_stream::iter(["a", "b"])
+            .map(|name| Event::default().event(name))
+            .chain(stream::pending());
+        let events = until_closing(queued, rx, 3000);
+        tx.send_replace(true);
+
+        let events: Vec<_> = events.map(|event| format!("{:?}", event)).collect().await;
+        assert_eq!(events.len(), 3);
+        assert!(events[0].contains("event: a"));
+        assert!(events[1].contains("event: b"));
+        assert!(events[2].contains("event: ServerClosing"));
+        assert!(events[2].contains("3000"));
+    }
 }